@@ -6,7 +6,7 @@ extern crate proc_macro;
 
 
 use syn::parse_macro_input;
-use syn::{Pat,PatType,ItemFn,FnArg,ReturnType};
+use syn::{Pat,PatType,ItemFn,ExprClosure,FnArg,ReturnType,Ident,Type};
 use syn::visit_mut::VisitMut;
 use quote::quote;
 
@@ -17,74 +17,65 @@ mod proposal;
 use proposal::ty_is_weak_trace_ref;
 
 
+/// Split the `(identifier, mutability, type)` of each argument of a generative function into
+/// a pattern destructuring its (tupled) args, and the type of the tupled args.
+///
+/// If the first argument is a `Weak` reference to a trace, the function is a proposal, and its remaining
+/// arguments are nested in their own tuple.
+fn args_tuple(args: Vec<(Ident,bool,Box<Type>)>) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let pattern = |(ident, is_mut): (&Ident, bool)| {
+        if is_mut {
+            quote! { mut #ident }
+        } else {
+            quote! { #ident }
+        }
+    };
+    if !args.is_empty() && ty_is_weak_trace_ref(&args[0].2) {
+        let trace_ident_token = pattern((&args[0].0, args[0].1));
+        let trace_ty = &args[0].2;
+        let proposal_arg_details = args[1..].iter().map(|(ident, is_mut, _)| pattern((ident, *is_mut))).collect::<Vec<_>>();
+        let proposal_arg_tys = args[1..].iter().map(|(_, _, ty)| ty).collect::<Vec<_>>();
+        (
+            quote! { (#trace_ident_token, (#(#proposal_arg_details),*)) },
+            quote! { (#trace_ty, (#(#proposal_arg_tys),*)) }
+        )
+    } else {
+        let arg_idents_tokens = args.iter().map(|(ident, is_mut, _)| pattern((ident, *is_mut))).collect::<Vec<_>>();
+        let arg_tys = args.iter().map(|(_, _, ty)| ty).collect::<Vec<_>>();
+        (
+            quote! { (#(#arg_idents_tokens),*) },
+            quote! { (#(#arg_tys),*) }
+        )
+    }
+}
+
+/// Extract the identifier, mutability and type of a typed argument pattern.
+fn typed_arg(pat_type: &PatType) -> (Ident, bool, Box<Type>) {
+    match *pat_type.pat {
+        Pat::Ident(ref pat_ident) => (pat_ident.ident.clone(), pat_ident.mutability.is_some(), pat_type.ty.clone()),
+        _ => panic!("Expected function arguments to have identifiers"),
+    }
+}
+
+/// Declare a `DynGenFn` with the modeling DSL, where `dist(args) %= addr` samples a choice and `gen_fn(args) /= addr` traces a call.
+///
+/// Given a function item `fn name(args) -> Ret { .. }`, declares a constant `DynGenFn` called `name`.
+/// Given a closure `move |args| -> Ret { .. }` (whose arguments must be typed), evaluates to a `DynGenFn`
+/// wrapping the closure, which can capture data from its environment.
 #[proc_macro]
 pub fn dyngen(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    // embed(TokenStream::from(input)).into()
+    if let Ok(closure) = syn::parse::<ExprClosure>(input.clone()) {
+        return dyngen_closure(closure).into();
+    }
     let input_fn = parse_macro_input!(input as ItemFn);
 
-    // Extracting types, identifiers, and mutability from the function arguments
-    let arg_details: Vec<_> = input_fn.sig.inputs.iter().map(|fn_arg| {
+    let args = input_fn.sig.inputs.iter().map(|fn_arg| {
         match fn_arg {
-            FnArg::Typed(PatType { pat, ty, .. }) => {
-                // Extract the identifier and check for mutability
-                let (ident, is_mut) = match **pat {
-                    Pat::Ident(ref pat_ident) => {
-                        (pat_ident.ident.clone(), pat_ident.mutability.is_some())
-                    },
-                    _ => panic!("Expected function arguments to have identifiers"),
-                };
-
-                // Extract the type
-                let arg_type = ty.clone();
-
-                (ident, is_mut, arg_type)
-            },
+            FnArg::Typed(pat_type) => typed_arg(pat_type),
             _ => panic!("Expected typed arguments"),
         }
-    }).collect();
-
-    // Unpacking the vectors of identifiers, mutabilities, and types
-    let (arg_idents, mutabilities, arg_tys): (Vec<_>, Vec<_>, Vec<_>) = arg_details.into_iter()
-        .fold((vec![], vec![], vec![]), |mut acc, (ident, is_mut, ty)| {
-            acc.0.push(ident);
-            acc.1.push(is_mut);
-            acc.2.push(ty);
-            acc
-        });
-    
-    let args_idents_tuple: proc_macro2::TokenStream; 
-    let args_ty_tuple: proc_macro2::TokenStream; 
-    if arg_tys.len() > 0 && ty_is_weak_trace_ref(&arg_tys[0]) {
-        let trace_ident = &arg_idents[0];
-        let trace_ty = &arg_tys[0];
-        let mut trace_ident_token = quote! { #trace_ident };
-        if mutabilities[0] {
-            trace_ident_token = quote! { mut #trace_ident };
-        }
-        
-        let proposal_arg_details = arg_idents.iter().zip(mutabilities.iter()).skip(1).map(|(ident, &is_mut)| {
-            if is_mut {
-                quote! { mut #ident }
-            } else {
-                quote! { #ident }
-            }
-        }).collect::<Vec<_>>();
-    
-        args_idents_tuple = quote! { (#trace_ident_token, (#(#proposal_arg_details),*)) };
-        let proposal_arg_tys = &arg_tys[1..].iter().collect::<Vec<_>>();
-        args_ty_tuple = quote! { (#trace_ty, (#(#proposal_arg_tys),*)) };
-    } else {
-        let arg_idents_tokens = arg_idents.iter().zip(mutabilities.iter()).map(|(ident, &is_mut)| {
-            if is_mut {
-                quote! { mut #ident }
-            } else {
-                quote! { #ident }
-            }
-        }).collect::<Vec<_>>();
-    
-        args_idents_tuple = quote! { (#(#arg_idents_tokens),*) };
-        args_ty_tuple = quote! { (#(#arg_tys),*) };
-    }
+    }).collect::<Vec<_>>();
+    let (args_idents_tuple, args_ty_tuple) = args_tuple(args);
 
     // Retrieve the return type
     let ret_ty = match input_fn.sig.output {
@@ -109,6 +100,33 @@ pub fn dyngen(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             let #args_idents_tuple: #args_ty_tuple = __args;
             #fn_body
         }
-        pub const #original_ident: #genfn_type = DynGenFn { func: #new_ident };
+        pub const #original_ident: #genfn_type = DynGenFn::new(#new_ident);
     }.into()
-}
\ No newline at end of file
+}
+
+fn dyngen_closure(closure: ExprClosure) -> proc_macro2::TokenStream {
+    let args = closure.inputs.iter().map(|pat| {
+        match pat {
+            Pat::Type(pat_type) => typed_arg(pat_type),
+            _ => panic!("Expected typed closure arguments"),
+        }
+    }).collect::<Vec<_>>();
+    let (args_idents_tuple, args_ty_tuple) = args_tuple(args);
+
+    // the return type may be left to inference
+    let ret_ty = match closure.output {
+        ReturnType::Default => quote! { _ },
+        ReturnType::Type(_, ref ty) => quote! { #ty },
+    };
+
+    let capture = closure.capture;
+    let mut body = *closure.body;
+    ReplaceAddressedCalls.visit_expr_mut(&mut body);
+
+    quote! {
+        DynGenFn::from_fn(#capture |__g: &mut DynGenFnHandler<#args_ty_tuple, #ret_ty>, __args: #args_ty_tuple| -> #ret_ty {
+            let #args_idents_tuple: #args_ty_tuple = __args;
+            #body
+        })
+    }
+}
@@ -0,0 +1,369 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add,Sub,Mul,Div,Neg,AddAssign,SubAssign,MulAssign,DivAssign};
+use std::sync::atomic::{AtomicU32,Ordering};
+use rand::Rng;
+use nalgebra::{DVector,DMatrix};
+use crate::{Addr,GenFnError};
+use crate::modeling::dists::{self,Distribution,DifferentiableDistribution};
+
+
+/// A scalar whose computation is recorded on the active gradient tape, so that gradients can be taken with respect to it.
+///
+/// A tape is only active while a `DynGenFn` takes gradients (eg. in `choice_gradients`). Outside of it, and for `Var`s
+/// recorded on another tape, a `Var` is a constant: arithmetic on it behaves exactly like arithmetic on its `value`.
+#[derive(Clone,Copy,Debug)]
+pub struct Var {
+    value: f64,
+    tape: u32,  // 0 for constants
+    index: u32
+}
+
+struct Tape {
+    id: u32,
+    /// Partial derivative of each node with respect to each of its parents, node after node.
+    partials: Vec<(u32,f64)>,
+    /// End of the partials of each node.
+    ends: Vec<usize>,
+    /// Leaves of the parameters read from a `ParamStore`.
+    params: Vec<ParamLeaves>
+}
+
+struct ParamLeaves {
+    store: usize,
+    name: Addr,
+    nodes: Vec<u32>
+}
+
+static NEXT_TAPE: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static TAPE: RefCell<Option<Tape>> = const { RefCell::new(None) };
+}
+
+impl Tape {
+    fn push(&mut self, value: f64, partials: impl IntoIterator<Item = (Var,f64)>) -> Var {
+        let id = self.id;
+        self.partials.extend(partials.into_iter().filter(|(x, _)| x.tape == id).map(|(x, d)| (x.index, d)));
+        self.ends.push(self.partials.len());
+        Var { value, tape: id, index: (self.ends.len() - 1) as u32 }
+    }
+
+    fn backward(self, output: Var) -> Adjoints {
+        let mut adjoints = vec![0.; self.ends.len()];
+        if output.tape == self.id {
+            adjoints[output.index as usize] = 1.;
+            for i in (0..=output.index as usize).rev() {
+                let adjoint = adjoints[i];
+                if adjoint == 0. { continue; }
+                let start = if i == 0 { 0 } else { self.ends[i-1] };
+                for &(parent, d) in &self.partials[start..self.ends[i]] {
+                    adjoints[parent as usize] += adjoint * d;
+                }
+            }
+        }
+        Adjoints { tape: self.id, adjoints, params: self.params }
+    }
+}
+
+/// Restores the previously active tape, even if the recorded function panics.
+struct TapeGuard(Option<Option<Tape>>);
+
+impl Drop for TapeGuard {
+    fn drop(&mut self) {
+        if let Some(outer) = self.0.take() {
+            TAPE.with(|tape| tape.replace(outer));
+        }
+    }
+}
+
+/// Run `f` on a fresh tape, then take the gradient of the `Var` it returns with respect to every node of the tape.
+pub(crate) fn gradients<R>(f: impl FnOnce() -> Result<(Var,R),GenFnError>) -> Result<(R,Adjoints),GenFnError> {
+    let id = NEXT_TAPE.fetch_add(1, Ordering::Relaxed);
+    let tape = Tape { id, partials: vec![], ends: vec![], params: vec![] };
+    let mut guard = TapeGuard(Some(TAPE.with(|active| active.replace(Some(tape)))));
+    let result = f();
+    let tape = TAPE.with(|active| active.replace(guard.0.take().unwrap())).unwrap();
+    let (output, r) = result?;
+    Ok((r, tape.backward(output)))
+}
+
+/// Record a leaf for each coordinate of the parameter `name` of the `store` (if a tape is active).
+pub(crate) fn param_leaves(store: usize, name: &Addr, coords: &[f64]) -> Vec<Var> {
+    TAPE.with(|active| match active.borrow_mut().as_mut() {
+        Some(tape) => {
+            let leaves = coords.iter().map(|&x| tape.push(x, [])).collect::<Vec<_>>();
+            tape.params.push(ParamLeaves { store, name: name.clone(), nodes: leaves.iter().map(|x| x.index).collect() });
+            leaves
+        }
+        None => coords.iter().map(|&x| Var::constant(x)).collect()
+    })
+}
+
+
+/// Gradient of the output of a recorded computation with respect to the `Var`s it was computed from.
+pub struct Adjoints {
+    tape: u32,
+    adjoints: Vec<f64>,
+    params: Vec<ParamLeaves>
+}
+
+impl Adjoints {
+    /// Return the gradient with respect to `x` (zero if `x` wasn't recorded on the same tape).
+    pub fn of(&self, x: &Var) -> f64 {
+        if x.tape == self.tape { self.adjoints[x.index as usize] } else { 0. }
+    }
+
+    /// Return the gradient with respect to each parameter of the `store` read during the computation.
+    pub(crate) fn params(&self, store: usize) -> impl Iterator<Item = (&Addr,Vec<f64>)> {
+        self.params.iter()
+            .filter(move |leaves| leaves.store == store)
+            .map(|leaves| (&leaves.name, leaves.nodes.iter().map(|&i| self.adjoints[i as usize]).collect()))
+    }
+}
+
+
+impl Var {
+    /// Construct a constant.
+    pub const fn constant(value: f64) -> Self {
+        Var { value, tape: 0, index: 0 }
+    }
+
+    /// Construct an independent variable: a leaf of the active tape, or a constant if there is none.
+    pub(crate) fn leaf(value: f64) -> Self {
+        TAPE.with(|active| match active.borrow_mut().as_mut() {
+            Some(tape) => tape.push(value, []),
+            None => Var::constant(value)
+        })
+    }
+
+    /// Construct the result of a differentiable function, with the given `value` and
+    /// partial derivatives with respect to each of its inputs (eg. to implement `Distribution::logpdf_var`).
+    pub fn from_partials(value: f64, partials: &[(Var,f64)]) -> Self {
+        TAPE.with(|active| match active.borrow_mut().as_mut() {
+            Some(tape) if partials.iter().any(|(x, _)| x.tape == tape.id) => tape.push(value, partials.iter().copied()),
+            _ => Var::constant(value)
+        })
+    }
+
+    /// Return the value.
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// Return `e^self`.
+    pub fn exp(self) -> Var {
+        let value = self.value.exp();
+        Var::unary(value, self, value)
+    }
+
+    /// Return the natural logarithm of `self`.
+    pub fn ln(self) -> Var {
+        Var::unary(self.value.ln(), self, 1. / self.value)
+    }
+
+    /// Return the square root of `self`.
+    pub fn sqrt(self) -> Var {
+        let value = self.value.sqrt();
+        Var::unary(value, self, 0.5 / value)
+    }
+
+    /// Return `self` to the integer power `n`.
+    pub fn powi(self, n: i32) -> Var {
+        Var::unary(self.value.powi(n), self, n as f64 * self.value.powi(n - 1))
+    }
+
+    /// Return `self` to the power `n`.
+    pub fn powf(self, n: f64) -> Var {
+        Var::unary(self.value.powf(n), self, n * self.value.powf(n - 1.))
+    }
+
+    /// Return the sine of `self` (in radians).
+    pub fn sin(self) -> Var {
+        Var::unary(self.value.sin(), self, self.value.cos())
+    }
+
+    /// Return the cosine of `self` (in radians).
+    pub fn cos(self) -> Var {
+        Var::unary(self.value.cos(), self, -self.value.sin())
+    }
+
+    /// Return the hyperbolic tangent of `self`.
+    pub fn tanh(self) -> Var {
+        let value = self.value.tanh();
+        Var::unary(value, self, 1. - value * value)
+    }
+
+    /// Return the absolute value of `self`.
+    pub fn abs(self) -> Var {
+        Var::unary(self.value.abs(), self, self.value.signum())
+    }
+
+    fn unary(value: f64, x: Var, d: f64) -> Var {
+        if x.tape == 0 { Var::constant(value) } else { Var::from_partials(value, &[(x, d)]) }
+    }
+
+    fn binary(value: f64, x: Var, dx: f64, y: Var, dy: f64) -> Var {
+        if x.tape == 0 && y.tape == 0 { Var::constant(value) } else { Var::from_partials(value, &[(x, dx), (y, dy)]) }
+    }
+}
+
+impl From<f64> for Var {
+    fn from(value: f64) -> Self {
+        Var::constant(value)
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl PartialEq for Var {
+    fn eq(&self, other: &Var) -> bool {
+        self.value == other.value
+    }
+}
+
+impl PartialOrd for Var {
+    fn partial_cmp(&self, other: &Var) -> Option<std::cmp::Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+impl Neg for Var {
+    type Output = Var;
+    fn neg(self) -> Var {
+        Var::unary(-self.value, self, -1.)
+    }
+}
+
+impl Add for Var {
+    type Output = Var;
+    fn add(self, rhs: Var) -> Var {
+        Var::binary(self.value + rhs.value, self, 1., rhs, 1.)
+    }
+}
+
+impl Sub for Var {
+    type Output = Var;
+    fn sub(self, rhs: Var) -> Var {
+        Var::binary(self.value - rhs.value, self, 1., rhs, -1.)
+    }
+}
+
+impl Mul for Var {
+    type Output = Var;
+    fn mul(self, rhs: Var) -> Var {
+        Var::binary(self.value * rhs.value, self, rhs.value, rhs, self.value)
+    }
+}
+
+impl Div for Var {
+    type Output = Var;
+    fn div(self, rhs: Var) -> Var {
+        let value = self.value / rhs.value;
+        Var::binary(value, self, 1. / rhs.value, rhs, -value / rhs.value)
+    }
+}
+
+/// Implement an arithmetic operator between `Var` and `f64` (in both orders), and its assignment operator.
+macro_rules! impl_mixed_op {
+    ($op:ident, $method:ident, $op_assign:ident, $method_assign:ident) => {
+        impl $op<f64> for Var {
+            type Output = Var;
+            fn $method(self, rhs: f64) -> Var { self.$method(Var::constant(rhs)) }
+        }
+
+        impl $op<Var> for f64 {
+            type Output = Var;
+            fn $method(self, rhs: Var) -> Var { Var::constant(self).$method(rhs) }
+        }
+
+        impl $op_assign<Var> for Var {
+            fn $method_assign(&mut self, rhs: Var) { *self = (*self).$method(rhs); }
+        }
+
+        impl $op_assign<f64> for Var {
+            fn $method_assign(&mut self, rhs: f64) { *self = (*self).$method(rhs); }
+        }
+    };
+}
+
+impl_mixed_op!(Add, add, AddAssign, add_assign);
+impl_mixed_op!(Sub, sub, SubAssign, sub_assign);
+impl_mixed_op!(Mul, mul, MulAssign, mul_assign);
+impl_mixed_op!(Div, div, DivAssign, div_assign);
+
+impl Sum for Var {
+    fn sum<I: Iterator<Item = Var>>(iter: I) -> Var {
+        iter.fold(Var::constant(0.), |total, x| total + x)
+    }
+}
+
+
+/// A differentiable distribution over `Var`s (with `Var` or `f64` parameters), whose log density is recorded on the
+/// active tape by chaining the distribution's `logpdf_grad`.
+///
+/// Sampling from a tracked distribution (eg. `ad::normal(mu, 1.) %= "x"`) is what makes a choice, and the parameters
+/// it depends on, differentiable in `choice_gradients`, `arg_gradients` and `accumulate_param_gradients`.
+/// The choices are still stored in traces by value (as `f64` or `DVector<f64>`).
+#[derive(Clone,Copy)]
+pub struct Tracked<D>(pub D);
+
+/// Gaussian distribution over `Var`s.
+pub const normal: Tracked<dists::Normal> = Tracked(dists::normal);
+
+/// Gamma distribution over `Var`s.
+pub const gamma: Tracked<dists::Gamma> = Tracked(dists::gamma);
+
+/// Beta distribution over `Var`s.
+pub const beta: Tracked<dists::Beta> = Tracked(dists::beta);
+
+/// Multivariate Gaussian distribution over vectors of `Var`s, with a constant covariance.
+pub const mvnormal: Tracked<dists::MvNormal> = Tracked(dists::mvnormal);
+
+impl<D: DifferentiableDistribution<f64,(f64,f64)>, A: Into<Var>, B: Into<Var>> Distribution<Var,(A,B)> for Tracked<D> {
+    fn logpdf(&self, x: &Var, params: (A,B)) -> f64 {
+        let (a, b): (Var, Var) = (params.0.into(), params.1.into());
+        self.0.logpdf(&x.value, (a.value, b.value))
+    }
+
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (A,B)) -> Var {
+        let (a, b): (Var, Var) = (params.0.into(), params.1.into());
+        Var::constant(self.0.random(rng, (a.value, b.value)))
+    }
+
+    fn logpdf_var(&self, x: &Var, params: (A,B)) -> Var {
+        let (a, b): (Var, Var) = (params.0.into(), params.1.into());
+        let logp = self.0.logpdf(&x.value, (a.value, b.value));
+        let (x_grad, (a_grad, b_grad)) = self.0.logpdf_grad(&x.value, (a.value, b.value));
+        Var::from_partials(logp, &[(*x, x_grad), (a, a_grad), (b, b_grad)])
+    }
+}
+
+impl<D: DifferentiableDistribution<DVector<f64>,(DVector<f64>,DMatrix<f64>)>> Distribution<DVector<Var>,(DVector<Var>,DMatrix<f64>)> for Tracked<D> {
+    fn logpdf(&self, x: &DVector<Var>, params: (DVector<Var>,DMatrix<f64>)) -> f64 {
+        let (mu, cov) = params;
+        self.0.logpdf(&x.map(|x| x.value), (mu.map(|x| x.value), cov))
+    }
+
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (DVector<Var>,DMatrix<f64>)) -> DVector<Var> {
+        let (mu, cov) = params;
+        self.0.random(rng, (mu.map(|x| x.value), cov)).map(Var::constant)
+    }
+
+    fn logpdf_var(&self, x: &DVector<Var>, params: (DVector<Var>,DMatrix<f64>)) -> Var {
+        let (mu, cov) = params;
+        let (x_value, mu_value) = (x.map(|x| x.value), mu.map(|x| x.value));
+        let logp = self.0.logpdf(&x_value, (mu_value.clone(), cov.clone()));
+        let (x_grad, (mu_grad, _)) = self.0.logpdf_grad(&x_value, (mu_value, cov));
+        let partials = x.iter().zip(x_grad.iter())
+            .chain(mu.iter().zip(mu_grad.iter()))
+            .map(|(&x, &d)| (x, d))
+            .collect::<Vec<_>>();
+        Var::from_partials(logp, &partials)
+    }
+}
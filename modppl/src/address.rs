@@ -1,9 +1,287 @@
-use regex::Regex;
-use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+use std::convert::Infallible;
+use std::str::FromStr;
 use std::collections::{HashMap, hash_map};
 
 
+/// A single component of an `Addr`.
+/// 
+/// Names and tuples are reference-counted, so cloning a key (eg. into a `Trie`) doesn't reallocate it.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum AddrKey {
+    /// A named component, eg. `"coeffs"`.
+    Str(Arc<str>),
+
+    /// An integer component, eg. `3`.
+    Int(i64),
+
+    /// A tuple of components, eg. `("y", 3)`.
+    Tuple(Arc<[AddrKey]>)
+}
+
+impl AddrKey {
+    /// Construct an `AddrKey::Str` from `name` as is, without parsing it as an integer, a tuple or a path.
+    /// 
+    /// Note that `AddrKey::name("3")` differs from `AddrKey::from("3")`, which is an `AddrKey::Int`.
+    pub fn name(name: impl Into<Arc<str>>) -> Self {
+        AddrKey::Str(name.into())
+    }
+
+    /// Parse a single component of a string address.
+    /// 
+    /// Parenthesized, comma-separated components (eg. `"(y, 3)"`) become an `AddrKey::Tuple`,
+    /// components that parse as integers become an `AddrKey::Int`, and anything else becomes an `AddrKey::Str`.
+    pub fn parse(key: &str) -> Self {
+        let key = key.trim();
+        match key.strip_prefix('(').and_then(|k| k.strip_suffix(')')) {
+            Some(inner) if is_balanced(inner) => {
+                if inner.trim().is_empty() {
+                    AddrKey::Tuple(Arc::new([]))
+                } else {
+                    AddrKey::Tuple(split_top_level(inner, ',').into_iter().map(AddrKey::parse).collect())
+                }
+            }
+            _ => { AddrKey::from(key) }
+        }
+    }
+}
+
+impl fmt::Display for AddrKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrKey::Str(s) => { write!(f, "{}", s) }
+            AddrKey::Int(n) => { write!(f, "{}", n) }
+            AddrKey::Tuple(keys) => {
+                write!(f, "(")?;
+                for (i, key) in keys.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", key)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl From<&str> for AddrKey {
+    fn from(key: &str) -> Self {
+        match key.parse::<i64>() {
+            Ok(n) => AddrKey::Int(n),
+            Err(_) => AddrKey::name(key)
+        }
+    }
+}
+
+impl From<String> for AddrKey {
+    fn from(key: String) -> Self {
+        match key.parse::<i64>() {
+            Ok(n) => AddrKey::Int(n),
+            Err(_) => AddrKey::name(key)
+        }
+    }
+}
+
+impl From<&String> for AddrKey {
+    fn from(key: &String) -> Self {
+        AddrKey::from(key.as_str())
+    }
+}
+
+macro_rules! impl_from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for AddrKey {
+                /// Panics if `n` doesn't fit in an `i64`.
+                fn from(n: $t) -> Self {
+                    AddrKey::Int(i64::try_from(n).expect("integer address components must fit in an i64"))
+                }
+            }
+
+            impl From<$t> for Addr {
+                fn from(n: $t) -> Self {
+                    Addr(vec![AddrKey::from(n)])
+                }
+            }
+        )*
+    };
+}
+impl_from_int!(i32, i64, u32, u64, usize);
+
+impl<A: Into<AddrKey>,B: Into<AddrKey>> From<(A,B)> for AddrKey {
+    fn from((a, b): (A,B)) -> Self {
+        AddrKey::Tuple(Arc::new([a.into(), b.into()]))
+    }
+}
+
+impl<A: Into<AddrKey>,B: Into<AddrKey>,C: Into<AddrKey>> From<(A,B,C)> for AddrKey {
+    fn from((a, b, c): (A,B,C)) -> Self {
+        AddrKey::Tuple(Arc::new([a.into(), b.into(), c.into()]))
+    }
+}
+
+
+/// A path of `AddrKey` components, identifying a value in a `Trie` or an `AddrMap`.
+/// 
+/// Any type that converts into an `Addr` can be used as an address, eg. `"x"`, `3`, `("y", 3)`, or a `"coeffs / (y, 3)"` string.
+/// Strings are parsed as a compatibility layer: components are separated by `/`, and each is parsed with `AddrKey::parse`.
+/// Structured addresses skip this parsing, though their names and tuples are still allocated: a name built once
+/// with `AddrKey::name` (eg. before a loop) is shared by its clones, and also keeps names that shouldn't be parsed (eg. containing `/`) as is.
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Default)]
+pub struct Addr(Vec<AddrKey>);
+
+impl Addr {
+    /// Construct an empty `Addr`.
+    pub fn new() -> Self {
+        Addr(vec![])
+    }
+
+    /// Parse a string address containing some number of `/` separators into an `Addr`.
+    pub fn parse(addr: &str) -> Self {
+        Addr(split_top_level(addr, '/').into_iter().map(AddrKey::parse).collect())
+    }
+
+    /// Return the components of `self`, from the root down.
+    pub fn keys(&self) -> &[AddrKey] {
+        &self.0
+    }
+
+    /// Append a component to the end of `self`.
+    pub fn push(&mut self, key: impl Into<AddrKey>) {
+        self.0.push(key.into());
+    }
+
+    /// Remove and return the last component of `self`, if any.
+    pub fn pop(&mut self) -> Option<AddrKey> {
+        self.0.pop()
+    }
+
+    /// Return the number of components in `self`.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Return `true` if `self` has no components, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " / ")?;
+            }
+            write!(f, "{}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Addr {
+    type Err = Infallible;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        Ok(Addr::parse(addr))
+    }
+}
+
+impl From<&str> for Addr {
+    fn from(addr: &str) -> Self {
+        Addr::parse(addr)
+    }
+}
+
+impl From<&String> for Addr {
+    fn from(addr: &String) -> Self {
+        Addr::parse(addr)
+    }
+}
+
+impl From<String> for Addr {
+    fn from(addr: String) -> Self {
+        Addr::parse(&addr)
+    }
+}
+
+impl From<&Addr> for Addr {
+    fn from(addr: &Addr) -> Self {
+        addr.clone()
+    }
+}
+
+impl From<AddrKey> for Addr {
+    fn from(key: AddrKey) -> Self {
+        Addr(vec![key])
+    }
+}
+
+impl From<&AddrKey> for Addr {
+    fn from(key: &AddrKey) -> Self {
+        Addr(vec![key.clone()])
+    }
+}
+
+impl From<Vec<AddrKey>> for Addr {
+    fn from(keys: Vec<AddrKey>) -> Self {
+        Addr(keys)
+    }
+}
+
+impl<A: Into<AddrKey>,B: Into<AddrKey>> From<(A,B)> for Addr {
+    fn from(key: (A,B)) -> Self {
+        Addr(vec![key.into()])
+    }
+}
+
+impl<A: Into<AddrKey>,B: Into<AddrKey>,C: Into<AddrKey>> From<(A,B,C)> for Addr {
+    fn from(key: (A,B,C)) -> Self {
+        Addr(vec![key.into()])
+    }
+}
+
+/// Split `s` on each `sep` that isn't enclosed by parentheses.
+fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '(' {
+            depth += 1;
+        } else if c == ')' {
+            depth -= 1;
+        } else if c == sep && depth == 0 {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Return `true` if no prefix of `s` closes more parentheses than it opens.
+fn is_balanced(s: &str) -> bool {
+    let mut depth = 0;
+    for c in s.chars() {
+        if c == '(' {
+            depth += 1;
+        } else if c == ')' {
+            depth -= 1;
+            if depth < 0 {
+                return false;
+            }
+        }
+    }
+    depth == 0
+}
+
+
 /// Enum representing possible parse variants for an address that contain some number of `/` separators.
+/// 
+/// Retained as a compatibility layer for string addresses; `Trie` and `AddrMap` use `Addr` instead.
 #[derive(Debug,PartialEq,Eq,Hash)]
 pub enum SplitAddr<'a> {
     /// Resultant type from a parse of `(addr)`.
@@ -14,29 +292,22 @@ pub enum SplitAddr<'a> {
 }
 use SplitAddr::{Prefix,Term};
 
-thread_local!(
-    /// Regex spec for address parsing.
-    static ADDR_RE: RefCell<Regex> = RefCell::new(Regex::new(r"^(.*?)/(.*)$").ok().unwrap())
-);
-
 impl<'a> SplitAddr<'a> {
     /// Parse a string address containing some number of `/` separators into a `SplitAddr` variant.
     pub fn from_addr(addr: &'a str) -> Self {
-        match ADDR_RE.with(|re| re.borrow().captures(&addr)) {
+        match addr.split_once('/') {
             None => {
-                Term(addr.trim_start().trim_end())
+                Term(addr.trim())
             },
-            Some(caps) => {
-                let first: &str = caps.get(1).unwrap().into();
-                let rest: &str = caps.get(2).unwrap().into();
-                Prefix(first.trim_start().trim_end(), rest)
+            Some((first, rest)) => {
+                Prefix(first.trim(), rest)
             }
         }
     }
 }
 
 /// Normalize whitespace between `/` separators in an `addr` to contain one space to the left and right of each separator.
-pub fn normalize_addr<'a>(addr: &'a str) -> String {
+pub fn normalize_addr(addr: &str) -> String {
     match SplitAddr::from_addr(addr) {
         Term(s) => {
             s.to_string()
@@ -48,9 +319,9 @@ pub fn normalize_addr<'a>(addr: &'a str) -> String {
 }
 
 
-/// A map of strings representing a mask.
+/// A map of addresses representing a mask.
 #[derive(Debug, Clone, PartialEq)]
-pub struct AddrMap(HashMap<String,AddrMap>);
+pub struct AddrMap(HashMap<AddrKey,AddrMap>);
 
 impl AddrMap {
     /// Construct an empty `AddrMap`.
@@ -64,33 +335,46 @@ impl AddrMap {
     }
 
     /// Return some reference to a descendant at `addr` if present, otherwise none.
-    pub fn search(&self, addr: &str) -> Option<&AddrMap> {
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                self.0.get(addr)
-            }
-            Prefix(first, rest) => {
-                match self.0.get(first) {
-                    Some(submask) => {
-                        submask.search(rest)
-                    }
-                    None => { None }
-                }
-            }
+    pub fn search(&self, addr: impl Into<Addr>) -> Option<&AddrMap> {
+        self.search_keys(addr.into().keys())
+    }
+
+    pub(crate) fn search_keys(&self, keys: &[AddrKey]) -> Option<&AddrMap> {
+        let (first, rest) = keys.split_first()?;
+        let submask = self.0.get(first)?;
+        if rest.is_empty() {
+            Some(submask)
+        } else {
+            submask.search_keys(rest)
         }
     }
 
-    /// Insert a descendant `sub` at `addr`. Panics if `addr` is occupied.
-    pub fn insert(&mut self, addr: &str, sub: AddrMap) {
-        self.0.insert(addr.to_string(), sub);
+    /// Insert a descendant `sub` at `addr`, replacing any previous descendant.
+    pub fn insert(&mut self, addr: impl Into<Addr>, sub: AddrMap) {
+        self.insert_keys(addr.into().keys(), sub)
+    }
+
+    fn insert_keys(&mut self, keys: &[AddrKey], sub: AddrMap) {
+        match keys.split_first() {
+            None => { }
+            Some((first, [])) => {
+                self.0.insert(first.clone(), sub);
+            }
+            Some((first, rest)) => {
+                self.0
+                    .entry(first.clone())
+                    .or_default()
+                    .insert_keys(rest, sub);
+            }
+        }
     }
 
     /// Return `true` if for every address in `other`,
     /// `self` visited either that address or its ancestor,
     /// otherwise `false`.
     pub fn all_visited(&self, other: &AddrMap) -> bool {
-        for (addr, sub) in other.iter() {
-            if let Some(subvisitor) = self.search(&addr) {
+        for (key, sub) in other.iter() {
+            if let Some(subvisitor) = self.0.get(key) {
                 if !subvisitor.is_leaf() && !subvisitor.all_visited(sub) {
                     return false;
                 }
@@ -98,22 +382,22 @@ impl AddrMap {
                 return false;
             }
         }
-        return true;
+        true
     }
 
     /// Add an `addr` to `self`.
-    pub fn visit(&mut self, addr: &str) {
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                self.0
-                    .entry(addr.to_string())
-                    .or_insert(AddrMap::new());
-            }
-            Prefix(first, rest) => {
-                let submask = self.0
-                    .entry(first.to_string())
-                    .or_insert(AddrMap::new());
-                submask.visit(rest);
+    pub fn visit(&mut self, addr: impl Into<Addr>) {
+        self.visit_keys(addr.into().keys())
+    }
+
+    pub(crate) fn visit_keys(&mut self, keys: &[AddrKey]) {
+        if let Some((first, rest)) = keys.split_first() {
+            if let Some(submask) = self.0.get_mut(first) {
+                submask.visit_keys(rest);
+            } else {
+                let mut submask = AddrMap::new();
+                submask.visit_keys(rest);
+                self.0.insert(first.clone(), submask);
             }
         }
     }
@@ -121,16 +405,16 @@ impl AddrMap {
     /// Get the complement of `mask` in `self`.
     pub fn complement(&self, mask: &Self) -> Self {
         let mut cmap = AddrMap::new();
-        for (addr, sub) in self.iter() {
-            match mask.search(addr) {
+        for (key, sub) in self.iter() {
+            match mask.0.get(key) {
                 None => {
-                    cmap.visit(addr);
+                    cmap.0.insert(key.clone(), AddrMap::new());
                 }
                 Some(submask) => {
                     if !sub.is_leaf() && !submask.is_leaf() {
                         let subcomplement = sub.complement(submask);
                         if !subcomplement.is_leaf() {
-                            cmap.insert(addr, subcomplement);
+                            cmap.0.insert(key.clone(), subcomplement);
                         }
                     }
                 }
@@ -139,12 +423,31 @@ impl AddrMap {
         cmap
     }
 
+    /// Return the (normalized) addresses of all leaf descendants of `self`.
+    pub fn leaves(&self) -> Vec<String> {
+        let mut addrs = vec![];
+        for (key, sub) in self.iter() {
+            if sub.is_leaf() {
+                addrs.push(key.to_string());
+            } else {
+                addrs.extend(sub.leaves().into_iter().map(|rest| format!("{} / {}", key, rest)));
+            }
+        }
+        addrs
+    }
+
     /// Iterate through the _direct_ descendants of `self`.
-    pub fn iter(&self) -> hash_map::Iter<'_, String, AddrMap> {
+    pub fn iter(&self) -> hash_map::Iter<'_, AddrKey, AddrMap> {
         self.0.iter()
     }
 }
 
+impl Default for AddrMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[test]
 fn test_split_addr() {
     let key = SplitAddr::from_addr("test");
@@ -171,4 +474,19 @@ fn test_split_addr() {
     let normalized_addr = "1 / 21f23 / 432 / 132 / (  y?A1 , grexxy )";
     assert_eq!(normalize_addr(hard_addr), normalized_addr);
     assert_eq!(normalize_addr(equiv_addr), normalized_addr);
-}
\ No newline at end of file
+}
+#[test]
+fn test_parse_addr() {
+    let addr = Addr::parse(" coeffs/ (y, 3) /2 ");
+    assert_eq!(addr.keys(), &[AddrKey::name("coeffs"), AddrKey::from(("y", 3)), AddrKey::Int(2)]);
+    assert_eq!(addr.to_string(), "coeffs / (y, 3) / 2");
+    assert_eq!(Addr::parse(&addr.to_string()), addr);
+
+    assert_eq!(Addr::from(("y", 3_usize)), Addr::from("(y, 3)"));
+    assert_eq!(Addr::from(7), Addr::from(String::from("7")));
+    assert_eq!(Addr::from(("a", (1, 2))), Addr::from("(a, (1, 2))"));
+
+    let hard_addr = " 1/ 21f23/432 / 132  /   (  y?A1 , grexxy )   ";
+    assert_eq!(Addr::parse(hard_addr).to_string(), "1 / 21f23 / 432 / 132 / (y?A1, grexxy)");
+    assert_eq!(Addr::parse("(a), (b)").keys(), &[AddrKey::name("(a), (b)")]);
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use crate::ArgDiff;
+
+
+/// Error raised when a generative function (or one of the data structures it records into) can't complete an operation.
+#[derive(Debug,Clone,PartialEq)]
+pub enum GenFnError {
+    /// Some constraints were not visited by the generative function, listed by their addresses.
+    UnconsumedConstraints(Vec<String>),
+
+    /// The value at `addr` could not be downcast into the `expected` type.
+    TypeMismatch {
+        /// The address of the value.
+        addr: String,
+        /// The name of the type the value was expected to have.
+        expected: &'static str
+    },
+
+    /// Attempted to put into an address that was already occupied.
+    AddressCollision(String),
+
+    /// No value was found at an address.
+    AddressNotFound(String),
+
+    /// The generative function can't handle this kind of change to its arguments.
+    UnsupportedArgDiff(ArgDiff),
+
+    /// The arguments (or constraints) are inconsistent with each other, or with the trace, for the reason given.
+    InvalidArgs(String),
+
+    /// The generative function does not implement the named GFI method.
+    Unimplemented(&'static str)
+}
+
+impl fmt::Display for GenFnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenFnError::UnconsumedConstraints(addrs) => {
+                write!(f, "not all constraints were consumed (residual addresses: {:?})", addrs)
+            }
+            GenFnError::TypeMismatch { addr, expected } => {
+                write!(f, "failed when downcasting to type {} at address \"{}\"", expected, addr)
+            }
+            GenFnError::AddressCollision(addr) => {
+                write!(f, "attempted to put into occupied address \"{}\"", addr)
+            }
+            GenFnError::AddressNotFound(addr) => {
+                write!(f, "failed when searching empty address \"{}\"", addr)
+            }
+            GenFnError::UnsupportedArgDiff(diff) => {
+                write!(f, "{:?} not supported", diff)
+            }
+            GenFnError::InvalidArgs(reason) => {
+                write!(f, "invalid arguments: {}", reason)
+            }
+            GenFnError::Unimplemented(method) => {
+                write!(f, "{}: impl not found", method)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GenFnError { }
\ No newline at end of file
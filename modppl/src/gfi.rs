@@ -1,4 +1,7 @@
-use crate::AddrMap;
+use rand::RngCore;
+use nalgebra::{DVector,DMatrix};
+use crate::{AddrMap,GenFnError,ParamStore,Var};
+use crate::ad::Adjoints;
 
 /// Representation of the probabilistic execution of a `GenFn`.
 #[derive(Clone)]
@@ -46,47 +49,166 @@ impl<Args,Data,Ret> Trace<Args,Data,Ret> {
 /// `trace.data` refers to all random variables, while `constraints` more precisely
 /// refers to an observed subset of the `trace.data`. `args` and `impl Data`
 /// are respectively the parameterizing arguments and implementing `Data` type.
+/// 
+/// Every method draws its randomness from an explicit `rng`, so that passing
+/// a seeded generator (eg. `StdRng::seed_from_u64`) makes an execution reproducible.
+/// 
+/// Implementors provide the fallible `try_` methods, which return a `GenFnError` instead
+/// of panicking. The methods without the `try_` prefix are convenience wrappers that panic on error.
 pub trait GenFn<Args,Data,Ret> {
 
     /// Execute the generative function and return a sampled trace.
-    fn simulate(&self, args: Args) -> Trace<Args,Data,Ret>;
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: Args) -> Result<Trace<Args,Data,Ret>, GenFnError>;
 
     /// Execute the generative function consistent with `constraints`.
-    fn generate(&self, args: Args, constraints: Data) -> (Trace<Args,Data,Ret>, f64);
+    fn try_generate(&self, rng: &mut dyn RngCore, args: Args, constraints: Data) -> Result<(Trace<Args,Data,Ret>, f64), GenFnError>;
 
     /// Update a trace.
-    fn update(&self,
+    /// 
+    /// Along with the new trace, the backward choices and the weight, return a _retdiff_
+    /// describing how the return value changed (`ArgDiff::NoChange` if it's provably unchanged).
+    fn try_update(&self,
+        rng: &mut dyn RngCore,
         trace: Trace<Args,Data,Ret>,
         args: Args,
         diff: ArgDiff,
-        constraints: Data                    // Data := forward choices
-    ) -> (Trace<Args,Data,Ret>, Data, f64);  // Data := backward choices
+        constraints: Data                                              // Data := forward choices
+    ) -> Result<(Trace<Args,Data,Ret>, Data, f64, ArgDiff), GenFnError>;  // Data := backward choices
 
     /// Regenerate a masked subset of a trace.
+    /// 
+    /// Along with the new trace and the weight, return a _retdiff_
+    /// describing how the return value changed (`ArgDiff::NoChange` if it's provably unchanged).
+    fn try_regenerate(&self,
+        _rng: &mut dyn RngCore,
+        _trace: Trace<Args,Data,Ret>,
+        _args: Args,
+        _diff: ArgDiff,
+        _mask: &AddrMap
+    ) -> Result<(Trace<Args,Data,Ret>, f64, ArgDiff), GenFnError> {
+        Err(GenFnError::Unimplemented("regenerate"))
+    }
+
+    /// Return the gradient of `trace.logjp` with respect to the continuous choices in `trace` selected by `selection`.
+    /// 
+    /// The gradients are returned in a `Data` with the same structure as `trace.data`, restricted to the selected addresses.
+    /// The `trace` itself is left unchanged.
+    fn try_choice_gradients(&self,
+        _rng: &mut dyn RngCore,
+        _trace: &Trace<Args,Data,Ret>,
+        _selection: &AddrMap
+    ) -> Result<Data, GenFnError> {
+        Err(GenFnError::Unimplemented("choice_gradients"))
+    }
+
+    /// Return the gradient of `trace.logjp` with respect to `trace.args`.
+    fn try_arg_gradients(&self,
+        _rng: &mut dyn RngCore,
+        _trace: &Trace<Args,Data,Ret>
+    ) -> Result<Args, GenFnError> where Args: Differentiable {
+        Err(GenFnError::Unimplemented("arg_gradients"))
+    }
+
+    /// Add `scale` times the gradient of `trace.logjp` with respect to the parameters in `store`
+    /// to the gradients accumulated in `store`.
+    fn try_accumulate_param_gradients(&self,
+        _rng: &mut dyn RngCore,
+        _trace: &Trace<Args,Data,Ret>,
+        _store: &ParamStore,
+        _scale: f64
+    ) -> Result<(), GenFnError> {
+        Err(GenFnError::Unimplemented("accumulate_param_gradients"))
+    }
+
+    /// Replay the choices in `data` (eg. of a trace) with the given `args`, recording `log[p(data; args)]` on the active tape,
+    /// as when a `DynGenFn` calling this function takes gradients.
+    /// 
+    /// Return the recorded log joint density, the choices that are `Var`s (as recorded) and the return value.
+    fn try_record_logjp(&self,
+        _rng: &mut dyn RngCore,
+        _data: &Data,
+        _args: Args
+    ) -> Result<(Var, Data, Ret), GenFnError> {
+        Err(GenFnError::Unimplemented("record_logjp"))
+    }
+
+    /// Call a generative function and return the output.
+    fn try_call(&self, rng: &mut dyn RngCore, args: Args) -> Result<Ret, GenFnError> {
+        Ok(self.try_simulate(rng, args)?.retv.unwrap())
+    }
+
+    /// Use a generative function to propose some data.
+    fn try_propose(&self, rng: &mut dyn RngCore, args: Args) -> Result<(Data, f64), GenFnError> {
+        let trace = self.try_simulate(rng, args)?;
+        Ok((trace.data, trace.logjp))
+    }
+
+    /// Assess the conditional probability of some proposed `constraints` under a generative function.
+    fn try_assess(&self, rng: &mut dyn RngCore, args: Args, constraints: Data) -> Result<f64, GenFnError> {
+        let (_, weight) = self.try_generate(rng, args, constraints)?;
+        Ok(weight)
+    }
+
+    /// Like `try_simulate`, but panics on error.
+    fn simulate(&self, rng: &mut dyn RngCore, args: Args) -> Trace<Args,Data,Ret> {
+        self.try_simulate(rng, args).unwrap_or_else(|e| panic!("simulate: {e}"))
+    }
+
+    /// Like `try_generate`, but panics on error.
+    fn generate(&self, rng: &mut dyn RngCore, args: Args, constraints: Data) -> (Trace<Args,Data,Ret>, f64) {
+        self.try_generate(rng, args, constraints).unwrap_or_else(|e| panic!("generate: {e}"))
+    }
+
+    /// Like `try_update`, but panics on error.
+    fn update(&self,
+        rng: &mut dyn RngCore,
+        trace: Trace<Args,Data,Ret>,
+        args: Args,
+        diff: ArgDiff,
+        constraints: Data
+    ) -> (Trace<Args,Data,Ret>, Data, f64, ArgDiff) {
+        self.try_update(rng, trace, args, diff, constraints).unwrap_or_else(|e| panic!("update: {e}"))
+    }
+
+    /// Like `try_regenerate`, but panics on error.
     fn regenerate(&self,
+        rng: &mut dyn RngCore,
         trace: Trace<Args,Data,Ret>,
         args: Args,
         diff: ArgDiff,
         mask: &AddrMap
-    ) -> (Trace<Args,Data,Ret>, f64) {
-        panic!("regenerate: impl not found")
+    ) -> (Trace<Args,Data,Ret>, f64, ArgDiff) {
+        self.try_regenerate(rng, trace, args, diff, mask).unwrap_or_else(|e| panic!("regenerate: {e}"))
     }
 
-    /// Call a generative function and return the output.
-    fn call(&self, args: Args) -> Ret {
-        self.simulate(args).retv.unwrap()
+    /// Like `try_choice_gradients`, but panics on error.
+    fn choice_gradients(&self, rng: &mut dyn RngCore, trace: &Trace<Args,Data,Ret>, selection: &AddrMap) -> Data {
+        self.try_choice_gradients(rng, trace, selection).unwrap_or_else(|e| panic!("choice_gradients: {e}"))
     }
 
-    /// Use a generative function to propose some data.
-    fn propose(&self, args: Args) -> (Data, f64) {
-        let trace = self.simulate(args);
-        (trace.data, trace.logjp)
+    /// Like `try_arg_gradients`, but panics on error.
+    fn arg_gradients(&self, rng: &mut dyn RngCore, trace: &Trace<Args,Data,Ret>) -> Args where Args: Differentiable {
+        self.try_arg_gradients(rng, trace).unwrap_or_else(|e| panic!("arg_gradients: {e}"))
     }
 
-    /// Assess the conditional probability of some proposed `constraints` under a generative function.
-    fn assess(&self, args: Args, constraints: Data) -> f64 {
-        let (_, weight) = self.generate(args, constraints);
-        weight
+    /// Like `try_accumulate_param_gradients`, but panics on error.
+    fn accumulate_param_gradients(&self, rng: &mut dyn RngCore, trace: &Trace<Args,Data,Ret>, store: &ParamStore, scale: f64) {
+        self.try_accumulate_param_gradients(rng, trace, store, scale).unwrap_or_else(|e| panic!("accumulate_param_gradients: {e}"))
+    }
+
+    /// Like `try_call`, but panics on error.
+    fn call(&self, rng: &mut dyn RngCore, args: Args) -> Ret {
+        self.try_call(rng, args).unwrap_or_else(|e| panic!("call: {e}"))
+    }
+
+    /// Like `try_propose`, but panics on error.
+    fn propose(&self, rng: &mut dyn RngCore, args: Args) -> (Data, f64) {
+        self.try_propose(rng, args).unwrap_or_else(|e| panic!("propose: {e}"))
+    }
+
+    /// Like `try_assess`, but panics on error.
+    fn assess(&self, rng: &mut dyn RngCore, args: Args, constraints: Data) -> f64 {
+        self.try_assess(rng, args, constraints).unwrap_or_else(|e| panic!("assess: {e}"))
     }
 
 }
@@ -95,6 +217,9 @@ pub trait GenFn<Args,Data,Ret> {
 /// Flag that gives information about the type of incremental difference a generative
 /// function can expect to a `Trace`'s arguments during an update.
 /// 
+/// Also returned by `update` and `regenerate` as a _retdiff_, giving the same information
+/// about the return value to the caller.
+/// 
 /// Can be used to increase efficiency with incremental computation.
 #[derive(Debug,Clone,PartialEq)]
 pub enum ArgDiff {
@@ -109,4 +234,157 @@ pub enum ArgDiff {
     /// Generally means the `trace` has a vector-valued
     /// `data` field that is being pushed to.
     Extend
-}
\ No newline at end of file
+}
+
+/// Values that gradients can be taken with respect to, viewed as a flat vector of `f64` coordinates.
+/// 
+/// A gradient with respect to a value is represented by a value of the same type (and shape).
+pub trait Differentiable: Clone {
+    /// Return the coordinates of `self`.
+    fn coords(&self) -> Vec<f64>;
+
+    /// Return a value with the same shape as `self`, built from the given `coords`.
+    /// 
+    /// While a gradient is being taken, the `Var`s of the returned value are independent variables of the tape.
+    fn with_coords(&self, coords: &[f64]) -> Self;
+
+    /// Return the gradient with respect to `self`, read from the `adjoints` of a recorded computation.
+    /// 
+    /// Only `Var`s are recorded, so the gradient with respect to other coordinates is zero.
+    fn gradient(&self, _adjoints: &Adjoints) -> Self {
+        self.with_coords(&vec![0.; self.coords().len()])
+    }
+}
+
+impl Differentiable for () {
+    fn coords(&self) -> Vec<f64> { vec![] }
+    fn with_coords(&self, _coords: &[f64]) -> Self { }
+}
+
+impl Differentiable for f64 {
+    fn coords(&self) -> Vec<f64> { vec![*self] }
+    fn with_coords(&self, coords: &[f64]) -> Self { coords[0] }
+}
+
+impl Differentiable for Var {
+    fn coords(&self) -> Vec<f64> { vec![self.value()] }
+    fn with_coords(&self, coords: &[f64]) -> Self { Var::leaf(coords[0]) }
+    fn gradient(&self, adjoints: &Adjoints) -> Self { Var::constant(adjoints.of(self)) }
+}
+
+impl Differentiable for Vec<f64> {
+    fn coords(&self) -> Vec<f64> { self.clone() }
+    fn with_coords(&self, coords: &[f64]) -> Self { coords.to_vec() }
+}
+
+impl Differentiable for DVector<f64> {
+    fn coords(&self) -> Vec<f64> { self.as_slice().to_vec() }
+    fn with_coords(&self, coords: &[f64]) -> Self { DVector::from_column_slice(coords) }
+}
+
+impl Differentiable for Vec<Var> {
+    fn coords(&self) -> Vec<f64> { self.iter().map(Var::value).collect() }
+    fn with_coords(&self, coords: &[f64]) -> Self { coords.iter().map(|&x| Var::leaf(x)).collect() }
+    fn gradient(&self, adjoints: &Adjoints) -> Self { self.iter().map(|x| Var::constant(adjoints.of(x))).collect() }
+}
+
+impl Differentiable for DVector<Var> {
+    fn coords(&self) -> Vec<f64> { self.iter().map(Var::value).collect() }
+    fn with_coords(&self, coords: &[f64]) -> Self { DVector::from_iterator(coords.len(), coords.iter().map(|&x| Var::leaf(x))) }
+    fn gradient(&self, adjoints: &Adjoints) -> Self { self.map(|x| Var::constant(adjoints.of(&x))) }
+}
+
+impl Differentiable for DMatrix<f64> {
+    fn coords(&self) -> Vec<f64> { self.as_slice().to_vec() }
+    fn with_coords(&self, coords: &[f64]) -> Self { DMatrix::from_column_slice(self.nrows(), self.ncols(), coords) }
+}
+
+impl<A: Differentiable, B: Differentiable> Differentiable for (A,B) {
+    fn coords(&self) -> Vec<f64> {
+        let mut coords = self.0.coords();
+        coords.extend(self.1.coords());
+        coords
+    }
+
+    fn with_coords(&self, coords: &[f64]) -> Self {
+        let n = self.0.coords().len();
+        (self.0.with_coords(&coords[..n]), self.1.with_coords(&coords[n..]))
+    }
+
+    fn gradient(&self, adjoints: &Adjoints) -> Self {
+        (self.0.gradient(adjoints), self.1.gradient(adjoints))
+    }
+}
+
+impl<A: Differentiable, B: Differentiable, C: Differentiable> Differentiable for (A,B,C) {
+    fn coords(&self) -> Vec<f64> {
+        let mut coords = self.0.coords();
+        coords.extend(self.1.coords());
+        coords.extend(self.2.coords());
+        coords
+    }
+
+    fn with_coords(&self, coords: &[f64]) -> Self {
+        let n0 = self.0.coords().len();
+        let n1 = n0 + self.1.coords().len();
+        (self.0.with_coords(&coords[..n0]), self.1.with_coords(&coords[n0..n1]), self.2.with_coords(&coords[n1..]))
+    }
+
+    fn gradient(&self, adjoints: &Adjoints) -> Self {
+        (self.0.gradient(adjoints), self.1.gradient(adjoints), self.2.gradient(adjoints))
+    }
+}
+
+
+/// Data that can be combined with other data of the same type,
+/// eg. to constrain a `GenFn` by both observations and the choices proposed by another `GenFn`.
+pub trait Merge: Sized {
+    /// Return the union of the values in `self` and `other`.
+    /// 
+    /// Returns `GenFnError::AddressCollision` if both hold a value at the same address.
+    fn try_merge(self, other: Self) -> Result<Self, GenFnError>;
+}
+
+impl<A> Merge for Option<A> {
+    fn try_merge(self, other: Self) -> Result<Self, GenFnError> {
+        match (self, other) {
+            (Some(_), Some(_)) => Err(GenFnError::AddressCollision(String::new())),
+            (Some(a), None) | (None, Some(a)) => Ok(Some(a)),
+            (None, None) => Ok(None)
+        }
+    }
+}
+
+/// Prefix the address of an `AddressCollision` in the `i`th component of a tuple (or element of a `Vec`).
+fn in_component(i: usize, err: GenFnError) -> GenFnError {
+    match err {
+        GenFnError::AddressCollision(addr) if addr.is_empty() => GenFnError::AddressCollision(i.to_string()),
+        GenFnError::AddressCollision(addr) => GenFnError::AddressCollision(format!("{} / {}", i, addr)),
+        err => err
+    }
+}
+
+impl<A: Merge, B: Merge> Merge for (A,B) {
+    fn try_merge(self, other: Self) -> Result<Self, GenFnError> {
+        Ok((
+            self.0.try_merge(other.0).map_err(|e| in_component(0, e))?,
+            self.1.try_merge(other.1).map_err(|e| in_component(1, e))?
+        ))
+    }
+}
+
+/// Merges element-wise (eg. the per-step constraints of a `DynUnfold`), keeping the extra elements of the longer `Vec`.
+impl<V: Merge> Merge for Vec<V> {
+    fn try_merge(self, other: Self) -> Result<Self, GenFnError> {
+        let (longer, shorter) = if self.len() >= other.len() { (self, other) } else { (other, self) };
+        let mut merged = Vec::with_capacity(longer.len());
+        let mut shorter = shorter.into_iter();
+        for (i, a) in longer.into_iter().enumerate() {
+            match shorter.next() {
+                Some(b) => merged.push(a.try_merge(b).map_err(|e| in_component(i, e))?),
+                None => merged.push(a)
+            }
+        }
+        Ok(merged)
+    }
+}
@@ -0,0 +1,155 @@
+use rand::{distributions::Uniform, Rng, RngCore};
+use nalgebra::{DVector,DMatrix};
+use crate::{AddrMap,ArgDiff,DynTrie,DynTrace,GenFn,GenFnError,Distribution,normal};
+use super::hmc::Layout;
+
+
+/// Online estimate of the mean and covariance of a sequence of vectors (Welford's algorithm).
+struct CovarianceEstimate {
+    count: f64,
+    mean: DVector<f64>,
+    m2: DMatrix<f64>
+}
+
+impl CovarianceEstimate {
+    fn new(dim: usize) -> Self {
+        CovarianceEstimate { count: 0., mean: DVector::zeros(dim), m2: DMatrix::zeros(dim, dim) }
+    }
+
+    fn push(&mut self, x: &DVector<f64>) {
+        if self.mean.len() != x.len() {
+            *self = CovarianceEstimate::new(x.len());
+        }
+        self.count += 1.;
+        let delta = x - &self.mean;
+        self.mean += &delta / self.count;
+        self.m2 += &delta * (x - &self.mean).transpose();
+    }
+
+    /// Sample covariance, with a small ridge to keep it positive definite.
+    fn covariance(&self) -> DMatrix<f64> {
+        let dim = self.mean.len();
+        &self.m2 / (self.count - 1.) + DMatrix::identity(dim, dim) * 1e-6
+    }
+}
+
+
+/// Adaptive random-walk Metropolis sampler.
+///
+/// Proposes `q' ~ N(q, scale^2 cov)` for the selected continuous choices `q` of a trace. During the first `num_warmup`
+/// calls to `step`, the `scale` is adapted by a Robbins-Monro recursion on its logarithm (towards a mean acceptance
+/// probability of `target_accept`). If constructed `with_covariance_adaptation`, the proposal covariance is also set to
+/// the covariance of the warmup samples (as in the adaptive Metropolis of Haario, Saksman and Tamminen, 2001).
+/// Both are frozen after warmup, so the later steps leave the posterior invariant.
+pub struct AdaptiveMh {
+    /// Scale of the random walk. Set to `2.38 / sqrt(d)` for `d` coordinates on the first `step` if `None`.
+    pub scale: Option<f64>,
+
+    /// Covariance of the random walk (before scaling). Defaults to the identity.
+    pub cov: Option<DMatrix<f64>>,
+
+    /// Target mean acceptance probability of the scale adaptation.
+    pub target_accept: f64,
+
+    /// Number of warmup iterations during which the scale (and covariance) are adapted.
+    pub num_warmup: usize,
+
+    /// Acceptance probability of the last `step`.
+    pub accept_prob: f64,
+
+    adapt_covariance: bool,
+    iteration: usize,
+    cov_estimate: CovarianceEstimate
+}
+
+impl AdaptiveMh {
+    /// Exponent of the decay of the scale adaptation's step sizes, `(t + 1)^-DECAY` at warmup iteration `t`.
+    const DECAY: f64 = 0.6;
+
+    /// Construct an adaptive random-walk sampler that adapts its scale during the first `num_warmup` steps.
+    ///
+    /// Defaults to a `target_accept` of 0.234 (optimal for many-dimensional targets; 0.44 is optimal for one-dimensional ones).
+    pub fn new(num_warmup: usize) -> Self {
+        AdaptiveMh {
+            scale: None,
+            cov: None,
+            target_accept: 0.234,
+            num_warmup,
+            accept_prob: 0.,
+            adapt_covariance: false,
+            iteration: 0,
+            cov_estimate: CovarianceEstimate::new(0)
+        }
+    }
+
+    /// Also adapt the full proposal covariance during warmup, from the covariance of the warmup samples.
+    pub fn with_covariance_adaptation(mut self) -> Self {
+        self.adapt_covariance = true;
+        self
+    }
+
+    /// Return `true` if the sampler is still in its warmup phase.
+    pub fn is_warmup(&self) -> bool {
+        self.iteration < self.num_warmup
+    }
+
+    /// Perform one random-walk Metropolis update of the continuous choices in the `selection` of the given `trace` under the `model`,
+    /// adapting the scale (and covariance) if still in warmup.
+    ///
+    /// The selected choices must be `f64` or `DVector<f64>` valued (others are left unchanged),
+    /// and selected addresses that aren't in the `trace` are skipped.
+    /// Returns the new trace, and whether the move was accepted.
+    ///
+    /// Returns an `InvalidArgs` error if `cov` isn't positive definite, and an error if the `model` fails, rather than rejecting the move.
+    pub fn step<Args: Clone,Ret: Clone>(
+        &mut self,
+        rng: &mut dyn RngCore,
+        model: &impl GenFn<Args,DynTrie,Ret>,
+        trace: DynTrace<Args,Ret>,
+        selection: &AddrMap
+    ) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+        let layout = Layout::select(&trace.data, selection);
+        let q = layout.read(&trace.data)?;
+        let dim = layout.dim();
+        if dim == 0 {
+            self.iteration += self.is_warmup() as usize;
+            return Ok((trace, false));
+        }
+        let cov = match &self.cov {
+            Some(cov) if cov.nrows() == dim => cov.clone(),
+            _ => DMatrix::identity(dim, dim)
+        };
+        let scale = *self.scale.get_or_insert(2.38 / (dim as f64).sqrt());
+
+        let chol = cov.cholesky()
+            .ok_or_else(|| GenFnError::InvalidArgs("`cov` must be positive definite".to_string()))?;
+        let new_q = &q + chol.l() * q.map(|_| normal.random(rng, (0., 1.))) * scale;
+        let args = trace.args.clone();
+        let (new_trace, _, weight, _) = model.try_update(rng, trace.clone(), args, ArgDiff::NoChange, layout.write(&new_q))?;
+
+        self.accept_prob = if weight.is_nan() { 0. } else { weight.min(0.).exp() };
+        let accepted = rng.sample(Uniform::new(0_f64, 1_f64)).ln() < weight;
+        self.adapt(if accepted { &new_q } else { &q });
+        if accepted {
+            Ok((new_trace, true))
+        } else {
+            Ok((trace, false))
+        }
+    }
+
+    /// Adapt the scale and covariance after a warmup step that ended at `q`.
+    fn adapt(&mut self, q: &DVector<f64>) {
+        if !self.is_warmup() {
+            return;
+        }
+        let step_size = (self.iteration as f64 + 1.).powf(-Self::DECAY);
+        self.scale = self.scale.map(|scale| scale * (step_size * (self.accept_prob - self.target_accept)).exp());
+        if self.adapt_covariance {
+            self.cov_estimate.push(q);
+            if self.cov_estimate.count >= 10. * q.len() as f64 {
+                self.cov = Some(self.cov_estimate.covariance());
+            }
+        }
+        self.iteration += 1;
+    }
+}
@@ -0,0 +1,159 @@
+use rand::RngCore;
+use crate::{Trace,GenFnError,Kernel,AcceptStats};
+
+/// Boxed constructor of the kernels of a `Chain`.
+type KernelFactory<'a,Args,Data,Ret> = Box<dyn FnMut() -> Box<dyn Kernel<Args,Data,Ret> + 'a> + 'a>;
+
+/// A kernel of a `Chain`, along with the factory that makes a fresh one at the start of each chain (if any).
+struct ChainKernel<'a,Args,Data,Ret> {
+    kernel: Box<dyn Kernel<Args,Data,Ret> + 'a>,
+    make: Option<KernelFactory<'a,Args,Data,Ret>>
+}
+
+/// Configuration of the MCMC chains run by `run_mcmc`: a sequence of kernels applied in turn at every iteration,
+/// the number of chains, the number of warmup iterations and the thinning interval.
+pub struct Chain<'a,Args,Data,Ret> {
+    kernels: Vec<ChainKernel<'a,Args,Data,Ret>>,
+    num_chains: usize,
+    num_warmup: usize,
+    thin: usize
+}
+
+impl<'a,Args,Data,Ret> Chain<'a,Args,Data,Ret> {
+    /// Construct a single chain with no kernels, no warmup and no thinning.
+    pub fn new() -> Self {
+        Chain { kernels: vec![], num_chains: 1, num_warmup: 0, thin: 1 }
+    }
+
+    /// Append a `kernel` (eg. `RegenMh::new(&model, mask)`, or `kernel_fn(|rng, trace| mh(rng, &model, trace, &proposal, args))`)
+    /// to the sequence applied at every iteration.
+    ///
+    /// The same `kernel` runs every chain, so it shouldn't have state that adapts to a chain: use `with_kernel_factory` for those.
+    pub fn with_kernel(mut self, kernel: impl Kernel<Args,Data,Ret> + 'a) -> Self {
+        self.kernels.push(ChainKernel { kernel: Box::new(kernel), make: None });
+        self
+    }
+
+    /// Append a kernel made by `make` to the sequence applied at every iteration, making a fresh one at the start of each chain
+    /// (eg. `|| { let mut nuts = Nuts::new(500); kernel_fn(move |rng, trace| nuts.step(rng, &model, trace, &selection)) }`),
+    /// so that a kernel with state (like the step size of an adaptive sampler) doesn't carry it over from one chain to the next.
+    pub fn with_kernel_factory<K: Kernel<Args,Data,Ret> + 'a>(mut self, mut make: impl FnMut() -> K + 'a) -> Self {
+        let kernel = Box::new(make());
+        self.kernels.push(ChainKernel { kernel, make: Some(Box::new(move || Box::new(make()))) });
+        self
+    }
+
+    /// Set the number of independent chains (1 by default).
+    pub fn with_num_chains(mut self, num_chains: usize) -> Self {
+        self.num_chains = num_chains;
+        self
+    }
+
+    /// Set the number of warmup (burn-in) iterations discarded at the start of each chain (0 by default).
+    pub fn with_warmup(mut self, num_warmup: usize) -> Self {
+        self.num_warmup = num_warmup;
+        self
+    }
+
+    /// Record a sample every `thin` iterations after warmup (1 by default). `run_mcmc` returns an error if `thin` is 0.
+    pub fn with_thinning(mut self, thin: usize) -> Self {
+        self.thin = thin;
+        self
+    }
+
+    /// Return the number of kernels applied at every iteration.
+    pub fn num_kernels(&self) -> usize {
+        self.kernels.len()
+    }
+
+    /// Return the number of chains.
+    pub fn num_chains(&self) -> usize {
+        self.num_chains
+    }
+}
+
+impl<Args,Data,Ret> Default for Chain<'_,Args,Data,Ret> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Samples and statistics of a run of `run_mcmc`.
+pub struct McmcSamples<T,Args,Data,Ret> {
+    /// Values extracted from each chain after warmup (`samples[c][i]` is the `i`th sample of chain `c`).
+    pub samples: Vec<Vec<T>>,
+
+    /// Acceptance statistics of each kernel in each chain after warmup (`accept_stats[c][k]` for kernel `k` of chain `c`).
+    pub accept_stats: Vec<Vec<AcceptStats>>,
+
+    /// Final trace of each chain.
+    pub traces: Vec<Trace<Args,Data,Ret>>
+}
+
+impl<T,Args,Data,Ret> McmcSamples<T,Args,Data,Ret> {
+    /// Return the acceptance rate of each kernel, over the moves of every chain.
+    pub fn mean_acceptance_rates(&self) -> Vec<f64> {
+        let num_kernels = self.accept_stats.first().map_or(0, |stats| stats.len());
+        (0..num_kernels)
+            .map(|k| self.accept_stats.iter().map(|stats| stats[k]).fold(AcceptStats::default(), |a, b| a + b).acceptance_rate())
+            .collect()
+    }
+
+    /// Return the samples of every chain, concatenated.
+    pub fn pooled(&self) -> Vec<&T> {
+        self.samples.iter().flatten().collect()
+    }
+}
+
+
+/// Run the MCMC `chain`, recording `num_samples` values per chain.
+///
+/// Each chain starts from a trace drawn by `init` (eg. `|rng| Ok(model.try_generate(rng, args.clone(), constraints.clone())?.0)`),
+/// runs `num_warmup` iterations, then `num_samples * thin` iterations, calling `extract` on the trace every `thin` iterations.
+/// An iteration applies each of the chain's kernels in turn. The chains run one after another from the same `rng`,
+/// each with fresh kernels from the factories of `Chain::with_kernel_factory`.
+///
+/// Returns the samples, per-kernel acceptance statistics and final trace of each chain, or the first error raised by `init` or a kernel.
+/// Returns an `InvalidArgs` error if the thinning interval is 0.
+pub fn run_mcmc<Args,Data,Ret,T>(
+    rng: &mut dyn RngCore,
+    chain: &mut Chain<Args,Data,Ret>,
+    num_samples: usize,
+    mut init: impl FnMut(&mut dyn RngCore) -> Result<Trace<Args,Data,Ret>,GenFnError>,
+    mut extract: impl FnMut(&Trace<Args,Data,Ret>) -> T
+) -> Result<McmcSamples<T,Args,Data,Ret>,GenFnError> {
+    if chain.thin == 0 {
+        return Err(GenFnError::InvalidArgs(String::from("the thinning interval must be at least 1")));
+    }
+    let mut out = McmcSamples { samples: vec![], accept_stats: vec![], traces: vec![] };
+    for c in 0..chain.num_chains {
+        if c > 0 {  // the first chain runs the kernels made when they were added
+            for ChainKernel { kernel, make } in chain.kernels.iter_mut() {
+                if let Some(make) = make {
+                    *kernel = make();
+                }
+            }
+        }
+        let mut trace = init(rng)?;
+        let mut samples = Vec::with_capacity(num_samples);
+        let mut accept_stats = vec![AcceptStats::default(); chain.kernels.len()];
+        for iter in 0..chain.num_warmup + num_samples * chain.thin {
+            let is_warmup = iter < chain.num_warmup;
+            for (ChainKernel { kernel, .. }, accept_stats) in chain.kernels.iter_mut().zip(accept_stats.iter_mut()) {
+                let step_stats;
+                (trace, step_stats) = kernel.apply(rng, trace)?;
+                if !is_warmup {
+                    *accept_stats += step_stats;
+                }
+            }
+            if !is_warmup && (iter - chain.num_warmup + 1) % chain.thin == 0 {
+                samples.push(extract(&trace));
+            }
+        }
+        out.samples.push(samples);
+        out.accept_stats.push(accept_stats);
+        out.traces.push(trace);
+    }
+    Ok(out)
+}
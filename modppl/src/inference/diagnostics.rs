@@ -0,0 +1,234 @@
+// follows the definitions of Vehtari, Gelman, Simpson, Carpenter and Bürkner (2021),
+// "Rank-normalization, folding, and localization: an improved R-hat for assessing convergence of MCMC",
+// and the implementation of the effective sample size in Stan.
+
+
+/// Return the mean of `xs`.
+fn mean(xs: &[f64]) -> f64 {
+    xs.iter().sum::<f64>() / xs.len() as f64
+}
+
+/// Return the sample variance of `xs`.
+fn variance(xs: &[f64]) -> f64 {
+    let mu = mean(xs);
+    xs.iter().map(|x| (x - mu).powi(2)).sum::<f64>() / (xs.len() as f64 - 1.)
+}
+
+/// Return the `p`th quantile of the sorted `xs`, interpolating linearly between order statistics.
+fn quantile(sorted: &[f64], p: f64) -> f64 {
+    let h = (sorted.len() - 1) as f64 * p;
+    let (lo, hi) = (h.floor() as usize, h.ceil() as usize);
+    sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo])
+}
+
+/// Return the inverse of the standard normal CDF at `p` in `(0, 1)` (Acklam's rational approximation, with a relative error below `1.2e-9`).
+fn probit(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e1, 2.209460984245205e2, -2.759285104469687e2, 1.38357751867269e2, -3.066479806614716e1, 2.506628277459239];
+    const B: [f64; 5] = [-5.447609879822406e1, 1.615858368580409e2, -1.556989798598866e2, 6.680131188771972e1, -1.328068155288572e1];
+    const C: [f64; 6] = [-7.784894002430293e-3, -3.223964580411365e-1, -2.400758277161838, -2.549732539343734, 4.374664141464968, 2.938163982698783];
+    const D: [f64; 4] = [7.784695709041462e-3, 3.224671290700398e-1, 2.445134137142996, 3.754408661907416];
+    let tail = |q: f64| {
+        (((((C[0]*q + C[1])*q + C[2])*q + C[3])*q + C[4])*q + C[5]) / ((((D[0]*q + D[1])*q + D[2])*q + D[3])*q + 1.)
+    };
+    if p < 0.02425 {
+        tail((-2. * p.ln()).sqrt())
+    } else if p > 1. - 0.02425 {
+        -tail((-2. * (1. - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0]*r + A[1])*r + A[2])*r + A[3])*r + A[4])*r + A[5])*q / (((((B[0]*r + B[1])*r + B[2])*r + B[3])*r + B[4])*r + 1.)
+    }
+}
+
+/// Return `true` if there is at least one of the `chains`, and each has enough draws (4) to be split into halves of at least two draws.
+fn splittable(chains: &[Vec<f64>]) -> bool {
+    !chains.is_empty() && chains.iter().all(|chain| chain.len() >= 4)
+}
+
+/// Split each of the `chains` into its first and second halves (dropping the middle draw of odd-length chains).
+fn split_chains(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    debug_assert!(splittable(chains));
+    chains.iter()
+        .flat_map(|chain| {
+            let half = chain.len() / 2;
+            [chain[..half].to_vec(), chain[chain.len() - half..].to_vec()]
+        })
+        .collect()
+}
+
+/// Replace the draws of the `chains` by the normal scores of their ranks among all draws (averaging the ranks of ties).
+fn rank_normalize(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let mut order = chains.iter()
+        .enumerate()
+        .flat_map(|(c, chain)| chain.iter().enumerate().map(move |(i, x)| (*x, c, i)))
+        .collect::<Vec<(f64,usize,usize)>>();
+    order.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let num_draws = order.len() as f64;
+    let mut normalized = chains.iter().map(|chain| vec![0.; chain.len()]).collect::<Vec<Vec<f64>>>();
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && order[end].0 == order[start].0 {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.;
+        let z = probit((rank - 3./8.) / (num_draws + 1./4.));
+        order[start..end].iter().for_each(|&(_, c, i)| normalized[c][i] = z);
+        start = end;
+    }
+    normalized
+}
+
+/// Return the absolute deviation of every draw of the `chains` from the median of all draws.
+fn fold(chains: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let mut sorted = chains.concat();
+    sorted.sort_by(f64::total_cmp);
+    let median = quantile(&sorted, 0.5);
+    chains.iter().map(|chain| chain.iter().map(|x| (x - median).abs()).collect()).collect()
+}
+
+/// Return the potential scale reduction of the `chains` (without splitting them).
+fn potential_scale_reduction(chains: &[Vec<f64>]) -> f64 {
+    let num_draws = chains[0].len() as f64;
+    let within = mean(&chains.iter().map(|chain| variance(chain)).collect::<Vec<f64>>());
+    let between = variance(&chains.iter().map(|chain| mean(chain)).collect::<Vec<f64>>());
+    ((num_draws - 1.) / num_draws + between / within).sqrt()
+}
+
+/// Return the effective sample size of the `chains` (without splitting them), truncating the autocorrelations by Geyer's initial monotone sequence.
+fn effective_sample_size(chains: &[Vec<f64>]) -> f64 {
+    let num_chains = chains.len();
+    let num_draws = chains[0].len();
+    let centered = chains.iter()
+        .map(|chain| {
+            let mu = mean(chain);
+            chain.iter().map(|x| x - mu).collect::<Vec<f64>>()
+        })
+        .collect::<Vec<Vec<f64>>>();
+    let mean_autocovariance = |lag: usize| {
+        centered.iter().map(|chain| autocovariance(chain, lag)).sum::<f64>() / num_chains as f64
+    };
+
+    let mean_var = mean_autocovariance(0) * num_draws as f64 / (num_draws as f64 - 1.);
+    let mut var_plus = mean_var * (num_draws as f64 - 1.) / num_draws as f64;
+    if num_chains > 1 {
+        var_plus += variance(&chains.iter().map(|chain| mean(chain)).collect::<Vec<f64>>());
+    }
+    if var_plus <= 0. {
+        return f64::NAN;
+    }
+    let rho = |lag: usize| 1. - (mean_var - mean_autocovariance(lag)) / var_plus;
+
+    let mut rhos = vec![];
+    let (mut rho_even, mut rho_odd) = (1., rho(1));
+    let mut t = 0;
+    while t + 5 < num_draws && rho_even + rho_odd > 0. {
+        rhos.push(rho_even);
+        rhos.push(rho_odd);
+        t += 2;
+        rho_even = rho(t);
+        rho_odd = rho(t + 1);
+    }
+    let last = if rho_even > 0. { rho_even } else { 0. };
+    for t in (1..rhos.len().saturating_sub(2)).step_by(2) {
+        if rhos[t + 1] + rhos[t + 2] > rhos[t - 1] + rhos[t] {
+            rhos[t + 1] = (rhos[t - 1] + rhos[t]) / 2.;
+            rhos[t + 2] = rhos[t + 1];
+        }
+    }
+    let num_total_draws = (num_chains * num_draws) as f64;
+    let tau = (-1. + 2. * rhos.iter().sum::<f64>() + last).max(1. / num_total_draws.log10());
+    num_total_draws / tau
+}
+
+/// Return the autocovariance of the (centered) `draws` at `lag`.
+fn autocovariance(centered: &[f64], lag: usize) -> f64 {
+    centered.iter().zip(&centered[lag..]).map(|(x, y)| x * y).sum::<f64>() / centered.len() as f64
+}
+
+
+/// Return the autocorrelation of a chain of `draws` at each lag from `0` to `max_lag` (or `draws.len() - 1`, if shorter).
+///
+/// Returns an empty vector if there are fewer than two `draws`, or if the `draws` are constant (so their autocorrelation is undefined).
+pub fn autocorrelation(draws: &[f64], max_lag: usize) -> Vec<f64> {
+    if draws.len() < 2 {
+        return vec![];
+    }
+    let mu = mean(draws);
+    let centered = draws.iter().map(|x| x - mu).collect::<Vec<f64>>();
+    let var = autocovariance(&centered, 0);
+    if var == 0. {
+        return vec![];
+    }
+    (0..=max_lag.min(draws.len() - 1)).map(|lag| autocovariance(&centered, lag) / var).collect()
+}
+
+/// Return the split-R-hat of the `chains` (each a vector of scalar draws of the same length), comparing the variance
+/// between and within the halves of each chain. Values near `1.` (eg. below `1.01`) suggest the chains have mixed.
+///
+/// Returns `f64::NAN` if there are no `chains`, if any has fewer than 4 draws, or if all the draws are equal.
+pub fn split_rhat(chains: &[Vec<f64>]) -> f64 {
+    if !splittable(chains) {
+        return f64::NAN;
+    }
+    potential_scale_reduction(&split_chains(chains))
+}
+
+/// Return the rank-normalized split-R-hat of the `chains`: the maximum of the `split_rhat` of their rank-normalized
+/// draws (sensitive to differences in location) and of their rank-normalized folded draws (sensitive to differences in scale).
+///
+/// Unlike `split_rhat`, this is well defined for heavy-tailed draws. Returns `f64::NAN` in the same cases as `split_rhat`.
+pub fn rhat(chains: &[Vec<f64>]) -> f64 {
+    if !splittable(chains) {
+        return f64::NAN;
+    }
+    let bulk = split_rhat(&rank_normalize(chains));
+    let tail = split_rhat(&rank_normalize(&fold(chains)));
+    if bulk.is_nan() || tail.is_nan() {
+        return f64::NAN;
+    }
+    bulk.max(tail)
+}
+
+/// Return the effective sample size of the mean of the `chains` (after splitting each in half).
+///
+/// Returns `f64::NAN` if there are no `chains`, if any has fewer than 4 draws, or if all the draws are equal.
+pub fn split_ess(chains: &[Vec<f64>]) -> f64 {
+    if !splittable(chains) {
+        return f64::NAN;
+    }
+    effective_sample_size(&split_chains(chains))
+}
+
+/// Return the bulk effective sample size of the `chains`: the `split_ess` of their rank-normalized draws,
+/// which measures how well the center of the distribution is sampled. Returns `f64::NAN` in the same cases as `split_ess`.
+pub fn ess_bulk(chains: &[Vec<f64>]) -> f64 {
+    split_ess(&rank_normalize(chains))
+}
+
+/// Return the tail effective sample size of the `chains`: the minimum `split_ess` of the indicators of the draws
+/// below the 5% quantile and above the 95% quantile, which measures how well the tails are sampled.
+/// Returns `f64::NAN` in the same cases as `split_ess`.
+pub fn ess_tail(chains: &[Vec<f64>]) -> f64 {
+    if !splittable(chains) {
+        return f64::NAN;
+    }
+    let mut sorted = chains.concat();
+    sorted.sort_by(f64::total_cmp);
+    let (lower, upper) = (quantile(&sorted, 0.05), quantile(&sorted, 0.95));
+    let indicators = |f: &dyn Fn(f64) -> bool| {
+        chains.iter().map(|chain| chain.iter().map(|x| f(*x) as u8 as f64).collect()).collect::<Vec<Vec<f64>>>()
+    };
+    let (lower_ess, upper_ess) = (split_ess(&indicators(&|x| x <= lower)), split_ess(&indicators(&|x| x >= upper)));
+    if lower_ess.is_nan() || upper_ess.is_nan() {
+        return f64::NAN;
+    }
+    lower_ess.min(upper_ess)
+}
+
+/// Return the Monte Carlo standard error of the mean of all the draws of the `chains`, from their `split_ess`.
+/// Returns `f64::NAN` in the same cases as `split_ess`.
+pub fn mcse_mean(chains: &[Vec<f64>]) -> f64 {
+    (variance(&chains.concat()) / split_ess(chains)).sqrt()
+}
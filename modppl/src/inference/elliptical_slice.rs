@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use std::f64::consts::PI;
+use rand::{distributions::Uniform, Rng, RngCore};
+use nalgebra::{DVector,DMatrix,Cholesky,Dyn};
+use crate::{Addr,ArgDiff,DynTrie,DynTrace,GenFn,GenFnError,Distribution,normal};
+
+
+/// Return the log density of `x` under the Gaussian with the given `mean` and `chol`esky factor of its covariance, up to a constant.
+fn unnormalized_logpdf(x: &DVector<f64>, mean: &DVector<f64>, chol: &Cholesky<f64,Dyn>) -> f64 {
+    let whitened = chol.l().solve_lower_triangular(&(x - mean)).unwrap();
+    -whitened.norm_squared() / 2.
+}
+
+
+/// Perform an elliptical slice sampling update (Murray, Adams and MacKay, 2010) of the `DVector<f64>` choice at `addr`
+/// in the given `trace` under the `model`, where the choice has a Gaussian prior with `prior_mean` and `prior_cov`
+/// (eg. `mvnormal(prior_mean, prior_cov) %= addr`).
+///
+/// Draws an auxiliary vector from the prior, then slice samples a new value on the ellipse through the current value
+/// and the auxiliary vector (centered at `prior_mean`), shrinking the bracket of angles until the likelihood of the
+/// new value exceeds the slice. Each point on the ellipse is scored with `GenFn::update`, so the likelihood is given by
+/// the rest of the `model`. The update needs no tuning, and always moves the choice (so it's always accepted).
+///
+/// Returns an `InvalidArgs` error if `prior_cov` isn't positive definite, and an error if the choice at `addr`
+/// isn't a `DVector<f64>`, or if the `model` fails.
+pub fn elliptical_slice<Args: Clone,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,DynTrie,Ret>,
+    trace: DynTrace<Args,Ret>,
+    addr: impl Into<Addr>,
+    prior_mean: &DVector<f64>,
+    prior_cov: &DMatrix<f64>
+) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+    let addr = addr.into();
+    let chol = prior_cov.clone().cholesky()
+        .ok_or_else(|| GenFnError::InvalidArgs("`prior_cov` must be positive definite".to_string()))?;
+    let x = trace.data.try_read::<DVector<f64>>(addr.clone())?;
+    let nu = chol.l() * x.map(|_| normal.random(rng, (0., 1.)));
+    let x_logp = unnormalized_logpdf(&x, prior_mean, &chol);
+    let log_slice = rng.sample(Uniform::new(0_f64, 1_f64)).ln();
+
+    let mut theta = rng.sample(Uniform::new(0., 2. * PI));
+    let (mut theta_min, mut theta_max) = (theta - 2. * PI, theta);
+    loop {
+        let new_x = prior_mean + (&x - prior_mean) * theta.cos() + &nu * theta.sin();
+        let new_x_logp = unnormalized_logpdf(&new_x, prior_mean, &chol);
+        let mut constraints = DynTrie::new();
+        constraints.observe(addr.clone(), Arc::new(new_x));
+        let args = trace.args.clone();
+        let (new_trace, _, weight, _) = model.try_update(rng, trace.clone(), args, ArgDiff::NoChange, constraints)?;
+
+        // the update weight is the log ratio of the joint densities, so subtract the prior's to get the likelihood's
+        if log_slice < weight - (new_x_logp - x_logp) {
+            return Ok((new_trace, true));
+        }
+        if theta < 0. {
+            theta_min = theta;
+        } else {
+            theta_max = theta;
+        }
+        theta = rng.sample(Uniform::new(theta_min, theta_max));
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use rand::RngCore;
+use crate::{Addr,ArgDiff,DynTrie,DynTrace,GenFn,GenFnError};
+use super::particle_gibbs::sample_index;
+
+
+/// Perform an enumerative Gibbs update of the discrete choice at `addr` in the given `trace` under the `model`,
+/// trying every value in the `support` of its distribution (eg. `bernoulli.support(p)`, see `FiniteDistribution`).
+///
+/// Each value is scored with `GenFn::update`, and a new value is drawn from the resulting conditional distribution.
+/// If the value also changes which other choices are made (eg. `is_linear` in a model that samples more coefficients
+/// when it's false), the choices made only under a new value are sampled from the model by `update`, and the choices
+/// made only under the current value are scored by their density in the `discard`. This is an exact Gibbs update on
+/// the space extended with the choices of every value (as in Carlin and Chib, 1995), provided the values either share
+/// each of those choices or make it only under a single value.
+///
+/// Returns the new trace and whether the value of the choice changed.
+/// Returns an error if the choice at `addr` isn't a `V`, if its current value isn't in the `support`, or if the `model` fails.
+pub fn enumerative_gibbs<Args: Clone,Ret: Clone,V: Clone + PartialEq + Send + Sync + 'static>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,DynTrie,Ret>,
+    trace: DynTrace<Args,Ret>,
+    addr: impl Into<Addr>,
+    support: &[V]
+) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+    let addr = addr.into();
+    let value = trace.data.try_read::<V>(addr.clone())?;
+    let Some(current) = support.iter().position(|v| *v == value) else {
+        return Err(GenFnError::InvalidArgs(format!("the value at \"{addr}\" is not in the support")));
+    };
+
+    let mut traces = vec![];
+    let mut log_weights = vec![];
+    for (i, value) in support.iter().enumerate() {
+        if i == current {
+            traces.push(None);
+            log_weights.push(0.);
+            continue;
+        }
+        let mut constraints = DynTrie::new();
+        constraints.observe(addr.clone(), Arc::new(value.clone()));
+        let args = trace.args.clone();
+        let (new_trace, discard, weight, _) = model.try_update(rng, trace.clone(), args, ArgDiff::NoChange, constraints)?;
+
+        // the weight already excludes the density of the new choices, so add back that of the discarded choices (other than `addr`)
+        let discard_weight = discard.weight() - discard.search(addr.clone()).map_or(0., |sub| sub.weight());
+        traces.push(Some(new_trace));
+        log_weights.push(weight + discard_weight);
+    }
+
+    let i = sample_index(rng, &log_weights);
+    match traces[i].take() {
+        Some(new_trace) => Ok((new_trace, true)),
+        None => Ok((trace, false))
+    }
+}
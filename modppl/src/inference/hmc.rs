@@ -0,0 +1,526 @@
+use std::sync::Arc;
+use std::slice;
+use std::any::Any;
+use rand::{distributions::Uniform, Rng, RngCore};
+use nalgebra::DVector;
+use crate::{Addr,AddrMap,ArgDiff,DynTrie,DynTrace,GenFn,GenFnError,Distribution,normal};
+
+
+/// Maximum drop in the joint log density (including the kinetic energy) before a trajectory is considered divergent.
+const MAX_ENERGY_ERROR: f64 = 1000.;
+
+/// Addresses of the selected continuous choices of a trace, and their number of coordinates (`None` for an `f64`).
+pub(crate) struct Layout(Vec<(Addr,Option<usize>)>);
+
+impl Layout {
+    /// Lay out the continuous leaves of `data` (eg. the result of `choice_gradients`), in the order of their addresses.
+    pub(crate) fn of(data: &DynTrie) -> Self {
+        let mut entries = vec![];
+        continuous_leaves(data, Addr::new(), &mut entries);
+        Self::sorted(entries)
+    }
+
+    /// Lay out the continuous choices of `data` selected by `selection`, in the order of their addresses.
+    ///
+    /// Selected addresses that aren't in `data` (eg. on a branch that wasn't taken) are skipped.
+    pub(crate) fn select(data: &DynTrie, selection: &AddrMap) -> Self {
+        fn visit(data: &DynTrie, selection: &AddrMap, addr: Addr, entries: &mut Vec<(Addr,Option<usize>)>) {
+            if selection.is_leaf() {
+                continuous_leaves(data, addr, entries);
+            } else {
+                for (key, subselection) in selection.iter() {
+                    if let Some(subdata) = data.search_keys(slice::from_ref(key)) {
+                        let mut subaddr = addr.clone();
+                        subaddr.push(key.clone());
+                        visit(subdata, subselection, subaddr, entries);
+                    }
+                }
+            }
+        }
+        let mut entries = vec![];
+        visit(data, selection, Addr::new(), &mut entries);
+        Self::sorted(entries)
+    }
+
+    fn sorted(mut entries: Vec<(Addr,Option<usize>)>) -> Self {
+        entries.sort_by_cached_key(|(addr, _)| addr.to_string());
+        Layout(entries)
+    }
+
+    pub(crate) fn dim(&self) -> usize {
+        self.0.iter().map(|(_, len)| len.unwrap_or(1)).sum()
+    }
+
+    /// Flatten the values of `data` at the addresses of `self` into a vector.
+    pub(crate) fn read(&self, data: &DynTrie) -> Result<DVector<f64>,GenFnError> {
+        let mut coords = Vec::with_capacity(self.dim());
+        for (addr, len) in &self.0 {
+            match len {
+                None => coords.push(data.try_read::<f64>(addr)?),
+                Some(_) => coords.extend(data.try_read::<DVector<f64>>(addr)?.iter())
+            }
+        }
+        Ok(DVector::from_vec(coords))
+    }
+
+    /// Build constraints setting the addresses of `self` to `coords`.
+    pub(crate) fn write(&self, coords: &DVector<f64>) -> DynTrie {
+        let mut data = DynTrie::new();
+        let mut i = 0;
+        for (addr, len) in &self.0 {
+            let value: Arc<dyn Any + Send + Sync> = match len {
+                None => Arc::new(coords[i]),
+                Some(n) => Arc::new(coords.rows(i, *n).into_owned())
+            };
+            i += len.unwrap_or(1);
+            data.observe(addr, value);
+        }
+        data
+    }
+}
+
+/// Collect the addresses (under `addr`) and lengths of the continuous leaves of `data` into `entries`.
+fn continuous_leaves(data: &DynTrie, addr: Addr, entries: &mut Vec<(Addr,Option<usize>)>) {
+    if let Some(v) = data.ref_inner() {
+        if v.is::<f64>() {
+            entries.push((addr, None));
+        } else if let Some(v) = v.downcast_ref::<DVector<f64>>() {
+            entries.push((addr, Some(v.len())));
+        }
+    } else {
+        for (key, sub) in data.iter() {
+            let mut subaddr = addr.clone();
+            subaddr.push(key.clone());
+            continuous_leaves(sub, subaddr, entries);
+        }
+    }
+}
+
+/// A point in phase space, with the log density and its gradient at the position `q`.
+#[derive(Clone)]
+struct Point {
+    q: DVector<f64>,
+    p: DVector<f64>,
+    logp: f64,
+    grad: DVector<f64>
+}
+
+/// The log density of a `model` as a function of the selected continuous choices of a `trace`.
+struct Target<'a,Args,Ret,F: GenFn<Args,DynTrie,Ret>> {
+    model: &'a F,
+    trace: DynTrace<Args,Ret>,
+    selection: &'a AddrMap,
+    layout: Layout
+}
+
+impl<'a,Args: Clone,Ret: Clone,F: GenFn<Args,DynTrie,Ret>> Target<'a,Args,Ret,F> {
+    fn new(rng: &mut dyn RngCore, model: &'a F, trace: DynTrace<Args,Ret>, selection: &'a AddrMap) -> Result<(Self, DVector<f64>, DVector<f64>),GenFnError> {
+        let gradients = model.try_choice_gradients(rng, &trace, selection)?;
+        let layout = Layout::of(&gradients);
+        let q = layout.read(&trace.data)?;
+        let grad = layout.read(&gradients)?;
+        Ok((Target { model, trace, selection, layout }, q, grad))
+    }
+
+    /// Return the trace with its selected choices set to `q`.
+    fn trace_at(&self, rng: &mut dyn RngCore, q: &DVector<f64>) -> Result<DynTrace<Args,Ret>,GenFnError> {
+        let args = self.trace.args.clone();
+        let (trace, _, _, _) = self.model.try_update(rng, self.trace.clone(), args, ArgDiff::NoChange, self.layout.write(q))?;
+        Ok(trace)
+    }
+
+    /// Return the log density and its gradient at `q`.
+    fn eval(&self, rng: &mut dyn RngCore, q: &DVector<f64>) -> Result<(f64, DVector<f64>),GenFnError> {
+        let trace = self.trace_at(rng, q)?;
+        if !trace.logjp.is_finite() {
+            return Ok((f64::NEG_INFINITY, DVector::zeros(q.len())));
+        }
+        let gradients = self.model.try_choice_gradients(rng, &trace, self.selection)?;
+        Ok((trace.logjp, self.layout.read(&gradients)?))
+    }
+
+    /// Take a leapfrog step of size `step_size` from `point`.
+    fn leapfrog(&self, rng: &mut dyn RngCore, point: &Point, step_size: f64, inv_mass: &DVector<f64>) -> Result<Point,GenFnError> {
+        let p = &point.p + &point.grad * (step_size / 2.);
+        let q = &point.q + inv_mass.component_mul(&p) * step_size;
+        let (logp, grad) = self.eval(rng, &q)?;
+        let p = p + &grad * (step_size / 2.);
+        Ok(Point { q, p, logp, grad })
+    }
+}
+
+/// Sample a momentum `p ~ N(0, M)` for the diagonal mass matrix `M` with inverse `inv_mass`.
+fn sample_momentum(rng: &mut dyn RngCore, inv_mass: &DVector<f64>) -> DVector<f64> {
+    inv_mass.map(|m| normal.random(rng, (0., 1.)) / m.sqrt())
+}
+
+/// Joint log density `log[p(q)] - p^T M^{-1} p / 2` of a `point`.
+fn joint_logp(point: &Point, inv_mass: &DVector<f64>) -> f64 {
+    let kinetic = point.p.component_mul(&point.p).dot(inv_mass) / 2.;
+    if point.logp.is_nan() { f64::NEG_INFINITY } else { point.logp - kinetic }
+}
+
+
+/// Perform a Hamiltonian Monte Carlo (HMC) update of the continuous choices in the `selection` of the given `trace` under the `model`.
+///
+/// The selected choices must be `f64` or `DVector<f64>` valued, and `model` must implement `choice_gradients`
+/// (a `DynGenFn` must sample them from the distributions of `ad`, eg. `ad::normal`).
+/// Simulates `n_leapfrog` leapfrog steps of size `step_size` (with an identity mass matrix),
+/// then accepts or rejects the end point with a Metropolis-Hastings correction.
+///
+/// Returns an error if the `model` fails, rather than rejecting the move.
+pub fn hamiltonian_monte_carlo<Args: Clone,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,DynTrie,Ret>,
+    trace: DynTrace<Args,Ret>,
+    selection: &AddrMap,
+    step_size: f64,
+    n_leapfrog: usize
+) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+    let (target, q, grad) = Target::new(rng, model, trace, selection)?;
+    let inv_mass = DVector::from_element(q.len(), 1.);
+    let start = Point { p: sample_momentum(rng, &inv_mass), q, logp: target.trace.logjp, grad };
+    let mut point = start.clone();
+    for _ in 0..n_leapfrog {
+        point = target.leapfrog(rng, &point, step_size, &inv_mass)?;
+        if !point.logp.is_finite() {
+            return Ok((target.trace, false));
+        }
+    }
+    let alpha = joint_logp(&point, &inv_mass) - joint_logp(&start, &inv_mass);
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < alpha {
+        Ok((target.trace_at(rng, &point.q)?, true))
+    } else {
+        Ok((target.trace, false))
+    }
+}
+
+/// Alias for `hamiltonian_monte_carlo`.
+pub fn hmc<Args: Clone,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,DynTrie,Ret>,
+    trace: DynTrace<Args,Ret>,
+    selection: &AddrMap,
+    step_size: f64,
+    n_leapfrog: usize
+) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+    hamiltonian_monte_carlo(rng, model, trace, selection, step_size, n_leapfrog)
+}
+
+
+/// Subtree built by one doubling of a NUTS trajectory.
+struct Subtree {
+    minus: Point,
+    plus: Point,
+    proposal: Point,
+    n_valid: usize,
+    valid: bool,
+    sum_accept: f64,
+    n_accept: usize
+}
+
+/// Return `true` if the trajectory from `minus` to `plus` has not yet made a U-turn.
+fn no_u_turn(minus: &Point, plus: &Point, inv_mass: &DVector<f64>) -> bool {
+    let dq = &plus.q - &minus.q;
+    dq.dot(&inv_mass.component_mul(&minus.p)) >= 0. && dq.dot(&inv_mass.component_mul(&plus.p)) >= 0.
+}
+
+/// Quantities fixed over the trajectory of one NUTS update.
+struct Trajectory<'t,'a,Args,Ret,F: GenFn<Args,DynTrie,Ret>> {
+    target: &'t Target<'a,Args,Ret,F>,
+    log_slice: f64,
+    joint0: f64,
+    step_size: f64,
+    inv_mass: &'t DVector<f64>
+}
+
+impl<Args: Clone,Ret: Clone,F: GenFn<Args,DynTrie,Ret>> Trajectory<'_,'_,Args,Ret,F> {
+    /// Recursively build a subtree of `2^depth` leapfrog steps from `point` in `direction`.
+    fn build_tree(&self, rng: &mut dyn RngCore, point: &Point, direction: f64, depth: usize) -> Result<Subtree,GenFnError> {
+        if depth == 0 {
+            let next = self.target.leapfrog(rng, point, direction * self.step_size, self.inv_mass)?;
+            let joint = joint_logp(&next, self.inv_mass);
+            return Ok(Subtree {
+                minus: next.clone(),
+                plus: next.clone(),
+                proposal: next,
+                n_valid: (self.log_slice <= joint) as usize,
+                valid: joint > self.log_slice - MAX_ENERGY_ERROR,
+                sum_accept: (joint - self.joint0).exp().min(1.),
+                n_accept: 1
+            });
+        }
+        let mut tree = self.build_tree(rng, point, direction, depth - 1)?;
+        if !tree.valid {
+            return Ok(tree);
+        }
+        let edge = if direction < 0. { tree.minus.clone() } else { tree.plus.clone() };
+        let other = self.build_tree(rng, &edge, direction, depth - 1)?;
+        if direction < 0. {
+            tree.minus = other.minus;
+        } else {
+            tree.plus = other.plus;
+        }
+        let n_total = tree.n_valid + other.n_valid;
+        if n_total > 0 && rng.sample(Uniform::new(0_f64, 1_f64)) < other.n_valid as f64 / n_total as f64 {
+            tree.proposal = other.proposal;
+        }
+        tree.sum_accept += other.sum_accept;
+        tree.n_accept += other.n_accept;
+        tree.valid = other.valid && no_u_turn(&tree.minus, &tree.plus, self.inv_mass);
+        tree.n_valid = n_total;
+        Ok(tree)
+    }
+}
+
+/// Adaptive No-U-Turn Sampler (NUTS).
+///
+/// Implements the efficient NUTS of Hoffman and Gelman (2014), with a diagonal mass matrix.
+/// During the first `num_warmup` calls to `step`, the step size is adapted by dual averaging
+/// (towards a mean acceptance statistic of `target_accept`), and the mass matrix is estimated
+/// from the variance of the samples in a series of doubling windows (as in Stan).
+pub struct Nuts {
+    /// Leapfrog step size. Set by a heuristic on the first `step` if `None`.
+    pub step_size: Option<f64>,
+
+    /// Diagonal of the inverse mass matrix (an estimate of the posterior variance). Defaults to the identity.
+    pub inv_mass: Option<DVector<f64>>,
+
+    /// Maximum depth of the trajectory tree (at most `2^max_depth` leapfrog steps per `step`).
+    pub max_depth: usize,
+
+    /// Target mean acceptance statistic of the step size adaptation.
+    pub target_accept: f64,
+
+    /// Number of warmup iterations during which the step size and mass matrix are adapted.
+    pub num_warmup: usize,
+
+    /// Mean acceptance statistic of the last `step`.
+    pub accept_stat: f64,
+
+    iteration: usize,
+    window_ends: Vec<usize>,
+    dual_avg: DualAveraging,
+    var_estimate: Welford
+}
+
+/// Dual averaging state for step size adaptation (Nesterov, 2009; Hoffman and Gelman, 2014).
+struct DualAveraging {
+    mu: f64,
+    h_bar: f64,
+    log_step_bar: f64,
+    count: f64
+}
+
+impl DualAveraging {
+    const GAMMA: f64 = 0.05;
+    const T0: f64 = 10.;
+    const KAPPA: f64 = 0.75;
+
+    fn new(step_size: f64) -> Self {
+        DualAveraging { mu: (10. * step_size).ln(), h_bar: 0., log_step_bar: 0., count: 0. }
+    }
+
+    /// Record an acceptance statistic and return the next step size.
+    fn update(&mut self, accept_stat: f64, target_accept: f64) -> f64 {
+        self.count += 1.;
+        let eta = 1. / (self.count + Self::T0);
+        self.h_bar = (1. - eta) * self.h_bar + eta * (target_accept - accept_stat);
+        let log_step = self.mu - self.count.sqrt() / Self::GAMMA * self.h_bar;
+        let x_eta = self.count.powf(-Self::KAPPA);
+        self.log_step_bar = x_eta * log_step + (1. - x_eta) * self.log_step_bar;
+        log_step.exp()
+    }
+}
+
+/// Online estimate of the per-coordinate variance (Welford's algorithm).
+struct Welford {
+    count: f64,
+    mean: DVector<f64>,
+    m2: DVector<f64>
+}
+
+impl Welford {
+    fn new(dim: usize) -> Self {
+        Welford { count: 0., mean: DVector::zeros(dim), m2: DVector::zeros(dim) }
+    }
+
+    fn push(&mut self, x: &DVector<f64>) {
+        if self.mean.len() != x.len() {
+            *self = Welford::new(x.len());
+        }
+        self.count += 1.;
+        let delta = x - &self.mean;
+        self.mean += &delta / self.count;
+        self.m2 += delta.component_mul(&(x - &self.mean));
+    }
+
+    /// Variance regularized towards `1e-3`, as in Stan. Requires at least 2 samples.
+    fn variance(&self) -> DVector<f64> {
+        let n = self.count;
+        let var = &self.m2 / (n - 1.);
+        var * (n / (n + 5.)) + DVector::from_element(self.mean.len(), 1e-3 * 5. / (n + 5.))
+    }
+}
+
+impl Nuts {
+    /// Construct an adaptive NUTS sampler that adapts during the first `num_warmup` steps.
+    ///
+    /// Defaults to a `max_depth` of 10 and a `target_accept` of 0.8.
+    pub fn new(num_warmup: usize) -> Self {
+        Nuts {
+            step_size: None,
+            inv_mass: None,
+            max_depth: 10,
+            target_accept: 0.8,
+            num_warmup,
+            accept_stat: 0.,
+            iteration: 0,
+            window_ends: Self::windows(num_warmup),
+            dual_avg: DualAveraging::new(1.),
+            var_estimate: Welford::new(0)
+        }
+    }
+
+    /// Ends of the mass matrix adaptation windows: after an initial buffer of 15% of the warmup,
+    /// windows double in size (from 25 steps) until a terminal buffer of 10% of the warmup.
+    fn windows(num_warmup: usize) -> Vec<usize> {
+        let init_buffer = (num_warmup as f64 * 0.15) as usize;
+        let slow_end = num_warmup - (num_warmup as f64 * 0.1) as usize;
+        let mut ends = vec![];
+        let (mut start, mut size) = (init_buffer, 25);
+        while start < slow_end {
+            let mut end = start + size;
+            if end + 2 * size > slow_end {
+                end = slow_end;
+            }
+            ends.push(end);
+            start = end;
+            size *= 2;
+        }
+        ends
+    }
+
+    /// Return `true` if the sampler is still in its warmup phase.
+    pub fn is_warmup(&self) -> bool {
+        self.iteration < self.num_warmup
+    }
+
+    /// Find a reasonable initial step size (Hoffman and Gelman, 2014, Algorithm 4).
+    fn initial_step_size<Args: Clone,Ret: Clone,F: GenFn<Args,DynTrie,Ret>>(
+        rng: &mut dyn RngCore,
+        target: &Target<Args,Ret,F>,
+        start: &Point,
+        inv_mass: &DVector<f64>
+    ) -> Result<f64,GenFnError> {
+        let mut step_size = 1.;
+        let log_accept = |point: &Point| joint_logp(point, inv_mass) - joint_logp(start, inv_mass);
+        let mut point = target.leapfrog(rng, start, step_size, inv_mass)?;
+        let direction = if log_accept(&point) > 0.5_f64.ln() { 1. } else { -1. };
+        for _ in 0..100 {
+            if direction * log_accept(&point) <= direction * 0.5_f64.ln() {
+                break;
+            }
+            step_size *= 2_f64.powf(direction);
+            point = target.leapfrog(rng, start, step_size, inv_mass)?;
+        }
+        Ok(step_size)
+    }
+
+    /// Perform one NUTS update of the continuous choices in the `selection` of the given `trace` under the `model`,
+    /// adapting the step size and mass matrix if still in warmup.
+    ///
+    /// The selected choices must be `f64` or `DVector<f64>` valued, and `model` must implement `choice_gradients`
+    /// (a `DynGenFn` must sample them from the distributions of `ad`, eg. `ad::normal`).
+    /// Returns the new trace, and whether it differs from the given `trace`.
+    ///
+    /// Returns an error if the `model` fails.
+    pub fn step<Args: Clone,Ret: Clone>(
+        &mut self,
+        rng: &mut dyn RngCore,
+        model: &impl GenFn<Args,DynTrie,Ret>,
+        trace: DynTrace<Args,Ret>,
+        selection: &AddrMap
+    ) -> Result<(DynTrace<Args,Ret>, bool),GenFnError> {
+        let (target, q, grad) = Target::new(rng, model, trace, selection)?;
+        let inv_mass = match &self.inv_mass {
+            Some(inv_mass) if inv_mass.len() == q.len() => inv_mass.clone(),
+            _ => DVector::from_element(q.len(), 1.)
+        };
+        let start = Point { p: sample_momentum(rng, &inv_mass), q, logp: target.trace.logjp, grad };
+        let step_size = match self.step_size {
+            Some(step_size) => step_size,
+            None => {
+                let step_size = Self::initial_step_size(rng, &target, &start, &inv_mass)?;
+                self.dual_avg = DualAveraging::new(step_size);
+                step_size
+            }
+        };
+
+        // build the trajectory by repeated doubling
+        let joint0 = joint_logp(&start, &inv_mass);
+        let log_slice = joint0 + rng.sample(Uniform::new(0_f64, 1_f64)).ln();
+        let trajectory = Trajectory { target: &target, log_slice, joint0, step_size, inv_mass: &inv_mass };
+        let (mut minus, mut plus) = (start.clone(), start.clone());
+        let mut proposal = start.clone();
+        let mut moved = false;
+        let mut n_valid = 1;
+        let (mut sum_accept, mut n_accept) = (0., 0);
+        for depth in 0..self.max_depth {
+            let direction = if rng.gen_bool(0.5) { 1. } else { -1. };
+            let edge = if direction < 0. { minus.clone() } else { plus.clone() };
+            let tree = trajectory.build_tree(rng, &edge, direction, depth)?;
+            if direction < 0. {
+                minus = tree.minus;
+            } else {
+                plus = tree.plus;
+            }
+            sum_accept += tree.sum_accept;
+            n_accept += tree.n_accept;
+            if !tree.valid {
+                break;
+            }
+            if rng.sample(Uniform::new(0_f64, 1_f64)) < tree.n_valid as f64 / n_valid as f64 {
+                proposal = tree.proposal;
+                moved = true;
+            }
+            n_valid += tree.n_valid;
+            if !no_u_turn(&minus, &plus, &inv_mass) {
+                break;
+            }
+        }
+        self.accept_stat = if n_accept > 0 { sum_accept / n_accept as f64 } else { 0. };
+        self.step_size = Some(step_size);
+        self.adapt(&proposal.q);
+
+        if moved {
+            Ok((target.trace_at(rng, &proposal.q)?, true))
+        } else {
+            Ok((target.trace, false))
+        }
+    }
+
+    /// Adapt the step size and mass matrix after a warmup step that ended at `q`.
+    fn adapt(&mut self, q: &DVector<f64>) {
+        if !self.is_warmup() {
+            return;
+        }
+        self.step_size = Some(self.dual_avg.update(self.accept_stat, self.target_accept));
+        let window_start = (self.num_warmup as f64 * 0.15) as usize;
+        let window_end = self.window_ends.last().copied().unwrap_or(0);
+        if self.iteration >= window_start && self.iteration < window_end {
+            self.var_estimate.push(q);
+            if self.window_ends.contains(&(self.iteration + 1)) {
+                if self.var_estimate.count >= 2. {  // else too short a window (for a tiny `num_warmup`) to estimate a variance
+                    self.inv_mass = Some(self.var_estimate.variance());
+                }
+                self.var_estimate = Welford::new(q.len());
+                self.dual_avg = DualAveraging::new(self.step_size.unwrap());
+            }
+        }
+        self.iteration += 1;
+        if !self.is_warmup() && self.dual_avg.count > 0. {
+            self.step_size = Some(self.dual_avg.log_step_bar.exp());
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::{logsumexp, Trace, GenFn, Distribution, categorical};
-use rand::rngs::ThreadRng;
+use crate::{logsumexp, Trace, GenFn, GenFnError, Merge, Distribution, categorical};
+use rand::RngCore;
 
 
 /// Performs inference for a `GenFn` via importance sampling.
@@ -9,15 +9,24 @@ use rand::rngs::ThreadRng;
 /// 1. a vector of traces generated from `model` under the `constraints`.
 /// 2. the log of the normalized weights using the internal proposal.
 /// 3. the log marginal likelihood estimate of the `constraints` under the `model`.
+/// 
+/// Returns the first error raised by `model.try_generate`, if any.
 pub fn importance_sampling<Args: Clone,Data: Clone,Ret>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     model_args: Args,
     constraints: Data,
     num_samples: u32
-) -> (Vec<Trace<Args,Data,Ret>>, Vec<f64>, f64) {
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<f64>, f64),GenFnError> {
     let out = (0..num_samples)
-        .map(|_| model.generate(model_args.clone(), constraints.clone()))
-        .collect::<Vec<(Trace<Args,Data,Ret>,f64)>>();
+        .map(|_| model.try_generate(rng, model_args.clone(), constraints.clone()))
+        .collect::<Result<Vec<(Trace<Args,Data,Ret>,f64)>,GenFnError>>()?;
+    Ok(normalize(out))
+}
+
+/// Split weighted traces into the traces, their log normalized weights, and the log marginal likelihood estimate.
+pub(crate) fn normalize<Args,Data,Ret>(out: Vec<(Trace<Args,Data,Ret>,f64)>) -> (Vec<Trace<Args,Data,Ret>>, Vec<f64>, f64) {
+    let num_samples = out.len();
     let log_total_weight = logsumexp(&out.iter().map(|(_, w)| *w).collect::<Vec<f64>>());
     let log_ml_estimate = log_total_weight - (num_samples as f64).ln();
     let log_normalized_weights = out.iter()
@@ -34,18 +43,84 @@ pub fn importance_sampling<Args: Clone,Data: Clone,Ret>(
 /// 1. a vector of traces generated from `model` under the `constraints`.
 /// 2. a resampled set of traces according to the normalized probabilities.
 /// 3. the log marginal likelihood estimate of the `constraints` under the `model`.
+/// 
+/// Returns the first error raised by `model.try_generate`, if any.
 pub fn importance_resampling<Args: Clone,Data: Clone,Ret>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     model_args: Args,
     constraints: Data,
     num_samples: u32,
     num_ret_samples: u32
-) -> (Vec<Trace<Args,Data,Ret>>, Vec<usize>, f64) {
-    let (traces, weights, log_ml_estimate) = importance_sampling(model, model_args, constraints, num_samples);
-    let mut rng = ThreadRng::default();
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<usize>, f64),GenFnError> {
+    let (traces, weights, log_ml_estimate) = importance_sampling(rng, model, model_args, constraints, num_samples)?;
+    let resampled_indices = resample_indices(rng, &weights, num_ret_samples);
+    Ok((traces, resampled_indices, log_ml_estimate))
+}
+
+/// Performs inference for a `GenFn` via importance sampling, using a custom `proposal`.
+/// 
+/// Each sample draws choices from the `proposal` (given `proposal_args`), then generates a trace from
+/// the `model` constrained by both the `constraints` and the proposed choices. Its weight is the
+/// difference of the scores of all these choices under the `model` and of the proposed choices under the `proposal`.
+/// So the `proposal` should sample every unconstrained choice of the `model`, and none of the `constraints`.
+/// 
+/// Given a `model`, input arguments `model_args`, `constraints`, a `proposal` and its `proposal_args`,
+/// returns a tuple of:
+/// 1. a vector of traces generated from `model` under the `constraints` and proposed choices.
+/// 2. the log of the normalized weights.
+/// 3. the log marginal likelihood estimate of the `constraints` under the `model`.
+/// 
+/// Returns the first error raised by `proposal.try_propose`, `model.try_generate`, or when merging
+/// the `constraints` and proposed choices (if the `proposal` samples a constrained address), if any.
+pub fn importance_sampling_with_proposal<Args: Clone,Data: Clone + Merge,Ret,ProposalArgs: Clone,ProposalRet>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Data,Ret>,
+    model_args: Args,
+    constraints: Data,
+    proposal: &impl GenFn<ProposalArgs,Data,ProposalRet>,
+    proposal_args: ProposalArgs,
+    num_samples: u32
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<f64>, f64),GenFnError> {
+    let out = (0..num_samples)
+        .map(|_| {
+            let (choices, proposal_score) = proposal.try_propose(rng, proposal_args.clone())?;
+            let (trace, model_score) = model.try_generate(rng, model_args.clone(), constraints.clone().try_merge(choices)?)?;
+            Ok((trace, model_score - proposal_score))
+        })
+        .collect::<Result<Vec<(Trace<Args,Data,Ret>,f64)>,GenFnError>>()?;
+    Ok(normalize(out))
+}
+
+/// Performs inference for a `GenFn` via importance resampling, using a custom `proposal`.
+/// 
+/// Given a `model`, input arguments `model_args`, `constraints`, a `proposal` and its `proposal_args`,
+/// returns a tuple of:
+/// 1. a vector of traces generated from `model` under the `constraints` and proposed choices.
+/// 2. a resampled set of traces according to the normalized probabilities.
+/// 3. the log marginal likelihood estimate of the `constraints` under the `model`.
+/// 
+/// Returns the first error raised by `importance_sampling_with_proposal`, if any.
+#[allow(clippy::too_many_arguments)]
+pub fn importance_resampling_with_proposal<Args: Clone,Data: Clone + Merge,Ret,ProposalArgs: Clone,ProposalRet>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Data,Ret>,
+    model_args: Args,
+    constraints: Data,
+    proposal: &impl GenFn<ProposalArgs,Data,ProposalRet>,
+    proposal_args: ProposalArgs,
+    num_samples: u32,
+    num_ret_samples: u32
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<usize>, f64),GenFnError> {
+    let (traces, weights, log_ml_estimate) = importance_sampling_with_proposal(rng, model, model_args, constraints, proposal, proposal_args, num_samples)?;
+    let resampled_indices = resample_indices(rng, &weights, num_ret_samples);
+    Ok((traces, resampled_indices, log_ml_estimate))
+}
+
+/// Draw `num_ret_samples` indices independently, in proportion to the exponentiated (normalized) log `weights`.
+pub(crate) fn resample_indices(rng: &mut dyn RngCore, weights: &[f64], num_ret_samples: u32) -> Vec<usize> {
     let probs = weights.iter().map(|w| w.exp()).collect::<Vec<f64>>();
-    let resampled_indices = (0..num_ret_samples).map(|_| {
-        categorical.random(&mut rng, probs.clone()) as usize
-    }).collect::<Vec<usize>>();
-    (traces, resampled_indices, log_ml_estimate)
-}
\ No newline at end of file
+    (0..num_ret_samples).map(|_| {
+        categorical.random(rng, probs.clone()) as usize
+    }).collect::<Vec<usize>>()
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc,Weak};
+use rand::{distributions::Uniform, Rng, RngCore};
+use nalgebra::{DVector,DMatrix};
+use crate::{Trace,GenFn,GenFnError,ArgDiff,Var,ad};
+
+
+/// Perform an involutive MCMC update of the `trace` under the `model`, as in Gen's `mh(trace, proposal, args, involution)`.
+///
+/// The `proposal` (which accepts a `Weak` reference to the `trace` as its first argument, like in `metropolis_hastings`)
+/// samples auxiliary choices `u`. The `involution` maps the current trace and `u` to a tuple of:
+/// 1. the constraints updating the model choices `x` to `x'` (including every choice `x'` has that `x` doesn't).
+/// 2. the backward choices `u'` of the `proposal` at the new trace (eg. the values of model choices removed by the move).
+/// 3. the log absolute determinant of the Jacobian of the map `(x, u) -> (x', u')` on the continuous choices it transforms
+///    (`0.` if it only copies values between them; see `log_abs_det_jacobian`).
+///
+/// The map must be an involution (applying it to `(x', u')` must give back `(x, u)`) for the move to leave the posterior
+/// invariant. Unlike `metropolis_hastings`, this supports moves that change the dimension of the trace (eg. split/merge moves).
+///
+/// Returns an error if the `model`, `proposal` or `involution` fails, rather than rejecting the move.
+pub fn involutive_mh<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static,ProposalArgs: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Data,Ret>,
+    trace: Trace<Args,Data,Ret>,
+    proposal: &impl GenFn<(Weak<Trace<Args,Data,Ret>>,ProposalArgs),Data,()>,
+    proposal_args: ProposalArgs,
+    involution: impl FnOnce(&Trace<Args,Data,Ret>, Data) -> Result<(Data,Data,f64),GenFnError>
+) -> Result<(Trace<Args,Data,Ret>, bool),GenFnError> {
+    let prev_trace = trace.clone();
+
+    let trace = Arc::new(trace);
+    let (fwd_choices, fwd_weight) = proposal.try_propose(rng, (Arc::downgrade(&trace), proposal_args.clone()))?;
+    let (constraints, bwd_choices, log_abs_det_jacobian) = involution(&trace, fwd_choices)?;
+    let trace = Arc::into_inner(trace).unwrap();
+
+    let args = trace.args.clone();
+    let (trace, _, weight, _) = model.try_update(rng, trace, args, ArgDiff::NoChange, constraints)?;
+
+    let trace = Arc::new(trace);
+    let bwd_weight = proposal.try_assess(rng, (Arc::downgrade(&trace), proposal_args), bwd_choices)?;
+    let trace = Arc::into_inner(trace).unwrap();
+
+    let alpha = weight - fwd_weight + bwd_weight + log_abs_det_jacobian;
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < alpha {
+        Ok((trace, true))
+    } else {
+        Ok((prev_trace, false))
+    }
+}
+
+
+/// Return the log absolute determinant of the Jacobian of `f` (a map from `R^n` to `R^n`) at `x`,
+/// computed exactly by automatic differentiation through the `Var`s `f` is applied to (see `ad`).
+///
+/// Useful to compute the Jacobian correction of an `involutive_mh` move from the map it applies to the continuous choices.
+/// Returns an `InvalidArgs` error if `f` doesn't map `x` to a vector of the same length.
+pub fn log_abs_det_jacobian(f: impl Fn(&DVector<Var>) -> DVector<Var>, x: &DVector<f64>) -> Result<f64,GenFnError> {
+    let n = x.len();
+    let mut jacobian = DMatrix::zeros(n, n);
+    for i in 0..n {
+        let (leaves, adjoints) = ad::gradients(|| {
+            let leaves = x.map(Var::leaf);
+            let y = f(&leaves);
+            if y.len() != n {
+                return Err(GenFnError::InvalidArgs(format!("`f` must map R^{} to R^{}, not R^{}", n, n, y.len())));
+            }
+            Ok((y[i], leaves))
+        })?;
+        jacobian.set_row(i, &leaves.map(|x| adjoints.of(&x)).transpose());
+    }
+    Ok(jacobian.determinant().abs().ln())
+}
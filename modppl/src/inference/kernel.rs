@@ -0,0 +1,324 @@
+use std::ops::{Add,AddAssign};
+use std::sync::Weak;
+use rand::RngCore;
+use crate::{Trace,GenFn,GenFnError,AddrMap,Distribution,categorical,metropolis_hastings,regenerative_metropolis_hastings,involutive_mh};
+
+
+/// Acceptance statistics of one or more applications of a `Kernel`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct AcceptStats {
+    /// Number of proposed moves.
+    pub num_proposed: usize,
+
+    /// Number of accepted moves.
+    pub num_accepted: usize
+}
+
+impl AcceptStats {
+    /// Return the statistics of a single move, which was `accepted` or not.
+    pub fn single(accepted: bool) -> Self {
+        AcceptStats { num_proposed: 1, num_accepted: accepted as usize }
+    }
+
+    /// Return the fraction of proposed moves that were accepted (`NaN` if none were proposed).
+    pub fn acceptance_rate(&self) -> f64 {
+        self.num_accepted as f64 / self.num_proposed as f64
+    }
+}
+
+impl Add for AcceptStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        AcceptStats {
+            num_proposed: self.num_proposed + other.num_proposed,
+            num_accepted: self.num_accepted + other.num_accepted
+        }
+    }
+}
+
+impl AddAssign for AcceptStats {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+
+/// Interface for MCMC transition kernels on the traces of a generative function.
+///
+/// Kernels are values that can be combined into larger inference programs with `then`, `repeat`, `when`,
+/// `Cycle`, `Mixture` and `Conditional`. Each combinator leaves the target invariant if its components do.
+pub trait Kernel<Args,Data,Ret> {
+    /// Apply the kernel to a `trace`, returning the new trace and the acceptance statistics of its moves.
+    ///
+    /// Returns the first error raised by the underlying model or proposal, rather than rejecting the move.
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError>;
+
+    /// Apply `self`, then `next`.
+    fn then<K: Kernel<Args,Data,Ret>>(self, next: K) -> Sequence<Self,K> where Self: Sized {
+        Sequence { first: self, second: next }
+    }
+
+    /// Apply `self` `n` times in a row.
+    fn repeat(self, n: usize) -> Repeat<Self> where Self: Sized {
+        Repeat { kernel: self, n }
+    }
+
+    /// Apply `self` only if the `predicate` holds for the trace (otherwise leave the trace unchanged).
+    ///
+    /// The kernel must not change what the `predicate` depends on, or the target won't be left invariant.
+    fn when<P: FnMut(&Trace<Args,Data,Ret>) -> bool>(self, predicate: P) -> Conditional<P,Self,Identity> where Self: Sized {
+        Conditional { predicate, kernel: self, otherwise: Identity }
+    }
+
+    /// Box `self`, eg. to collect kernels of different types into a `Cycle` or `Mixture`.
+    fn boxed<'a>(self) -> Box<dyn Kernel<Args,Data,Ret> + 'a> where Self: Sized + 'a {
+        Box::new(self)
+    }
+}
+
+impl<Args,Data,Ret> Kernel<Args,Data,Ret> for Box<dyn Kernel<Args,Data,Ret> + '_> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        (**self).apply(rng, trace)
+    }
+}
+
+/// Any function that returns the new trace and whether its move was accepted
+/// (eg. `|rng, trace| hmc(rng, &model, trace, &selection, 0.1, 10)`) is a `Kernel` proposing a single move.
+impl<Args,Data,Ret,F> Kernel<Args,Data,Ret> for F
+where F: FnMut(&mut dyn RngCore, Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,bool),GenFnError> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let (trace, accepted) = self(rng, trace)?;
+        Ok((trace, AcceptStats::single(accepted)))
+    }
+}
+
+/// Return the function `f` as it is, for its arguments' types to be inferred as those of a `Kernel`
+/// (eg. `kernel_fn(|rng, trace| hmc(rng, &model, trace, &selection, 0.1, 10)).repeat(5)`).
+pub fn kernel_fn<Args,Data,Ret,F>(f: F) -> F
+where F: FnMut(&mut dyn RngCore, Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,bool),GenFnError> {
+    f
+}
+
+
+/// The kernel that leaves every trace unchanged (proposing no moves).
+#[derive(Debug,Clone,Copy,Default)]
+pub struct Identity;
+
+impl<Args,Data,Ret> Kernel<Args,Data,Ret> for Identity {
+    fn apply(&mut self, _: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        Ok((trace, AcceptStats::default()))
+    }
+}
+
+/// `regenerative_metropolis_hastings` of the `mask` of choices under the `model`, as a `Kernel`.
+pub struct RegenMh<'a,F> {
+    /// The model whose traces are updated.
+    pub model: &'a F,
+
+    /// The choices to regenerate.
+    pub mask: AddrMap
+}
+
+impl<'a,F> RegenMh<'a,F> {
+    /// Construct a kernel that regenerates the `mask` of choices under the `model`.
+    pub fn new(model: &'a F, mask: AddrMap) -> Self {
+        RegenMh { model, mask }
+    }
+}
+
+impl<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static,F: GenFn<Args,Data,Ret>> Kernel<Args,Data,Ret> for RegenMh<'_,F> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let (trace, accepted) = regenerative_metropolis_hastings(rng, self.model, trace, &self.mask)?;
+        Ok((trace, AcceptStats::single(accepted)))
+    }
+}
+
+/// `metropolis_hastings` with a custom `proposal` under the `model`, as a `Kernel`.
+pub struct Mh<'a,F,P,ProposalArgs> {
+    /// The model whose traces are updated.
+    pub model: &'a F,
+
+    /// The proposal, which accepts a `Weak` reference to the trace as its first argument.
+    pub proposal: &'a P,
+
+    /// The remaining arguments of the proposal.
+    pub proposal_args: ProposalArgs
+}
+
+impl<'a,F,P,ProposalArgs> Mh<'a,F,P,ProposalArgs> {
+    /// Construct a kernel that proposes new choices under the `model` from the `proposal` with `proposal_args`.
+    pub fn new(model: &'a F, proposal: &'a P, proposal_args: ProposalArgs) -> Self {
+        Mh { model, proposal, proposal_args }
+    }
+}
+
+impl<Args,Data,Ret,F,P,ProposalArgs> Kernel<Args,Data,Ret> for Mh<'_,F,P,ProposalArgs>
+where
+    Args: Clone + 'static, Data: Clone + 'static, Ret: Clone + 'static, ProposalArgs: Clone,
+    F: GenFn<Args,Data,Ret>,
+    P: GenFn<(Weak<Trace<Args,Data,Ret>>,ProposalArgs),Data,()>
+{
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let (trace, accepted) = metropolis_hastings(rng, self.model, trace, self.proposal, self.proposal_args.clone())?;
+        Ok((trace, AcceptStats::single(accepted)))
+    }
+}
+
+
+/// `involutive_mh` with a `proposal` of auxiliary choices and an `involution` under the `model`, as a `Kernel`.
+pub struct InvolutiveMh<'a,F,P,ProposalArgs,I> {
+    /// The model whose traces are updated.
+    pub model: &'a F,
+
+    /// The proposal of the auxiliary choices, which accepts a `Weak` reference to the trace as its first argument.
+    pub proposal: &'a P,
+
+    /// The remaining arguments of the proposal.
+    pub proposal_args: ProposalArgs,
+
+    /// The involution, returning the model constraints, the backward proposal choices and the log absolute Jacobian determinant.
+    pub involution: I
+}
+
+impl<'a,F,P,ProposalArgs,I> InvolutiveMh<'a,F,P,ProposalArgs,I> {
+    /// Construct a kernel that proposes auxiliary choices from the `proposal` with `proposal_args` and transforms them with the `involution`.
+    pub fn new(model: &'a F, proposal: &'a P, proposal_args: ProposalArgs, involution: I) -> Self {
+        InvolutiveMh { model, proposal, proposal_args, involution }
+    }
+}
+
+impl<Args,Data,Ret,F,P,ProposalArgs,I> Kernel<Args,Data,Ret> for InvolutiveMh<'_,F,P,ProposalArgs,I>
+where
+    Args: Clone + 'static, Data: Clone + 'static, Ret: Clone + 'static, ProposalArgs: Clone,
+    F: GenFn<Args,Data,Ret>,
+    P: GenFn<(Weak<Trace<Args,Data,Ret>>,ProposalArgs),Data,()>,
+    I: FnMut(&Trace<Args,Data,Ret>, Data) -> Result<(Data,Data,f64),GenFnError>
+{
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let (trace, accepted) = involutive_mh(rng, self.model, trace, self.proposal, self.proposal_args.clone(), &mut self.involution)?;
+        Ok((trace, AcceptStats::single(accepted)))
+    }
+}
+
+
+/// Apply the `first` kernel, then the `second` (see `Kernel::then`).
+pub struct Sequence<K1,K2> {
+    first: K1,
+    second: K2
+}
+
+impl<Args,Data,Ret,K1: Kernel<Args,Data,Ret>,K2: Kernel<Args,Data,Ret>> Kernel<Args,Data,Ret> for Sequence<K1,K2> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let (trace, first_stats) = self.first.apply(rng, trace)?;
+        let (trace, second_stats) = self.second.apply(rng, trace)?;
+        Ok((trace, first_stats + second_stats))
+    }
+}
+
+/// Apply a kernel `n` times in a row (see `Kernel::repeat`).
+pub struct Repeat<K> {
+    kernel: K,
+    n: usize
+}
+
+impl<Args,Data,Ret,K: Kernel<Args,Data,Ret>> Kernel<Args,Data,Ret> for Repeat<K> {
+    fn apply(&mut self, rng: &mut dyn RngCore, mut trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let mut stats = AcceptStats::default();
+        for _ in 0..self.n {
+            let step_stats;
+            (trace, step_stats) = self.kernel.apply(rng, trace)?;
+            stats += step_stats;
+        }
+        Ok((trace, stats))
+    }
+}
+
+/// Apply the next of a list of kernels at each application, cycling back to the first after the last
+/// (a deterministic scan, one kernel at a time).
+pub struct Cycle<'a,Args,Data,Ret> {
+    kernels: Vec<Box<dyn Kernel<Args,Data,Ret> + 'a>>,
+    next: usize
+}
+
+impl<'a,Args,Data,Ret> Cycle<'a,Args,Data,Ret> {
+    /// Construct a cycle through the `kernels`, starting from the first.
+    ///
+    /// Panics if `kernels` is empty.
+    pub fn new(kernels: Vec<Box<dyn Kernel<Args,Data,Ret> + 'a>>) -> Self {
+        assert!(!kernels.is_empty(), "Cycle::new: `kernels` must not be empty");
+        Cycle { kernels, next: 0 }
+    }
+}
+
+impl<Args,Data,Ret> Kernel<Args,Data,Ret> for Cycle<'_,Args,Data,Ret> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let i = self.next;
+        self.next = (self.next + 1) % self.kernels.len();
+        self.kernels[i].apply(rng, trace)
+    }
+}
+
+/// Apply one of a list of kernels, chosen at random with fixed probabilities at each application (a random scan).
+pub struct Mixture<'a,Args,Data,Ret> {
+    kernels: Vec<Box<dyn Kernel<Args,Data,Ret> + 'a>>,
+    probs: Vec<f64>
+}
+
+impl<'a,Args,Data,Ret> Mixture<'a,Args,Data,Ret> {
+    /// Construct a mixture of the `kernels`, each chosen in proportion to its weight.
+    ///
+    /// Panics if `weighted_kernels` is empty, if a weight is negative or not finite, or if every weight is zero.
+    pub fn new(weighted_kernels: Vec<(f64,Box<dyn Kernel<Args,Data,Ret> + 'a>)>) -> Self {
+        assert!(!weighted_kernels.is_empty(), "Mixture::new: `weighted_kernels` must not be empty");
+        for (i, (w, _)) in weighted_kernels.iter().enumerate() {
+            assert!(w.is_finite() && *w >= 0., "Mixture::new: the weight of kernel {i} must be finite and non-negative (got {w})");
+        }
+        let total_weight = weighted_kernels.iter().map(|(w, _)| w).sum::<f64>();
+        assert!(total_weight > 0., "Mixture::new: at least one weight must be positive");
+        let (probs, kernels) = weighted_kernels.into_iter().map(|(w, kernel)| (w / total_weight, kernel)).unzip();
+        Mixture { kernels, probs }
+    }
+
+    /// Construct a mixture choosing uniformly among the `kernels`.
+    ///
+    /// Panics if `kernels` is empty.
+    pub fn uniform(kernels: Vec<Box<dyn Kernel<Args,Data,Ret> + 'a>>) -> Self {
+        Self::new(kernels.into_iter().map(|kernel| (1., kernel)).collect())
+    }
+}
+
+impl<Args,Data,Ret> Kernel<Args,Data,Ret> for Mixture<'_,Args,Data,Ret> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        let i = categorical.random(rng, self.probs.clone()) as usize;
+        self.kernels[i].apply(rng, trace)
+    }
+}
+
+/// Apply a `kernel` if the `predicate` holds for the trace, and otherwise the kernel `otherwise` (see `Kernel::when`).
+///
+/// Neither kernel may change what the `predicate` depends on (eg. a discrete choice selecting the model structure),
+/// or the target won't be left invariant.
+pub struct Conditional<P,K1,K2> {
+    predicate: P,
+    kernel: K1,
+    otherwise: K2
+}
+
+impl<P,K1,K2> Conditional<P,K1,K2> {
+    /// Construct a kernel that applies `kernel` if the `predicate` holds for the trace, and otherwise `otherwise`.
+    pub fn new(predicate: P, kernel: K1, otherwise: K2) -> Self {
+        Conditional { predicate, kernel, otherwise }
+    }
+}
+
+impl<Args,Data,Ret,P,K1,K2> Kernel<Args,Data,Ret> for Conditional<P,K1,K2>
+where P: FnMut(&Trace<Args,Data,Ret>) -> bool, K1: Kernel<Args,Data,Ret>, K2: Kernel<Args,Data,Ret> {
+    fn apply(&mut self, rng: &mut dyn RngCore, trace: Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,AcceptStats),GenFnError> {
+        if (self.predicate)(&trace) {
+            self.kernel.apply(rng, trace)
+        } else {
+            self.otherwise.apply(rng, trace)
+        }
+    }
+}
@@ -1,30 +1,33 @@
 use std::sync::{Arc,Weak};
-use rand::{distributions::Uniform, rngs::ThreadRng, Rng};
-use crate::{Trace,GenFn,AddrMap,ArgDiff};
+use rand::{distributions::Uniform, Rng, RngCore};
+use crate::{Trace,GenFn,GenFnError,AddrMap,ArgDiff};
 
 
 /// Perform a Metropolis-Hastings update that proposes new values for some subset of random choices in the given `trace` under the `model` using the given `proposal` generative function.
 /// 
 /// The `proposal` shares the same trace data structure as the `model`, but must accept a `Weak` reference to the `trace` as its first argument and return an empty tuple `()`.
+/// 
+/// Returns an error if the `model` or `proposal` fails, rather than rejecting the move.
 pub fn metropolis_hastings<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static,ProposalArgs: Clone>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     trace: Trace<Args,Data,Ret>,
     proposal: &impl GenFn<(Weak<Trace<Args,Data,Ret>>,ProposalArgs),Data,()>,
     proposal_args: ProposalArgs
-) -> (Trace<Args,Data,Ret>, bool) {
+) -> Result<(Trace<Args,Data,Ret>, bool),GenFnError> {
     let prev_trace = trace.clone();
 
     let trace = Arc::new(trace);
     let proposal_args_forward = (Arc::downgrade(&trace), proposal_args.clone());
-    let (fwd_choices, fwd_weight) = proposal.propose(proposal_args_forward);
+    let (fwd_choices, fwd_weight) = proposal.try_propose(rng, proposal_args_forward)?;
     let trace = Arc::into_inner(trace).unwrap();
 
     let args = trace.args.clone();
-    let (trace, discard, weight) = model.update(trace, args.clone(), ArgDiff::NoChange, fwd_choices);
+    let (trace, discard, weight, _) = model.try_update(rng, trace, args.clone(), ArgDiff::NoChange, fwd_choices)?;
 
     let trace = Arc::new(trace);
     let proposal_args_backward = (Arc::downgrade(&trace), proposal_args);
-    let bwd_weight = proposal.assess(proposal_args_backward, discard);
+    let bwd_weight = proposal.try_assess(rng, proposal_args_backward, discard)?;
     let trace = Arc::into_inner(trace).unwrap();
 
     // dbg!(weight);
@@ -32,45 +35,50 @@ pub fn metropolis_hastings<Args: Clone + 'static,Data: Clone + 'static,Ret: Clon
     // dbg!(bwd_weight);
 
     let alpha = weight - fwd_weight + bwd_weight;
-    if ThreadRng::default().sample(Uniform::new(0_f64, 1_f64)).ln() < alpha {
-        (trace, true)
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < alpha {
+        Ok((trace, true))
     } else {
-        (prev_trace, false)
+        Ok((prev_trace, false))
     }
 }
 
 /// Alias for `metropolis_hastings`.
 pub fn mh<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static,ProposalArgs: Clone>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     trace: Trace<Args,Data,Ret>,
     proposal: &impl GenFn<(Weak<Trace<Args,Data,Ret>>,ProposalArgs),Data,()>,
     proposal_args: ProposalArgs
-) -> (Trace<Args,Data,Ret>, bool) {
-    metropolis_hastings(model, trace, proposal, proposal_args)
+) -> Result<(Trace<Args,Data,Ret>, bool),GenFnError> {
+    metropolis_hastings(rng, model, trace, proposal, proposal_args)
 }
 
 
 /// Perform a Metropolis-Hastings update that proposes new values for some `mask` of random choices in the given `trace` under the `model` using the internal proposal.
+/// 
+/// Returns an error if the `model` fails to regenerate, rather than rejecting the move.
 pub fn regenerative_metropolis_hastings<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     trace: Trace<Args,Data,Ret>,
     mask: &AddrMap,
-) -> (Trace<Args,Data,Ret>, bool) {
+) -> Result<(Trace<Args,Data,Ret>, bool),GenFnError> {
     let prev_trace = trace.clone();
     let args = trace.args.clone();
-    let (trace, weight) = model.regenerate(trace, args, ArgDiff::NoChange, mask);
-    if ThreadRng::default().sample(Uniform::new(0_f64, 1_f64)).ln() < weight {
-        (trace, true)
+    let (trace, weight, _) = model.try_regenerate(rng, trace, args, ArgDiff::NoChange, mask)?;
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < weight {
+        Ok((trace, true))
     } else {
-        (prev_trace, false)
+        Ok((prev_trace, false))
     }
 }
 
 /// Alias for `regenerative_metropolis_hastings`.
 pub fn regen_mh<Args: Clone + 'static,Data: Clone + 'static,Ret: Clone + 'static>(
+    rng: &mut dyn RngCore,
     model: &impl GenFn<Args,Data,Ret>,
     trace: Trace<Args,Data,Ret>,
     mask: &AddrMap,
-) -> (Trace<Args,Data,Ret>, bool) {
-    regenerative_metropolis_hastings(model, trace, mask)
+) -> Result<(Trace<Args,Data,Ret>, bool),GenFnError> {
+    regenerative_metropolis_hastings(rng, model, trace, mask)
 }
\ No newline at end of file
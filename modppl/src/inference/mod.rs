@@ -1,10 +1,54 @@
-///
+/// Importance sampling and importance resampling.
 pub mod importance;
-///
+/// Metropolis-Hastings kernels.
 pub mod mh;
-///
+/// Adaptive random-walk Metropolis.
+pub mod adaptive_mh;
+/// Involutive MCMC, for moves that change the structure or dimension of a trace.
+pub mod involutive;
+/// Hamiltonian Monte Carlo and the No-U-Turn Sampler.
+pub mod hmc;
+/// Elliptical slice sampling for choices with Gaussian priors.
+pub mod elliptical_slice;
+/// Enumerative Gibbs updates of discrete choices.
+pub mod gibbs;
+/// MCMC chain driver with warmup, thinning and multiple chains.
+pub mod chain;
+/// Composable MCMC kernels and kernel combinators.
+pub mod kernel;
+/// Convergence diagnostics for MCMC output: R-hat, effective sample sizes, Monte Carlo standard errors and autocorrelation.
+pub mod diagnostics;
+/// Gradient-based optimizers for the parameters in a `ParamStore`.
+pub mod optimizers;
+/// Particle filtering with `ParticleSystem`.
 pub mod particle_filter;
+/// High-level sequential Monte Carlo driver.
+pub mod smc;
+/// Conditional SMC and particle Gibbs for state-space models.
+pub mod particle_gibbs;
+/// Smoothing of particle filters over `DynUnfold` models by backward simulation.
+pub mod smoothing;
+/// Annealed importance sampling and tempered SMC for static models.
+pub mod tempering;
+/// Parallel importance sampling and MCMC chains on the rayon thread pool (requires the `parallel` feature).
+#[cfg(feature = "parallel")]
+pub mod parallel;
 
-pub use self::importance::{importance_sampling, importance_resampling};
+pub use self::importance::{importance_sampling, importance_resampling, importance_sampling_with_proposal, importance_resampling_with_proposal};
 pub use self::mh::{metropolis_hastings, mh, regenerative_metropolis_hastings, regen_mh};
-pub use self::particle_filter::ParticleSystem;
\ No newline at end of file
+pub use self::adaptive_mh::AdaptiveMh;
+pub use self::involutive::{involutive_mh, log_abs_det_jacobian};
+pub use self::hmc::{hamiltonian_monte_carlo, hmc, Nuts};
+pub use self::elliptical_slice::elliptical_slice;
+pub use self::gibbs::enumerative_gibbs;
+pub use self::chain::{Chain, McmcSamples, run_mcmc};
+pub use self::kernel::{Kernel, AcceptStats, Identity, kernel_fn, RegenMh, Mh, InvolutiveMh, Sequence, Repeat, Cycle, Mixture, Conditional};
+pub use self::diagnostics::{autocorrelation, split_rhat, rhat, split_ess, ess_bulk, ess_tail, mcse_mean};
+pub use self::optimizers::{Optimizer, Sgd, Adam};
+pub use self::particle_filter::{ParticleSystem, ResamplingScheme};
+pub use self::smc::{smc, SmcSummary};
+pub use self::particle_gibbs::{conditional_smc, particle_gibbs};
+pub use self::smoothing::backward_simulation;
+pub use self::tempering::{tempered_smc, annealed_importance_sampling, tempered_mh, tempered_regen_mh};
+#[cfg(feature = "parallel")]
+pub use self::parallel::{rng_streams, par_importance_sampling, par_importance_resampling, par_mcmc};
\ No newline at end of file
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use crate::{Addr,ParamStore};
+
+
+/// Interface for gradient-based optimizers of the parameters in a `ParamStore`.
+///
+/// Gradients accumulated by `GenFn::accumulate_param_gradients` are gradients of log densities,
+/// so optimizers take steps of gradient _ascent_ (eg. towards a maximum-likelihood estimate).
+pub trait Optimizer {
+    /// Update every parameter in `store` with its accumulated gradient, then reset the gradients to zero.
+    fn apply(&mut self, store: &ParamStore);
+}
+
+
+/// Stochastic gradient ascent with a fixed `learning_rate`.
+pub struct Sgd {
+    /// Scale of each step along the accumulated gradient.
+    pub learning_rate: f64
+}
+
+impl Sgd {
+    /// Construct an `Sgd` optimizer with the given `learning_rate`.
+    pub fn new(learning_rate: f64) -> Self {
+        Sgd { learning_rate }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn apply(&mut self, store: &ParamStore) {
+        for name in store.names() {
+            let grad = store.grad_coords(&name).unwrap();
+            let coords = store.coords(&name).unwrap().iter()
+                .zip(grad)
+                .map(|(x, g)| x + self.learning_rate * g)
+                .collect::<Vec<f64>>();
+            store.set_coords(&name, &coords).unwrap();
+        }
+        store.zero_grads();
+    }
+}
+
+
+/// The Adam optimizer, as described in:
+///
+/// > Adam: A Method for Stochastic Optimization.
+/// > Kingma, D. P.; and Ba, J.
+/// > In Proceedings of the 3rd International Conference on Learning Representations (ICLR ‘15).
+pub struct Adam {
+    /// Scale of each step.
+    pub learning_rate: f64,
+
+    /// Exponential decay rate of the first moment estimates.
+    pub beta1: f64,
+
+    /// Exponential decay rate of the second moment estimates.
+    pub beta2: f64,
+
+    /// Small constant for numerical stability.
+    pub epsilon: f64,
+
+    t: i32,
+    moments: HashMap<Addr,(Vec<f64>,Vec<f64>)>
+}
+
+impl Adam {
+    /// Construct an `Adam` optimizer with the given `learning_rate`, and the default
+    /// `beta1 = 0.9`, `beta2 = 0.999` and `epsilon = 1e-8`.
+    pub fn new(learning_rate: f64) -> Self {
+        Adam { learning_rate, beta1: 0.9, beta2: 0.999, epsilon: 1e-8, t: 0, moments: HashMap::new() }
+    }
+}
+
+impl Optimizer for Adam {
+    fn apply(&mut self, store: &ParamStore) {
+        self.t += 1;
+        let bias1 = 1. - self.beta1.powi(self.t);
+        let bias2 = 1. - self.beta2.powi(self.t);
+        for name in store.names() {
+            let grad = store.grad_coords(&name).unwrap();
+            let (m, v) = self.moments.entry(name.clone())
+                .or_insert_with(|| (vec![0.; grad.len()], vec![0.; grad.len()]));
+            let mut coords = store.coords(&name).unwrap();
+            for i in 0..coords.len() {
+                m[i] = self.beta1 * m[i] + (1. - self.beta1) * grad[i];
+                v[i] = self.beta2 * v[i] + (1. - self.beta2) * grad[i] * grad[i];
+                let m_hat = m[i] / bias1;
+                let v_hat = v[i] / bias2;
+                coords[i] += self.learning_rate * m_hat / (v_hat.sqrt() + self.epsilon);
+            }
+            store.set_coords(&name, &coords).unwrap();
+        }
+        store.zero_grads();
+    }
+}
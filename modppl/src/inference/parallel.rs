@@ -0,0 +1,77 @@
+use rand::{Rng,RngCore,SeedableRng,rngs::StdRng};
+use rayon::prelude::*;
+use crate::{Trace,GenFn,GenFnError};
+use super::importance::{normalize,resample_indices};
+
+
+/// Draw `n` independent `StdRng` streams, each seeded from `rng`.
+///
+/// Every parallel task runs on its own stream, so a run is reproducible from the seed of `rng`,
+/// regardless of the number of threads or how the tasks are scheduled.
+pub fn rng_streams<R: Rng + ?Sized>(rng: &mut R, n: usize) -> Vec<StdRng> {
+    (0..n).map(|_| StdRng::seed_from_u64(rng.gen())).collect()
+}
+
+/// Like `importance_sampling`, but generates the samples in parallel on the rayon thread pool.
+///
+/// Returns the first error raised by `model.try_generate`, if any.
+pub fn par_importance_sampling<Args: Clone + Send + Sync,Data: Clone + Send + Sync,Ret: Send>(
+    rng: &mut dyn RngCore,
+    model: &(impl GenFn<Args,Data,Ret> + Sync),
+    model_args: Args,
+    constraints: Data,
+    num_samples: u32
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<f64>, f64),GenFnError> {
+    let out = rng_streams(rng, num_samples as usize)
+        .into_par_iter()
+        .map(|mut stream| model.try_generate(&mut stream, model_args.clone(), constraints.clone()))
+        .collect::<Result<Vec<(Trace<Args,Data,Ret>,f64)>,GenFnError>>()?;
+    Ok(normalize(out))
+}
+
+/// Like `importance_resampling`, but generates the samples in parallel on the rayon thread pool.
+///
+/// Returns the first error raised by `model.try_generate`, if any.
+pub fn par_importance_resampling<Args: Clone + Send + Sync,Data: Clone + Send + Sync,Ret: Send>(
+    rng: &mut dyn RngCore,
+    model: &(impl GenFn<Args,Data,Ret> + Sync),
+    model_args: Args,
+    constraints: Data,
+    num_samples: u32,
+    num_ret_samples: u32
+) -> Result<(Vec<Trace<Args,Data,Ret>>, Vec<usize>, f64),GenFnError> {
+    let (traces, weights, log_ml_estimate) = par_importance_sampling(rng, model, model_args, constraints, num_samples)?;
+    let resampled_indices = resample_indices(rng, &weights, num_ret_samples);
+    Ok((traces, resampled_indices, log_ml_estimate))
+}
+
+/// Run an independent MCMC chain from each of the initial `traces` in parallel on the rayon thread pool.
+///
+/// Each chain applies the `kernel` (eg. `|rng, trace| regen_mh(rng, &model, trace, &mask)`) `num_iters` times,
+/// calling `record` on the trace after every iteration.
+///
+/// Returns, for each chain, its final trace, its recorded values and its number of accepted moves,
+/// or the first error raised by the `kernel`.
+pub fn par_mcmc<Args: Send,Data: Send,Ret: Send,T: Send>(
+    rng: &mut dyn RngCore,
+    traces: Vec<Trace<Args,Data,Ret>>,
+    num_iters: usize,
+    kernel: impl Fn(&mut dyn RngCore, Trace<Args,Data,Ret>) -> Result<(Trace<Args,Data,Ret>,bool),GenFnError> + Sync,
+    record: impl Fn(&Trace<Args,Data,Ret>) -> T + Sync
+) -> Result<Vec<(Trace<Args,Data,Ret>,Vec<T>,usize)>,GenFnError> {
+    let streams = rng_streams(rng, traces.len());
+    traces.into_par_iter()
+        .zip(streams)
+        .map(|(mut trace, mut stream)| {
+            let mut records = Vec::with_capacity(num_iters);
+            let mut num_accepted = 0;
+            for _ in 0..num_iters {
+                let accepted;
+                (trace, accepted) = kernel(&mut stream, trace)?;
+                num_accepted += accepted as usize;
+                records.push(record(&trace));
+            }
+            Ok((trace, records, num_accepted))
+        })
+        .collect()
+}
@@ -1,11 +1,105 @@
 // mostly copied verbatim from: https://github.com/OpenGen/GenTL/blob/main/include/gentl/inference/particle_filter.h
 
-use rand::rngs::ThreadRng;
-use crate::{Trace,GenFn,ArgDiff,Distribution,categorical,logsumexp};
+use std::sync::{Arc,Weak};
+use rand::{Rng,RngCore,rngs::ThreadRng};
+use crate::{Trace,GenFn,GenFnError,ArgDiff,Merge,u01,logsumexp};
+
+
+/// Scheme for drawing the parents of the particles in `ParticleSystem::resample`.
+///
+/// All schemes are unbiased and run in O(N) for N particles. `Systematic`, `Stratified` and `Residual`
+/// resampling have lower variance than `Multinomial` resampling.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum ResamplingScheme {
+    /// Draw each parent independently from the normalized weights.
+    #[default]
+    Multinomial,
+
+    /// Invert the weights' CDF at a single uniform offset into each of N equal strata.
+    Systematic,
+
+    /// Invert the weights' CDF at an independent uniform point within each of N equal strata.
+    Stratified,
+
+    /// Deterministically keep `floor(N * w_i)` copies of each particle, then draw the remainder
+    /// by multinomial resampling of the residual weights.
+    Residual
+}
+
+impl ResamplingScheme {
+    /// Draw `num_samples` parent indices according to the `normalized_weights` (which must sum to 1).
+    ///
+    /// The indices are returned in ascending order.
+    pub fn resample<R: Rng + ?Sized>(&self, rng: &mut R, normalized_weights: &[f64], num_samples: usize) -> Vec<usize> {
+        let n = num_samples as f64;
+        match self {
+            ResamplingScheme::Multinomial => {
+                invert_cdf(normalized_weights, &sorted_uniforms(rng, num_samples))
+            },
+            ResamplingScheme::Systematic => {
+                let offset = u01(rng);
+                let points = (0..num_samples).map(|i| (i as f64 + offset) / n).collect::<Vec<f64>>();
+                invert_cdf(normalized_weights, &points)
+            },
+            ResamplingScheme::Stratified => {
+                let points = (0..num_samples).map(|i| (i as f64 + u01(rng)) / n).collect::<Vec<f64>>();
+                invert_cdf(normalized_weights, &points)
+            },
+            ResamplingScheme::Residual => {
+                let mut parents = Vec::with_capacity(num_samples);
+                let mut residuals = Vec::with_capacity(normalized_weights.len());
+                for (i, w) in normalized_weights.iter().enumerate() {
+                    let copies = (n * w).floor();
+                    parents.extend(std::iter::repeat(i).take(copies as usize));
+                    residuals.push(n * w - copies);
+                }
+                let num_residual = num_samples.saturating_sub(parents.len());
+                if num_residual > 0 {
+                    let total = residuals.iter().sum::<f64>();
+                    residuals.iter_mut().for_each(|r| *r /= total);
+                    parents.extend(invert_cdf(&residuals, &sorted_uniforms(rng, num_residual)));
+                    parents.sort_unstable();
+                }
+                parents.truncate(num_samples);
+                parents
+            }
+        }
+    }
+}
+
+/// Draw `n` sorted uniforms in O(n), as the normalized cumulative sums of `n + 1` exponential spacings.
+fn sorted_uniforms<R: Rng + ?Sized>(rng: &mut R, n: usize) -> Vec<f64> {
+    let mut total = 0.;
+    let mut points = Vec::with_capacity(n);
+    for _ in 0..n {
+        total -= (1. - u01(rng)).ln();
+        points.push(total);
+    }
+    total -= (1. - u01(rng)).ln();
+    points.iter_mut().for_each(|u| *u /= total);
+    points
+}
+
+/// Return the index of the `weights`' CDF at each of the (ascending) `points`, in a single pass.
+fn invert_cdf(weights: &[f64], points: &[f64]) -> Vec<usize> {
+    let mut indices = Vec::with_capacity(points.len());
+    let mut i = 0;
+    let mut cumulative = weights[0];
+    for u in points {
+        while *u >= cumulative && i < weights.len() - 1 {
+            i += 1;
+            cumulative += weights[i];
+        }
+        indices.push(i);
+    }
+    indices
+}
 
 
 /// Basic particle filter for generative functions with a time parameter as the first input argument.
-pub struct ParticleSystem<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args),Data,Ret>> {
+/// 
+/// All randomness is drawn from the owned `rng`, so seeding it makes a run reproducible.
+pub struct ParticleSystem<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args),Data,Ret>,R: Rng = ThreadRng> {
     num_particles: usize,
     model: Box<F>,
 
@@ -14,109 +108,348 @@ pub struct ParticleSystem<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args)
 
     log_weights: Vec<f64>,
     log_normalized_weights: Vec<f64>,
-    two_times_log_normalized_weights: Vec<f64>,
     normalized_weights: Vec<f64>,
 
     parents: Vec<usize>,
-    rng: ThreadRng,
+    ancestry: Vec<Vec<usize>>,
+    history: Option<Vec<(Vec<Trace<(i64,Args),Data,Ret>>,Vec<f64>)>>,
+    resampling_scheme: ResamplingScheme,
+    rng: R,
 
     log_ml_estimate: f64
 }
 
-impl<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args),Data,Ret>> ParticleSystem<Args,Data,Ret,F> {
+impl<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args),Data,Ret>,R: Rng> ParticleSystem<Args,Data,Ret,F,R> {
     fn normalize_weights(&mut self) -> f64 {
         let log_total_weight = logsumexp(&self.log_weights);
         for i in 0..self.num_particles {
             self.log_normalized_weights[i] = self.log_weights[i] - log_total_weight;
-            self.two_times_log_normalized_weights[i] = 2.0 * self.log_normalized_weights[i];
             self.normalized_weights[i] = self.log_normalized_weights[i].exp();
         }
         log_total_weight
     }
 
-    fn multinomial_resampling(&mut self) {
-        for i in 0..self.num_particles {
-            self.parents[i] = categorical.random(&mut self.rng, self.normalized_weights.clone()) as usize;
+    /// Record the parents of the particles of a new step, ie. the indices (at the previous step) of the particles they extend.
+    fn extend_ancestry(&mut self) {
+        let parents = std::mem::replace(&mut self.parents, (0..self.num_particles).collect());
+        self.ancestry.push(parents);
+    }
+
+    /// Record a copy of the current particles and their log weights, if `with_history` is enabled.
+    fn record_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.push((self.traces.clone(), self.log_weights.clone()));
         }
     }
 
     /// Construct a new particle filter under the `model` with `num_particles` particles.
-    pub fn new(model: F, num_particles: usize, rng: ThreadRng) -> Self {
+    pub fn new(model: F, num_particles: usize, rng: R) -> Self {
         ParticleSystem {
             num_particles,
             model: Box::new(model),
             traces: vec![],
             log_weights: vec![0.; num_particles],
             log_normalized_weights: vec![0.; num_particles],
-            two_times_log_normalized_weights: vec![0.; num_particles],
             normalized_weights: vec![0.; num_particles],
-            parents: vec![0; num_particles],
-            rng: rng,
+            parents: (0..num_particles).collect(),
+            ancestry: vec![],
+            history: None,
+            resampling_scheme: ResamplingScheme::default(),
+            rng,
             log_ml_estimate: 0.
         }
     }
 
+    /// Set the `ResamplingScheme` used by `resample` (`ResamplingScheme::Multinomial` by default).
+    pub fn with_resampling_scheme(mut self, scheme: ResamplingScheme) -> Self {
+        self.resampling_scheme = scheme;
+        self
+    }
+
+    /// Record the particles and their log weights at every step (see `history`), eg. for `backward_simulation`.
+    /// 
+    /// This keeps a copy of the particles at each step, so uses memory in proportion to the number of steps.
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(vec![]);
+        self
+    }
+
     /// Initialize the particle filter by generating `self.num_particles` traces from the `model` with `(1, args)`.
+    /// 
+    /// Returns the first error raised by `model.try_generate`, if any.
     pub fn init_step(
         &mut self,
         args: Args,
         constraints: Data
-    ) {
+    ) -> Result<(),GenFnError> {
         for i in 0..self.num_particles {
-            let (trace, log_weight) = self.model.generate((1, args.clone()), constraints.clone());
+            let (trace, log_weight) = self.model.try_generate(&mut self.rng, (1, args.clone()), constraints.clone())?;
             self.traces.push(trace);
             self.log_weights[i] = log_weight;
         }
+        self.parents = (0..self.num_particles).collect();
+        self.ancestry.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        self.record_history();
+        Ok(())
     }
 
     /// Extend the current filter from `t` to `t+1` with new `constraints`.
-    pub fn step(self, constraints: Data) -> Self {
+    /// 
+    /// Returns the first error raised by `model.try_update`, if any (which leaves the particles unchanged).
+    pub fn step(&mut self, constraints: Data) -> Result<(),GenFnError> {
         let mut tmp_traces = vec![];
         let mut tmp_log_weights = vec![];
-        for (i, trace) in self.traces.into_iter().enumerate() {
+        for (i, trace) in self.traces.iter().enumerate() {
             let args = trace.args.clone();
             let new_args = (args.0 + 1, args.1);
-            let (new_trace, _, log_weight) = self.model.update(trace, new_args, ArgDiff::Extend, constraints.clone());
+            let (new_trace, _, log_weight, _) = self.model.try_update(&mut self.rng, trace.clone(), new_args, ArgDiff::Extend, constraints.clone())?;
             tmp_traces.push(new_trace);
             tmp_log_weights.push(self.log_weights[i] + log_weight);
         }
-        ParticleSystem {
-            num_particles: self.num_particles,
-            model: self.model,
-            traces: tmp_traces,
-            log_weights: tmp_log_weights,
-            log_normalized_weights: self.log_normalized_weights,
-            two_times_log_normalized_weights: self.two_times_log_normalized_weights,
-            normalized_weights: self.normalized_weights,
-            parents: self.parents,
-            rng: self.rng,
-            log_ml_estimate: self.log_ml_estimate
+        self.traces = tmp_traces;
+        self.log_weights = tmp_log_weights;
+        self.extend_ancestry();
+        self.record_history();
+        Ok(())
+    }
+
+    /// Extend the current filter from `t` to `t+1` with new `constraints`, sampling the new choices of each particle
+    /// from a `proposal` (eg. one that looks ahead at the new observations) instead of from the `model`.
+    /// 
+    /// Like the proposals of `metropolis_hastings`, the `proposal` accepts a `Weak` reference to the previous trace of the
+    /// particle as its first argument. Its choices are merged into the `constraints`, and the weight of each particle is
+    /// corrected by the proposal's score.
+    /// 
+    /// Returns an error if the proposed choices overlap the `constraints`, or the first error raised by the `model` or `proposal`
+    /// (which leaves the particles unchanged). Returns an `InvalidArgs` error if the `proposal` keeps a strong reference to the trace.
+    pub fn step_with_proposal<ProposalArgs: Clone>(
+        &mut self,
+        proposal: &impl GenFn<(Weak<Trace<(i64,Args),Data,Ret>>,ProposalArgs),Data,()>,
+        proposal_args: ProposalArgs,
+        constraints: Data
+    ) -> Result<(),GenFnError> where Data: Merge {
+        let mut tmp_traces = vec![];
+        let mut tmp_log_weights = vec![];
+        for (i, trace) in self.traces.iter().enumerate() {
+            let trace = Arc::new(trace.clone());
+            let (choices, proposal_weight) = proposal.try_propose(&mut self.rng, (Arc::downgrade(&trace), proposal_args.clone()))?;
+            let trace = Arc::into_inner(trace).ok_or_else(|| GenFnError::InvalidArgs(
+                String::from("the proposal must not keep a strong reference to the previous trace")
+            ))?;
+
+            let args = trace.args.clone();
+            let new_args = (args.0 + 1, args.1);
+            let step_constraints = constraints.clone().try_merge(choices)?;
+            let (new_trace, _, log_weight, _) = self.model.try_update(&mut self.rng, trace, new_args, ArgDiff::Extend, step_constraints)?;
+            tmp_traces.push(new_trace);
+            tmp_log_weights.push(self.log_weights[i] + log_weight - proposal_weight);
         }
+        self.traces = tmp_traces;
+        self.log_weights = tmp_log_weights;
+        self.extend_ancestry();
+        self.record_history();
+        Ok(())
+    }
+
+    /// Return the number of particles in the system.
+    pub fn num_particles(&self) -> usize {
+        self.num_particles
     }
 
     /// Calculate the effective sample size (ESS) with the current paticle weights.
     pub fn effective_sample_size(&self) -> f64 {
-        (-logsumexp(&self.two_times_log_normalized_weights)).exp()
+        let two_times_log_weights = self.log_weights.iter().map(|w| 2. * w).collect::<Vec<f64>>();
+        (2. * logsumexp(&self.log_weights) - logsumexp(&two_times_log_weights)).exp()
     }
 
-    /// Perform multinomial resampling based on the normalized particle weights, and return the log total weight.
+    /// Resample (as in `resample`) only if the effective sample size is below `ess_fraction * num_particles`.
+    ///
+    /// Returns the log total weight if the particles were resampled, otherwise `None`.
+    pub fn adaptive_resample(&mut self, ess_fraction: f64) -> Option<f64> {
+        if self.effective_sample_size() < ess_fraction * self.num_particles as f64 {
+            Some(self.resample())
+        } else {
+            None
+        }
+    }
+
+    /// Resample the particles based on their normalized weights with the configured `ResamplingScheme`,
+    /// and return the log total weight.
     pub fn resample(&mut self) -> f64 {
         let log_total_weight = self.normalize_weights();
         self.log_ml_estimate += log_total_weight - (self.num_particles as f64).ln();
 
-        self.multinomial_resampling();
+        let indices = self.resampling_scheme.resample(&mut self.rng, &self.normalized_weights, self.num_particles);
 
         let mut tmp_traces = vec![];
-        for i in 0..self.num_particles {
-            tmp_traces.push(self.traces[self.parents[i]].clone());
+        for &j in indices.iter() {
+            tmp_traces.push(self.traces[j].clone());
         }
         self.traces = tmp_traces;
+        self.parents = indices.iter().map(|&j| self.parents[j]).collect();
         self.log_weights.fill(0.);
         log_total_weight
     }
 
+    /// Apply an MCMC `kernel` to every particle (eg. after `resample`, as in resample-move SMC), to restore the diversity lost to resampling.
+    /// 
+    /// The `kernel` receives the system's `rng`, the `model` and a trace, and returns the new trace and whether the move was accepted,
+    /// eg. `|rng, model, trace| regen_mh(rng, model, trace, &mask)`. Because the kernel leaves the posterior invariant, the weights are unchanged.
+    /// 
+    /// Returns the number of accepted moves, or the first error raised by the `kernel` (which leaves the particles unchanged).
+    pub fn rejuvenate(
+        &mut self,
+        mut kernel: impl FnMut(&mut dyn RngCore, &F, Trace<(i64,Args),Data,Ret>) -> Result<(Trace<(i64,Args),Data,Ret>,bool),GenFnError>
+    ) -> Result<usize,GenFnError> {
+        let mut num_accepted = 0;
+        let mut new_traces = Vec::with_capacity(self.traces.len());
+        for trace in self.traces.iter() {
+            let (new_trace, accepted) = kernel(&mut self.rng, &self.model, trace.clone())?;
+            new_traces.push(new_trace);
+            num_accepted += accepted as usize;
+        }
+        self.traces = new_traces;
+        Ok(num_accepted)
+    }
+
+    /// Return the parent indices recorded at each step after the first: `ancestry()[k][i]` is the index (among the particles at
+    /// step `k + 1`, before resampling) of the particle that the `i`th particle at step `k + 2` extends.
+    /// 
+    /// These parent pointers form the genealogy tree of the system, rooted at the particles of the first step.
+    pub fn ancestry(&self) -> &[Vec<usize>] {
+        &self.ancestry
+    }
+
+    /// Return the backward trajectory of the `i`th current particle: the index of its ancestor among the particles
+    /// at each step (before resampling), from the first step to the current step.
+    pub fn lineage(&self, i: usize) -> Vec<usize> {
+        let mut lineage = vec![self.parents[i]];
+        for parents in self.ancestry.iter().rev() {
+            lineage.push(parents[*lineage.last().unwrap()]);
+        }
+        lineage.reverse();
+        lineage
+    }
+
+    /// Return the genealogy of the current particles, as the `lineage` of each.
+    pub fn genealogy(&self) -> Vec<Vec<usize>> {
+        (0..self.traces.len()).map(|i| self.lineage(i)).collect()
+    }
+
+    /// Return the number of distinct ancestors of the current particles at each step, from the first step to the current step.
+    /// 
+    /// Path degeneracy shows up as a single ancestor at early steps.
+    pub fn num_unique_ancestors(&self) -> Vec<usize> {
+        let mut is_ancestor = vec![false; self.num_particles];
+        self.parents.iter().for_each(|&j| is_ancestor[j] = true);
+        let mut counts = vec![is_ancestor.iter().filter(|a| **a).count()];
+        for parents in self.ancestry.iter().rev() {
+            let mut is_parent = vec![false; self.num_particles];
+            parents.iter().zip(&is_ancestor).filter(|(_, a)| **a).for_each(|(&j, _)| is_parent[j] = true);
+            is_ancestor = is_parent;
+            counts.push(is_ancestor.iter().filter(|a| **a).count());
+        }
+        counts.reverse();
+        counts
+    }
+
+    /// Return the particles and their (unnormalized) log weights at each step, before any resampling,
+    /// or `None` unless the system was constructed `with_history`.
+    pub fn history(&self) -> Option<&[(Vec<Trace<(i64,Args),Data,Ret>>,Vec<f64>)]> {
+        self.history.as_deref()
+    }
+
+    /// Return a reference to the `model` of the system.
+    pub fn model(&self) -> &F {
+        &self.model
+    }
+
     /// Return the current log marginal likelihood estimate from the particles.
     pub fn log_marginal_likelihood_estimate(&self) -> f64 {
         self.log_ml_estimate + logsumexp(&self.log_weights) - (self.num_particles as f64).ln()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "parallel")]
+impl<Args: Clone + Send + Sync,Data: Clone + Send + Sync,Ret: Clone + Send,F: GenFn<(i64,Args),Data,Ret> + Sync,R: Rng> ParticleSystem<Args,Data,Ret,F,R> {
+    /// Like `init_step`, but generates the particles in parallel on the rayon thread pool
+    /// (each on its own stream seeded from the system's `rng`, see `rng_streams`).
+    /// 
+    /// Returns the first error raised by `model.try_generate`, if any.
+    pub fn par_init_step(&mut self, args: Args, constraints: Data) -> Result<(),GenFnError> {
+        use rayon::prelude::*;
+        let model = &self.model;
+        let out = crate::rng_streams(&mut self.rng, self.num_particles)
+            .into_par_iter()
+            .map(|mut stream| model.try_generate(&mut stream, (1, args.clone()), constraints.clone()))
+            .collect::<Result<Vec<_>,GenFnError>>()?;
+        for (i, (trace, log_weight)) in out.into_iter().enumerate() {
+            self.traces.push(trace);
+            self.log_weights[i] = log_weight;
+        }
+        self.parents = (0..self.num_particles).collect();
+        self.ancestry.clear();
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        self.record_history();
+        Ok(())
+    }
+
+    /// Like `step`, but extends the particles in parallel on the rayon thread pool
+    /// (each on its own stream seeded from the system's `rng`, see `rng_streams`).
+    /// 
+    /// Returns the first error raised by `model.try_update`, if any (which leaves the particles unchanged).
+    pub fn par_step(&mut self, constraints: Data) -> Result<(),GenFnError> {
+        use rayon::prelude::*;
+        let model = &self.model;
+        let streams = crate::rng_streams(&mut self.rng, self.traces.len());
+        let out = self.traces.clone()
+            .into_par_iter()
+            .zip(streams)
+            .map(|(trace, mut stream)| {
+                let args = trace.args.clone();
+                let new_args = (args.0 + 1, args.1);
+                let (new_trace, _, log_weight, _) = model.try_update(&mut stream, trace, new_args, ArgDiff::Extend, constraints.clone())?;
+                Ok((new_trace, log_weight))
+            })
+            .collect::<Result<Vec<_>,GenFnError>>()?;
+        self.traces.clear();
+        for (i, (trace, log_weight)) in out.into_iter().enumerate() {
+            self.traces.push(trace);
+            self.log_weights[i] += log_weight;
+        }
+        self.extend_ancestry();
+        self.record_history();
+        Ok(())
+    }
+
+    /// Like `rejuvenate`, but applies the `kernel` to the particles in parallel on the rayon thread pool
+    /// (each on its own stream seeded from the system's `rng`, see `rng_streams`).
+    /// 
+    /// Returns the number of accepted moves, or the first error raised by the `kernel` (which leaves the particles unchanged).
+    pub fn par_rejuvenate(
+        &mut self,
+        kernel: impl Fn(&mut dyn RngCore, &F, Trace<(i64,Args),Data,Ret>) -> Result<(Trace<(i64,Args),Data,Ret>,bool),GenFnError> + Sync
+    ) -> Result<usize,GenFnError> {
+        use rayon::prelude::*;
+        let model = &self.model;
+        let streams = crate::rng_streams(&mut self.rng, self.traces.len());
+        let out = self.traces.clone()
+            .into_par_iter()
+            .zip(streams)
+            .map(|(trace, mut stream)| kernel(&mut stream, model, trace))
+            .collect::<Result<Vec<_>,GenFnError>>()?;
+        let mut num_accepted = 0;
+        self.traces = out.into_iter()
+            .map(|(trace, accepted)| {
+                num_accepted += accepted as usize;
+                trace
+            })
+            .collect();
+        Ok(num_accepted)
+    }
+}
@@ -0,0 +1,176 @@
+use rand::RngCore;
+use crate::{Trace,GenFn,GenFnError,ArgDiff,Merge,ResamplingScheme,logsumexp};
+
+
+/// Generate the first step of a trace (if `trace` is `None`), or extend `trace` by one step, under `constraints`.
+fn extend<Args: Clone,Data,Ret>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<(i64,Args),Data,Ret>,
+    args: &Args,
+    trace: Option<Trace<(i64,Args),Data,Ret>>,
+    constraints: Data
+) -> Result<(Trace<(i64,Args),Data,Ret>,f64),GenFnError> {
+    match trace {
+        None => model.try_generate(rng, (1, args.clone()), constraints),
+        Some(trace) => {
+            let (t, args) = trace.args.clone();
+            let (trace, _, weight, _) = model.try_update(rng, trace, (t + 1, args), ArgDiff::Extend, constraints)?;
+            Ok((trace, weight))
+        }
+    }
+}
+
+/// Extend `trace` by one step along the `reference`, then constrain the `observations` of that step.
+///
+/// The weight is that of the `observations` only, as if the `reference` choices were proposed by the `model` (as for the other particles):
+/// the update replaces the observed choices sampled by the extension, whose density is what the extension added to `logjp`
+/// besides the `reference` (which must hold every latent choice of the step).
+fn extend_reference<Args: Clone,V: Clone + Default,Ret>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<(i64,Args),Vec<V>,Ret>,
+    args: &Args,
+    trace: Option<Trace<(i64,Args),Vec<V>,Ret>>,
+    observations: Vec<V>,
+    reference: Vec<V>
+) -> Result<(Trace<(i64,Args),Vec<V>,Ret>,f64),GenFnError> {
+    let prev_logjp = trace.as_ref().map_or(0., |trace| trace.logjp);
+    let (trace, reference_weight) = extend(rng, model, args, trace, reference)?;
+    let sampled_weight = trace.logjp - prev_logjp - reference_weight;
+
+    // constraints of an update are indexed by step, from the first
+    let mut step_observations = vec![V::default(); (trace.args.0 as usize).saturating_sub(observations.len())];
+    step_observations.extend(observations);
+    let step_args = trace.args.clone();
+    let (trace, _, observation_weight, _) = model.try_update(rng, trace, step_args, ArgDiff::NoChange, step_observations)?;
+    Ok((trace, sampled_weight + observation_weight))
+}
+
+/// Draw an index with probability proportional to the exponentiated `log_weights`.
+pub(crate) fn sample_index(rng: &mut dyn RngCore, log_weights: &[f64]) -> usize {
+    let log_total_weight = logsumexp(&log_weights.to_vec());
+    let probs = log_weights.iter().map(|w| (w - log_total_weight).exp()).collect::<Vec<f64>>();
+    ResamplingScheme::Multinomial.resample(rng, &probs, 1)[0]
+}
+
+/// Run a conditional SMC sweep: a bootstrap particle filter in which the last of the `num_particles` particles
+/// is held to a `reference` trajectory.
+///
+/// Like a `ParticleSystem`, the `model` is generated at `(1, args)` and extended by one step per element of `constraints`
+/// (the observations at each step). Its data holds the choices of each step in turn (eg. the `Vec<DynTrie>` of a `DynUnfold`).
+/// `reference` holds the remaining (latent) choices of the reference trajectory at each step.
+/// The free particles are resampled (multinomially) at every step.
+///
+/// With `ancestor_sampling`, the ancestor of the reference particle is resampled at each step in proportion to the weight of
+/// each particle times the probability of the rest of the reference given its history, which improves mixing of early steps.
+/// This extends a copy of every particle by the rest of the reference, so costs `O(num_particles * T^2)` for `T` steps.
+///
+/// Returns a tuple of:
+/// 1. the particles at the last step (with the reference last).
+/// 2. the log of their normalized weights.
+/// 3. the log marginal likelihood estimate of the `constraints` under the `model`.
+///
+/// Returns an error if `num_particles` is less than 2, if `constraints` is empty or doesn't have as many steps as `reference`,
+/// if the `reference` choices overlap the `constraints`, or the first error raised by the `model`.
+pub fn conditional_smc<Args: Clone,V: Clone + Default + Merge,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<(i64,Args),Vec<V>,Ret>,
+    args: Args,
+    constraints: &[Vec<V>],
+    reference: &[Vec<V>],
+    num_particles: usize,
+    ancestor_sampling: bool
+) -> Result<(Vec<Trace<(i64,Args),Vec<V>,Ret>>, Vec<f64>, f64),GenFnError> {
+    if num_particles < 2 {
+        return Err(GenFnError::InvalidArgs(format!("num_particles must be at least 2 (got {num_particles})")));
+    }
+    if constraints.is_empty() {
+        return Err(GenFnError::InvalidArgs("constraints must hold at least one step".to_string()));
+    }
+    if constraints.len() != reference.len() {
+        return Err(GenFnError::InvalidArgs(format!(
+            "constraints and reference must have the same number of steps (got {} and {})", constraints.len(), reference.len()
+        )));
+    }
+    for (step_constraints, step_reference) in constraints.iter().zip(reference) {
+        step_constraints.clone().try_merge(step_reference.clone())?;
+    }
+    let num_free = num_particles - 1;
+    let log_num_particles = (num_particles as f64).ln();
+
+    let mut traces = vec![];
+    let mut log_weights = vec![];
+    for _ in 0..num_free {
+        let (trace, log_weight) = extend(rng, model, &args, None, constraints[0].clone())?;
+        traces.push(trace);
+        log_weights.push(log_weight);
+    }
+    let (trace, log_weight) = extend_reference(rng, model, &args, None, constraints[0].clone(), reference[0].clone())?;
+    traces.push(trace);
+    log_weights.push(log_weight);
+
+    let mut log_ml_estimate = 0.;
+    for t in 1..constraints.len() {
+        let log_total_weight = logsumexp(&log_weights);
+        log_ml_estimate += log_total_weight - log_num_particles;
+        let probs = log_weights.iter().map(|w| (w - log_total_weight).exp()).collect::<Vec<f64>>();
+        let parents = ResamplingScheme::Multinomial.resample(rng, &probs, num_free);
+
+        let reference_parent = if ancestor_sampling {
+            let mut ancestor_log_weights = vec![];
+            for (trace, log_weight) in traces.iter().zip(&log_weights) {
+                let mut trace = trace.clone();
+                let mut log_weight = *log_weight;
+                for s in t..constraints.len() {
+                    let step_weight;
+                    (trace, step_weight) = extend(rng, model, &args, Some(trace), constraints[s].clone().try_merge(reference[s].clone())?)?;
+                    log_weight += step_weight;
+                }
+                ancestor_log_weights.push(log_weight);
+            }
+            sample_index(rng, &ancestor_log_weights)
+        } else {
+            num_free
+        };
+
+        let mut new_traces = vec![];
+        let mut new_log_weights = vec![];
+        for j in parents {
+            let (trace, log_weight) = extend(rng, model, &args, Some(traces[j].clone()), constraints[t].clone())?;
+            new_traces.push(trace);
+            new_log_weights.push(log_weight);
+        }
+        let parent = traces.swap_remove(reference_parent);
+        let (trace, log_weight) = extend_reference(rng, model, &args, Some(parent), constraints[t].clone(), reference[t].clone())?;
+        new_traces.push(trace);
+        new_log_weights.push(log_weight);
+        traces = new_traces;
+        log_weights = new_log_weights;
+    }
+
+    let log_total_weight = logsumexp(&log_weights);
+    log_ml_estimate += log_total_weight - log_num_particles;
+    let log_normalized_weights = log_weights.iter().map(|w| w - log_total_weight).collect::<Vec<f64>>();
+    Ok((traces, log_normalized_weights, log_ml_estimate))
+}
+
+/// Perform a particle Gibbs update of the latent trajectory of a state-space `model`: run `conditional_smc` with the
+/// `reference` trajectory (eg. the latent choices of the current trace), and return a trace drawn from its final particles.
+///
+/// The update leaves the posterior over trajectories given the `constraints` invariant, so can be alternated with updates
+/// of static parameters (passed in `args`). For a `DynUnfold`, the next `reference` can be read from the returned trace with
+/// `DynUnfold::latent_choices`.
+///
+/// Returns an error if the `reference` choices overlap the `constraints`, or the first error raised by the `model`.
+pub fn particle_gibbs<Args: Clone,V: Clone + Default + Merge,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<(i64,Args),Vec<V>,Ret>,
+    args: Args,
+    constraints: &[Vec<V>],
+    reference: &[Vec<V>],
+    num_particles: usize,
+    ancestor_sampling: bool
+) -> Result<Trace<(i64,Args),Vec<V>,Ret>,GenFnError> {
+    let (mut traces, log_normalized_weights, _) = conditional_smc(rng, model, args, constraints, reference, num_particles, ancestor_sampling)?;
+    let i = sample_index(rng, &log_normalized_weights);
+    Ok(traces.swap_remove(i))
+}
@@ -0,0 +1,64 @@
+use rand::Rng;
+use crate::{GenFn,GenFnError,ParticleSystem};
+
+
+/// Per-step record of a run of `smc`.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct SmcSummary {
+    /// Effective sample size at each step, before any resampling.
+    pub ess: Vec<f64>,
+
+    /// Increment of the log marginal likelihood estimate at each step.
+    pub log_ml_increments: Vec<f64>,
+
+    /// Whether the particles were resampled at the end of each step.
+    pub resampled: Vec<bool>,
+
+    /// Final log marginal likelihood estimate (the sum of the `log_ml_increments`).
+    pub log_ml_estimate: f64
+}
+
+impl SmcSummary {
+    /// Return the number of steps in the run.
+    pub fn num_steps(&self) -> usize {
+        self.ess.len()
+    }
+
+    /// Return the number of steps at which the particles were resampled.
+    pub fn num_resamples(&self) -> usize {
+        self.resampled.iter().filter(|r| **r).count()
+    }
+}
+
+
+/// Run sequential Monte Carlo with the `filter` over a sequence of per-step `constraints`.
+///
+/// The first constraints initialize the filter (with `ParticleSystem::init_step` and `args`), and each
+/// subsequent constraints extend it by one step. After every step the particles are resampled if
+/// their effective sample size falls below `ess_fraction * num_particles` (so `0.` never resamples, and
+/// `1.` resamples at nearly every step).
+///
+/// Returns the final `ParticleSystem` alongside an `SmcSummary` of the run, or the first error raised by the `model`.
+pub fn smc<Args: Clone,Data: Clone,Ret: Clone,F: GenFn<(i64,Args),Data,Ret>,R: Rng>(
+    mut filter: ParticleSystem<Args,Data,Ret,F,R>,
+    args: Args,
+    constraints: impl IntoIterator<Item=Data>,
+    ess_fraction: f64
+) -> Result<(ParticleSystem<Args,Data,Ret,F,R>,SmcSummary),GenFnError> {
+    let mut summary = SmcSummary::default();
+    let mut log_ml_estimate = 0.;
+    for (t, step_constraints) in constraints.into_iter().enumerate() {
+        if t == 0 {
+            filter.init_step(args.clone(), step_constraints)?;
+        } else {
+            filter.step(step_constraints)?;
+        }
+        let new_log_ml_estimate = filter.log_marginal_likelihood_estimate();
+        summary.log_ml_increments.push(new_log_ml_estimate - log_ml_estimate);
+        log_ml_estimate = new_log_ml_estimate;
+        summary.ess.push(filter.effective_sample_size());
+        summary.resampled.push(filter.adaptive_resample(ess_fraction).is_some());
+    }
+    summary.log_ml_estimate = log_ml_estimate;
+    Ok((filter, summary))
+}
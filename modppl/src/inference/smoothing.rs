@@ -0,0 +1,54 @@
+use rand::{Rng,RngCore};
+use crate::{DynTrie,DynUnfold,GenFn,GenFnError,ParticleSystem};
+use super::particle_gibbs::sample_index;
+
+
+/// Draw `num_samples` smoothed trajectories from a finished `filter` over a `DynUnfold` by backward simulation
+/// (the backward pass of forward-filtering backward-sampling).
+///
+/// Each trajectory starts from a particle at the last step drawn by its weight. Going back in time, the particle at
+/// each step is drawn among all the particles at that step in proportion to its filtering weight times the density
+/// of the choices of the next step of the trajectory given its state (see `DynUnfold::assess_step`). Unlike the
+/// lineages of the filter, the trajectories don't degenerate to a single ancestor at early steps.
+///
+/// The `filter` must have been constructed `with_history`. Each trajectory costs `O(num_particles * T)` kernel assessments for `T` steps.
+///
+/// Returns the states of each trajectory (as output by the kernel at each step), or the first error raised by the kernel.
+/// Returns `GenFnError::InvalidArgs` if the `filter` has no history, or hasn't taken any steps.
+pub fn backward_simulation<State: Clone,R: Rng>(
+    rng: &mut dyn RngCore,
+    filter: &ParticleSystem<State,Vec<DynTrie>,Vec<State>,DynUnfold<State>,R>,
+    num_samples: usize
+) -> Result<Vec<Vec<State>>,GenFnError> {
+    let history = filter.history()
+        .ok_or_else(|| GenFnError::InvalidArgs("the filter must be constructed `with_history`".to_string()))?;
+    let model = filter.model();
+    let (last_traces, last_log_weights) = history.last()
+        .ok_or_else(|| GenFnError::InvalidArgs("the filter has no steps".to_string()))?;
+    let final_t = history.len();
+
+    let mut trajectories = vec![];
+    for _ in 0..num_samples {
+        let j = sample_index(rng, last_log_weights);
+        let mut choices = vec![last_traces[j].data[final_t - 1].clone()];
+        for t in (0..final_t - 1).rev() {
+            let (traces, log_weights) = &history[t];
+            let next_choices = choices.last().unwrap();
+            let mut backward_log_weights = vec![];
+            for (trace, log_weight) in traces.iter().zip(log_weights) {
+                let state = trace.retv.as_ref().unwrap()[t].clone();
+                let (transition_weight, _) = model.try_assess_step(rng, t as i64 + 1, state, next_choices.clone())?;
+                backward_log_weights.push(log_weight + transition_weight);
+            }
+            let j = sample_index(rng, &backward_log_weights);
+            choices.push(traces[j].data[t].clone());
+        }
+        choices.reverse();
+
+        // replay the kernel over the chosen choices, to recompute the state at each step
+        let initial_state = last_traces[j].args.1.clone();
+        let (trace, _) = model.try_generate(rng, (final_t as i64, initial_state), choices)?;
+        trajectories.push(trace.retv.unwrap());
+    }
+    Ok(trajectories)
+}
@@ -0,0 +1,170 @@
+use std::slice;
+use std::sync::{Arc,Weak};
+use rand::{distributions::Uniform, Rng, RngCore};
+use crate::{Trace,GenFn,GenFnError,ArgDiff,AddrMap,Trie,ResamplingScheme,SmcSummary,logsumexp};
+
+
+/// Return the log likelihood of the `observations` in `data`, ie. the total weight of the choices at the leaves of `observations`.
+fn log_likelihood<V>(data: &Trie<V>, observations: &AddrMap) -> f64 {
+    observations.iter()
+        .map(|(key, subobservations)| match data.search_keys(slice::from_ref(key)) {
+            Some(sub) if subobservations.is_leaf() => sub.weight(),
+            Some(sub) => log_likelihood(sub, subobservations),
+            None => 0.
+        })
+        .sum()
+}
+
+/// Run a tempered SMC sampler for a static `model`, moving `num_particles` particles through the sequence of tempered posteriors
+/// `p(x) p(y | x)^temperature` for each of the `temperatures` (increasing, and ending at `1.`), where `y` are the `constraints`.
+///
+/// The particles are generated from the prior under the `constraints` (at temperature `0.`). At each temperature they're reweighted
+/// by their likelihood, resampled if their effective sample size falls below `ess_fraction * num_particles` (as in `smc`), then moved
+/// by the MCMC `kernel`, which must leave the tempered posterior at the given temperature invariant (eg. `tempered_mh` or `tempered_regen_mh`).
+///
+/// Returns a tuple of:
+/// 1. the final particles, targeting the posterior.
+/// 2. the log of their normalized weights.
+/// 3. an `SmcSummary` with an entry per temperature, and the log marginal likelihood estimate of the `constraints`.
+///
+/// Returns an error if the `temperatures` are empty, not strictly increasing, not positive or don't end at `1.`,
+/// or the first error raised by the `model` or the `kernel`.
+#[allow(clippy::too_many_arguments)]
+pub fn tempered_smc<Args: Clone,V: Clone,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Trie<V>,Ret>,
+    model_args: Args,
+    constraints: Trie<V>,
+    num_particles: usize,
+    temperatures: &[f64],
+    ess_fraction: f64,
+    mut kernel: impl FnMut(&mut dyn RngCore, Trace<Args,Trie<V>,Ret>, f64) -> Result<(Trace<Args,Trie<V>,Ret>,bool),GenFnError>
+) -> Result<(Vec<Trace<Args,Trie<V>,Ret>>, Vec<f64>, SmcSummary),GenFnError> {
+    let Some(&last) = temperatures.last() else {
+        return Err(GenFnError::InvalidArgs("temperatures must not be empty".to_string()));
+    };
+    if last != 1. {
+        return Err(GenFnError::InvalidArgs(format!("the last temperature must be 1 (got {last})")));
+    }
+    if temperatures[0] <= 0. {
+        return Err(GenFnError::InvalidArgs(format!("temperatures must be positive (got {})", temperatures[0])));
+    }
+    if let Some(w) = temperatures.windows(2).find(|w| w[0].is_nan() || w[0] >= w[1]) {
+        return Err(GenFnError::InvalidArgs(format!("temperatures must be strictly increasing (got {} then {})", w[0], w[1])));
+    }
+    let observations = constraints.schema();
+    let mut traces = (0..num_particles)
+        .map(|_| Ok(model.try_generate(rng, model_args.clone(), constraints.clone())?.0))
+        .collect::<Result<Vec<Trace<Args,Trie<V>,Ret>>,GenFnError>>()?;
+    let mut log_normalized_weights = vec![-(num_particles as f64).ln(); num_particles];
+
+    let mut summary = SmcSummary::default();
+    let mut prev_temperature = 0.;
+    for &temperature in temperatures {
+        let log_weights = traces.iter()
+            .zip(&log_normalized_weights)
+            .map(|(trace, w)| w + (temperature - prev_temperature) * log_likelihood(&trace.data, &observations))
+            .collect::<Vec<f64>>();
+        let log_ml_increment = logsumexp(&log_weights);
+        log_normalized_weights = log_weights.iter().map(|w| w - log_ml_increment).collect();
+        summary.log_ml_increments.push(log_ml_increment);
+        summary.log_ml_estimate += log_ml_increment;
+
+        let two_times_log_weights = log_normalized_weights.iter().map(|w| 2. * w).collect::<Vec<f64>>();
+        let ess = (-logsumexp(&two_times_log_weights)).exp();
+        summary.ess.push(ess);
+        let resample = ess < ess_fraction * num_particles as f64;
+        if resample {
+            let probs = log_normalized_weights.iter().map(|w| w.exp()).collect::<Vec<f64>>();
+            let indices = ResamplingScheme::Multinomial.resample(rng, &probs, num_particles);
+            traces = indices.iter().map(|&j| traces[j].clone()).collect();
+            log_normalized_weights.fill(-(num_particles as f64).ln());
+        }
+        summary.resampled.push(resample);
+
+        let mut new_traces = Vec::with_capacity(num_particles);
+        for trace in traces {
+            let (trace, _) = kernel(rng, trace, temperature)?;
+            new_traces.push(trace);
+        }
+        traces = new_traces;
+        prev_temperature = temperature;
+    }
+    Ok((traces, log_normalized_weights, summary))
+}
+
+/// Run annealed importance sampling (AIS) for a static `model`: `tempered_smc` without resampling, so each of the
+/// `num_samples` particles follows an independent annealing path through the `temperatures`.
+///
+/// Returns the same tuple as `tempered_smc`, or the same errors.
+pub fn annealed_importance_sampling<Args: Clone,V: Clone,Ret: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Trie<V>,Ret>,
+    model_args: Args,
+    constraints: Trie<V>,
+    num_samples: usize,
+    temperatures: &[f64],
+    kernel: impl FnMut(&mut dyn RngCore, Trace<Args,Trie<V>,Ret>, f64) -> Result<(Trace<Args,Trie<V>,Ret>,bool),GenFnError>
+) -> Result<(Vec<Trace<Args,Trie<V>,Ret>>, Vec<f64>, SmcSummary),GenFnError> {
+    tempered_smc(rng, model, model_args, constraints, num_samples, temperatures, 0., kernel)
+}
+
+/// Like `metropolis_hastings`, but targets the tempered posterior `p(x) p(y | x)^temperature` of the `observations` `y`
+/// (eg. `constraints.schema()`), as in the `kernel` of `tempered_smc`.
+///
+/// Returns an error if the `model` or `proposal` fails, rather than rejecting the move.
+pub fn tempered_mh<Args: Clone + 'static,V: Clone + 'static,Ret: Clone + 'static,ProposalArgs: Clone>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Trie<V>,Ret>,
+    trace: Trace<Args,Trie<V>,Ret>,
+    proposal: &impl GenFn<(Weak<Trace<Args,Trie<V>,Ret>>,ProposalArgs),Trie<V>,()>,
+    proposal_args: ProposalArgs,
+    observations: &AddrMap,
+    temperature: f64
+) -> Result<(Trace<Args,Trie<V>,Ret>, bool),GenFnError> {
+    let prev_trace = trace.clone();
+    let prev_log_likelihood = log_likelihood(&prev_trace.data, observations);
+
+    let trace = Arc::new(trace);
+    let (fwd_choices, fwd_weight) = proposal.try_propose(rng, (Arc::downgrade(&trace), proposal_args.clone()))?;
+    let trace = Arc::into_inner(trace).unwrap();
+
+    let args = trace.args.clone();
+    let (trace, discard, weight, _) = model.try_update(rng, trace, args, ArgDiff::NoChange, fwd_choices)?;
+
+    let trace = Arc::new(trace);
+    let bwd_weight = proposal.try_assess(rng, (Arc::downgrade(&trace), proposal_args), discard)?;
+    let trace = Arc::into_inner(trace).unwrap();
+
+    let log_likelihood_ratio = log_likelihood(&trace.data, observations) - prev_log_likelihood;
+    let alpha = weight - (1. - temperature) * log_likelihood_ratio - fwd_weight + bwd_weight;
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < alpha {
+        Ok((trace, true))
+    } else {
+        Ok((prev_trace, false))
+    }
+}
+
+/// Like `regenerative_metropolis_hastings`, but targets the tempered posterior `p(x) p(y | x)^temperature` of the `observations` `y`
+/// (eg. `constraints.schema()`), as in the `kernel` of `tempered_smc`.
+///
+/// Returns an error if the `model` fails to regenerate, rather than rejecting the move.
+pub fn tempered_regen_mh<Args: Clone + 'static,V: Clone + 'static,Ret: Clone + 'static>(
+    rng: &mut dyn RngCore,
+    model: &impl GenFn<Args,Trie<V>,Ret>,
+    trace: Trace<Args,Trie<V>,Ret>,
+    mask: &AddrMap,
+    observations: &AddrMap,
+    temperature: f64
+) -> Result<(Trace<Args,Trie<V>,Ret>, bool),GenFnError> {
+    let prev_trace = trace.clone();
+    let prev_log_likelihood = log_likelihood(&prev_trace.data, observations);
+    let args = trace.args.clone();
+    let (trace, weight, _) = model.try_regenerate(rng, trace, args, ArgDiff::NoChange, mask)?;
+    let log_likelihood_ratio = log_likelihood(&trace.data, observations) - prev_log_likelihood;
+    if rng.sample(Uniform::new(0_f64, 1_f64)).ln() < weight - (1. - temperature) * log_likelihood_ratio {
+        Ok((trace, true))
+    } else {
+        Ok((prev_trace, false))
+    }
+}
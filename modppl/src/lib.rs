@@ -6,24 +6,34 @@
 
 #![deny(missing_docs)]
 #![allow(non_upper_case_globals)]
+#![allow(clippy::type_complexity)]
 
 extern crate approx;
 extern crate nalgebra;
 extern crate rand;
-extern crate regex;
 
-///
+/// Commonly used types, traits and functions (`use modppl::prelude::*;`).
 pub mod prelude;
 
 /// Definition of the Generative Function Interface (GFI).
 pub mod gfi;
 
-/// Utilities for parsing addresses (keys used in the `Trie` data structure).
+/// Errors returned by the fallible (`try_`) methods of the GFI.
+pub mod error;
+
+/// Structured addresses (keys used in the `Trie` data structure), and utilities for parsing them from strings.
 pub mod address;
 
 /// Implementations of the `Trie` data structure, used extensively in `modeling::DynGenFn`. 
 pub mod trie;
 
+/// Trainable parameters of generative functions (`ParamStore`).
+pub mod params;
+
+/// Reverse-mode automatic differentiation (`Var`), and the differentiable distributions over `Var`s
+/// (eg. `ad::normal`) used to take gradients of a `DynGenFn`.
+pub mod ad;
+
 /// Distributions and a modeling DSL built on `Trie`s.
 pub mod modeling;
 
@@ -46,10 +56,13 @@ pub fn logsumexp(xs: &Vec<f64>) -> f64 {
 
 // modeling libs
 pub use trie::Trie;
-pub use address::{SplitAddr, AddrMap, normalize_addr};
-pub use gfi::{Trace, GenFn, ArgDiff};
+pub use address::{Addr, AddrKey, SplitAddr, AddrMap, normalize_addr};
+pub use gfi::{Trace, GenFn, ArgDiff, Differentiable, Merge};
+pub use error::GenFnError;
+pub use params::ParamStore;
+pub use ad::Var;
 pub use modeling::dists::{
-    u01,Distribution,
+    u01,Distribution,DifferentiableDistribution,FiniteDistribution,
     bernoulli,
     uniform_continuous,
     uniform,
@@ -62,10 +75,21 @@ pub use modeling::dists::{
     gamma,
     beta
 };
-pub use modeling::dyngenfn::{DynTrie,DynTrace,DynGenFn,DynGenFnHandler};
+pub use modeling::dyngenfn::{DynTrie,DynTrace,DynGenFn,DynFn,DynFnArc,DynGenFnHandler};
 pub use modeling::dynunfold::{DynUnfold,DynParticles};
 
 // inference libs
-pub use inference::{importance_sampling, importance_resampling};
+pub use inference::{importance_sampling, importance_resampling, importance_sampling_with_proposal, importance_resampling_with_proposal};
 pub use inference::{metropolis_hastings, mh, regenerative_metropolis_hastings, regen_mh};
-pub use inference::ParticleSystem;
\ No newline at end of file
+pub use inference::AdaptiveMh;
+pub use inference::{involutive_mh, log_abs_det_jacobian};
+pub use inference::{hamiltonian_monte_carlo, hmc, Nuts};
+pub use inference::{elliptical_slice, enumerative_gibbs};
+pub use inference::{Chain, McmcSamples, run_mcmc};
+pub use inference::{Kernel, AcceptStats, Identity, kernel_fn, RegenMh, Mh, InvolutiveMh, Sequence, Repeat, Cycle, Mixture, Conditional};
+pub use inference::{autocorrelation, split_rhat, rhat, split_ess, ess_bulk, ess_tail, mcse_mean};
+pub use inference::{ParticleSystem, ResamplingScheme, smc, SmcSummary, conditional_smc, particle_gibbs, backward_simulation};
+pub use inference::{tempered_smc, annealed_importance_sampling, tempered_mh, tempered_regen_mh};
+pub use inference::{Optimizer, Sgd, Adam};
+#[cfg(feature = "parallel")]
+pub use inference::{rng_streams, par_importance_sampling, par_importance_resampling, par_mcmc};
\ No newline at end of file
@@ -1,5 +1,5 @@
-use rand::rngs::ThreadRng;
-use super::{Distribution,u01};
+use rand::Rng;
+use super::{Distribution,FiniteDistribution,u01};
 
 
 /// Bernoulli distribution type
@@ -13,7 +13,13 @@ impl Distribution<bool,f64> for Bernoulli {
         (if *a { p } else { 1. - p }).ln()
     }
 
-    fn random(&self, rng: &mut ThreadRng, p: f64) -> bool {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, p: f64) -> bool {
         p > u01(rng)
     }
-}
\ No newline at end of file
+}
+
+impl FiniteDistribution<bool,f64> for Bernoulli {
+    fn support(&self, _: f64) -> Vec<bool> {
+        vec![false, true]
+    }
+}
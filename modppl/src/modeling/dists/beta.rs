@@ -1,6 +1,6 @@
-use rand::rngs::ThreadRng;
-use super::Distribution;
-use compute::functions::gamma;
+use rand::Rng;
+use super::{Distribution,DifferentiableDistribution};
+use compute::functions::{gamma, digamma};
 use rand_distr::{
     Distribution as _,
     Beta as BetaSampler
@@ -20,9 +20,19 @@ impl Distribution<f64,(f64,f64)> for Beta {
         (beta_f * x.powf(a-1.)*(1.-x).powf(b-1.)).ln()
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (f64,f64)) -> f64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (f64,f64)) -> f64 {
         let (a, b) = params;
         let beta_sampler = BetaSampler::new(a, b).ok().unwrap();
         beta_sampler.sample(rng)
     }
-}
\ No newline at end of file
+}
+
+impl DifferentiableDistribution<f64,(f64,f64)> for Beta {
+    fn logpdf_grad(&self, x: &f64, params: (f64,f64)) -> (f64, (f64,f64)) {
+        let (a, b) = params;
+        let x_grad = (a-1.)/x - (b-1.)/(1.-x);
+        let a_grad = digamma(a + b) - digamma(a) + x.ln();
+        let b_grad = digamma(a + b) - digamma(b) + (1.-x).ln();
+        (x_grad, (a_grad, b_grad))
+    }
+}
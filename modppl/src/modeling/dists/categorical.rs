@@ -1,6 +1,6 @@
-use rand::rngs::ThreadRng;
+use rand::Rng;
 use approx;
-use super::{Distribution,u01};
+use super::{Distribution,FiniteDistribution,u01};
 
 
 /// Categorical distribution type
@@ -12,14 +12,14 @@ pub const categorical: Categorical = Categorical { };
 impl Distribution<i64,Vec<f64>> for Categorical {
     fn logpdf(&self, x: &i64, probs: Vec<f64>) -> f64 {
         approx::assert_abs_diff_eq!(probs.iter().sum::<f64>(), 1.0, epsilon = 1e-8);
-        return if *x < probs.len() as i64 {
+        if *x < probs.len() as i64 {
             probs[*x as usize].ln()
         } else {
             f64::NEG_INFINITY
         }
     }
 
-    fn random(&self, rng: &mut ThreadRng, probs: Vec<f64>) -> i64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, probs: Vec<f64>) -> i64 {
         approx::assert_abs_diff_eq!(probs.iter().sum::<f64>(), 1.0, epsilon = 1e-8);
         let u = u01(rng);
         let mut t = 0.;
@@ -28,6 +28,12 @@ impl Distribution<i64,Vec<f64>> for Categorical {
             t += probs[x as usize];
             x += 1;
         }
-        return x - 1;
+        x - 1
     }
-}
\ No newline at end of file
+}
+
+impl FiniteDistribution<i64,Vec<f64>> for Categorical {
+    fn support(&self, probs: Vec<f64>) -> Vec<i64> {
+        (0..probs.len() as i64).collect()
+    }
+}
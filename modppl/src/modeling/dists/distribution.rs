@@ -1,8 +1,9 @@
-use rand::{Rng,rngs::ThreadRng};
+use rand::Rng;
+use crate::ad::Var;
 
 
 /// Sample a random variable uniformly in the interval [0., 1.].
-pub fn u01(rng: &mut ThreadRng) -> f64 {
+pub fn u01<R: Rng + ?Sized>(rng: &mut R) -> f64 {
     rng.sample(rand::distributions::Uniform::new(0., 1.))
 }
 
@@ -12,7 +13,30 @@ pub trait Distribution<T,U> {
     /// Return the `log[p(x; params)]`.
     fn logpdf(&self, x: &T, params: U) -> f64;
 
-    /// Sample a random value `x ~ p(. ; params)`.
-    fn random(&self, rng: &mut ThreadRng, params: U) -> T;
+    /// Sample a random value `x ~ p(. ; params)` using the random number generator `rng`.
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: U) -> T;
 
-}
\ No newline at end of file
+    /// Return `log[p(x; params)]` as a `Var`, recording its dependence on any `Var`s in `x` and `params` on the active tape.
+    ///
+    /// Defaults to a constant, which is exact for distributions over values that aren't `Var`s (eg. `normal` as opposed to `ad::normal`).
+    fn logpdf_var(&self, x: &T, params: U) -> Var {
+        Var::constant(self.logpdf(x, params))
+    }
+
+}
+
+/// Trait for distributions whose log density is differentiable in its value and parameters.
+pub trait DifferentiableDistribution<T,U>: Distribution<T,U> {
+
+    /// Return the gradient of `log[p(x; params)]` with respect to `x` and with respect to each of the `params`.
+    fn logpdf_grad(&self, x: &T, params: U) -> (T, U);
+
+}
+
+/// Trait for distributions with a finite support, whose values can be enumerated (eg. by `enumerative_gibbs`).
+pub trait FiniteDistribution<T,U>: Distribution<T,U> {
+
+    /// Return every value `x` in the support of `p(. ; params)`.
+    fn support(&self, params: U) -> Vec<T>;
+
+}
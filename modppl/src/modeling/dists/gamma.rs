@@ -1,6 +1,6 @@
-use rand::rngs::ThreadRng;
-use super::Distribution;
-use compute::functions::gamma as gamma_f;
+use rand::Rng;
+use super::{Distribution,DifferentiableDistribution};
+use compute::functions::{gamma as gamma_f, digamma};
 use rand_distr::{
     Distribution as _,
     Gamma as GammaSampler
@@ -19,9 +19,19 @@ impl Distribution<f64,(f64,f64)> for Gamma {
         (a-1.)*x.ln() - x/b - gamma_f(a).ln() - a*b.ln() 
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (f64,f64)) -> f64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (f64,f64)) -> f64 {
         let (a, b) = params;
         let gamma_sampler = GammaSampler::new(a, b).ok().unwrap();
         gamma_sampler.sample(rng)
     }
-}
\ No newline at end of file
+}
+
+impl DifferentiableDistribution<f64,(f64,f64)> for Gamma {
+    fn logpdf_grad(&self, x: &f64, params: (f64,f64)) -> (f64, (f64,f64)) {
+        let (a, b) = params;
+        let x_grad = (a-1.)/x - 1./b;
+        let a_grad = x.ln() - digamma(a) - b.ln();
+        let b_grad = x/(b*b) - a/b;
+        (x_grad, (a_grad, b_grad))
+    }
+}
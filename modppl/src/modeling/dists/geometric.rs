@@ -1,4 +1,4 @@
-use rand::rngs::ThreadRng;
+use rand::Rng;
 use super::Distribution;
 use rand_distr::{
     Distribution as _,
@@ -18,7 +18,7 @@ impl Distribution<i64,f64> for Geometric {
         ((1. - p).powf(*k as f64)*p).ln()
     }
 
-    fn random(&self, rng: &mut ThreadRng, p: f64) -> i64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, p: f64) -> i64 {
         debug_assert!(0. < p && p < 1.);
         let geometric_sampler = GeometricSampler::new(p).ok().unwrap();
         geometric_sampler.sample(rng) as i64
@@ -11,7 +11,7 @@ mod gamma;
 mod beta;
 
 
-pub use self::distribution::{u01,Distribution};
+pub use self::distribution::{u01,Distribution,DifferentiableDistribution,FiniteDistribution};
 pub use {
     self::bernoulli::*,
     self::uniform::*,
@@ -1,10 +1,13 @@
-use rand::rngs::ThreadRng;
-use super::{Distribution,normal};
+use rand::Rng;
+use super::{Distribution,DifferentiableDistribution,normal};
 use std::f64::consts::PI;
 use nalgebra::{DVector,DMatrix};
 
 
 /// Multivariate Gaussian distribution type
+/// 
+/// The density is only defined for a positive definite covariance: otherwise (eg. if it is singular),
+/// `logpdf` returns `NaN`, and so do the gradients of `logpdf_grad`.
 pub struct MvNormal { }
 
 /// Instantiation of the Multivariate Gaussian distribution
@@ -14,26 +17,37 @@ impl Distribution<DVector<f64>,(DVector<f64>,DMatrix<f64>)> for MvNormal {
     fn logpdf(&self, x: &DVector<f64>, params: (DVector<f64>,DMatrix<f64>)) -> f64 {
         let (mu, cov) = params;
         let k = mu.len() as f64;
-        let cov_det = cov.determinant();
-        let cov_inv = cov.clone().try_inverse().unwrap();
+        let Some(chol) = cov.cholesky() else { return f64::NAN; };
+        let cov_log_det = 2. * chol.l_dirty().diagonal().iter().map(|l| l.ln()).sum::<f64>();
         let centered_x = x - mu;
-        let mahalanobis_squared = (centered_x.transpose() * cov_inv * centered_x).trace();
-        -(k*(2.*PI).ln() + cov_det.ln() + mahalanobis_squared)/2.
+        let mahalanobis_squared = centered_x.dot(&chol.solve(&centered_x));
+        -(k*(2.*PI).ln() + cov_log_det + mahalanobis_squared)/2.
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (DVector<f64>,DMatrix<f64>)) -> DVector<f64> {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (DVector<f64>,DMatrix<f64>)) -> DVector<f64> {
         let (mu, cov) = params;
-        let transform: DMatrix<f64>;
-        match cov.clone().cholesky() {
+        let transform: DMatrix<f64> = match cov.clone().cholesky() {
             Some(c) => {
-                transform = c.l();
+                c.l()
             },
             None => {
                 let decomp = cov.clone().symmetric_eigen();
-                transform = decomp.eigenvectors * DMatrix::from_diagonal(&decomp.eigenvalues.map(|v| v.sqrt()));
+                decomp.eigenvectors * DMatrix::from_diagonal(&decomp.eigenvalues.map(|v| v.sqrt()))
             }
-        }
-        let samples = transform * &mu.map(|_| normal.random(rng, (0.,1.))) + mu;
-        samples
+        };
+        transform * &mu.map(|_| normal.random(rng, (0.,1.))) + mu
     }
-}
\ No newline at end of file
+}
+
+impl DifferentiableDistribution<DVector<f64>,(DVector<f64>,DMatrix<f64>)> for MvNormal {
+    fn logpdf_grad(&self, x: &DVector<f64>, params: (DVector<f64>,DMatrix<f64>)) -> (DVector<f64>, (DVector<f64>,DMatrix<f64>)) {
+        let (mu, cov) = params;
+        let Some(chol) = cov.clone().cholesky() else {
+            return (x.map(|_| f64::NAN), (mu.map(|_| f64::NAN), cov.map(|_| f64::NAN)));
+        };
+        let cov_inv = chol.inverse();
+        let mu_grad = chol.solve(&(x - mu));
+        let cov_grad = (&mu_grad * mu_grad.transpose() - cov_inv) / 2.;
+        (-mu_grad.clone(), (mu_grad, cov_grad))
+    }
+}
@@ -1,5 +1,5 @@
-use rand::rngs::ThreadRng;
-use super::{Distribution,u01};
+use rand::Rng;
+use super::{Distribution,DifferentiableDistribution,u01};
 use std::f64::consts::PI;
 
 
@@ -16,13 +16,23 @@ impl Distribution<f64,(f64,f64)> for Normal {
         -(z.abs().powf(2.) + (2.*PI).ln())/2. - std.ln()
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (f64,f64)) -> f64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (f64,f64)) -> f64 {
         let (mu, std) = params;
         let u: f64 = u01(rng) * 2. - 1.;
         let v: f64 = u01(rng) * 2. - 1.;
         let r: f64 = u * u + v * v;
         if r == 0. || r > 1. { return self.random(rng, params); }
         let c = (-2. * r.ln() / r).sqrt();
-        return u * c * std + mu;
+        u * c * std + mu
+    }
+}
+
+impl DifferentiableDistribution<f64,(f64,f64)> for Normal {
+    fn logpdf_grad(&self, x: &f64, params: (f64,f64)) -> (f64, (f64,f64)) {
+        let (mu, std) = params;
+        let z = (x - mu) / std;
+        let mu_grad = z / std;
+        let std_grad = (z*z - 1.) / std;
+        (-mu_grad, (mu_grad, std_grad))
     }
 }
@@ -1,4 +1,4 @@
-use rand::rngs::ThreadRng;
+use rand::Rng;
 use super::Distribution;
 use rand_distr::{
     Distribution as _,
@@ -17,7 +17,7 @@ impl Distribution<i64,f64> for Poisson {
         (*k as f64)*rate.ln() - rate - (1..=*k).map(|v| (v as f64).ln()).sum::<f64>()
     }
 
-    fn random(&self, rng: &mut ThreadRng, rate: f64) -> i64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, rate: f64) -> i64 {
         let poisson_sampler = PoissonSampler::new(rate).ok().unwrap();
         poisson_sampler.sample(rng) as i64
     }
@@ -1,6 +1,6 @@
 use std::fmt::Display;
-use rand::rngs::ThreadRng;
-use super::{Distribution,u01};
+use rand::Rng;
+use super::{Distribution,FiniteDistribution,u01};
 
 
 fn check_bounds<T: PartialOrd + Display>(a: T, b: T) {
@@ -25,7 +25,7 @@ impl Distribution<f64,(f64,f64)> for UniformContinuous {
         if a <= *x && *x <= b { -(b - a).ln() } else { f64::NEG_INFINITY }
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (f64,f64)) -> f64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (f64,f64)) -> f64 {
         let (a, b) = params;
         check_bounds(a, b);
         u01(rng) * (b - a) + a
@@ -46,9 +46,17 @@ impl Distribution<i64,(i64,i64)> for UniformDiscrete {
         if a <= *x && *x <= b { -((b - a + 1) as f64).ln() } else { f64::NEG_INFINITY }
     }
 
-    fn random(&self, rng: &mut ThreadRng, params: (i64,i64)) -> i64 {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, params: (i64,i64)) -> i64 {
         let (a, b) = params;
         check_bounds(a, b);
         (u01(rng) * (b - a + 1) as f64).trunc() as i64 + a
     }
-}
\ No newline at end of file
+}
+
+impl FiniteDistribution<i64,(i64,i64)> for UniformDiscrete {
+    fn support(&self, params: (i64,i64)) -> Vec<i64> {
+        let (a, b) = params;
+        check_bounds(a, b);
+        (a..=b).collect()
+    }
+}
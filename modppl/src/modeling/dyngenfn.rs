@@ -1,115 +1,247 @@
 use std::sync::Arc;
-use std::any::Any;
-use rand::rngs::ThreadRng;
-use crate::AddrMap;
+use std::slice;
+use std::any::{Any,TypeId};
+use std::panic::{self,AssertUnwindSafe};
+use rand::RngCore;
+use crate::{Addr,AddrMap};
 use crate::modeling::dists::Distribution;
-use crate::{Trie,GenFn,ArgDiff,Trace};
+use crate::{Trie,GenFn,GenFnError,ArgDiff,Trace,Differentiable,ParamStore,Var};
+use crate::ad::{self,Adjoints};
+use nalgebra::DVector;
 
 
-///
+/// A `Trie` of dynamically-typed, thread-safe values, used as the `Data` of a `DynGenFn`.
 pub type DynTrie = Trie<Arc<dyn Any + Send + Sync>>;
 
-///
+/// A `Trace` whose `data` is a `DynTrie`.
 pub type DynTrace<Args,Ret> = Trace<Args,DynTrie,Ret>;
 
 impl DynTrie {
+    /// Cast the inner `dyn Any` at `addr` into type `V` at runtime (choices sampled as `Var`s can be read as `f64`s or `Var`s).
+    /// 
+    /// Returns an error if `addr` is empty or holds a value of another type.
+    pub fn try_read<V: 'static + Clone>(&self, addr: impl Into<Addr>) -> Result<V,GenFnError> {
+        let addr = addr.into();
+        let v = self.search_keys(addr.keys())
+            .and_then(|sub| sub.ref_inner())
+            .ok_or_else(|| GenFnError::AddressNotFound(addr.to_string()))?;
+        v.downcast_ref::<V>()
+            .cloned()
+            .or_else(|| tracked::<V>(v.as_ref()))
+            .ok_or_else(|| type_mismatch::<V>(&addr))
+    }
+
     /// Cast the inner `dyn Any` at `addr` into type `V` at runtime.
-    pub fn read<V: 'static + Clone>(&self, addr: &str) -> V {
-        match self.search(addr) {
-            Some(v) => {
-                let v_typed = v
-                    .ref_inner()
-                    .unwrap()
-                    .downcast_ref::<V>();
-                match v_typed {
-                    Some(v) => { v.clone() }
-                    None => {
-                        panic!("read: failed when downcasting type at address \"{}\"", addr);
-                    }
-                }
-            }
-            None => {
-                panic!("read: failed when searching empty address \"{}\"", addr);
-            }
+    /// 
+    /// Panics if `addr` is empty or holds a value of another type.
+    pub fn read<V: 'static + Clone>(&self, addr: impl Into<Addr>) -> V {
+        self.try_read(addr).unwrap_or_else(|err| panic!("read: {err}"))
+    }
+}
+
+fn type_mismatch<V>(addr: &Addr) -> GenFnError {
+    GenFnError::TypeMismatch { addr: addr.to_string(), expected: std::any::type_name::<V>() }
+}
+
+/// Keep the first error raised during an execution, so that it can be returned once the function completes.
+fn record(error: &mut Option<GenFnError>, result: Result<(),GenFnError>) {
+    if let Err(err) = result {
+        error.get_or_insert(err);
+    }
+}
+
+/// Store a choice sampled as a `Var` (or a `DVector<Var>`) by value, so that traces hold the same data
+/// whether or not a choice is differentiable.
+fn detach<V: Send + Sync + 'static>(x: Arc<V>) -> Arc<dyn Any + Send + Sync> {
+    let x: Arc<dyn Any + Send + Sync> = x;
+    if let Some(x) = x.downcast_ref::<Var>() {
+        Arc::new(x.value())
+    } else if let Some(x) = x.downcast_ref::<DVector<Var>>() {
+        Arc::new(x.map(|x| x.value()))
+    } else {
+        x
+    }
+}
+
+/// Read a stored `f64` (or `DVector<f64>`) choice as independent `Var`s, if `V` is `Var` (or `DVector<Var>`).
+fn tracked<V: Clone + 'static>(x: &dyn Any) -> Option<V> {
+    if TypeId::of::<V>() == TypeId::of::<Var>() {
+        x.downcast_ref::<f64>().and_then(|&x| (&Var::leaf(x) as &dyn Any).downcast_ref::<V>().cloned())
+    } else if TypeId::of::<V>() == TypeId::of::<DVector<Var>>() {
+        x.downcast_ref::<DVector<f64>>().and_then(|x| (&x.map(Var::leaf) as &dyn Any).downcast_ref::<V>().cloned())
+    } else {
+        None
+    }
+}
+
+/// Cast a stored choice into type `V` (see `tracked`).
+fn attach<V: Clone + Send + Sync + 'static>(x: Arc<dyn Any + Send + Sync>) -> Option<Arc<V>> {
+    match x.downcast::<V>() {
+        Ok(x) => Some(x),
+        Err(x) => tracked::<V>(x.as_ref()).map(Arc::new)
+    }
+}
+
+fn downcast_choice<V: Clone + Send + Sync + 'static>(mut choice: DynTrie, addr: &Addr) -> Result<Arc<V>,GenFnError> {
+    debug_assert!(choice.is_leaf());
+    choice.take_inner()
+        .and_then(attach::<V>)
+        .ok_or_else(|| type_mismatch::<V>(addr))
+}
+
+/// Return the gradient with respect to a recorded `Var` choice (or `DVector<Var>` choice) as an `f64` (or a `DVector<f64>`).
+fn choice_gradient(x: &Arc<dyn Any + Send + Sync>, adjoints: &Adjoints) -> Option<Arc<dyn Any + Send + Sync>> {
+    if let Some(x) = x.downcast_ref::<Var>() {
+        Some(Arc::new(adjoints.of(x)))
+    } else {
+        x.downcast_ref::<DVector<Var>>().map(|x| Arc::new(x.map(|x| adjoints.of(&x))) as Arc<dyn Any + Send + Sync>)
+    }
+}
+
+fn sample_fresh<V,W: Clone>(prng: &mut dyn RngCore, dist: &impl Distribution<V,W>, args: W) -> (Arc<V>,f64) {
+    let x = dist.random(prng, args.clone());
+    let logp = dist.logpdf(&x, args);
+    (Arc::new(x), logp)
+}
+
+/// Unwinding payload that interrupts the execution of a `DynGenFn` once an error has been recorded in its handler,
+/// when the execution can't continue with a fallback (see `DynFn::run`).
+struct Interrupt;
+
+/// Interrupt the execution of the `DynGenFn` whose handler just recorded an error.
+fn interrupt() -> ! {
+    panic::resume_unwind(Box::new(Interrupt))
+}
+
+/// Fallback after a nested call has failed: simulate the callee so that execution can continue.
+/// If the callee can't be simulated either, record its error and interrupt the execution.
+fn simulate_fallback<X,Y>(
+    prng: &mut dyn RngCore,
+    gen_fn: &impl GenFn<X,DynTrie,Y>,
+    args: X,
+    error: &mut Option<GenFnError>
+) -> (DynTrie,Option<Y>) {
+    match gen_fn.try_simulate(prng, args) {
+        Ok(subtrace) => (subtrace.data, subtrace.retv),
+        Err(err) => {
+            record(error, Err(err));
+            interrupt()
         }
     }
 }
 
 /// Incremental computational state of a `trace` during the execution of the different `GenFn` methods with a `DynGenFn`.
+/// 
+/// Errors raised while executing (eg. a constraint of the wrong type, or sampling twice at the same address)
+/// don't interrupt the function: the first one is kept in `error` and execution continues with a valid fallback,
+/// so that the `try_` method can return it once the function completes. If there is no fallback (eg. a callee that
+/// failed can't be simulated either), the execution is interrupted and the `try_` method returns the error right away.
 pub enum DynGenFnHandler<'a,A,T> {
     /// State for executing `GenFn::simulate` in a `DynGenFn`.
     Simulate {
-        ///
-        prng: &'a mut ThreadRng,
-        ///
+        /// Random number generator used to sample unconstrained choices.
+        prng: &'a mut dyn RngCore,
+        /// The trace under construction.
         trace: DynTrace<A,T>,
+        /// First error raised during the execution.
+        error: Option<GenFnError>
     },
 
     /// State for executing `GenFn::generate` in a `DynGenFn`.
     Generate {
-        ///
-        prng: &'a mut ThreadRng,
-        ///
+        /// Random number generator used to sample unconstrained choices.
+        prng: &'a mut dyn RngCore,
+        /// The trace under construction.
         trace: DynTrace<A,T>,
-        ///
+        /// Accumulated incremental importance weight.
         weight: f64,
-        ///
+        /// Constraints that have not yet been consumed.
         constraints: DynTrie,
+        /// First error raised during the execution.
+        error: Option<GenFnError>
     },
 
     /// State for executing `GenFn::update` in a `DynGenFn`.
     Update {
-        ///
-        prng: &'a mut ThreadRng,
-        ///
+        /// Random number generator used to sample unconstrained choices.
+        prng: &'a mut dyn RngCore,
+        /// The trace under construction.
         trace: DynTrace<A,T>,
-        ///
+        /// Change to the arguments of the trace, widened to `ArgDiff::Unknown` once a choice or the return value of a subcall changes.
         diff: ArgDiff,
-        ///
+        /// Constraints that have not yet been consumed.
         constraints: DynTrie,
-        ///
+        /// Accumulated incremental importance weight.
         weight: f64,
-        ///
+        /// Previous choices that were overwritten or removed.
         discard: DynTrie,
-        ///
-        visitor: AddrMap
+        /// Addresses visited during the execution.
+        visitor: AddrMap,
+        /// First error raised during the execution.
+        error: Option<GenFnError>
     },
 
     /// State for executing `GenFn::regenerate` in a `DynGenFn`.
     Regenerate {
-        ///
-        prng: &'a mut ThreadRng,
-        ///
+        /// Random number generator used to sample unconstrained choices.
+        prng: &'a mut dyn RngCore,
+        /// The trace under construction.
         trace: DynTrace<A,T>,
-        ///
+        /// Change to the arguments of the trace, widened to `ArgDiff::Unknown` once a choice or the return value of a subcall changes.
         diff: ArgDiff,
-        ///
+        /// Addresses to resample from the internal proposal.
         mask: &'a AddrMap,
-        ///
+        /// Accumulated incremental importance weight.
         weight: f64,
-        ///
-        visitor: AddrMap
+        /// Addresses visited during the execution.
+        visitor: AddrMap,
+        /// First error raised during the execution.
+        error: Option<GenFnError>
+    },
+
+    /// State for replaying the choices of a trace in a `DynGenFn` while recording its log joint density on the tape,
+    /// to take gradients (eg. in `GenFn::choice_gradients`).
+    Gradient {
+        /// Random number generator used to sample choices missing from `data` (which is an error).
+        prng: &'a mut dyn RngCore,
+        /// The choices being replayed.
+        data: &'a DynTrie,
+        /// The replayed choices that are `Var`s, as recorded on the tape.
+        choices: DynTrie,
+        /// Log joint density of the choices replayed so far.
+        logjp: Var,
+        /// First error raised during the execution.
+        error: Option<GenFnError>
     }
 }
 
 
 impl<A,T> DynGenFnHandler<'_,A,T> {
+    /// Return the first error raised during the execution.
+    fn error(&mut self) -> &mut Option<GenFnError> {
+        match self {
+            Self::Simulate { error, .. } | Self::Generate { error, .. } | Self::Update { error, .. }
+                | Self::Regenerate { error, .. } | Self::Gradient { error, .. } => error
+        }
+    }
+
     /// Sample a random value from a distribution and observe it in the `self.trace.data` trie as a weighted leaf node.
     /// 
     /// Return a clone of the sampled value.
     pub fn sample_at<
         V: Clone + Send + Sync + 'static,
         W: Clone + 'static
-    >(&mut self, dist: &impl Distribution<V,W>, args: W, addr: &str) -> V {
+    >(&mut self, dist: &impl Distribution<V,W>, args: W, addr: impl Into<Addr>) -> V {
+        let addr = addr.into();
         match self {
             DynGenFnHandler::Simulate {
                 prng,
                 trace,
+                error
             } => {
-                let x = dist.random(prng, args.clone());
-                let logp = dist.logpdf(&x, args);
-                trace.data.w_observe(addr, Arc::new(x.clone()), logp);
-                x
+                let (x, logp) = sample_fresh(*prng, dist, args);
+                record(error, trace.data.try_w_observe(addr, detach(x.clone()), logp));
+                x.as_ref().clone()
             }
 
             DynGenFnHandler::Generate {
@@ -117,26 +249,24 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
                 trace,
                 weight,
                 constraints,
+                error
             } => {
-                let (x, logp) = match constraints.remove(addr) {
-                    Some(choice) => {
-                        debug_assert!(choice.is_leaf());
-                        let x = choice
-                            .expect_inner(&format!("error: no value found in {addr}"))
-                            .downcast::<V>()
-                            .expect(&format!("error: downcast failed at {addr}"));
+                let (x, logp) = match constraints.remove_keys(addr.keys()).map(|choice| downcast_choice::<V>(choice, &addr)) {
+                    Some(Ok(x)) => {
                         let logp = dist.logpdf(x.as_ref(), args);
                         *weight += logp;
                         (x, logp)
                     }
+                    Some(Err(err)) => {
+                        record(error, Err(err));
+                        sample_fresh(*prng, dist, args)
+                    }
                     None => {
-                        let x = dist.random(prng, args.clone());
-                        let logp = dist.logpdf(&x, args);
-                        (Arc::new(x), logp)
+                        sample_fresh(*prng, dist, args)
                     }
                 };
 
-                trace.data.w_observe(addr, x.clone(), logp);
+                record(error, trace.data.try_w_observe(addr, detach(x.clone()), logp));
                 x.as_ref().clone()
             }
 
@@ -147,66 +277,62 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
                 constraints,
                 weight,
                 discard,
-                visitor
+                visitor,
+                error
             } => {
-                visitor.visit(addr);
+                visitor.visit_keys(addr.keys());
 
-                let (x, logp) = match constraints.remove(addr) {
+                let (x, logp) = match constraints.remove_keys(addr.keys()) {
                     Some(choice) => {
-                        debug_assert!(choice.is_leaf());
-                        if let Some(call) = trace.data.remove(addr) {
+                        if let Some(call) = trace.data.remove_keys(addr.keys()) {
                             *weight -= call.weight();
-                            discard.insert(addr, call);
+                            record(error, discard.try_insert(&addr, call));
                         };
-                        let x = choice
-                            .expect_inner(&format!("error: no value found in {addr}"))
-                            .downcast::<V>()
-                            .expect(&format!("error: downcast failed at {addr}"));
-                        let logp = dist.logpdf(x.as_ref(), args);
                         *diff = ArgDiff::Unknown;
-                        *weight += logp;
-                        (x, logp)
+                        match downcast_choice::<V>(choice, &addr) {
+                            Ok(x) => {
+                                let logp = dist.logpdf(x.as_ref(), args);
+                                *weight += logp;
+                                (x, logp)
+                            }
+                            Err(err) => {
+                                record(error, Err(err));
+                                sample_fresh(*prng, dist, args)
+                            }
+                        }
                     }
                     None => {
-                        match trace.data.remove(addr) {
+                        match trace.data.remove_keys(addr.keys()) {
                             Some(call) => {
-                                match diff {
-                                    ArgDiff::NoChange => {
-                                        let x = call
-                                            .clone()
-                                            .expect_inner(&format!("error: no value found in {addr}"))
-                                            .downcast::<V>()
-                                            .expect(&format!("error: downcast failed at {addr}"));
+                                if *diff == ArgDiff::Extend {
+                                    record(error, Err(GenFnError::UnsupportedArgDiff(ArgDiff::Extend)));
+                                }
+                                let prev_logp = call.weight();
+                                match downcast_choice::<V>(call.clone(), &addr) {
+                                    Ok(x) if *diff == ArgDiff::NoChange => {
                                         trace.data.insert(addr, call);
                                         return x.as_ref().clone();
                                     }
-                                    ArgDiff::Unknown => {
-                                        let prev_logp = call.weight();
-                                        let x = call
-                                            .clone()
-                                            .expect_inner(&format!("error: no value found in {addr}"))
-                                            .downcast::<V>()
-                                            .expect(&format!("error: downcast failed at {addr}"));
+                                    Ok(x) => {
                                         let logp = dist.logpdf(x.as_ref(), args);
                                         *weight += logp - prev_logp;
                                         (x, logp)
                                     }
-                                    _ => {
-                                        panic!("update: ArgDiff::Extend not supported");
+                                    Err(err) => {
+                                        record(error, Err(err));
+                                        sample_fresh(*prng, dist, args)
                                     }
                                 }
                             }
                             None => {
-                                let x = Arc::new(dist.random(prng, args.clone()));
-                                let logp = dist.logpdf(x.as_ref(), args);
                                 *diff = ArgDiff::Unknown;
-                                (x, logp)
+                                sample_fresh(*prng, dist, args)
                             }
                         }
                     }
                 };
 
-                trace.data.w_observe(addr, x.clone(), logp);
+                record(error, trace.data.try_w_observe(addr, detach(x.clone()), logp));
                 x.as_ref().clone()
             }
 
@@ -216,59 +342,75 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
                 diff,
                 mask,
                 weight,
-                visitor
+                visitor,
+                error
             } => {
-                visitor.visit(addr);
+                visitor.visit_keys(addr.keys());
 
-                let (x, logp) = match mask.search(addr) {
+                let (x, logp) = match mask.search_keys(addr.keys()) {
                     Some(submask) => {
                         debug_assert!(submask.is_leaf());
-                        trace.data.remove(addr);  // remove (if has previous)
-                        let x = Arc::new(dist.random(prng, args.clone()));
-                        let logp = dist.logpdf(x.as_ref(), args);
+                        trace.data.remove_keys(addr.keys());  // remove (if has previous)
                         *diff = ArgDiff::Unknown;
-                        (x, logp)
+                        sample_fresh(*prng, dist, args)
                     }
                     None => {
-                        match trace.data.remove(addr) {
+                        match trace.data.remove_keys(addr.keys()) {
                             Some(call) => {
-                                match diff {
-                                    ArgDiff::NoChange => {
-                                        let x = call
-                                            .clone()
-                                            .expect_inner(&format!("error: no value found in {addr}"))
-                                            .downcast::<V>()
-                                            .expect(&format!("error: downcast failed at {addr}"));
+                                if *diff == ArgDiff::Extend {
+                                    record(error, Err(GenFnError::UnsupportedArgDiff(ArgDiff::Extend)));
+                                }
+                                let prev_logp = call.weight();
+                                match downcast_choice::<V>(call.clone(), &addr) {
+                                    Ok(x) if *diff == ArgDiff::NoChange => {
                                         trace.data.insert(addr, call);
                                         return x.as_ref().clone();
                                     }
-                                    ArgDiff::Unknown => {
-                                        let prev_logp = call.weight();
-                                        let x = call
-                                            .clone()
-                                            .expect_inner(&format!("error: no value found in {addr}"))
-                                            .downcast::<V>()
-                                            .expect(&format!("error: downcast failed at {addr}"));
+                                    Ok(x) => {
                                         let logp = dist.logpdf(x.as_ref(), args);
                                         *weight += logp - prev_logp;
                                         (x, logp)
                                     }
-                                    _ => {
-                                        panic!("regenerate: ArgDiff::Extend not supported");
+                                    Err(err) => {
+                                        record(error, Err(err));
+                                        sample_fresh(*prng, dist, args)
                                     }
                                 }
                             }
                             None => {
-                                let x = Arc::new(dist.random(prng, args.clone()));
-                                let logp = dist.logpdf(x.as_ref(), args);
                                 *diff = ArgDiff::Unknown;
-                                (x, logp)
+                                sample_fresh(*prng, dist, args)
                             }
                         }
                     }
                 };
 
-                trace.data.w_observe(addr, x.clone(), logp);
+                record(error, trace.data.try_w_observe(addr, detach(x.clone()), logp));
+                x.as_ref().clone()
+            }
+
+            DynGenFnHandler::Gradient {
+                prng,
+                data,
+                choices,
+                logjp,
+                error
+            } => {
+                let x = match data.search_keys(addr.keys()).map(|choice| downcast_choice::<V>(choice.clone(), &addr)) {
+                    Some(Ok(x)) => x,
+                    Some(Err(err)) => {
+                        record(error, Err(err));
+                        sample_fresh(*prng, dist, args.clone()).0
+                    }
+                    None => {
+                        record(error, Err(GenFnError::AddressNotFound(addr.to_string())));
+                        sample_fresh(*prng, dist, args.clone()).0
+                    }
+                };
+                *logjp += dist.logpdf_var(x.as_ref(), args);
+                if TypeId::of::<V>() == TypeId::of::<Var>() || TypeId::of::<V>() == TypeId::of::<DVector<Var>>() {
+                    record(error, choices.try_observe(addr, x.clone()));
+                }
                 x.as_ref().clone()
             }
         }
@@ -283,168 +425,248 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
     pub fn trace_at<
         X: Clone + 'static,
         Y: Clone + Send + Sync + 'static
-    >(&mut self, gen_fn: &impl GenFn<X,DynTrie,Y>, args: X, addr: &str) -> Y {
+    >(&mut self, gen_fn: &impl GenFn<X,DynTrie,Y>, args: X, addr: impl Into<Addr>) -> Y {
+        let addr = addr.into();
         match self {
             DynGenFnHandler::Simulate {
-                prng: _,
+                prng,
                 trace,
+                error
             } => {
-                let mut subtrace = gen_fn.simulate(args);
-                subtrace.data.replace_inner(Arc::new(subtrace.retv.clone().unwrap()));
-                trace.data.insert(addr, subtrace.data);
-                subtrace.retv.unwrap()
+                let (mut sub, retv) = simulate_fallback(*prng, gen_fn, args, error);
+                sub.replace_inner(Arc::new(retv.clone().unwrap()));
+                record(error, trace.data.try_insert(addr, sub));
+                retv.unwrap()
             }
 
             DynGenFnHandler::Generate {
-                prng: _,
+                prng,
                 trace,
                 weight,
                 constraints,
+                error
             } => {
-                let (mut sub, retv) = match constraints.remove(addr) {
+                let (mut sub, retv) = match constraints.remove_keys(addr.keys()) {
                     Some(choices) => {
                         debug_assert!(!choices.is_leaf());
-                        let (subtrace, d_weight) = gen_fn.generate(args, choices);
-                        *weight += d_weight;
-                        (subtrace.data, subtrace.retv)
+                        match gen_fn.try_generate(*prng, args.clone(), choices) {
+                            Ok((subtrace, d_weight)) => {
+                                *weight += d_weight;
+                                (subtrace.data, subtrace.retv)
+                            }
+                            Err(err) => {
+                                record(error, Err(err));
+                                simulate_fallback(*prng, gen_fn, args, error)
+                            }
+                        }
                     }
                     None => {
-                        let subtrace = gen_fn.simulate(args);
-                        (subtrace.data, subtrace.retv)
+                        simulate_fallback(*prng, gen_fn, args, error)
                     }
                 };
                 sub.replace_inner(Arc::new(retv.clone().unwrap()));
-                trace.data.insert(addr, sub);
+                record(error, trace.data.try_insert(addr, sub));
                 retv.unwrap()
             },
 
             DynGenFnHandler::Update {
-                prng: _,
+                prng,
                 trace,
                 diff,
                 constraints,
                 weight,
                 discard,
-                visitor
+                visitor,
+                error
             } => {
-                visitor.visit(addr);
+                visitor.visit_keys(addr.keys());
 
-                let (mut sub, retv) = match constraints.remove(addr) {
+                let (mut sub, retv) = match constraints.remove_keys(addr.keys()) {
                     Some(choices) => {
-                        match trace.data.remove(addr) {
+                        debug_assert!(!choices.is_leaf());
+                        let result = match trace.data.remove_keys(addr.keys()) {
                             Some(sub) => {
-                                debug_assert!(!choices.is_leaf());
                                 let logjp = sub.weight();
                                 let subtrace = Trace { args: args.clone(), data: sub, retv: None, logjp };
-                                let (subtrace, subdiscard, d_weight) = gen_fn.update(subtrace, args, diff.clone(), choices);
+                                gen_fn.try_update(*prng, subtrace, args.clone(), diff.clone(), choices)
+                            }
+                            None => {
+                                gen_fn.try_generate(*prng, args.clone(), choices)
+                                    .map(|(subtrace, d_weight)| (subtrace, DynTrie::new(), d_weight, ArgDiff::Unknown))
+                            }
+                        };
+                        match result {
+                            Ok((subtrace, subdiscard, d_weight, retdiff)) => {
                                 if !subdiscard.is_empty() {
-                                    discard.insert(addr, subdiscard);
+                                    record(error, discard.try_insert(&addr, subdiscard));
+                                }
+                                if retdiff != ArgDiff::NoChange {
+                                    *diff = ArgDiff::Unknown;
                                 }
-                                *diff = ArgDiff::Unknown;
                                 *weight += d_weight;
                                 (subtrace.data, subtrace.retv)
                             }
-                            None => {
-                                let (subtrace, d_weight) = gen_fn.generate(args, choices);
+                            Err(err) => {
+                                record(error, Err(err));
                                 *diff = ArgDiff::Unknown;
-                                *weight += d_weight;
-                                (subtrace.data, subtrace.retv)
+                                simulate_fallback(*prng, gen_fn, args, error)
                             }
                         }
                     }
                     None => {
-                        match trace.data.remove(addr) {
+                        match trace.data.remove_keys(addr.keys()) {
                             Some(sub) => {
                                 match diff {
                                     ArgDiff::NoChange => {
-                                        let retv = sub.ref_inner().unwrap().downcast_ref::<Y>().unwrap().clone();
-                                        trace.data.insert(addr, sub);
-                                        return retv;
+                                        match sub.ref_inner().and_then(|v| v.downcast_ref::<Y>()).cloned() {
+                                            Some(retv) => {
+                                                trace.data.insert(addr, sub);
+                                                return retv;
+                                            }
+                                            None => {
+                                                record(error, Err(type_mismatch::<Y>(&addr)));
+                                                simulate_fallback(*prng, gen_fn, args, error)
+                                            }
+                                        }
                                     }
-                                    ArgDiff::Unknown => {
+                                    _ => {
+                                        if *diff == ArgDiff::Extend {
+                                            record(error, Err(GenFnError::UnsupportedArgDiff(ArgDiff::Extend)));
+                                        }
                                         let logjp = sub.weight();
                                         let subtrace = Trace { args: args.clone(), data: sub, retv: None, logjp };
-                                        let (subtrace, subdiscard, d_weight) = gen_fn.update(subtrace, args, ArgDiff::Unknown, DynTrie::new());
-                                        if !(subdiscard.is_empty()) {
-                                            discard.insert(addr, subdiscard);
+                                        match gen_fn.try_update(*prng, subtrace, args.clone(), ArgDiff::Unknown, DynTrie::new()) {
+                                            Ok((subtrace, subdiscard, d_weight, _)) => {
+                                                if !subdiscard.is_empty() {
+                                                    record(error, discard.try_insert(&addr, subdiscard));
+                                                }
+                                                *weight += d_weight;
+                                                (subtrace.data, subtrace.retv)
+                                            }
+                                            Err(err) => {
+                                                record(error, Err(err));
+                                                simulate_fallback(*prng, gen_fn, args, error)
+                                            }
                                         }
-                                        *weight += d_weight;
-                                        (subtrace.data, subtrace.retv)
-                                    }
-                                    _ => {
-                                        panic!("update: ArgDiff::Extend not supported");
                                     }
                                 }
                             }
                             None => {
-                                let subtrace = gen_fn.simulate(args);
                                 *diff = ArgDiff::Unknown;
-                                (subtrace.data, subtrace.retv)
+                                simulate_fallback(*prng, gen_fn, args, error)
                             }
                         }
                     }
                 };
 
                 sub.replace_inner(Arc::new(retv.clone().unwrap()));
-                trace.data.insert(addr, sub);
+                record(error, trace.data.try_insert(addr, sub));
                 retv.unwrap()
             }
 
             DynGenFnHandler::Regenerate {
-                prng: _prng,
+                prng,
                 trace,
                 diff,
                 mask,
                 weight,
-                visitor
+                visitor,
+                error
             } => {
-                visitor.visit(addr);
+                visitor.visit_keys(addr.keys());
 
-                let submask = mask.search(addr);
+                let submask = mask.search_keys(addr.keys());
 
-                let (mut sub, retv) = match trace.data.remove(addr) {
+                let (mut sub, retv) = match trace.data.remove_keys(addr.keys()) {
                     Some(sub) => {
                         let logjp = sub.weight();
                         match submask {
                             Some(submask) => {
                                 let subtrace = Trace { args: args.clone(), data: sub, retv: None, logjp };
-                                let (subtrace, d_weight) = gen_fn.regenerate(subtrace, args, diff.clone(), submask);
-                                *diff = ArgDiff::Unknown;
-                                *weight += d_weight;
-                                (subtrace.data, subtrace.retv)
+                                match gen_fn.try_regenerate(*prng, subtrace, args.clone(), diff.clone(), submask) {
+                                    Ok((subtrace, d_weight, retdiff)) => {
+                                        if retdiff != ArgDiff::NoChange {
+                                            *diff = ArgDiff::Unknown;
+                                        }
+                                        *weight += d_weight;
+                                        (subtrace.data, subtrace.retv)
+                                    }
+                                    Err(err) => {
+                                        record(error, Err(err));
+                                        *diff = ArgDiff::Unknown;
+                                        simulate_fallback(*prng, gen_fn, args, error)
+                                    }
+                                }
                             }
                             None => {  // submask is absent
                                 match diff {
                                     ArgDiff::NoChange => {
-                                        let retv = sub.ref_inner().unwrap().downcast_ref::<Y>().unwrap().clone();
-                                        trace.data.insert(addr, sub);
-                                        return retv;
-                                    }
-                                    ArgDiff::Unknown => {
-                                        let prev_weight = sub.weight();
-                                        let (subtrace, new_weight) = gen_fn.generate(args, sub);
-                                        *weight += new_weight - prev_weight;
-                                        (subtrace.data, subtrace.retv)
+                                        match sub.ref_inner().and_then(|v| v.downcast_ref::<Y>()).cloned() {
+                                            Some(retv) => {
+                                                trace.data.insert(addr, sub);
+                                                return retv;
+                                            }
+                                            None => {
+                                                record(error, Err(type_mismatch::<Y>(&addr)));
+                                                simulate_fallback(*prng, gen_fn, args, error)
+                                            }
+                                        }
                                     }
                                     _ => {
-                                        panic!("regenerate: ArgDiff::Extend not supported");
+                                        if *diff == ArgDiff::Extend {
+                                            record(error, Err(GenFnError::UnsupportedArgDiff(ArgDiff::Extend)));
+                                        }
+                                        match gen_fn.try_generate(*prng, args.clone(), sub) {
+                                            Ok((subtrace, new_weight)) => {
+                                                *weight += new_weight - logjp;
+                                                (subtrace.data, subtrace.retv)
+                                            }
+                                            Err(err) => {
+                                                record(error, Err(err));
+                                                simulate_fallback(*prng, gen_fn, args, error)
+                                            }
+                                        }
                                     }
                                 }
                             }
                         }
                     }
                     None => {
-                        let subtrace = gen_fn.simulate(args);
                         *diff = ArgDiff::Unknown;
-                        (subtrace.data, subtrace.retv)
+                        simulate_fallback(*prng, gen_fn, args, error)
                     }
                 };
 
                 sub.replace_inner(Arc::new(retv.clone().unwrap()));
-                trace.data.insert(addr, sub);
+                record(error, trace.data.try_insert(addr, sub));
                 retv.unwrap()
             }
 
+            DynGenFnHandler::Gradient {
+                prng,
+                data,
+                choices,
+                logjp,
+                error
+            } => {
+                let result = match data.search_keys(addr.keys()) {
+                    Some(sub) => gen_fn.try_record_logjp(*prng, sub, args.clone()),
+                    None => Err(GenFnError::AddressNotFound(addr.to_string()))
+                };
+                match result {
+                    Ok((sub_logjp, subchoices, retv)) => {
+                        *logjp += sub_logjp;
+                        if !subchoices.is_empty() {
+                            record(error, choices.try_insert(addr, subchoices));
+                        }
+                        retv
+                    }
+                    Err(err) => {
+                        record(error, Err(err));
+                        interrupt()
+                    }
+                }
+            }
         }
     }
 
@@ -453,7 +675,7 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
     /// Panics on other variants.
     pub fn gc(self) -> Self {
         match self {
-            Self::Update { prng, trace, diff, constraints, weight, mut discard, visitor } => {
+            Self::Update { prng, trace, diff, constraints, weight, mut discard, visitor, error } => {
                 let schema = trace.data.schema();
                 let (data, complement, complement_weight) = trace.data.collect(&schema.complement(&visitor));
                 debug_assert!(visitor.all_visited(&data.schema()));  // all unvisited nodes garbage-collected
@@ -465,10 +687,11 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
                     constraints,
                     weight: weight - complement_weight,
                     discard,
-                    visitor
+                    visitor,
+                    error
                 }
             }
-            Self::Regenerate { prng, trace, diff, mask, weight, visitor } => {
+            Self::Regenerate { prng, trace, diff, mask, weight, visitor, error } => {
                 let schema = trace.data.schema();
                 let (data, _, _) = trace.data.collect(&schema.complement(&visitor));
                 debug_assert!(visitor.all_visited(&data.schema()));  // all unvisited nodes garbage-collected
@@ -478,7 +701,8 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
                     diff,
                     mask,
                     weight,
-                    visitor
+                    visitor,
+                    error
                 }
             }
             _ => { panic!("garbage-collect (gc): called outside of update or regenerate context") }
@@ -487,98 +711,288 @@ impl<A,T> DynGenFnHandler<'_,A,T> {
 }
 
 
+/// The retdiff of a `DynGenFn` whose execution ended with the handler in state `diff`.
+/// 
+/// The handler's `diff` stays `NoChange` only if the arguments and every choice and subcall
+/// return value were unchanged, in which case so is the return value.
+fn retdiff(diff: ArgDiff) -> ArgDiff {
+    match diff {
+        ArgDiff::NoChange => ArgDiff::NoChange,
+        _ => ArgDiff::Unknown
+    }
+}
+
+/// Collect the gradients with respect to the choices of `data` selected by `selection` into `gradients`,
+/// given the `choices` that were recorded as `Var`s.
+/// 
+/// Choices selected by their own address must be `Var`s (eg. sampled from `ad::normal`), else an `InvalidArgs` error
+/// is returned, while other choices in a selected subtree are skipped.
+fn selected_gradients(
+    data: &DynTrie,
+    choices: Option<&DynTrie>,
+    selection: &AddrMap,
+    adjoints: &Adjoints,
+    addr: Addr,
+    gradients: &mut DynTrie
+) -> Result<(),GenFnError> {
+    if !selection.is_leaf() {
+        for (key, subselection) in selection.iter() {
+            if let Some(subdata) = data.search_keys(slice::from_ref(key)) {
+                let subchoices = choices.and_then(|choices| choices.search_keys(slice::from_ref(key)));
+                let mut subaddr = addr.clone();
+                subaddr.push(key.clone());
+                selected_gradients(subdata, subchoices, subselection, adjoints, subaddr, gradients)?;
+            }
+        }
+    } else if data.is_leaf() {
+        let gradient = choices
+            .and_then(|choices| choices.ref_inner())
+            .and_then(|x| choice_gradient(x, adjoints))
+            .ok_or_else(|| GenFnError::InvalidArgs(format!(
+                "the choice at \"{}\" isn't differentiable (sample it from a distribution of `ad`, eg. `ad::normal`)", addr
+            )))?;
+        gradients.observe(addr, gradient);
+    } else if let Some(choices) = choices {
+        for (key, subchoices) in choices.iter() {
+            let mut subaddr = addr.clone();
+            subaddr.push(key.clone());
+            match subchoices.ref_inner().and_then(|x| choice_gradient(x, adjoints)) {
+                Some(gradient) => gradients.observe(subaddr, gradient),
+                None => selected_gradients(subchoices, Some(subchoices), selection, adjoints, subaddr, gradients)?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A shared, thread-safe closure with the signature of the function wrapped by a `DynGenFn`.
+pub type DynFnArc<A,T> = Arc<dyn Fn(&mut DynGenFnHandler<A,T>, A) -> T + Send + Sync>;
+
+/// The function wrapped by a `DynGenFn`.
+pub enum DynFn<A,T> {
+    /// A function pointer, which (unlike a closure) can be wrapped in a `const` (eg. as declared by `dyngen!`).
+    Ptr(fn(&mut DynGenFnHandler<A,T>, A) -> T),
+
+    /// A shared closure, which can capture (read-only) data like a dataset or configuration.
+    Closure(DynFnArc<A,T>)
+}
+
+impl<A,T> Clone for DynFn<A,T> {
+    fn clone(&self) -> Self {
+        match self {
+            DynFn::Ptr(func) => DynFn::Ptr(*func),
+            DynFn::Closure(func) => DynFn::Closure(func.clone())
+        }
+    }
+}
+
+impl<A,T> DynFn<A,T> {
+    /// Call the wrapped function.
+    pub fn call(&self, g: &mut DynGenFnHandler<A,T>, args: A) -> T {
+        match self {
+            DynFn::Ptr(func) => func(g, args),
+            DynFn::Closure(func) => func(g, args)
+        }
+    }
+
+    /// Call the wrapped function, or return the error that interrupted its execution
+    /// (when the handler `g` couldn't continue with a fallback).
+    pub(crate) fn run(&self, g: &mut DynGenFnHandler<A,T>, args: A) -> Result<T,GenFnError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.call(g, args))) {
+            Ok(retv) => Ok(retv),
+            Err(payload) if payload.is::<Interrupt>() => {
+                Err(g.error().take().expect("interrupted without an error"))
+            }
+            Err(payload) => panic::resume_unwind(payload)
+        }
+    }
+}
+
 /// Wrapper struct for functions that use the `DynGenFnHandler` DSL (`sample_at` and `trace_at`).
+/// 
+/// Cloning a `DynGenFn` is cheap, and shares any data captured by its closure.
 pub struct DynGenFn<A,T> {
     /// A stochastic function that takes in a mutable reference to a `DynGenFnHandler<A,T>` and some args `A`, effectfully mutates the state, and produces a value `T`.
-    pub func: fn(&mut DynGenFnHandler<A,T>, A) -> T,
+    pub func: DynFn<A,T>,
+}
+
+impl<A,T> Clone for DynGenFn<A,T> {
+    fn clone(&self) -> Self {
+        DynGenFn { func: self.func.clone() }
+    }
 }
 
 impl<Args,Ret> DynGenFn<Args,Ret> {
     /// Dynamically construct a `DynGenFn` from a function at run-time.
     pub const fn new(func: fn(&mut DynGenFnHandler<Args,Ret>, Args) -> Ret) -> Self {
-        DynGenFn { func }
+        DynGenFn { func: DynFn::Ptr(func) }
+    }
+
+    /// Construct a `DynGenFn` from a closure, which can capture data from its environment.
+    pub fn from_fn(func: impl Fn(&mut DynGenFnHandler<Args,Ret>, Args) -> Ret + Send + Sync + 'static) -> Self {
+        DynGenFn { func: DynFn::Closure(Arc::new(func)) }
+    }
+
+    /// Construct a `DynGenFn` from a shared closure.
+    pub fn from_arc(func: DynFnArc<Args,Ret>) -> Self {
+        DynGenFn { func: DynFn::Closure(func) }
     }
 }
 
 impl<Args: Clone,Ret> GenFn<Args,DynTrie,Ret> for DynGenFn<Args,Ret> {
-    fn simulate(&self, args: Args) -> DynTrace<Args,Ret> {
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: Args) -> Result<DynTrace<Args,Ret>,GenFnError> {
         let mut g = DynGenFnHandler::Simulate {
-            prng: &mut ThreadRng::default(),
+            prng: rng,
             trace: Trace { args: args.clone(), data: Trie::new(), retv: None, logjp: 0. },
+            error: None
         };
-        let retv = (self.func)(&mut g, args);
-        let DynGenFnHandler::Simulate {prng: _, mut trace} = g else { unreachable!() };
+        let retv = self.func.run(&mut g, args)?;
+        let DynGenFnHandler::Simulate {prng: _, mut trace, error} = g else { unreachable!() };
+        if let Some(err) = error {
+            return Err(err);
+        }
         trace.set_retv(retv);
         trace.logjp = trace.data.weight();
-        trace
+        Ok(trace)
     }
 
-    fn generate(&self, args: Args, mut constraints: DynTrie) -> (DynTrace<Args,Ret>, f64) {
+    fn try_generate(&self, rng: &mut dyn RngCore, args: Args, mut constraints: DynTrie) -> Result<(DynTrace<Args,Ret>,f64),GenFnError> {
         constraints.take_inner();  // in case constraints came from a proposal
         let mut g = DynGenFnHandler::Generate {
-            prng: &mut ThreadRng::default(),
+            prng: rng,
             trace: Trace { args: args.clone(), data: Trie::new(), retv: None, logjp: 0. },
             weight: 0.,
-            constraints: constraints,
+            constraints,
+            error: None
         };
-        let retv = (self.func)(&mut g, args);
-        let DynGenFnHandler::Generate {prng: _, mut trace, weight, constraints} = g else { unreachable!() };
+        let retv = self.func.run(&mut g, args)?;
+        let DynGenFnHandler::Generate {prng: _, mut trace, weight, constraints, error} = g else { unreachable!() };
+        if let Some(err) = error {
+            return Err(err);
+        }
         if !constraints.is_empty() {
-            println!("residual found:\n{:#?}", constraints);
-            panic!("generate error: not all constraints were consumed!");
+            return Err(GenFnError::UnconsumedConstraints(constraints.schema().leaves()));
         }  // else all constraints bound to trace
         trace.logjp = trace.data.weight();
         trace.set_retv(retv);
-        (trace, weight)
+        Ok((trace, weight))
     }
 
-    fn update(&self,
+    fn try_update(&self,
+        rng: &mut dyn RngCore,
         trace: DynTrace<Args,Ret>,
         args: Args,
         diff: ArgDiff,
         mut constraints: DynTrie
-    ) -> (DynTrace<Args,Ret>, DynTrie, f64) {
+    ) -> Result<(DynTrace<Args,Ret>,DynTrie,f64,ArgDiff),GenFnError> {
         constraints.take_inner();  // in case constraints came from a proposal
         let mut g = DynGenFnHandler::Update {
-            prng: &mut ThreadRng::default(),
+            prng: rng,
             trace,
             diff,
             weight: 0.,
-            constraints: constraints,
+            constraints,
             discard: Trie::new(),
-            visitor: AddrMap::new()
+            visitor: AddrMap::new(),
+            error: None
         };
-        let retv = (self.func)(&mut g, args);
+        let retv = self.func.run(&mut g, args)?;
         let g = g.gc();  // subtract weight of complement and add complement to discard
-        let DynGenFnHandler::Update {prng: _, mut trace, diff: _diff, weight, constraints, discard, visitor: _visitor} = g else { unreachable!() };
+        let DynGenFnHandler::Update {prng: _, mut trace, diff, weight, constraints, discard, visitor: _visitor, error} = g else { unreachable!() };
+        if let Some(err) = error {
+            return Err(err);
+        }
         if !constraints.is_empty() {
-            println!("residual found:\n{:#?}", constraints);
-            panic!("update error: not all constraints were consumed!");
+            return Err(GenFnError::UnconsumedConstraints(constraints.schema().leaves()));
         }  // else all constraints bound to trace
         trace.logjp = trace.data.weight();
         trace.set_retv(retv);
-        (trace, discard, weight)
+        Ok((trace, discard, weight, retdiff(diff)))
     }
 
-    fn regenerate(&self,
+    fn try_regenerate(&self,
+        rng: &mut dyn RngCore,
         trace: DynTrace<Args,Ret>,
         args: Args,
         diff: ArgDiff,
         mask: &AddrMap
-    ) -> (DynTrace<Args,Ret>, f64) {
+    ) -> Result<(DynTrace<Args,Ret>,f64,ArgDiff),GenFnError> {
         let mut g = DynGenFnHandler::Regenerate {
-            prng: &mut ThreadRng::default(),
+            prng: rng,
             mask: if mask.is_leaf() { &trace.data.schema() } else { mask },
             trace,
             diff,
             weight: 0.,
-            visitor: AddrMap::new()
+            visitor: AddrMap::new(),
+            error: None
         };
-        let retv = (self.func)(&mut g, args);
+        let retv = self.func.run(&mut g, args)?;
         let g = g.gc();
-        let DynGenFnHandler::Regenerate {prng: _, mut trace, diff: _diff, mask: _mask, weight, visitor: _visitor} = g else { unreachable!() };
+        let DynGenFnHandler::Regenerate {prng: _, mut trace, diff, mask: _mask, weight, visitor: _visitor, error} = g else { unreachable!() };
+        if let Some(err) = error {
+            return Err(err);
+        }
         trace.logjp = trace.data.weight();
         trace.set_retv(retv);
-        (trace, weight)
+        Ok((trace, weight, retdiff(diff)))
     }
-}
\ No newline at end of file
+
+    /// Gradients are taken by reverse-mode automatic differentiation, through the choices sampled as `Var`s
+    /// (eg. from `ad::normal`): every other choice is a constant. Selecting such a choice by its own address returns
+    /// an `InvalidArgs` error, while selecting a subtree skips them.
+    fn try_choice_gradients(&self,
+        rng: &mut dyn RngCore,
+        trace: &DynTrace<Args,Ret>,
+        selection: &AddrMap
+    ) -> Result<DynTrie,GenFnError> {
+        let (choices, adjoints) = ad::gradients(|| {
+            let (logjp, choices, _) = self.try_record_logjp(rng, &trace.data, trace.args.clone())?;
+            Ok((logjp, choices))
+        })?;
+        let mut gradients = Trie::new();
+        selected_gradients(&trace.data, Some(&choices), selection, &adjoints, Addr::new(), &mut gradients)?;
+        Ok(gradients)
+    }
+
+    /// Gradients are taken by reverse-mode automatic differentiation, through the `Var`s in `trace.args`:
+    /// the gradient with respect to every other coordinate is zero.
+    fn try_arg_gradients(&self, rng: &mut dyn RngCore, trace: &DynTrace<Args,Ret>) -> Result<Args,GenFnError> where Args: Differentiable {
+        let (args, adjoints) = ad::gradients(|| {
+            let args = trace.args.with_coords(&trace.args.coords());
+            let (logjp, _, _) = self.try_record_logjp(rng, &trace.data, args.clone())?;
+            Ok((logjp, args))
+        })?;
+        Ok(args.gradient(&adjoints))
+    }
+
+    /// Gradients are taken by reverse-mode automatic differentiation, through the parameters that the function
+    /// reads from `store` with `ParamStore::var` or `ParamStore::vars`.
+    fn try_accumulate_param_gradients(&self,
+        rng: &mut dyn RngCore,
+        trace: &DynTrace<Args,Ret>,
+        store: &ParamStore,
+        scale: f64
+    ) -> Result<(),GenFnError> {
+        let ((), adjoints) = ad::gradients(|| {
+            let (logjp, _, _) = self.try_record_logjp(rng, &trace.data, trace.args.clone())?;
+            Ok((logjp, ()))
+        })?;
+        store.accumulate_adjoints(&adjoints, scale)
+    }
+
+    fn try_record_logjp(&self, rng: &mut dyn RngCore, data: &DynTrie, args: Args) -> Result<(Var,DynTrie,Ret),GenFnError> {
+        let mut g = DynGenFnHandler::Gradient {
+            prng: rng,
+            data,
+            choices: Trie::new(),
+            logjp: Var::constant(0.),
+            error: None
+        };
+        let retv = self.func.run(&mut g, args)?;
+        let DynGenFnHandler::Gradient {prng: _, data: _, choices, logjp, error} = g else { unreachable!() };
+        if let Some(err) = error {
+            return Err(err);
+        }
+        Ok((logjp, choices, retv))
+    }
+}
@@ -1,9 +1,10 @@
-use crate::{DynGenFn, DynGenFnHandler, DynTrie, GenFn, ArgDiff, Trace, ParticleSystem};
-use rand::rngs::ThreadRng;
+use crate::{DynGenFn, DynGenFnHandler, DynTrie, DynTrace, GenFn, GenFnError, ArgDiff, AddrMap, Trace, ParticleSystem};
+use rand::RngCore;
 
 
 /// Combinator struct for kernels that use the `DynGenFnHandler` DSL (`sample_at` and `trace_at`).
 /// Supports memory-efficient extension via the `GfDiff::Extend` flag (eg. as passed during a `ParticleSystem::step`).
+/// Past steps can be revisited with `ArgDiff::NoChange`: `update` takes (and discards) one `DynTrie` per step, and `regenerate` a mask keyed by step.
 pub struct DynUnfold<State> {
     /// A stochastic kernel that takes in a mutable reference to a `DynGenFnHandler<A,T>` and some `State`, effectfully mutates it, and produces a new `State`.
     pub kernel: DynGenFn<(i64,State),State>
@@ -14,88 +15,236 @@ impl<State> DynUnfold<State> {
     pub fn new(kernel: DynGenFn<(i64,State),State>) -> Self {
         DynUnfold { kernel }
     }
+
+    /// Return the choices at each step of `trace` that are not among the per-step `observations`
+    /// (eg. as the `reference` trajectory of `particle_gibbs`). Observed addresses missing from a step are skipped.
+    pub fn latent_choices(trace: &Trace<(i64,State),Vec<DynTrie>,Vec<State>>, observations: &[Vec<DynTrie>]) -> Vec<Vec<DynTrie>> {
+        trace.data.iter()
+            .zip(observations)
+            .map(|(data, step_observations)| {
+                let mut latents = data.clone();
+                for observations in step_observations {
+                    (latents, _, _) = latents.collect(&observations.schema());
+                }
+                vec![latents]
+            })
+            .collect()
+    }
+}
+
+impl<State: Clone> DynUnfold<State> {
+    /// Assess the `choices` of the kernel at step `t`, given the `state` output by the previous step (or the initial state at step `0`).
+    /// 
+    /// Returns the log density of the `choices` (which should hold every choice of the step, eg. as read from the `data` of a trace)
+    /// and the state output by the step, or the first error raised by the kernel (eg. if some of the `choices` weren't visited).
+    pub fn try_assess_step(&self, rng: &mut dyn RngCore, t: i64, state: State, choices: DynTrie) -> Result<(f64,State),GenFnError> {
+        let (step_trace, weight) = self.kernel.try_generate(rng, (t, state), choices)?;
+        Ok((weight, step_trace.retv.unwrap()))
+    }
+
+    /// Like `try_assess_step`, but panics on error.
+    pub fn assess_step(&self, rng: &mut dyn RngCore, t: i64, state: State, choices: DynTrie) -> (f64,State) {
+        self.try_assess_step(rng, t, state, choices).unwrap_or_else(|e| panic!("assess_step: {e}"))
+    }
+
+    /// Return the trace of the kernel at step `t` (taking its choices from `vec_trace`), with the input `state`.
+    fn step_trace(vec_trace: &mut Trace<(i64,State),Vec<DynTrie>,Vec<State>>, t: usize, state: State) -> DynTrace<(i64,State),State> {
+        let data = std::mem::take(&mut vec_trace.data[t]);
+        let logjp = data.weight();
+        Trace { args: (t as i64, state), data, retv: Some(vec_trace.retv.as_ref().unwrap()[t].clone()), logjp }
+    }
+
+    /// Re-execute every step of `vec_trace` whose input state changed or that `visit` selects, by applying `revisit` to the trace of that step.
+    /// 
+    /// `visit(t)` returns the per-step argument of `revisit` (eg. constraints or a mask), or `None` if step `t` can be
+    /// skipped when its input state is unchanged. Returns the total weight, along with the value returned by `revisit` for each step.
+    fn revisit<X,D: Default>(&self,
+        rng: &mut dyn RngCore,
+        vec_trace: &mut Trace<(i64,State),Vec<DynTrie>,Vec<State>>,
+        mut visit: impl FnMut(usize) -> Option<X>,
+        mut revisit: impl FnMut(&mut dyn RngCore, DynTrace<(i64,State),State>, ArgDiff, Option<X>) -> Result<(DynTrace<(i64,State),State>,D,f64,ArgDiff),GenFnError>
+    ) -> Result<(Vec<D>,f64,ArgDiff),GenFnError> {
+        let mut state = vec_trace.args.1.clone();
+        let mut state_diff = ArgDiff::NoChange;
+        let mut changed = false;
+        let mut total_weight = 0.;
+        let mut outputs = vec![];
+        for t in 0..vec_trace.data.len() {
+            let x = visit(t);
+            if x.is_none() && state_diff == ArgDiff::NoChange {
+                outputs.push(D::default());
+            } else {
+                let step_trace = Self::step_trace(vec_trace, t, state.clone());
+                let (step_trace, output, weight, retdiff) = revisit(rng, step_trace, state_diff, x)?;
+                vec_trace.data[t] = step_trace.data;
+                vec_trace.retv.as_mut().unwrap()[t] = step_trace.retv.unwrap();
+                total_weight += weight;
+                changed |= retdiff != ArgDiff::NoChange;
+                state_diff = retdiff;
+                outputs.push(output);
+            }
+            state = vec_trace.retv.as_ref().unwrap()[t].clone();
+        }
+        vec_trace.logjp = vec_trace.data.iter().map(|data| data.weight()).sum();
+        Ok((outputs, total_weight, if changed { ArgDiff::Unknown } else { ArgDiff::NoChange }))
+    }
 }
 
-///
+/// A `ParticleSystem` over the traces of a `DynUnfold`.
 pub type DynParticles<State> = ParticleSystem<State,Vec<DynTrie>,Vec<State>,DynUnfold<State>>;
 
 impl<State: Clone> GenFn<(i64,State),Vec<DynTrie>,Vec<State>> for DynUnfold<State> {
-    fn simulate(&self, final_t_and_args: (i64, State)) -> Trace<(i64,State),Vec<DynTrie>,Vec<State>> {
+    fn try_simulate(&self, rng: &mut dyn RngCore, final_t_and_args: (i64, State)) -> Result<Trace<(i64,State),Vec<DynTrie>,Vec<State>>,GenFnError> {
         let (final_t, mut state) = final_t_and_args;
-        assert!(final_t >= 1);
+        if final_t < 1 {
+            return Err(GenFnError::InvalidArgs(format!("final_t must be at least 1 (got {final_t})")));
+        }
         let mut vec_trace = Trace { args: (final_t, state.clone()), data: vec![], retv: Some(vec![]), logjp: 0. };
         for t in 0..final_t {
             let mut g = DynGenFnHandler::Simulate {
-                prng: &mut ThreadRng::default(),
-                trace: Trace { args: (t as i64, state.clone()), data: DynTrie::new(), retv: None, logjp: 0. },
+                prng: rng,
+                trace: Trace { args: (t, state.clone()), data: DynTrie::new(), retv: None, logjp: 0. },
+                error: None
             };
-            state = (self.kernel.func)(&mut g, (t as i64, state.clone()));
-            let DynGenFnHandler::Simulate {prng: _, trace} = g else { unreachable!() };
+            state = self.kernel.func.run(&mut g, (t, state.clone()))?;
+            let DynGenFnHandler::Simulate {prng: _, trace, error} = g else { unreachable!() };
+            if let Some(err) = error {
+                return Err(err);
+            }
             vec_trace.retv.as_mut().unwrap().push(state.clone());
+            vec_trace.logjp += trace.data.weight();
             vec_trace.data.push(trace.data);
-            vec_trace.logjp += trace.logjp;
         }
-        vec_trace
+        Ok(vec_trace)
     }
 
-    fn generate(&self, final_t_and_args: (i64, State), vec_constraints: Vec<DynTrie>) 
-        -> (Trace<(i64,State),Vec<DynTrie>,Vec<State>>, f64)
+    fn try_generate(&self, rng: &mut dyn RngCore, final_t_and_args: (i64, State), vec_constraints: Vec<DynTrie>) 
+        -> Result<(Trace<(i64,State),Vec<DynTrie>,Vec<State>>, f64),GenFnError>
     {
         let (final_t, mut state) = final_t_and_args;
-        assert!(final_t >= 1);
+        if final_t < 1 {
+            return Err(GenFnError::InvalidArgs(format!("final_t must be at least 1 (got {final_t})")));
+        }
         let mut vec_trace = Trace { args: (final_t, state.clone()), data: vec![], retv: Some(vec![]), logjp: 0. };
         let mut gen_weight = 0.;
         for (t,constraints) in vec_constraints.into_iter().enumerate() {
             let mut g = DynGenFnHandler::Generate {
-                prng: &mut ThreadRng::default(),
+                prng: rng,
                 trace: Trace { args: (t as i64, state.clone()), data: DynTrie::new(), retv: None, logjp: 0. },
                 weight: 0.,
-                constraints
+                constraints,
+                error: None
             };
-            state = (self.kernel.func)(&mut g, (t as i64, state.clone()));
-            let DynGenFnHandler::Generate {prng: _, trace, weight, constraints} = g else { unreachable!() };
-            assert!(constraints.is_empty());
+            state = self.kernel.func.run(&mut g, (t as i64, state.clone()))?;
+            let DynGenFnHandler::Generate {prng: _, trace, weight, constraints, error} = g else { unreachable!() };
+            if let Some(err) = error {
+                return Err(err);
+            }
+            if !constraints.is_empty() {
+                return Err(GenFnError::UnconsumedConstraints(constraints.schema().leaves()));
+            }
             vec_trace.retv.as_mut().unwrap().push(state.clone());
+            vec_trace.logjp += trace.data.weight();
             vec_trace.data.push(trace.data);
-            vec_trace.logjp += trace.logjp;
             gen_weight += weight;
         }
-        (vec_trace, gen_weight)
+        Ok((vec_trace, gen_weight))
     }
 
-    fn update(&self,
+    fn try_update(&self,
+        rng: &mut dyn RngCore,
         mut vec_trace: Trace<(i64,State),Vec<DynTrie>,Vec<State>>,
         final_t_and_args: (i64, State),
         diff: ArgDiff,
         vec_constraints: Vec<DynTrie>
-    ) -> (Trace<(i64,State),Vec<DynTrie>,Vec<State>>, Vec<DynTrie>, f64) {
+    ) -> Result<(Trace<(i64,State),Vec<DynTrie>,Vec<State>>, Vec<DynTrie>, f64, ArgDiff),GenFnError> {
         let (final_t, _) = final_t_and_args;
-        assert!(final_t >= 1);
+        if final_t < 1 {
+            return Err(GenFnError::InvalidArgs(format!("final_t must be at least 1 (got {final_t})")));
+        }
         let prev_t = vec_trace.args.0;
-        assert!(final_t - prev_t == vec_constraints.len() as i64);
+        if diff == ArgDiff::NoChange {
+            // constraints (and discards) are indexed by time step, and may revisit any past step
+            if final_t != prev_t {
+                return Err(GenFnError::InvalidArgs(format!("final_t changed from {prev_t} to {final_t} with ArgDiff::NoChange")));
+            }
+            if vec_constraints.len() as i64 > final_t {
+                return Err(GenFnError::InvalidArgs(format!("{} steps of constraints for {final_t} time steps", vec_constraints.len())));
+            }
+            let mut vec_constraints = vec_constraints.into_iter().map(Some).collect::<Vec<_>>();
+            let (discard, weight, retdiff) = self.revisit(rng, &mut vec_trace,
+                |t| vec_constraints.get_mut(t).and_then(|c| c.take()).filter(|c| !c.is_empty()),
+                |rng, step_trace, state_diff, constraints| {
+                    let args = step_trace.args.clone();
+                    self.kernel.try_update(rng, step_trace, args, state_diff, constraints.unwrap_or_default())
+                }
+            )?;
+            return Ok((vec_trace, discard, weight, retdiff));
+        }
         let mut state = vec_trace.retv.as_ref().unwrap().last().unwrap().clone();
         let mut update_weight = 0.;
         match diff {
             ArgDiff::Extend => {
+                if final_t - prev_t != vec_constraints.len() as i64 {
+                    return Err(GenFnError::InvalidArgs(format!("{} steps of constraints to extend from {prev_t} to {final_t} time steps", vec_constraints.len())));
+                }
                 for (t,constraints) in vec_constraints.into_iter().enumerate() {
                     let mut g = DynGenFnHandler::Generate {
-                        prng: &mut ThreadRng::default(),
+                        prng: rng,
                         trace: Trace { args: (prev_t + (t as i64), state.clone()), data: DynTrie::new(), retv: None, logjp: 0. },
                         weight: 0.,
-                        constraints
+                        constraints,
+                        error: None
                     };
-                    state = (self.kernel.func)(&mut g, (prev_t + (t as i64), state.clone()));
-                    let DynGenFnHandler::Generate {prng: _, trace, weight, constraints} = g else { unreachable!() };
-                    assert!(constraints.is_empty());
+                    state = self.kernel.func.run(&mut g, (prev_t + (t as i64), state.clone()))?;
+                    let DynGenFnHandler::Generate {prng: _, trace, weight, constraints, error} = g else { unreachable!() };
+                    if let Some(err) = error {
+                        return Err(err);
+                    }
+                    if !constraints.is_empty() {
+                        return Err(GenFnError::UnconsumedConstraints(constraints.schema().leaves()));
+                    }
                     vec_trace.args.0 += 1;
                     vec_trace.retv.as_mut().unwrap().push(state.clone());
+                    vec_trace.logjp += trace.data.weight();
                     vec_trace.data.push(trace.data);
-                    vec_trace.logjp += trace.logjp;
                     update_weight += weight;
                 }
             },
-            _ => { panic!("Can't handle GF change type: {:?}", diff) },
+            _ => { return Err(GenFnError::UnsupportedArgDiff(diff)) },
         }
-        (vec_trace, (prev_t..final_t).map(|_| DynTrie::new()).collect::<_>(), update_weight)
+        Ok((vec_trace, (prev_t..final_t).map(|_| DynTrie::new()).collect::<_>(), update_weight, ArgDiff::Extend))
     }
-}
\ No newline at end of file
+
+    /// Regenerates the choices of each step `t` selected by the submask at `t` of the `mask`
+    /// (eg. `mask.visit("3 / x")` selects `"x"` at step 3), or every choice if `mask` is a leaf.
+    /// Only `ArgDiff::NoChange` is supported.
+    fn try_regenerate(&self,
+        rng: &mut dyn RngCore,
+        mut vec_trace: Trace<(i64,State),Vec<DynTrie>,Vec<State>>,
+        _final_t_and_args: (i64, State),
+        diff: ArgDiff,
+        mask: &AddrMap
+    ) -> Result<(Trace<(i64,State),Vec<DynTrie>,Vec<State>>, f64, ArgDiff),GenFnError> {
+        if diff != ArgDiff::NoChange {
+            return Err(GenFnError::UnsupportedArgDiff(diff));
+        }
+        let (_, weight, retdiff) = self.revisit(rng, &mut vec_trace,
+            |t| if mask.is_leaf() { Some(mask) } else { mask.search(t) },
+            |rng, step_trace, state_diff, submask| {
+                let args = step_trace.args.clone();
+                match submask {
+                    Some(submask) => {
+                        let (step_trace, weight, retdiff) = self.kernel.try_regenerate(rng, step_trace, args, state_diff, submask)?;
+                        Ok((step_trace, (), weight, retdiff))
+                    },
+                    None => {
+                        let (step_trace, _, weight, retdiff) = self.kernel.try_update(rng, step_trace, args, state_diff, DynTrie::new())?;
+                        Ok((step_trace, (), weight, retdiff))
+                    }
+                }
+            }
+        )?;
+        Ok((vec_trace, weight, retdiff))
+    }
+}
@@ -1,8 +1,8 @@
-///
+/// Probability distributions implementing `Distribution`.
 pub mod dists;
 
-/// 
+/// Dynamically-typed generative functions (`DynGenFn`) and their handler.
 pub mod dyngenfn;
 
-///
+/// Unfold combinator over `DynGenFn` kernels (`DynUnfold`).
 pub mod dynunfold;
\ No newline at end of file
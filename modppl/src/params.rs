@@ -0,0 +1,180 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc,RwLock};
+use nalgebra::DVector;
+use crate::{Addr,Differentiable,GenFnError,Var};
+use crate::ad::{self,Adjoints};
+
+
+/// Type-erased `Differentiable` value of a parameter.
+trait ParamValue: Send + Sync {
+    fn coords(&self) -> Vec<f64>;
+    fn set_coords(&mut self, coords: &[f64]);
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<V: Differentiable + Send + Sync + 'static> ParamValue for V {
+    fn coords(&self) -> Vec<f64> {
+        Differentiable::coords(self)
+    }
+
+    fn set_coords(&mut self, coords: &[f64]) {
+        *self = self.with_coords(coords);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct Param {
+    value: Box<dyn ParamValue>,
+    grad: Vec<f64>
+}
+
+/// A store of named trainable parameters, and the gradients accumulated for them.
+///
+/// A `ParamStore` is a shared handle: clones refer to the same parameters, so a store can be passed
+/// to a generative function in its arguments (and read during its execution), while an `Optimizer` updates it.
+/// Parameters can have any `Differentiable` type (eg. `f64` or `DVector<f64>`), and a generative function
+/// reads them with `var` or `vars` so that `accumulate_param_gradients` can take gradients with respect to them.
+#[derive(Clone,Default)]
+pub struct ParamStore(Arc<RwLock<HashMap<Addr,Param>>>);
+
+impl ParamStore {
+    /// Construct an empty `ParamStore`.
+    pub fn new() -> Self {
+        ParamStore::default()
+    }
+
+    /// Initialize the parameter `name` to `value`, with a zero gradient.
+    ///
+    /// Replaces any previous parameter with the same `name`.
+    pub fn init<V: Differentiable + Send + Sync + 'static>(&self, name: impl Into<Addr>, value: V) {
+        let grad = vec![0.; value.coords().len()];
+        self.0.write().unwrap().insert(name.into(), Param { value: Box::new(value), grad });
+    }
+
+    /// Return `true` if the store holds no parameters, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Return the names of all parameters in the store.
+    pub fn names(&self) -> Vec<Addr> {
+        self.0.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Return the value of the parameter `name`.
+    ///
+    /// Returns an error if there is no such parameter, or if it isn't of type `V`.
+    pub fn try_get<V: Clone + 'static>(&self, name: impl Into<Addr>) -> Result<V,GenFnError> {
+        let name = name.into();
+        let params = self.0.read().unwrap();
+        let param = params.get(&name).ok_or_else(|| GenFnError::AddressNotFound(name.to_string()))?;
+        param.value.as_any()
+            .downcast_ref::<V>()
+            .cloned()
+            .ok_or_else(|| GenFnError::TypeMismatch { addr: name.to_string(), expected: std::any::type_name::<V>() })
+    }
+
+    /// Like `try_get`, but panics on error.
+    pub fn get<V: Clone + 'static>(&self, name: impl Into<Addr>) -> V {
+        self.try_get(name).unwrap_or_else(|e| panic!("get: {e}"))
+    }
+
+    /// Return the value of the `f64` parameter `name` as a `Var`, recorded on the active tape.
+    ///
+    /// Returns an error if there is no such parameter, or if it isn't of type `f64`.
+    pub fn try_var(&self, name: impl Into<Addr>) -> Result<Var,GenFnError> {
+        let name = name.into();
+        let value = self.try_get::<f64>(&name)?;
+        Ok(ad::param_leaves(self.id(), &name, &[value])[0])
+    }
+
+    /// Like `try_var`, but panics on error.
+    pub fn var(&self, name: impl Into<Addr>) -> Var {
+        self.try_var(name).unwrap_or_else(|e| panic!("var: {e}"))
+    }
+
+    /// Return the value of the `DVector<f64>` parameter `name` as a vector of `Var`s, recorded on the active tape.
+    ///
+    /// Returns an error if there is no such parameter, or if it isn't of type `DVector<f64>`.
+    pub fn try_vars(&self, name: impl Into<Addr>) -> Result<DVector<Var>,GenFnError> {
+        let name = name.into();
+        let value = self.try_get::<DVector<f64>>(&name)?;
+        Ok(DVector::from_vec(ad::param_leaves(self.id(), &name, value.as_slice())))
+    }
+
+    /// Like `try_vars`, but panics on error.
+    pub fn vars(&self, name: impl Into<Addr>) -> DVector<Var> {
+        self.try_vars(name).unwrap_or_else(|e| panic!("vars: {e}"))
+    }
+
+    /// Return the gradient accumulated for the parameter `name`, with the same type as its value.
+    ///
+    /// Returns an error if there is no such parameter, or if it isn't of type `V`.
+    pub fn try_grad<V: Differentiable + 'static>(&self, name: impl Into<Addr>) -> Result<V,GenFnError> {
+        let name = name.into();
+        let value = self.try_get::<V>(&name)?;
+        Ok(value.with_coords(&self.grad_coords(&name)?))
+    }
+
+    /// Like `try_grad`, but panics on error.
+    pub fn grad<V: Differentiable + 'static>(&self, name: impl Into<Addr>) -> V {
+        self.try_grad(name).unwrap_or_else(|e| panic!("grad: {e}"))
+    }
+
+    /// Add `scale * grad` to the gradient accumulated for the parameter `name`.
+    ///
+    /// Returns an error if there is no such parameter.
+    pub fn accumulate_grad<V: Differentiable>(&self, name: impl Into<Addr>, grad: &V, scale: f64) -> Result<(),GenFnError> {
+        self.accumulate_grad_coords(&name.into(), &grad.coords(), scale)
+    }
+
+    /// Reset the gradients of all parameters to zero.
+    pub fn zero_grads(&self) {
+        for param in self.0.write().unwrap().values_mut() {
+            param.grad.iter_mut().for_each(|g| *g = 0.);
+        }
+    }
+
+    /// Add `scale` times the gradient with respect to each parameter read (with `var` or `vars`) during a recorded computation.
+    pub(crate) fn accumulate_adjoints(&self, adjoints: &Adjoints, scale: f64) -> Result<(),GenFnError> {
+        for (name, grad) in adjoints.params(self.id()) {
+            self.accumulate_grad_coords(name, &grad, scale)?;
+        }
+        Ok(())
+    }
+
+    /// Identity of the shared parameters, which clones of the store have in common.
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as usize
+    }
+
+    pub(crate) fn coords(&self, name: &Addr) -> Result<Vec<f64>,GenFnError> {
+        let params = self.0.read().unwrap();
+        let param = params.get(name).ok_or_else(|| GenFnError::AddressNotFound(name.to_string()))?;
+        Ok(param.value.coords())
+    }
+
+    pub(crate) fn set_coords(&self, name: &Addr, coords: &[f64]) -> Result<(),GenFnError> {
+        let mut params = self.0.write().unwrap();
+        let param = params.get_mut(name).ok_or_else(|| GenFnError::AddressNotFound(name.to_string()))?;
+        param.value.set_coords(coords);
+        Ok(())
+    }
+
+    pub(crate) fn grad_coords(&self, name: &Addr) -> Result<Vec<f64>,GenFnError> {
+        let params = self.0.read().unwrap();
+        let param = params.get(name).ok_or_else(|| GenFnError::AddressNotFound(name.to_string()))?;
+        Ok(param.grad.clone())
+    }
+
+    pub(crate) fn accumulate_grad_coords(&self, name: &Addr, grad: &[f64], scale: f64) -> Result<(),GenFnError> {
+        let mut params = self.0.write().unwrap();
+        let param = params.get_mut(name).ok_or_else(|| GenFnError::AddressNotFound(name.to_string()))?;
+        param.grad.iter_mut().zip(grad).for_each(|(g, dg)| *g += scale * dg);
+        Ok(())
+    }
+}
@@ -1,15 +1,27 @@
-pub use rand::rngs::ThreadRng;
+pub use rand::{RngCore,SeedableRng,rngs::{ThreadRng,StdRng}};
 pub use std::sync::{Arc,Weak};
 pub use std::any::Any;
 
 pub use crate::{modeling::dists::*,
-    Trace,GenFn, ArgDiff,
-    AddrMap,
+    Trace,GenFn, ArgDiff, Differentiable, Merge, GenFnError, ParamStore, Var, ad,
+    Addr, AddrKey, AddrMap,
     Trie,
-    DynTrie,DynTrace,DynGenFn,DynGenFnHandler,
+    DynTrie,DynTrace,DynGenFn,DynFn,DynFnArc,DynGenFnHandler,
     importance_sampling,importance_resampling,
+    importance_sampling_with_proposal,importance_resampling_with_proposal,
     metropolis_hastings,mh,
     regenerative_metropolis_hastings, regen_mh,
-    ParticleSystem,DynUnfold,DynParticles
+    AdaptiveMh,
+    involutive_mh, log_abs_det_jacobian,
+    hamiltonian_monte_carlo, hmc, Nuts,
+    elliptical_slice, enumerative_gibbs,
+    Chain, McmcSamples, run_mcmc,
+    Kernel, AcceptStats, Identity, kernel_fn, RegenMh, Mh, InvolutiveMh, Cycle, Mixture, Conditional,
+    autocorrelation, split_rhat, rhat, split_ess, ess_bulk, ess_tail, mcse_mean,
+    ParticleSystem,ResamplingScheme,smc,SmcSummary,conditional_smc,particle_gibbs,backward_simulation,DynUnfold,DynParticles,
+    tempered_smc,annealed_importance_sampling,tempered_mh,tempered_regen_mh,
+    Optimizer,Sgd,Adam
 };
-pub use modppl_macros::dyngen;
\ No newline at end of file
+pub use modppl_macros::dyngen;
+#[cfg(feature = "parallel")]
+pub use crate::{rng_streams,par_importance_sampling,par_importance_resampling,par_mcmc};
\ No newline at end of file
@@ -1,11 +1,12 @@
+use std::slice;
 use std::collections::{HashMap, hash_map};
-use crate::{SplitAddr::{self,Prefix,Term}, AddrMap};
+use crate::{Addr, AddrKey, AddrMap, GenFnError, Merge};
 
 
 /// Weighted Digital Trie
 #[derive(Debug,Clone,PartialEq)]
 pub struct Trie<V> {
-    mapping: HashMap<String,Trie<V>>,
+    mapping: HashMap<AddrKey,Trie<V>>,
     value: Option<V>,
     weight: f64
 }
@@ -27,7 +28,7 @@ impl<V> Trie<V> {
         Trie {
             mapping: HashMap::new(),
             value: Some(value),
-            weight: weight
+            weight
         }
     }
 
@@ -67,148 +68,166 @@ impl<V> Trie<V> {
     }
 
     /// Iterate through the _direct_ descendants of `self`.
-    pub fn iter(&self) -> hash_map::Iter<'_, String, Trie<V>> {
+    pub fn iter(&self) -> hash_map::Iter<'_, AddrKey, Trie<V>> {
         self.mapping.iter()
     }
 
     /// Iterate mutably through the _direct_ descendants of `self`.
-    pub fn iter_mut(&mut self) -> hash_map::IterMut<'_, String, Trie<V>> {
+    pub fn iter_mut(&mut self) -> hash_map::IterMut<'_, AddrKey, Trie<V>> {
         self.mapping.iter_mut()
     }
 
-    /// Move `self` into an iterator over the _direct_ descendants of `self`.
-    pub fn into_iter(self) -> hash_map::IntoIter<String, Trie<V>> {
-        self.mapping.into_iter()
-    }
-
     /// Return the sum of the weight of all descendants.
     pub fn weight(&self) -> f64 {
         self.weight
     }
 
     /// Return some reference to a descendant at `addr` if present, otherwise none.
-    pub fn search(&self, addr: &str) -> Option<&Trie<V>> {
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                self.mapping.get(addr)
-            }
-            Prefix(first, rest) => {
-                self.mapping[first].search(rest)
-            }
+    pub fn search(&self, addr: impl Into<Addr>) -> Option<&Trie<V>> {
+        self.search_keys(addr.into().keys())
+    }
+
+    pub(crate) fn search_keys(&self, keys: &[AddrKey]) -> Option<&Trie<V>> {
+        let (first, rest) = keys.split_first()?;
+        let sub = self.mapping.get(first)?;
+        if rest.is_empty() {
+            Some(sub)
+        } else {
+            sub.search_keys(rest)
         }
     }
 
     /// Observe an unweighted `value` at `addr`. Panic if `addr` is occupied.
-    pub fn observe(&mut self, addr: &str, value: V) {
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                if self.mapping.contains_key(addr) {
-                    panic!("observe: attempted to put into occupied address \"{addr}\"");
-                } else {
-                    self.mapping.insert(addr.to_string(), Trie::leaf(value, 0.0));
-                }
-            }
-            Prefix(first, rest) => {
-                let submap = self.mapping
-                    .entry(first.to_string())
-                    .or_insert(Trie::new());
-                submap.observe(rest, value)
-            }
-        }
+    pub fn observe(&mut self, addr: impl Into<Addr>, value: V) {
+        self.try_observe(addr, value).unwrap_or_else(|e| panic!("observe: {e}"))
     }
 
     /// Observe a weighted `value` at `addr`, summing the weight by `weight`. Panic if `addr` is occupied.
-    pub fn w_observe(&mut self, addr: &str, value: V, weight: f64) { 
-        self.weight += weight;
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                if self.mapping.contains_key(addr) {
-                    panic!("w_observe: attempted to put into occupied address \"{addr}\"");
-                } else {
-                    self.mapping.insert(addr.to_string(), Trie::leaf(value, weight));
-                }
-            }
-            Prefix(first, rest) => {
-                let submap = self.mapping
-                    .entry(first.to_string())
-                    .or_insert(Trie::new());
-                submap.w_observe(rest, value, weight)
-            }
-        }
+    pub fn w_observe(&mut self, addr: impl Into<Addr>, value: V, weight: f64) { 
+        self.try_w_observe(addr, value, weight).unwrap_or_else(|e| panic!("w_observe: {e}"))
     }
 
     /// Insert a descendant `sub` at `addr`. Panic if `addr` is occupied.
-    pub fn insert(&mut self, addr: &str, sub: Trie<V>) {
-        self.weight += sub.weight;
-        match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                if self.mapping.contains_key(addr) {
-                    panic!("insert: attempted to put into occupied address \"{addr}\"");
-                } else {
-                    self.mapping.insert(addr.to_string(), sub);
-                }
-            }
-            Prefix(first, rest) => {
-                let submap = self.mapping
-                    .entry(first.to_string())
-                    .or_insert(Trie::new());
-                submap.insert(rest, sub)
+    pub fn insert(&mut self, addr: impl Into<Addr>, sub: Trie<V>) {
+        self.try_insert(addr, sub).unwrap_or_else(|e| panic!("insert: {e}"))
+    }
+
+    /// Observe an unweighted `value` at `addr`. Return an `AddressCollision` error if `addr` is occupied.
+    pub fn try_observe(&mut self, addr: impl Into<Addr>, value: V) -> Result<(), GenFnError> {
+        self.try_insert(addr, Trie::leaf(value, 0.))
+    }
+
+    /// Observe a weighted `value` at `addr`, summing the weight by `weight`.
+    /// Return an `AddressCollision` error (leaving `self` unchanged) if `addr` is occupied.
+    pub fn try_w_observe(&mut self, addr: impl Into<Addr>, value: V, weight: f64) -> Result<(), GenFnError> {
+        self.try_insert(addr, Trie::leaf(value, weight))
+    }
+
+    /// Insert a descendant `sub` at `addr`.
+    /// Return an `AddressCollision` error (leaving `self` unchanged) if `addr` is occupied.
+    pub fn try_insert(&mut self, addr: impl Into<Addr>, sub: Trie<V>) -> Result<(), GenFnError> {
+        let addr = addr.into();
+        if self.put(addr.keys(), sub) {
+            Ok(())
+        } else {
+            Err(GenFnError::AddressCollision(addr.to_string()))
+        }
+    }
+
+    fn put(&mut self, keys: &[AddrKey], sub: Trie<V>) -> bool {
+        let Some((first, rest)) = keys.split_first() else { return false };
+        let weight = sub.weight;
+        let inserted = if rest.is_empty() {
+            if self.mapping.contains_key(first) {
+                false
+            } else {
+                self.mapping.insert(first.clone(), sub);
+                true
             }
+        } else {
+            self.mapping
+                .entry(first.clone())
+                .or_default()
+                .put(rest, sub)
+        };
+        if inserted {
+            self.weight += weight;
         }
+        inserted
     }
 
     /// Return a descendant at `addr` if present (removing it), otherwise just return none.
-    pub fn remove(&mut self, addr: &str) -> Option<Trie<V>> {
-        if let Some(sub) = match SplitAddr::from_addr(addr) {
-            Term(addr) => {
-                self.mapping.remove(addr)
+    pub fn remove(&mut self, addr: impl Into<Addr>) -> Option<Trie<V>> {
+        self.remove_keys(addr.into().keys())
+    }
+
+    pub(crate) fn remove_keys(&mut self, keys: &[AddrKey]) -> Option<Trie<V>> {
+        let (first, rest) = keys.split_first()?;
+        let sub = if rest.is_empty() {
+            self.mapping.remove(first)?
+        } else {
+            let node = self.mapping.get_mut(first)?;
+            let leaf = node.remove_keys(rest)?;
+            if node.is_empty() {
+                self.mapping.remove(first);
             }
-            Prefix(first, rest) => {
-                match self.mapping.get_mut(first) {
-                    Some(node) => {
-                        let leaf = node.remove(rest);
-                        if node.is_empty() {
-                            self.remove(first);
-                        }
-                        leaf
+            leaf
+        };
+        self.weight -= sub.weight;
+        Some(sub)
+    }
+
+    /// Merge an `other` Trie into `self`, preferentially using the values (and weights) of `other` at overlapping addresses.
+    /// 
+    /// Use `Merge::try_merge` instead to return an `AddressCollision` error at overlapping addresses.
+    pub fn merge(&mut self, other: Self) {
+        for (key, othersub) in other.mapping.into_iter() {
+            match self.mapping.get_mut(&key) {
+                Some(sub) if !sub.is_leaf() && !othersub.is_leaf() => {
+                    let weight = sub.weight;
+                    sub.merge(othersub);
+                    self.weight += sub.weight - weight;
+                }
+                _ => {
+                    self.weight += othersub.weight;
+                    if let Some(sub) = self.mapping.insert(key, othersub) {
+                        self.weight -= sub.weight;
                     }
-                    None => { None }
                 }
             }
-        } {
-            self.weight -= sub.weight;
-            Some(sub)
-        } else {
-            None
         }
     }
 
-    /// Merge an `other` Trie into `self`, preferentially using the values of `other` at overlapping addresses.
-    pub fn merge(&mut self, other: Self) {
-        for (addr, othersub) in other.into_iter() {
-            if othersub.is_leaf() {
-                self.w_observe(&addr, othersub.value.unwrap(), othersub.weight);
-            } else {
-                match self.mapping.get_mut(&addr) {
-                    Some(sub) => {
-                        sub.merge(othersub);
-                    }
-                    None => {
-                        self.insert(&addr, othersub);
-                    }
+    fn try_merge_at(&mut self, other: Self, addr: &mut Addr) -> Result<(), GenFnError> {
+        for (key, othersub) in other.mapping.into_iter() {
+            addr.push(key.clone());
+            match self.mapping.get_mut(&key) {
+                Some(sub) if !sub.is_leaf() && !othersub.is_leaf() => {
+                    let weight = othersub.weight;
+                    sub.try_merge_at(othersub, addr)?;
+                    self.weight += weight;
+                }
+                Some(_) => {
+                    return Err(GenFnError::AddressCollision(addr.to_string()));
+                }
+                None => {
+                    self.weight += othersub.weight;
+                    self.mapping.insert(key, othersub);
                 }
             }
+            addr.pop();
         }
+        Ok(())
     }
 
     /// Return an `AddrMap` representing the address schema of `self`.
     pub fn schema(&self) -> AddrMap {
         let mut amap = AddrMap::new();
-        for (addr, subtrie) in self.iter() {
+        for (key, subtrie) in self.iter() {
             if subtrie.is_leaf() {
-                amap.visit(addr);
+                amap.visit_keys(slice::from_ref(key));
             } else {
-                amap.insert(addr, subtrie.schema());
+                amap.insert(key, subtrie.schema());
             }
         }
         amap
@@ -216,10 +235,11 @@ impl<V> Trie<V> {
 
     /// Collect the set of values identified by `mask` into a new `Trie`,
     /// leaving values in `self` that are in the complement of `mask`.
+    /// Addresses of `mask` that `self` doesn't hold are skipped.
     /// 
     /// Return the new `self`, the collected value trie, and the weight of the collected value trie.
     pub fn collect(
-        mut self: Self,
+        mut self,
         mask: &AddrMap
     ) -> (Self,Self,f64) {
         let mut collected = Trie::new();
@@ -227,17 +247,18 @@ impl<V> Trie<V> {
             let weight = self.weight();
             return (collected, self, weight);
         } else if !mask.is_leaf() {
-            for (addr, submask) in mask.iter() {
-                let Some(sub) = self.remove(addr) else { unreachable!() };
+            for (key, submask) in mask.iter() {
+                let keys = slice::from_ref(key);
+                let Some(sub) = self.remove_keys(keys) else { continue; };
                 if submask.is_leaf() {
-                    collected.insert(addr, sub);
+                    collected.put(keys, sub);
                 } else {
                     let (sub, subcollected, _) = sub.collect(submask);
                     if !sub.is_empty() {
-                        self.insert(addr, sub);
+                        self.put(keys, sub);
                     }
                     if !subcollected.is_empty() {
-                        collected.insert(addr, subcollected);
+                        collected.put(keys, subcollected);
                     }
                 }
             }
@@ -246,4 +267,28 @@ impl<V> Trie<V> {
         (self, collected, weight)
     }
 
-}
\ No newline at end of file
+}
+
+impl<V> Default for Trie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> IntoIterator for Trie<V> {
+    type Item = (AddrKey, Trie<V>);
+    type IntoIter = hash_map::IntoIter<AddrKey, Trie<V>>;
+
+    /// Move `self` into an iterator over the _direct_ descendants of `self`.
+    fn into_iter(self) -> Self::IntoIter {
+        self.mapping.into_iter()
+    }
+}
+
+impl<V> Merge for Trie<V> {
+    /// The inner value of `other` (eg. the return value of a proposal) is dropped.
+    fn try_merge(mut self, other: Self) -> Result<Self, GenFnError> {
+        self.try_merge_at(other, &mut Addr::new())?;
+        Ok(self)
+    }
+}
@@ -0,0 +1,163 @@
+#![allow(non_upper_case_globals)]
+
+use nalgebra::{DVector,DMatrix,dvector,dmatrix};
+use modppl::prelude::*;
+
+
+fn x_cov() -> DMatrix<f64> {
+    dmatrix![4., 1.8; 1.8, 1.]
+}
+
+// a strongly correlated (and poorly scaled) gaussian over `x`, an independent `s`, and a discrete `flag`
+dyngen!(
+fn correlated_model() {
+    bernoulli(0.5) %= "flag";
+    mvnormal(dvector![1., -1.], x_cov()) %= "x";
+    normal(2., 3.) %= "s";
+});
+
+fn selection() -> AddrMap {
+    let mut selection = AddrMap::new();
+    selection.visit("flag");
+    selection.visit("x");
+    selection.visit("s");
+    selection
+}
+
+// the selected coordinates, in the order of their addresses
+fn coords(trace: &DynTrace<(),()>) -> DVector<f64> {
+    let x = trace.data.read::<DVector<f64>>("x");
+    dvector![trace.data.read::<f64>("s"), x[0], x[1]]
+}
+
+// run `sampler` through its warmup, then return its samples and acceptance rate over `num_samples` more steps
+fn run(rng: &mut StdRng, sampler: &mut AdaptiveMh, num_samples: usize) -> (Vec<DVector<f64>>, f64) {
+    let selection = selection();
+    let mut trace = correlated_model.simulate(rng, ());
+    let flag = trace.data.read::<bool>("flag");
+    while sampler.is_warmup() {
+        (trace, _) = sampler.step(rng, &correlated_model, trace, &selection).unwrap();
+    }
+    let scale = sampler.scale;
+    let cov = sampler.cov.clone();
+    let mut samples = vec![];
+    let mut num_accepted = 0;
+    for _ in 0..num_samples {
+        let accepted;
+        (trace, accepted) = sampler.step(rng, &correlated_model, trace, &selection).unwrap();
+        num_accepted += accepted as usize;
+        samples.push(coords(&trace));
+    }
+
+    // the adaptation is frozen after warmup, and discrete choices are left unchanged
+    assert_eq!(sampler.scale, scale);
+    assert_eq!(sampler.cov, cov);
+    assert_eq!(trace.data.read::<bool>("flag"), flag);
+    (samples, num_accepted as f64 / num_samples as f64)
+}
+
+fn mean(samples: &[DVector<f64>]) -> DVector<f64> {
+    samples.iter().sum::<DVector<f64>>() / samples.len() as f64
+}
+
+
+#[test]
+fn test_adaptive_mh_scale() {
+    let mut rng = StdRng::seed_from_u64(110);
+    let mut sampler = AdaptiveMh::new(5000);
+    assert!(sampler.is_warmup());
+    let (samples, acceptance_rate) = run(&mut rng, &mut sampler, 20000);
+    assert!(!sampler.is_warmup());
+    assert!(sampler.scale.unwrap() != 2.38 / 3_f64.sqrt());
+    assert!(sampler.cov.is_none());
+    approx::assert_abs_diff_eq!(acceptance_rate, sampler.target_accept, epsilon = 0.05);
+
+    let mean = mean(&samples);
+    approx::assert_abs_diff_eq!(mean, dvector![2., 1., -1.], epsilon = 0.3);
+}
+
+#[test]
+fn test_adaptive_mh_covariance() {
+    let mut rng = StdRng::seed_from_u64(111);
+    let mut sampler = AdaptiveMh::new(5000).with_covariance_adaptation();
+    let (samples, acceptance_rate) = run(&mut rng, &mut sampler, 20000);
+    approx::assert_abs_diff_eq!(acceptance_rate, sampler.target_accept, epsilon = 0.05);
+
+    // the adapted covariance matches the target's
+    let mut target_cov = DMatrix::zeros(3, 3);
+    target_cov[(0, 0)] = 9.;
+    target_cov.view_mut((1, 1), (2, 2)).copy_from(&x_cov());
+    let cov = sampler.cov.clone().unwrap();
+    let correlation = |cov: &DMatrix<f64>, i: usize, j: usize| cov[(i, j)] / (cov[(i, i)] * cov[(j, j)]).sqrt();
+    for i in 0..3 {
+        approx::assert_relative_eq!(cov[(i, i)], target_cov[(i, i)], max_relative = 0.2);
+        for j in 0..i {
+            approx::assert_abs_diff_eq!(correlation(&cov, i, j), correlation(&target_cov, i, j), epsilon = 0.1);
+        }
+    }
+
+    let mean = mean(&samples);
+    approx::assert_abs_diff_eq!(mean, dvector![2., 1., -1.], epsilon = 0.3);
+
+    // the adapted proposal mixes better than the identity with the same acceptance rate
+    let mut identity_sampler = AdaptiveMh::new(5000);
+    let (identity_samples, _) = run(&mut rng, &mut identity_sampler, 20000);
+    let lag1_autocorrelation = |samples: &[DVector<f64>]| autocorrelation(&samples.iter().map(|q| q[1]).collect::<Vec<f64>>(), 1)[1];
+    assert!(lag1_autocorrelation(&samples) < lag1_autocorrelation(&identity_samples));
+}
+
+// `b` is only sampled when `flag` is set
+dyngen!(
+fn branching_model() {
+    if bernoulli(0.5) %= "flag" {
+        normal(0., 1.) %= "b";
+    }
+    normal(0., 1.) %= "a";
+});
+
+#[test]
+fn test_adaptive_mh_missing_address() {
+    let mut rng = StdRng::seed_from_u64(112);
+    let mut constraints = DynTrie::new();
+    constraints.observe("flag", std::sync::Arc::new(false));
+    let (mut trace, _) = branching_model.generate(&mut rng, (), constraints);
+
+    // selected addresses that aren't in the trace are skipped
+    let mut selection = AddrMap::new();
+    selection.visit("a");
+    selection.visit("b");
+    let mut sampler = AdaptiveMh::new(100);
+    let mut num_accepted = 0;
+    for _ in 0..200 {
+        let accepted;
+        (trace, accepted) = sampler.step(&mut rng, &branching_model, trace, &selection).unwrap();
+        num_accepted += accepted as usize;
+        assert!(trace.data.search("b").is_none());
+    }
+    assert!(num_accepted > 0);
+
+    // with nothing to update, the step is a rejection that still counts towards warmup
+    let mut selection = AddrMap::new();
+    selection.visit("b");
+    let mut sampler = AdaptiveMh::new(10);
+    for _ in 0..10 {
+        let accepted;
+        (trace, accepted) = sampler.step(&mut rng, &branching_model, trace, &selection).unwrap();
+        assert!(!accepted);
+    }
+    assert!(!sampler.is_warmup());
+}
+
+#[test]
+fn test_adaptive_mh_singular_covariance() {
+    let mut rng = StdRng::seed_from_u64(113);
+    let (trace, _) = branching_model.generate(&mut rng, (), DynTrie::new());
+    let mut selection = AddrMap::new();
+    selection.visit("a");
+    let mut sampler = AdaptiveMh::new(10);
+    sampler.cov = Some(dmatrix![0.]);
+    assert_eq!(
+        sampler.step(&mut rng, &branching_model, trace, &selection).err(),
+        Some(GenFnError::InvalidArgs("`cov` must be positive definite".to_string()))
+    );
+}
@@ -0,0 +1,146 @@
+#![allow(non_upper_case_globals)]
+
+use modppl::prelude::*;
+
+mod pointed_model;
+mod dyngenfns;
+use dyngenfns::{gaussian_model, mu_drift_proposal, gaussian_observations};
+
+
+
+#[test]
+fn test_run_mcmc() {
+    let mut rng = StdRng::seed_from_u64(50);
+    let ys = [1.2, 0.4, 2.1, 1.5, 0.9];
+    let observations = gaussian_observations(&ys);
+    let (expected_mean, expected_var) = (ys.iter().sum::<f64>() / 6., 1. / 6.);
+
+    let mut mask = AddrMap::new();
+    mask.visit("mu");
+    let mut chain = Chain::new()
+        .with_kernel(kernel_fn(|rng, trace| regen_mh(rng, &gaussian_model, trace, &mask)))
+        .with_kernel(kernel_fn(|rng, trace| mh(rng, &gaussian_model, trace, &mu_drift_proposal, 0.5)))
+        .with_num_chains(4)
+        .with_warmup(100)
+        .with_thinning(2);
+    assert_eq!(chain.num_kernels(), 2);
+    assert_eq!(chain.num_chains(), 4);
+
+    let out = run_mcmc(&mut rng, &mut chain, 1000,
+        |rng| Ok(gaussian_model.try_generate(rng, ys.len(), observations.clone())?.0),
+        |trace| trace.data.read::<f64>("mu")
+    ).unwrap();
+    assert_eq!(out.samples.len(), 4);
+    assert!(out.samples.iter().all(|samples| samples.len() == 1000));
+    assert_eq!(out.traces.len(), 4);
+    assert_eq!(out.samples[2][999], out.traces[2].data.read::<f64>("mu"));
+
+    // the chains are independent
+    assert_ne!(out.samples[0][0], out.samples[1][0]);
+
+    // the prior proposal is accepted less often than the drift proposal
+    let rates = out.mean_acceptance_rates();
+    assert_eq!(rates.len(), 2);
+    assert!(out.accept_stats.iter().flatten().all(|stats| stats.num_proposed == 2000 && stats.num_accepted > 0 && stats.num_accepted < 2000));
+    assert!(rates[0] < rates[1]);
+
+    let pooled = out.pooled();
+    assert_eq!(pooled.len(), 4000);
+    let mean = pooled.iter().copied().sum::<f64>() / 4000.;
+    let var = pooled.iter().map(|mu| (*mu - mean).powi(2)).sum::<f64>() / 4000.;
+    approx::assert_abs_diff_eq!(mean, expected_mean, epsilon = 0.05);
+    approx::assert_abs_diff_eq!(var, expected_var, epsilon = 0.03);
+}
+
+#[test]
+fn test_run_mcmc_errors() {
+    let mut rng = StdRng::seed_from_u64(51);
+    let observations = gaussian_observations(&[0.5]);
+
+    // the first error raised by a kernel stops the run
+    let mut num_calls = 0;
+    let mut chain = Chain::new()
+        .with_kernel(kernel_fn(|rng, trace| { num_calls += 1; mh(rng, &gaussian_model, trace, &mu_drift_proposal, 0.5) }))
+        .with_kernel(kernel_fn(|_, _| Err(GenFnError::Unimplemented("kernel"))));
+    let result = run_mcmc(&mut rng, &mut chain, 10,
+        |rng| Ok(gaussian_model.try_generate(rng, 1, observations.clone())?.0),
+        |_| ()
+    );
+    assert_eq!(result.err(), Some(GenFnError::Unimplemented("kernel")));
+    drop(chain);
+    assert_eq!(num_calls, 1);
+
+    // as is an error raised while initializing a chain
+    let mut chain = Chain::new().with_kernel(kernel_fn(|rng, trace| regen_mh(rng, &gaussian_model, trace, &AddrMap::new())));
+    let result = run_mcmc(&mut rng, &mut chain, 10,
+        |rng| Ok(gaussian_model.try_generate(rng, 0, observations.clone())?.0),
+        |_| ()
+    );
+    assert_eq!(result.err(), Some(GenFnError::UnconsumedConstraints(vec!["(y, 0)".to_string()])));
+
+    // as is a thinning interval of 0
+    let mut chain = Chain::new().with_kernel(drift_kernel).with_thinning(0);
+    let result = run_mcmc(&mut rng, &mut chain, 10,
+        |rng| Ok(gaussian_model.try_generate(rng, 1, observations.clone())?.0),
+        |_| ()
+    );
+    assert!(matches!(result.err(), Some(GenFnError::InvalidArgs(_))));
+}
+
+// a kernel function, usable as a `Kernel` without `kernel_fn`
+fn drift_kernel(rng: &mut dyn RngCore, trace: DynTrace<usize,()>) -> Result<(DynTrace<usize,()>,bool),GenFnError> {
+    mh(rng, &gaussian_model, trace, &mu_drift_proposal, 0.5)
+}
+
+#[test]
+fn test_run_mcmc_kernels() {
+    let mut rng = StdRng::seed_from_u64(52);
+    let observations = gaussian_observations(&[0.5, 1.5]);
+    let model = gaussian_model;
+    let mut mask = AddrMap::new();
+    mask.visit("mu");
+
+    // combined kernels report the statistics of all their moves
+    let mut chain = Chain::new()
+        .with_kernel(RegenMh::new(&model, mask).repeat(3))
+        .with_kernel(drift_kernel)
+        .with_num_chains(2)
+        .with_warmup(10);
+    let out = run_mcmc(&mut rng, &mut chain, 100,
+        |rng| Ok(model.try_generate(rng, 2, observations.clone())?.0),
+        |_| ()
+    ).unwrap();
+    for stats in &out.accept_stats {
+        assert_eq!(stats[0].num_proposed, 300);
+        assert_eq!(stats[1].num_proposed, 100);
+    }
+    let total = out.accept_stats.iter().map(|stats| stats[0]).fold(AcceptStats::default(), |a, b| a + b);
+    assert_eq!(out.mean_acceptance_rates()[0], total.acceptance_rate());
+}
+
+#[test]
+fn test_run_mcmc_kernel_factory() {
+    let mut rng = StdRng::seed_from_u64(53);
+    let observations = gaussian_observations(&[0.5]);
+
+    // a kernel with state (here, accepting its first 5 moves only) starts afresh in each chain
+    let mut num_made = 0;
+    let mut chain = Chain::new()
+        .with_kernel_factory(|| {
+            num_made += 1;
+            let mut num_steps = 0;
+            kernel_fn(move |_, trace| {
+                num_steps += 1;
+                Ok((trace, num_steps <= 5))
+            })
+        })
+        .with_num_chains(3)
+        .with_warmup(2);
+    let out = run_mcmc(&mut rng, &mut chain, 10,
+        |rng| Ok(gaussian_model.try_generate(rng, 1, observations.clone())?.0),
+        |_| ()
+    ).unwrap();
+    assert!(out.accept_stats.iter().all(|stats| stats[0].num_proposed == 10 && stats[0].num_accepted == 3));
+    drop(chain);
+    assert_eq!(num_made, 3);
+}
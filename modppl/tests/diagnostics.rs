@@ -0,0 +1,125 @@
+use modppl::prelude::*;
+
+
+// gaussian AR(1) chains with autocorrelation `phi` at lag 1 and unit stationary variance
+fn ar1_chains(rng: &mut StdRng, num_chains: usize, num_draws: usize, phi: f64, offsets: &[f64]) -> Vec<Vec<f64>> {
+    (0..num_chains).map(|c| {
+        let mut x = normal.random(rng, (0., 1.));
+        (0..num_draws).map(|_| {
+            x = phi * x + (1. - phi * phi).sqrt() * normal.random(rng, (0., 1.));
+            x + offsets[c]
+        }).collect()
+    }).collect()
+}
+
+#[test]
+fn test_autocorrelation() {
+    let mut rng = StdRng::seed_from_u64(60);
+    let chain = ar1_chains(&mut rng, 1, 20000, 0.8, &[0.]).remove(0);
+    let acf = autocorrelation(&chain, 5);
+    assert_eq!(acf.len(), 6);
+    assert_eq!(acf[0], 1.);
+    for (lag, rho) in acf.iter().enumerate() {
+        approx::assert_abs_diff_eq!(*rho, 0.8_f64.powi(lag as i32), epsilon = 0.05);
+    }
+    assert_eq!(autocorrelation(&chain[..3], 10).len(), 3);
+    assert!(autocorrelation(&chain[..1], 10).is_empty());
+    assert!(autocorrelation(&[], 10).is_empty());
+}
+
+#[test]
+fn test_degenerate_chains() {
+    assert!(autocorrelation(&[2.; 10], 5).is_empty());
+
+    // no chains, chains too short to split, and constant chains
+    let short = vec![vec![0.1, 0.5, 0.2]; 4];
+    let constant = vec![vec![1.; 100]; 4];
+    for chains in [vec![], short, constant] {
+        assert!(split_rhat(&chains).is_nan());
+        assert!(rhat(&chains).is_nan());
+        assert!(split_ess(&chains).is_nan());
+        assert!(ess_bulk(&chains).is_nan());
+        assert!(ess_tail(&chains).is_nan());
+        assert!(mcse_mean(&chains).is_nan());
+    }
+}
+
+#[test]
+fn test_mixed_chains() {
+    let mut rng = StdRng::seed_from_u64(61);
+    let (num_chains, num_draws) = (4, 2000);
+
+    // independent draws
+    let chains = ar1_chains(&mut rng, num_chains, num_draws, 0., &[0.; 4]);
+    let num_total = (num_chains * num_draws) as f64;
+    assert!(split_rhat(&chains) < 1.01);
+    assert!(rhat(&chains) < 1.01);
+    approx::assert_relative_eq!(split_ess(&chains), num_total, max_relative = 0.15);
+    approx::assert_relative_eq!(ess_bulk(&chains), num_total, max_relative = 0.15);
+    approx::assert_relative_eq!(ess_tail(&chains), num_total, max_relative = 0.2);
+    approx::assert_relative_eq!(mcse_mean(&chains), num_total.powf(-0.5), max_relative = 0.1);
+
+    // autocorrelated draws have an effective sample size of about N (1 - phi) / (1 + phi)
+    let chains = ar1_chains(&mut rng, num_chains, num_draws, 0.5, &[0.; 4]);
+    assert!(rhat(&chains) < 1.01);
+    approx::assert_relative_eq!(split_ess(&chains), num_total / 3., max_relative = 0.15);
+    approx::assert_relative_eq!(ess_bulk(&chains), num_total / 3., max_relative = 0.15);
+    approx::assert_relative_eq!(mcse_mean(&chains), (3. / num_total).sqrt(), max_relative = 0.1);
+}
+
+#[test]
+fn test_unmixed_chains() {
+    let mut rng = StdRng::seed_from_u64(62);
+
+    // chains stuck in different modes
+    let chains = ar1_chains(&mut rng, 4, 1000, 0.5, &[0., 0., 0., 1.]);
+    assert!(split_rhat(&chains) > 1.1);
+    assert!(rhat(&chains) > 1.1);
+    assert!(ess_bulk(&chains) < 0.2 * 4000.);
+
+    // a single chain drifting over time is caught by splitting it
+    let chain = ar1_chains(&mut rng, 1, 1000, 0.5, &[0.]).remove(0)
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| x + i as f64 / 250.)
+        .collect::<Vec<f64>>();
+    assert!(split_rhat(std::slice::from_ref(&chain)) > 1.1);
+
+    // chains with the same location but different scales are caught by the folded draws
+    let mut chains = ar1_chains(&mut rng, 4, 1000, 0., &[0.; 4]);
+    chains[3].iter_mut().for_each(|x| *x *= 3.);
+    assert!(split_rhat(&chains) < rhat(&chains));
+    assert!(rhat(&chains) > 1.05);
+}
+
+#[test]
+fn test_diagnostics_of_run_mcmc() {
+    let mut rng = StdRng::seed_from_u64(63);
+    let model = dyngen!(|n: usize| {
+        let mu = normal(0., 1.) %= "mu";
+        for i in 0..n {
+            normal(mu, 1.) %= ("y", i as i64);
+        }
+    });
+    let mut observations = DynTrie::new();
+    [0.8, 1.7, 1.1].iter().enumerate().for_each(|(i, y)| { observations.observe(("y", i as i64), Arc::new(*y)); });
+    let mut mask = AddrMap::new();
+    mask.visit("mu");
+
+    let mut chain = Chain::new()
+        .with_kernel(kernel_fn(|rng, trace| regen_mh(rng, &model, trace, &mask)))
+        .with_num_chains(4)
+        .with_warmup(100);
+    let out = run_mcmc(&mut rng, &mut chain, 1000,
+        |rng| Ok(model.try_generate(rng, 3, observations.clone())?.0),
+        |trace| trace.data.read::<f64>("mu")
+    ).unwrap();
+    assert!(rhat(&out.samples) < 1.01);
+
+    // rejected prior proposals repeat draws, so there are fewer effective samples than draws
+    let ess = ess_bulk(&out.samples);
+    assert!(ess > 200. && ess < 4000.);
+    let posterior_mean = 3.6 / 4.;
+    let mean = out.pooled().iter().copied().sum::<f64>() / 4000.;
+    assert!((mean - posterior_mean).abs() < 4. * mcse_mean(&out.samples));
+}
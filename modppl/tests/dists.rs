@@ -1,11 +1,14 @@
+#![allow(clippy::approx_constant)]
+#![allow(clippy::excessive_precision)]
+
 use std::collections::HashMap;
 use nalgebra::{dvector,dmatrix};
 
 use rand::rngs::ThreadRng;
-use approx;
-use modppl::{Distribution, bernoulli, uniform, uniform_discrete, categorical, normal, mvnormal, geometric, poisson, beta, gamma};
+use modppl::{Distribution, DifferentiableDistribution, bernoulli, uniform, uniform_discrete, categorical, normal, mvnormal, geometric, poisson, beta, gamma};
 
 const LOGPDF_EPSILON: f64 = f32::EPSILON as f64;
+const GRAD_EPSILON: f64 = 1e-5;
 
 fn mean(v: &[f64]) -> f64 {
     v.iter().sum::<f64>() / v.len() as f64
@@ -85,7 +88,7 @@ fn test_uniform() {
 #[test]
 fn test_categorical() {
     let mut rng = ThreadRng::default();
-    let labels = vec!["a", "b", "c", "d", "e", "f"];
+    let labels = ["a", "b", "c", "d", "e", "f"];
     let probs = vec![0.1, 0.3, 0.2, 0.1, 0.05, 0.25];
     let num_samples = 50000;
     let sample_indices = (0..num_samples).map(|_| categorical.random(&mut rng, probs.clone())).collect::<Vec<i64>>();
@@ -209,4 +212,51 @@ pub fn test_gamma() {
     approx::assert_abs_diff_eq!(-1.414334369005868, gamma.logpdf(&1.7, (1.23, 1.46)), epsilon = LOGPDF_EPSILON);
     approx::assert_abs_diff_eq!(-3.4049256003700052, gamma.logpdf(&8.4, (4.5, 1.0)), epsilon = LOGPDF_EPSILON);
     approx::assert_abs_diff_eq!(-528.8122715889206, gamma.logpdf(&0.03, (50.0, 70.0)), epsilon = LOGPDF_EPSILON);
-}
\ No newline at end of file
+}
+
+/// Central difference of `f` at `x`.
+fn finite_difference(f: impl Fn(f64) -> f64, x: f64) -> f64 {
+    let h = 1e-6;
+    (f(x + h) - f(x - h)) / (2.*h)
+}
+
+/// Check the gradients of a scalar distribution with two parameters against central differences of its `logpdf`.
+fn check_logpdf_grad(dist: &impl DifferentiableDistribution<f64,(f64,f64)>, x: f64, (a, b): (f64,f64)) {
+    let (x_grad, (a_grad, b_grad)) = dist.logpdf_grad(&x, (a, b));
+    approx::assert_abs_diff_eq!(x_grad, finite_difference(|x| dist.logpdf(&x, (a, b)), x), epsilon = GRAD_EPSILON);
+    approx::assert_abs_diff_eq!(a_grad, finite_difference(|a| dist.logpdf(&x, (a, b)), a), epsilon = GRAD_EPSILON);
+    approx::assert_abs_diff_eq!(b_grad, finite_difference(|b| dist.logpdf(&x, (a, b)), b), epsilon = GRAD_EPSILON);
+}
+
+#[test]
+pub fn test_logpdf_grad() {
+    check_logpdf_grad(&normal, 0.4, (-1.3, 2.1));
+    check_logpdf_grad(&gamma, 1.7, (1.23, 1.46));
+    check_logpdf_grad(&gamma, 8.4, (4.5, 1.0));
+    check_logpdf_grad(&beta, 0.7, (1.5, 2.0));
+    check_logpdf_grad(&beta, 0.3, (0.5, 0.5));
+}
+
+#[test]
+pub fn test_mvnormal_logpdf_grad() {
+    let x = dvector![0.3, -1.2];
+    let mu = dvector![0.5, -0.5];
+    let cov = dmatrix![2.0, 0.3; 0.3, 0.7];
+    let (x_grad, (mu_grad, cov_grad)) = mvnormal.logpdf_grad(&x, (mu.clone(), cov.clone()));
+    for i in 0..2 {
+        let expected = finite_difference(|v| { let mut x = x.clone(); x[i] = v; mvnormal.logpdf(&x, (mu.clone(), cov.clone())) }, x[i]);
+        approx::assert_abs_diff_eq!(x_grad[i], expected, epsilon = GRAD_EPSILON);
+        let expected = finite_difference(|v| { let mut mu = mu.clone(); mu[i] = v; mvnormal.logpdf(&x, (mu, cov.clone())) }, mu[i]);
+        approx::assert_abs_diff_eq!(mu_grad[i], expected, epsilon = GRAD_EPSILON);
+    }
+
+    // perturbations of the covariance must be symmetric, so compare directional derivatives
+    let direction = dmatrix![0.4, 0.1; 0.1, -0.2];
+    let expected = finite_difference(|t| mvnormal.logpdf(&x, (mu.clone(), &cov + &direction * t)), 0.);
+    approx::assert_abs_diff_eq!(cov_grad.dot(&direction), expected, epsilon = GRAD_EPSILON);
+
+    // the density isn't defined for a singular covariance
+    let singular = dmatrix![1., 1.; 1., 1.];
+    assert!(mvnormal.logpdf(&x, (mu.clone(), singular.clone())).is_nan());
+    assert!(mvnormal.logpdf_grad(&x, (mu, singular)).0.iter().all(|g| g.is_nan()));
+}
@@ -1,3 +1,6 @@
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+
 use modppl::prelude::*;
 
 mod pointed_model;
@@ -7,28 +10,29 @@ use dyngenfns::grass;
 
 pub fn _DynGenFn_prototype(state: &mut DynGenFnHandler<f64,f64>,noise: f64) -> f64 {
     let mut sum = 0.;
-    for i in (1..3000).into_iter() {
-        let x = state.sample_at(&normal, (1., noise), &format!("{}", i));
+    for i in 1..3000 {
+        let x = state.sample_at(&normal, (1., noise), i);
         sum += x;
     }
     sum
 }
-const DynGenFn_prototype: DynGenFn<f64,f64> = DynGenFn { func: _DynGenFn_prototype };
+const DynGenFn_prototype: DynGenFn<f64,f64> = DynGenFn::new(_DynGenFn_prototype);
 
 #[test]
 pub fn test_DynGenFn_prototype() {
-    for _ in (0..100).into_iter() {
-        let _trace = DynGenFn_prototype.simulate(1.);
+    let mut rng = ThreadRng::default();
+    for _ in 0..100 {
+        let _trace = DynGenFn_prototype.simulate(&mut rng, 1.);
         let mut constraints = DynTrie::new();
         constraints.observe("1", Arc::new(100.));
         constraints.observe("5", Arc::new(200.));
-        let (trace, weight) = DynGenFn_prototype.generate(0.1, constraints);
+        let (trace, weight) = DynGenFn_prototype.generate(&mut rng, 0.1, constraints);
         approx::assert_abs_diff_eq!(trace.retv.unwrap(), 3298., epsilon = 50.);
         dbg!(trace.logjp);
         dbg!(weight);
     }
 
-    grass.simulate(());
+    grass.simulate(&mut rng, ());
 }
 
 
@@ -38,7 +42,7 @@ pub fn _DynGenFn_sample_at_update_weight_regression(state: &mut DynGenFnHandler<
         state.sample_at(&normal, (0., 1.), "x");
     }
 }
-const DynGenFn_sample_at_update_weight_regression: DynGenFn<(),()> = DynGenFn { func: _DynGenFn_sample_at_update_weight_regression };
+const DynGenFn_sample_at_update_weight_regression: DynGenFn<(),()> = DynGenFn::new(_DynGenFn_sample_at_update_weight_regression);
 
 pub fn _DynGenFn_trace_at_update_weight_regression(state: &mut DynGenFnHandler<(),()>,_: ()) {
     let b = state.sample_at(&bernoulli, 0.25, "b");
@@ -46,91 +50,165 @@ pub fn _DynGenFn_trace_at_update_weight_regression(state: &mut DynGenFnHandler<(
         state.trace_at(&DynGenFn_prototype, 1.0, "sub");
     }
 }
-const DynGenFn_trace_at_update_weight_regression: DynGenFn<(),()> = DynGenFn { func: _DynGenFn_trace_at_update_weight_regression };
+const DynGenFn_trace_at_update_weight_regression: DynGenFn<(),()> = DynGenFn::new(_DynGenFn_trace_at_update_weight_regression);
 
 pub fn _DynGenFn_sample_at_update_weight_regression2(state: &mut DynGenFnHandler<(),()>,_: ()) {
     let m = state.sample_at(&uniform, (0.,1.), "m");
     state.sample_at(&normal, (m, 1.), "x");
     state.sample_at(&normal, (m, 1.), "y");
 }
-const DynGenFn_sample_at_update_weight_regression2: DynGenFn<(),()> = DynGenFn { func: _DynGenFn_sample_at_update_weight_regression2 };
+const DynGenFn_sample_at_update_weight_regression2: DynGenFn<(),()> = DynGenFn::new(_DynGenFn_sample_at_update_weight_regression2);
 
 #[test]
 pub fn test_sample_at_update_prev_and_constrained() {
+    let mut rng = ThreadRng::default();
     // sample_at
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(true));
     constraints.observe("x", Arc::new(0.0));
-    let tr = DynGenFn_sample_at_update_weight_regression.generate((), constraints).0;
+    let tr = DynGenFn_sample_at_update_weight_regression.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("x", Arc::new(1.0));
-    let w = DynGenFn_sample_at_update_weight_regression.update(tr, (), modppl::ArgDiff::Unknown, constraints).2;
+    let w = DynGenFn_sample_at_update_weight_regression.update(&mut rng, tr, (), modppl::ArgDiff::Unknown, constraints).2;
     assert_eq!(w, -0.5);
 }
 
 #[test]
 pub fn test_sample_at_update_no_prev_and_constrained() {
+    let mut rng = ThreadRng::default();
     // sample_at
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(false));
-    let tr = DynGenFn_sample_at_update_weight_regression.generate((), constraints).0;
+    let tr = DynGenFn_sample_at_update_weight_regression.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(true));
     constraints.observe("x", Arc::new(1.0));
-    let w = DynGenFn_sample_at_update_weight_regression.update(tr, (), modppl::ArgDiff::Unknown, constraints).2;
+    let w = DynGenFn_sample_at_update_weight_regression.update(&mut rng, tr, (), modppl::ArgDiff::Unknown, constraints).2;
     approx::assert_abs_diff_eq!(w, -2.517551, epsilon = 1e-6);
 }
 
 #[test]
 pub fn test_update_sample_at_prev_and_unconstrained() {
+    let mut rng = ThreadRng::default();
     // sample_at
     let mut constraints = DynTrie::new();
     constraints.observe("m", Arc::new(1.0));
     constraints.observe("x", Arc::new(1.0));
     constraints.observe("y", Arc::new(-0.3));
-    let tr = DynGenFn_sample_at_update_weight_regression2.generate((), constraints).0;
+    let tr = DynGenFn_sample_at_update_weight_regression2.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("m", Arc::new(0.5));
-    let w = DynGenFn_sample_at_update_weight_regression2.update(tr, (), modppl::ArgDiff::Unknown, constraints).2;
+    let w = DynGenFn_sample_at_update_weight_regression2.update(&mut rng, tr, (), modppl::ArgDiff::Unknown, constraints).2;
     approx::assert_abs_diff_eq!(w, 0.4000000, epsilon = 1e-6);
 }
 
 #[test]
 pub fn test_update_no_prev_and_unconstrained() {
+    let mut rng = ThreadRng::default();
     // sample_at
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(false));
-    let tr = DynGenFn_sample_at_update_weight_regression.generate((), constraints).0;
+    let tr = DynGenFn_sample_at_update_weight_regression.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(true));
-    let w = DynGenFn_sample_at_update_weight_regression.update(tr, (), modppl::ArgDiff::Unknown, constraints).2;
+    let w = DynGenFn_sample_at_update_weight_regression.update(&mut rng, tr, (), modppl::ArgDiff::Unknown, constraints).2;
     approx::assert_abs_diff_eq!(w, -1.098612, epsilon = 1e-6);
 
     // trace_at
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(false));
-    let tr = DynGenFn_trace_at_update_weight_regression.generate((), constraints).0;
+    let tr = DynGenFn_trace_at_update_weight_regression.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("b", Arc::new(true));
-    let w = DynGenFn_trace_at_update_weight_regression.update(tr, (), modppl::ArgDiff::Unknown, constraints).2;
+    let w = DynGenFn_trace_at_update_weight_regression.update(&mut rng, tr, (), modppl::ArgDiff::Unknown, constraints).2;
     approx::assert_abs_diff_eq!(w, -1.098612, epsilon = 1e-6);
 }
 
 #[test]
 #[should_panic]
 pub fn test_generate_residual_constraints_panic() {
+    let mut rng = ThreadRng::default();
     let mut constraints = DynTrie::new();
     constraints.observe("abc", Arc::new(0.));
-    DynGenFn_prototype.generate(0.1, constraints);
+    DynGenFn_prototype.generate(&mut rng, 0.1, constraints);
 }
 
 #[test]
 #[should_panic]
 pub fn test_update_residual_constraints_panic() {
+    let mut rng = ThreadRng::default();
+    let mut constraints = DynTrie::new();
+    constraints.observe("abc", Arc::new(0.));
+    let trace = DynGenFn_prototype.simulate(&mut rng, 0.1);
+    DynGenFn_prototype.update(&mut rng, trace, 0.1, ArgDiff::NoChange, constraints);
+}
+
+#[test]
+pub fn test_try_generate_residual_constraints_err() {
+    let mut rng = ThreadRng::default();
     let mut constraints = DynTrie::new();
     constraints.observe("abc", Arc::new(0.));
-    let trace = DynGenFn_prototype.simulate(0.1);
-    DynGenFn_prototype.update(trace, 0.1, ArgDiff::NoChange, constraints);
+    let result = DynGenFn_prototype.try_generate(&mut rng, 0.1, constraints);
+    assert_eq!(result.err(), Some(GenFnError::UnconsumedConstraints(vec![String::from("abc")])));
+}
+
+#[test]
+pub fn test_try_generate_type_mismatch_err() {
+    let mut rng = ThreadRng::default();
+    let mut constraints = DynTrie::new();
+    constraints.observe("1", Arc::new(true));
+    let result = DynGenFn_prototype.try_generate(&mut rng, 0.1, constraints);
+    assert_eq!(result.err(), Some(GenFnError::TypeMismatch { addr: String::from("1"), expected: "f64" }));
+}
+
+#[test]
+pub fn test_try_simulate_address_collision_err() {
+    let mut rng = ThreadRng::default();
+    dyngen!(
+    fn twice() -> f64 {
+        let x = normal(0., 1.) %= "x";
+        normal(x, 1.) %= "x"
+    });
+    let result = twice.try_simulate(&mut rng, ());
+    assert_eq!(result.err(), Some(GenFnError::AddressCollision(String::from("x"))));
+}
+
+// errors raised in a callee are returned by the caller (rather than panicking), even when it can't be simulated either
+#[test]
+pub fn test_try_callee_address_collision_err() {
+    let mut rng = StdRng::seed_from_u64(5);
+    dyngen!(
+    fn twice() -> f64 {
+        let x = normal(0., 1.) %= "x";
+        normal(x, 1.) %= "x"
+    });
+    dyngen!(
+    fn maybe_calls_twice(call: bool) -> f64 {
+        let x = if call { twice() /= "sub" } else { 0. };
+        normal(x, 1.) %= "y"
+    });
+    let collision = Some(GenFnError::AddressCollision(String::from("x")));
+    assert_eq!(maybe_calls_twice.try_simulate(&mut rng, true).err(), collision);
+    let mut constraints = DynTrie::new();
+    constraints.observe("sub / x", Arc::new(0.5));
+    assert_eq!(maybe_calls_twice.try_generate(&mut rng, true, constraints).err(), collision);
+
+    let trace = maybe_calls_twice.simulate(&mut rng, false);
+    let result = maybe_calls_twice.try_update(&mut rng, trace.clone(), true, ArgDiff::Unknown, DynTrie::new());
+    assert_eq!(result.err(), collision);
+    let mut mask = AddrMap::new();
+    mask.visit("y");
+    let result = maybe_calls_twice.try_regenerate(&mut rng, trace, true, ArgDiff::Unknown, &mask);
+    assert_eq!(result.err(), collision);
+}
+
+#[test]
+pub fn test_try_read() {
+    let mut rng = ThreadRng::default();
+    let trace = DynGenFn_prototype.simulate(&mut rng, 0.1);
+    assert!(trace.data.try_read::<f64>("1").is_ok());
+    assert_eq!(trace.data.try_read::<f64>("0"), Err(GenFnError::AddressNotFound(String::from("0"))));
+    assert_eq!(trace.data.try_read::<bool>("1"), Err(GenFnError::TypeMismatch { addr: String::from("1"), expected: "bool" }));
 }
 
 dyngen!(
@@ -156,11 +234,12 @@ fn proposal(tr: Weak<DynTrace<(),f64>>, drift: f64, addr: String) {
 
 #[test]
 pub fn test_parse() {
+    let mut rng = ThreadRng::default();
     let mut constraints = DynTrie::new();
     constraints.observe("y", Arc::new(0.3));
-    let mut tr = model.simulate(());
+    let mut tr = model.simulate(&mut rng, ());
     for _ in 0..1000 {
-        let (new_tr, accepted) = mh(&model, tr, &proposal, (0.5,String::from("var/prob_is_small")));
+        let (new_tr, accepted) = mh(&mut rng, &model, tr, &proposal, (0.5,String::from("var/prob_is_small"))).unwrap();
         dbg!(accepted);
         tr = new_tr;
     }
@@ -168,13 +247,14 @@ pub fn test_parse() {
 
 #[test]
 pub fn test_simulate() {
+    let mut rng = ThreadRng::default();
     dyngen!(
     fn foo(p: f64) -> bool {
         bernoulli(p) %= "x"
     });
 
     let p = 0.4;
-    let trace = foo.simulate(p);
+    let trace = foo.simulate(&mut rng, p);
     assert_eq!(trace.data.read::<bool>("x"), trace.retv.unwrap());
     assert_eq!(trace.args, p);
     assert_eq!(trace.logjp, if trace.data.read::<bool>("x") { p.ln() } else { (1.-p).ln() });
@@ -182,6 +262,7 @@ pub fn test_simulate() {
 
 #[test]
 pub fn test_update() {
+    let mut rng = ThreadRng::default();
     dyngen!(
     fn bar() -> f64 {
         normal(0., 1.) %= "a"
@@ -206,7 +287,7 @@ pub fn test_update() {
     // get a trace which follows the first branch
     let mut constraints = DynTrie::new();
     constraints.observe("branch", Arc::new(true));
-    let (trace, _) = foo.generate((), constraints);
+    let (trace, _) = foo.generate(&mut rng, (), constraints);
     let x = trace.data.read::<f64>("x");
     let a = trace.data.read::<f64>("u/a");
 
@@ -217,10 +298,10 @@ pub fn test_update() {
     constraints.observe("branch", Arc::new(false));
     constraints.observe("y", Arc::new(y));
     constraints.observe("v/b", Arc::new(b));
-    let (new_trace, discard, weight) = foo.update(trace, (), ArgDiff::NoChange, constraints);
+    let (new_trace, discard, weight, _) = foo.update(&mut rng, trace, (), ArgDiff::NoChange, constraints);
 
     // test discard
-    assert_eq!(discard.read::<bool>("branch"), true);
+    assert!(discard.read::<bool>("branch"));
     assert_eq!(discard.read::<f64>("x"), x);
     assert_eq!(discard.read::<f64>("u/a"), a);
     assert_eq!(discard.iter().fold(0, |l, (_, tr)| l + tr.is_leaf() as usize), 2);
@@ -228,7 +309,7 @@ pub fn test_update() {
 
     // test new trace
     let new_assignment = new_trace.data;
-    assert_eq!(new_assignment.read::<bool>("branch"), false);
+    assert!(!new_assignment.read::<bool>("branch"));
     assert_eq!(new_assignment.read::<f64>("y"), y);
     assert_eq!(new_assignment.read::<f64>("v/b"), b);
     assert_eq!(new_assignment.iter().fold(0, |l, (_, tr)| l + tr.is_leaf() as usize), 2);
@@ -261,14 +342,14 @@ pub fn test_update() {
     let mut constraints = DynTrie::new();
     constraints.observe("a", Arc::new(0.));
     for i in 0..5 {
-        constraints.observe(&format!("data/{i}"), Arc::new(0.));
+        constraints.observe(format!("data/{i}"), Arc::new(0.));
     }
-    let (trace, _) = loopy.generate((), constraints);
+    let (trace, _) = loopy.generate(&mut rng, (), constraints);
 
     // update "a"
     let mut constraints = DynTrie::new();
     constraints.observe("a", Arc::new(1.));
-    let (new_trace, discard, weight) = loopy.update(trace, (), ArgDiff::NoChange, constraints);
+    let (new_trace, discard, weight, _) = loopy.update(&mut rng, trace, (), ArgDiff::NoChange, constraints);
 
     // test discard, logjp, weight
     assert_eq!(discard.read::<f64>("a"), 0.);
@@ -288,10 +369,10 @@ pub fn test_update() {
 
     let mut constraints = DynTrie::new();
     constraints.observe("k", Arc::new(3_i64));
-    let trace = hierarchical_update.generate((), constraints).0;
+    let trace = hierarchical_update.generate(&mut rng, (), constraints).0;
     let mut constraints = DynTrie::new();
     constraints.observe("k", Arc::new(1_i64));
-    let (_, discard, weight) = hierarchical_update.update(trace, (), ArgDiff::Unknown, constraints);
+    let (_, discard, weight, _) = hierarchical_update.update(&mut rng, trace, (), ArgDiff::Unknown, constraints);
     assert!(discard.search("value/1").is_some());
     assert!(discard.search("value/2").is_some());
     assert_eq!(
@@ -303,8 +384,76 @@ pub fn test_update() {
     );
 }
 
+// a hand-coded generative function whose return value doesn't depend on its choice "x"
+struct Constant;
+
+impl GenFn<(),DynTrie,f64> for Constant {
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: ()) -> Result<DynTrace<(),f64>,GenFnError> {
+        Ok(self.try_generate(rng, args, DynTrie::new())?.0)
+    }
+
+    fn try_generate(&self, rng: &mut dyn RngCore, args: (), constraints: DynTrie) -> Result<(DynTrace<(),f64>,f64),GenFnError> {
+        let (x, weight) = match constraints.search("x") {
+            Some(_) => { let x = constraints.try_read::<f64>("x")?; (x, normal.logpdf(&x, (0., 1.))) }
+            None => { (normal.random(rng, (0., 1.)), 0.) }
+        };
+        let logp = normal.logpdf(&x, (0., 1.));
+        let mut data = DynTrie::new();
+        data.w_observe("x", Arc::new(x), logp);
+        Ok((Trace::new(args, data, 1., logp), weight))
+    }
+
+    fn try_update(&self, rng: &mut dyn RngCore, trace: DynTrace<(),f64>, args: (), _: ArgDiff, constraints: DynTrie)
+        -> Result<(DynTrace<(),f64>,DynTrie,f64,ArgDiff),GenFnError>
+    {
+        if constraints.is_empty() {
+            return Ok((trace, DynTrie::new(), 0., ArgDiff::NoChange));
+        }
+        let (new_trace, weight) = self.try_generate(rng, args, constraints)?;
+        let mut discard = DynTrie::new();
+        discard.w_observe("x", Arc::new(trace.data.try_read::<f64>("x")?), trace.logjp);
+        let weight = weight - trace.logjp;
+        Ok((new_trace, discard, weight, ArgDiff::NoChange))
+    }
+}
+
+#[test]
+pub fn test_update_retdiff() {
+    let mut rng = ThreadRng::default();
+    dyngen!(
+    fn shifted() -> f64 {
+        let c = Constant() /= "c";
+        normal(c, 1.) %= "y"
+    });
+
+    let trace = shifted.simulate(&mut rng, ());
+    let y = trace.data.read::<f64>("y");
+
+    // nothing changes
+    let (trace, _, weight, retdiff) = shifted.update(&mut rng, trace, (), ArgDiff::NoChange, DynTrie::new());
+    assert_eq!(retdiff, ArgDiff::NoChange);
+    assert_eq!(weight, 0.);
+
+    // only a choice that doesn't affect the return value of "c" changes
+    let mut constraints = DynTrie::new();
+    constraints.observe("c / x", Arc::new(0.5));
+    let prev_x = trace.data.read::<f64>("c / x");
+    let (trace, discard, weight, retdiff) = shifted.update(&mut rng, trace, (), ArgDiff::NoChange, constraints);
+    assert_eq!(retdiff, ArgDiff::NoChange);
+    assert_eq!(discard.read::<f64>("c / x"), prev_x);
+    approx::assert_abs_diff_eq!(weight, normal.logpdf(&0.5, (0., 1.)) - normal.logpdf(&prev_x, (0., 1.)), epsilon = 1e-10);
+    assert_eq!(trace.data.read::<f64>("y"), y);
+
+    // the return value changes
+    let mut constraints = DynTrie::new();
+    constraints.observe("y", Arc::new(0.));
+    let (_, _, _, retdiff) = shifted.update(&mut rng, trace, (), ArgDiff::NoChange, constraints);
+    assert_eq!(retdiff, ArgDiff::Unknown);
+}
+
 #[test]
 pub fn test_regenerate() {
+    let mut rng = ThreadRng::default();
     dyngen!(
     fn bar(mu: f64) -> f64 {
         normal(mu, 1.) %= "a"
@@ -330,22 +479,19 @@ pub fn test_regenerate() {
     let mut mu = 0.123;
     let mut constraints = DynTrie::new();
     constraints.observe("branch", Arc::new(true));
-    let (mut trace, _) = foo.generate(mu, constraints);
-    let x = trace.data.read::<f64>("x");
-    let a = trace.data.read::<f64>("u/a");
+    let (mut trace, _) = foo.generate(&mut rng, mu, constraints);
 
     let mut mask = AddrMap::new();
     mask.visit("branch");
 
     // change the argument so that the weights can be nonzero
-    let mut rng = ThreadRng::default();
-    for i in 0..10 {
+    for _ in 0..10 {
         let prev_branch = trace.data.read::<bool>("branch");
 
         // test logjp
         let prev_mu = mu;
         mu = u01(&mut rng);
-        let (new_trace, weight) = foo.regenerate(trace, mu, ArgDiff::Unknown, &mask);
+        let (new_trace, weight, _) = foo.regenerate(&mut rng, trace, mu, ArgDiff::Unknown, &mask);
         trace = new_trace;
 
         // test logjp
@@ -388,4 +534,165 @@ pub fn test_regenerate() {
         }
         approx::assert_abs_diff_eq!(expected_weight, weight, epsilon = 1e-3);
     }
-}
\ No newline at end of file
+}
+
+dyngen!(
+fn regression(xs: Vec<Var>) -> Var {
+    let slope = ad::normal(0., 1.) %= "slope";
+    let intercept = ad::normal(0., 2.) %= "intercept";
+    let flip = bernoulli(0.5) %= "flip";
+    normal(0., 1.) %= "noise";
+    for (i, &x) in xs.iter().enumerate() {
+        ad::normal(slope * x + intercept, 0.5) %= ("ys", i);
+    }
+    if flip { slope } else { intercept }
+});
+
+dyngen!(
+fn noisy_line(slope: Var, x: f64) -> Var {
+    ad::normal(slope * x, 0.5) %= "y"
+});
+
+dyngen!(
+fn nested_regression(xs: Vec<f64>) -> Var {
+    let slope = ad::normal(0., 1.) %= "slope";
+    for (i, &x) in xs.iter().enumerate() {
+        noisy_line(slope, x) /= ("lines", i);
+    }
+    slope
+});
+
+#[test]
+pub fn test_choice_gradients() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let xs = vec![-1., 0.5, 2.];
+    let trace = regression.simulate(&mut rng, xs.iter().map(|&x| Var::constant(x)).collect());
+    let slope = trace.data.read::<f64>("slope");
+    let intercept = trace.data.read::<f64>("intercept");
+    let residuals = xs.iter().enumerate()
+        .map(|(i, x)| (trace.data.read::<f64>(("ys", i)) - slope * x - intercept) / 0.25)
+        .collect::<Vec<f64>>();
+
+    // select two of the choices
+    let mut selection = AddrMap::new();
+    selection.visit("slope");
+    selection.visit("intercept");
+    let gradients = regression.choice_gradients(&mut rng, &trace, &selection);
+    let expected_slope_grad = -slope + residuals.iter().zip(&xs).map(|(r, x)| r * x).sum::<f64>();
+    let expected_intercept_grad = -intercept / 4. + residuals.iter().sum::<f64>();
+    approx::assert_abs_diff_eq!(gradients.read::<f64>("slope"), expected_slope_grad, epsilon = 1e-10);
+    approx::assert_abs_diff_eq!(gradients.read::<f64>("intercept"), expected_intercept_grad, epsilon = 1e-10);
+    assert!(gradients.search("ys").is_none());
+
+    // select the whole trace, skipping the discrete choice and the choice that isn't a `Var`
+    let gradients = regression.choice_gradients(&mut rng, &trace, &AddrMap::new());
+    assert!(gradients.search("flip").is_none());
+    assert!(gradients.search("noise").is_none());
+    for (i, r) in residuals.iter().enumerate() {
+        approx::assert_abs_diff_eq!(gradients.read::<f64>(("ys", i)), -r, epsilon = 1e-10);
+    }
+
+    // the discrete choice and the choice that isn't a `Var` can't be selected by their own address
+    for addr in ["flip", "noise"] {
+        let mut selection = AddrMap::new();
+        selection.visit(addr);
+        assert_eq!(
+            regression.try_choice_gradients(&mut rng, &trace, &selection).err(),
+            Some(GenFnError::InvalidArgs(format!(
+                "the choice at \"{}\" isn't differentiable (sample it from a distribution of `ad`, eg. `ad::normal`)", addr
+            )))
+        );
+    }
+
+    // gradients with respect to the arguments
+    let arg_gradients = regression.arg_gradients(&mut rng, &trace);
+    for (arg_grad, r) in arg_gradients.iter().zip(&residuals) {
+        approx::assert_abs_diff_eq!(arg_grad.value(), r * slope, epsilon = 1e-10);
+    }
+
+    // gradients flow through the arguments of nested calls
+    let trace = nested_regression.simulate(&mut rng, xs.clone());
+    let y_addr = |i: usize| Addr::from(vec![AddrKey::from(("lines", i)), AddrKey::from("y")]);
+    let slope = trace.data.read::<f64>("slope");
+    let expected_slope_grad = -slope + xs.iter().enumerate()
+        .map(|(i, x)| (trace.data.read::<f64>(y_addr(i)) - slope * x) / 0.25 * x)
+        .sum::<f64>();
+    let gradients = nested_regression.choice_gradients(&mut rng, &trace, &AddrMap::new());
+    approx::assert_abs_diff_eq!(gradients.read::<f64>("slope"), expected_slope_grad, epsilon = 1e-10);
+    for (i, x) in xs.iter().enumerate() {
+        let residual = (trace.data.read::<f64>(y_addr(i)) - slope * x) / 0.25;
+        approx::assert_abs_diff_eq!(gradients.read::<f64>(y_addr(i)), -residual, epsilon = 1e-10);
+    }
+}
+
+dyngen!(
+fn transformed(theta: Var) {
+    let mu = (theta * theta + theta.sin()) / 2. - 1.;
+    ad::normal(mu, theta.exp()) %= "x";
+    ad::gamma(theta.powi(2) + 1., 2. / (1. + theta.tanh().abs())) %= "g";
+    ad::beta(theta.sqrt(), theta.cos().powf(2.) + 1.) %= "b";
+});
+
+#[test]
+pub fn test_var_arithmetic_gradients() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let theta = 0.7;
+    let trace = transformed.simulate(&mut rng, Var::constant(theta));
+    let logjp = |theta: f64| transformed.generate(&mut rng.clone(), Var::constant(theta), trace.data.clone()).0.logjp;
+    let h = 1e-6;
+    let expected = (logjp(theta + h) - logjp(theta - h)) / (2. * h);
+    approx::assert_abs_diff_eq!(transformed.arg_gradients(&mut rng, &trace).value(), expected, epsilon = 1e-5);
+}
+
+#[test]
+pub fn test_dyngenfn_from_closure() {
+    let mut rng = StdRng::seed_from_u64(8);
+
+    // configuration and data, eg. as loaded at run-time
+    let noise = 0.25;
+    let xs = Arc::new(vec![-1., 0., 1., 2.]);
+
+    let prior = dyngen!(move || -> f64 {
+        normal(0., 1.) %= "slope"
+    });
+    let line = {
+        let xs = xs.clone();
+        dyngen!(move |intercept: f64| -> Vec<f64> {
+            let slope = prior() /= "prior";
+            xs.iter().enumerate()
+                .map(|(i, x)| normal(intercept + slope * x, noise) %= ("y", i))
+                .collect::<Vec<f64>>()
+        })
+    };
+
+    let trace = line.simulate(&mut rng, 0.5);
+    let slope = trace.data.read::<f64>("prior / slope");
+    let expected_logjp = normal.logpdf(&slope, (0., 1.)) + xs.iter().enumerate()
+        .map(|(i, x)| normal.logpdf(&trace.data.read::<f64>(("y", i)), (0.5 + slope * x, noise)))
+        .sum::<f64>();
+    approx::assert_abs_diff_eq!(trace.logjp, expected_logjp, epsilon = 1e-10);
+    assert_eq!(trace.retv.as_ref().unwrap().len(), xs.len());
+
+    // clones share the closure, and can be sent to other threads
+    let shared = line.clone();
+    let handle = std::thread::spawn(move || {
+        let mut rng = StdRng::seed_from_u64(9);
+        shared.simulate(&mut rng, 0.5).retv.unwrap().len()
+    });
+    assert_eq!(handle.join().unwrap(), xs.len());
+}
+
+#[test]
+pub fn test_dyngenfn_from_arc() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let scale = 3.;
+    let func: DynFnArc<f64,f64> = Arc::new(move |g: &mut DynGenFnHandler<f64,f64>, mu: f64| {
+        g.sample_at(&normal, (mu, scale), "x")
+    });
+    let scaled = DynGenFn::from_arc(func);
+    let mut constraints = DynTrie::new();
+    constraints.observe("x", Arc::new(1.));
+    let (trace, weight) = scaled.generate(&mut rng, 2., constraints);
+    assert_eq!(trace.retv.unwrap(), 1.);
+    approx::assert_abs_diff_eq!(weight, normal.logpdf(&1., (2., scale)), epsilon = 1e-10);
+}
@@ -13,7 +13,7 @@ pub fn grass() -> (bool,bool) {
         bernoulli(0.4) %= "sprinkler"
     };
 
-    let grass_wet = bernoulli(
+    let _grass_wet = bernoulli(
         if sprinkler && rain { 0.99 }
         else if sprinkler && !rain { 0.9 }
         else if !sprinkler && rain { 0.8 }
@@ -35,12 +35,12 @@ pub fn hierarchical_model(xs: Vec<f64>) -> Vec<f64> {
     if bernoulli(0.7) %= "is_linear" {
         let coeffs = linear() /= "coeffs";
         xs.iter().enumerate().map(|(i, x)| 
-            normal(coeffs.0 + coeffs.1 * x, noise) %= &format!("(y, {})", i)
+            normal(coeffs.0 + coeffs.1 * x, noise) %= ("y", i)
         ).collect::<_>()
     } else {
         let coeffs = quadratic() /= "coeffs";
         xs.iter().enumerate().map(|(i, x)| 
-            normal(coeffs.0 + coeffs.1 * x + coeffs.2 * x * x, noise) %= &format!("(y, {})", i)
+            normal(coeffs.0 + coeffs.1 * x + coeffs.2 * x * x, noise) %= ("y", i)
         ).collect::<_>()
     }
 });
@@ -1,4 +1,4 @@
-use std::sync::Weak;
+use std::sync::{Arc,Weak};
 use modppl::prelude::*;
 use nalgebra::{DMatrix, DVector};
 
@@ -6,12 +6,53 @@ use crate::pointed_model;
 use pointed_model::types_2d::{Bounds, Point, uniform_2d};
 
 
+// normal-normal model, with a differentiable mean (eg. for `hmc`)
+dyngen!(
+pub fn normal_normal(n: usize) -> f64 {
+    let mu = ad::normal(0., 1.) %= "mu";
+    for i in 0..n {
+        ad::normal(mu, 1.) %= ("y", i);
+    }
+    mu.value()
+});
+
+pub fn normal_normal_observations(ys: &[f64]) -> DynTrie {
+    let mut observations = DynTrie::new();
+    for (i, y) in ys.iter().enumerate() {
+        observations.observe(("y", i), Arc::new(*y));
+    }
+    observations
+}
+
+
+// gaussian model with a drift proposal on its mean (eg. for `Chain` and `Kernel`s)
+dyngen!(
+pub fn gaussian_model(n: usize) {
+    let mu = normal(0., 1.) %= "mu";
+    for i in 0..n {
+        normal(mu, 1.) %= ("y", i as i64);
+    }
+});
+
+dyngen!(
+pub fn mu_drift_proposal(trace: Weak<DynTrace<usize,()>>, drift_std: f64) {
+    let trace = trace.upgrade().unwrap();
+    normal(trace.data.read::<f64>("mu"), drift_std) %= "mu";
+});
+
+pub fn gaussian_observations(ys: &[f64]) -> DynTrie {
+    let mut observations = DynTrie::new();
+    ys.iter().enumerate().for_each(|(i, y)| { observations.observe(("y", i as i64), Arc::new(*y)); });
+    observations
+}
+
+
 // bayesian linear regression model
 dyngen!(
 fn obs_model(slope: f64, intercept: f64, xs: Vec<f64>) -> Vec<f64> {
     xs.into_iter()
         .enumerate()
-        .map(|(i, x)| normal(slope * x + intercept, 0.1) %= &format!("{}", i))
+        .map(|(i, x)| normal(slope * x + intercept, 0.1) %= i)
         .collect::<_>()
 });
 
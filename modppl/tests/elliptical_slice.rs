@@ -0,0 +1,102 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::Arc;
+use nalgebra::{DVector,DMatrix};
+use modppl::prelude::*;
+
+
+// squared exponential covariance of a GP at the inputs `xs`, with a little jitter
+fn gp_cov(xs: &[f64]) -> DMatrix<f64> {
+    DMatrix::from_fn(xs.len(), xs.len(), |i, j| (-(xs[i] - xs[j]).powi(2) / 2.).exp() + if i == j { 1e-6 } else { 0. })
+}
+
+dyngen!(
+fn gp_model(xs: Vec<f64>, noise: f64) {
+    let scale = gamma(2., 1.) %= "scale";
+    let f = mvnormal(DVector::zeros(xs.len()), gp_cov(&xs)) %= "f";
+    for i in 0..xs.len() {
+        normal(f[i], noise * scale.sqrt()) %= ("y", i as i64);
+    }
+});
+
+type GpTrace = DynTrace<(Vec<f64>,f64),()>;
+
+
+#[test]
+fn test_elliptical_slice() {
+    let mut rng = StdRng::seed_from_u64(90);
+    let xs = vec![-1., 0., 0.5, 2.];
+    let ys = [0.4, 1.1, 0.9, -0.7];
+    let noise: f64 = 0.5;
+    let scale = 1.;
+
+    let mut constraints = DynTrie::new();
+    constraints.observe("scale", Arc::new(scale));
+    ys.iter().enumerate().for_each(|(i, y)| { constraints.observe(("y", i as i64), Arc::new(*y)); });
+
+    // with the scale fixed, the posterior of `f` is Gaussian
+    let prior_mean = DVector::zeros(xs.len());
+    let prior_cov = gp_cov(&xs);
+    let precision = prior_cov.clone().try_inverse().unwrap() + DMatrix::identity(xs.len(), xs.len()) / noise.powi(2);
+    let posterior_cov = precision.try_inverse().unwrap();
+    let posterior_mean = &posterior_cov * DVector::from_row_slice(&ys) / noise.powi(2);
+
+    let (mut trace, _) = gp_model.generate(&mut rng, (xs.clone(), noise), constraints);
+    const NUM_WARMUP: usize = 100;
+    const NUM_ITERS: usize = 4000;
+    let mut samples = vec![];
+    for iter in 0..NUM_WARMUP + NUM_ITERS {
+        let accepted;
+        (trace, accepted) = elliptical_slice(&mut rng, &gp_model, trace, "f", &prior_mean, &prior_cov).unwrap();
+        assert!(accepted);
+        assert_eq!(trace.data.read::<f64>("scale"), scale);
+        if iter >= NUM_WARMUP {
+            samples.push(trace.data.read::<DVector<f64>>("f"));
+        }
+    }
+
+    let mean = samples.iter().sum::<DVector<f64>>() / NUM_ITERS as f64;
+    for i in 0..xs.len() {
+        let var = samples.iter().map(|f| (f[i] - mean[i]).powi(2)).sum::<f64>() / NUM_ITERS as f64;
+        approx::assert_abs_diff_eq!(mean[i], posterior_mean[i], epsilon = 0.05);
+        approx::assert_abs_diff_eq!(var, posterior_cov[(i, i)], epsilon = 0.03);
+    }
+}
+
+#[test]
+fn test_elliptical_slice_in_chain() {
+    let mut rng = StdRng::seed_from_u64(91);
+    let xs = vec![0., 1., 3.];
+    let mut constraints = DynTrie::new();
+    [1.2, 0.8, -0.3].iter().enumerate().for_each(|(i, y)| { constraints.observe(("y", i as i64), Arc::new(*y)); });
+    let xs_len = xs.len();
+    let prior_mean = DVector::zeros(xs_len);
+    let prior_cov = gp_cov(&xs);
+
+    // alternate with updates of the scale, which the elliptical slice sampler leaves unchanged
+    let model = gp_model;
+    let mut mask = AddrMap::new();
+    mask.visit("scale");
+    let mut kernel = kernel_fn(|rng, trace: GpTrace| elliptical_slice(rng, &model, trace, "f", &prior_mean, &prior_cov))
+        .then(RegenMh::new(&model, mask));
+    let (mut trace, _) = model.generate(&mut rng, (xs, 0.5), constraints);
+    let mut stats = AcceptStats::default();
+    for _ in 0..200 {
+        let step_stats;
+        (trace, step_stats) = kernel.apply(&mut rng, trace).unwrap();
+        stats += step_stats;
+    }
+    assert_eq!(stats.num_proposed, 400);
+    assert!(stats.num_accepted > 200);
+
+    // the choice must be a `DVector<f64>`
+    assert!(elliptical_slice(&mut rng, &gp_model, trace.clone(), "scale", &prior_mean, &prior_cov).is_err());
+    assert!(elliptical_slice(&mut rng, &gp_model, trace.clone(), "g", &prior_mean, &prior_cov).is_err());
+
+    // the prior covariance must be positive definite
+    let singular_cov = DMatrix::from_element(xs_len, xs_len, 1.);
+    assert_eq!(
+        elliptical_slice(&mut rng, &gp_model, trace, "f", &prior_mean, &singular_cov).err(),
+        Some(GenFnError::InvalidArgs("`prior_cov` must be positive definite".to_string()))
+    );
+}
@@ -0,0 +1,133 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::Arc;
+use modppl::prelude::*;
+use modppl::logsumexp;
+
+mod pointed_model;
+
+mod dyngenfns;
+use dyngenfns::hierarchical_model;
+
+
+const MEANS: [f64; 3] = [-1., 0.5, 2.];
+
+dyngen!(
+fn mixture_model(probs: Vec<f64>) {
+    let k = categorical(probs) %= "k";
+    normal(MEANS[k as usize], 1.) %= "y";
+});
+
+// a mean `m` or the average of two means `(a, b)`, with an observation `y` of it
+dyngen!(
+fn one_or_two_means_model() {
+    let mean = if bernoulli(0.4) %= "two" {
+        let a = normal(0., 1.) %= "a";
+        let b = normal(0., 1.) %= "b";
+        (a + b) / 2.
+    } else {
+        normal(0., 1.) %= "m"
+    };
+    normal(mean, 0.5) %= "y";
+});
+
+fn observe_y(y: f64) -> DynTrie {
+    let mut observations = DynTrie::new();
+    observations.observe("y", Arc::new(y));
+    observations
+}
+
+
+#[test]
+fn test_finite_support() {
+    assert_eq!(bernoulli.support(0.3), vec![false, true]);
+    assert_eq!(categorical.support(vec![0.2, 0.5, 0.3]), vec![0, 1, 2]);
+    assert_eq!(uniform_discrete.support((-1, 2)), vec![-1, 0, 1, 2]);
+
+    // the probabilities of the support sum to one
+    let total = |logps: Vec<f64>| logsumexp(&logps).exp();
+    approx::assert_abs_diff_eq!(total(bernoulli.support(0.3).iter().map(|x| bernoulli.logpdf(x, 0.3)).collect()), 1., epsilon = 1e-12);
+    let probs = vec![0.2, 0.5, 0.3];
+    approx::assert_abs_diff_eq!(total(categorical.support(probs.clone()).iter().map(|x| categorical.logpdf(x, probs.clone())).collect()), 1., epsilon = 1e-12);
+    approx::assert_abs_diff_eq!(total(uniform_discrete.support((-1, 2)).iter().map(|x| uniform_discrete.logpdf(x, (-1, 2))).collect()), 1., epsilon = 1e-12);
+}
+
+#[test]
+fn test_enumerative_gibbs() {
+    let mut rng = StdRng::seed_from_u64(100);
+    let probs: Vec<f64> = vec![0.2, 0.5, 0.3];
+    let y = 1.3;
+
+    let log_joints = MEANS.iter().zip(&probs).map(|(mean, p)| p.ln() + normal.logpdf(&y, (*mean, 1.))).collect::<Vec<f64>>();
+    let log_evidence = logsumexp(&log_joints);
+    let posterior = log_joints.iter().map(|w| (w - log_evidence).exp()).collect::<Vec<f64>>();
+
+    // the choice is the only latent, so every update is an independent draw from the posterior
+    const NUM_ITERS: usize = 10000;
+    let (mut trace, _) = mixture_model.generate(&mut rng, probs.clone(), observe_y(y));
+    let mut counts = [0; 3];
+    let mut num_changed = 0;
+    for _ in 0..NUM_ITERS {
+        let changed;
+        (trace, changed) = enumerative_gibbs(&mut rng, &mixture_model, trace, "k", &categorical.support(probs.clone())).unwrap();
+        counts[trace.data.read::<i64>("k") as usize] += 1;
+        num_changed += changed as usize;
+    }
+    for (count, p) in counts.iter().zip(&posterior) {
+        approx::assert_abs_diff_eq!(*count as f64 / NUM_ITERS as f64, p, epsilon = 0.015);
+    }
+    let prob_unchanged = posterior.iter().map(|p| p * p).sum::<f64>();
+    approx::assert_abs_diff_eq!(num_changed as f64 / NUM_ITERS as f64, 1. - prob_unchanged, epsilon = 0.015);
+    approx::assert_abs_diff_eq!(trace.logjp, log_joints[trace.data.read::<i64>("k") as usize], epsilon = 1e-12);
+}
+
+#[test]
+fn test_enumerative_gibbs_changing_structure() {
+    let mut rng = StdRng::seed_from_u64(101);
+    let y = 1.5;
+
+    // y ~ N(0, 1 + 0.25) with one mean, and y ~ N(0, 1/2 + 0.25) with two
+    let log_one = 0.6_f64.ln() + normal.logpdf(&y, (0., 1.25_f64.sqrt()));
+    let log_two = 0.4_f64.ln() + normal.logpdf(&y, (0., 0.75_f64.sqrt()));
+    let expected_prob_two = 1. / (1. + (log_one - log_two).exp());
+
+    let model = one_or_two_means_model;
+    let mut mask = AddrMap::new();
+    mask.visit("m");
+    mask.visit("a");
+    mask.visit("b");
+    let mut kernel = kernel_fn(|rng, trace| enumerative_gibbs(rng, &model, trace, "two", &bernoulli.support(0.4)))
+        .then(RegenMh::new(&model, mask));
+
+    const NUM_ITERS: usize = 20000;
+    let (mut trace, _) = model.generate(&mut rng, (), observe_y(y));
+    let mut num_two = 0;
+    for _ in 0..NUM_ITERS {
+        (trace, _) = kernel.apply(&mut rng, trace).unwrap();
+        let two = trace.data.read::<bool>("two");
+        num_two += two as usize;
+        assert_eq!(trace.data.search("m").is_none(), two);
+        assert_eq!(trace.data.search("a").is_some(), two);
+    }
+    approx::assert_abs_diff_eq!(num_two as f64 / NUM_ITERS as f64, expected_prob_two, epsilon = 0.015);
+
+    // in the hierarchical example, switching to the quadratic model adds the coefficient `c`
+    let model = hierarchical_model;
+    let xs = vec![-1., 0., 1.];
+    let mut constraints = DynTrie::new();
+    constraints.observe("is_linear", Arc::new(true));
+    let (mut trace, _) = model.generate(&mut rng, xs, constraints);
+    let mut num_changed = 0;
+    for _ in 0..100 {
+        let changed;
+        (trace, changed) = enumerative_gibbs(&mut rng, &model, trace, "is_linear", &bernoulli.support(0.7)).unwrap();
+        num_changed += changed as usize;
+        assert_eq!(trace.data.search("coeffs / c").is_some(), !trace.data.read::<bool>("is_linear"));
+    }
+    assert!(num_changed > 0);
+
+    // the choice must be in the support, and of the support's type
+    assert!(enumerative_gibbs(&mut rng, &model, trace.clone(), "is_linear", &[0_i64, 1]).is_err());
+    let value = trace.data.read::<bool>("is_linear");
+    assert!(matches!(enumerative_gibbs(&mut rng, &model, trace, "is_linear", &[!value]), Err(GenFnError::InvalidArgs(_))));
+}
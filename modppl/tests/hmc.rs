@@ -0,0 +1,150 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::Arc;
+use nalgebra::{DVector, DMatrix, dvector, dmatrix};
+
+use modppl::prelude::*;
+
+mod pointed_model;
+mod dyngenfns;
+use dyngenfns::{normal_normal, normal_normal_observations};
+
+
+dyngen!(
+fn noisy_point_2d(cov: DMatrix<f64>) -> DVector<f64> {
+    let latent = ad::mvnormal(DVector::from_element(2, Var::constant(0.)), DMatrix::identity(2, 2) * 100.) %= "latent";
+    let obs = ad::mvnormal(latent, cov) %= "obs";
+    obs.map(|x| x.value())
+});
+
+fn mean(v: &[f64]) -> f64 {
+    v.iter().sum::<f64>() / v.len() as f64
+}
+
+fn variance(v: &[f64]) -> f64 {
+    let c = mean(v);
+    v.iter().map(|x| (*x - c) * (*x - c)).sum::<f64>() / (v.len() as f64 - 1.)
+}
+
+
+#[test]
+pub fn test_hmc_normal_normal() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let ys = [0.8, 1.9, 1.1, 1.6];
+    let (mut trace, _) = normal_normal.generate(&mut rng, ys.len(), normal_normal_observations(&ys));
+
+    let mut selection = AddrMap::new();
+    selection.visit("mu");
+    let mut samples = vec![];
+    let mut num_accepted = 0;
+    for _ in 0..2000 {
+        let (new_trace, accepted) = hmc(&mut rng, &normal_normal, trace, &selection, 0.2, 4).unwrap();
+        trace = new_trace;
+        num_accepted += accepted as usize;
+        samples.push(trace.data.read::<f64>("mu"));
+    }
+
+    // conjugate posterior N(sum(ys) / (n + 1), 1 / (n + 1))
+    let n = ys.len() as f64;
+    assert!(num_accepted > 1500);
+    approx::assert_abs_diff_eq!(mean(&samples), ys.iter().sum::<f64>() / (n + 1.), epsilon = 0.05);
+    approx::assert_abs_diff_eq!(variance(&samples), 1. / (n + 1.), epsilon = 0.05);
+
+    // the observations are untouched
+    for (i, y) in ys.iter().enumerate() {
+        assert_eq!(trace.data.read::<f64>(("y", i)), *y);
+    }
+}
+
+#[test]
+pub fn test_nuts_normal_normal() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let ys = [0.8, 1.9, 1.1, 1.6];
+    let (mut trace, _) = normal_normal.generate(&mut rng, ys.len(), normal_normal_observations(&ys));
+
+    let mut selection = AddrMap::new();
+    selection.visit("mu");
+    let mut nuts = Nuts::new(300);
+    let mut samples = vec![];
+    for _ in 0..1800 {
+        let warmup = nuts.is_warmup();
+        (trace, _) = nuts.step(&mut rng, &normal_normal, trace, &selection).unwrap();
+        if !warmup {
+            samples.push(trace.data.read::<f64>("mu"));
+        }
+    }
+
+    // the adapted inverse mass approximates the posterior variance
+    let n = ys.len() as f64;
+    let inv_mass = nuts.inv_mass.clone().unwrap();
+    approx::assert_abs_diff_eq!(inv_mass[0], 1. / (n + 1.), epsilon = 0.1);
+    assert!(nuts.step_size.unwrap() > 0.1);
+    approx::assert_abs_diff_eq!(mean(&samples), ys.iter().sum::<f64>() / (n + 1.), epsilon = 0.05);
+    approx::assert_abs_diff_eq!(variance(&samples), 1. / (n + 1.), epsilon = 0.05);
+}
+
+#[test]
+pub fn test_nuts_short_warmup() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let ys = [0.8, 1.9];
+    let mut selection = AddrMap::new();
+    selection.visit("mu");
+
+    // warmups too short to fill an adaptation window with 2 samples keep the identity mass matrix
+    for num_warmup in 0..5 {
+        let (mut trace, _) = normal_normal.generate(&mut rng, ys.len(), normal_normal_observations(&ys));
+        let mut nuts = Nuts::new(num_warmup);
+        for _ in 0..num_warmup + 10 {
+            (trace, _) = nuts.step(&mut rng, &normal_normal, trace, &selection).unwrap();
+            assert!(trace.data.read::<f64>("mu").is_finite());
+        }
+        assert!(nuts.step_size.unwrap().is_finite());
+        assert_eq!(nuts.inv_mass.is_some(), num_warmup >= 2);
+        assert!(nuts.inv_mass.iter().flatten().all(|x| x.is_finite() && *x > 0.));
+    }
+}
+
+#[test]
+pub fn test_nuts_mvnormal() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let cov = dmatrix![1., -3./5.; -3./5., 2.];
+    let mut observations = DynTrie::new();
+    observations.observe("obs", Arc::new(dvector![0., 0.]));
+    let (mut trace, _) = noisy_point_2d.generate(&mut rng, cov.clone(), observations);
+
+    let mut selection = AddrMap::new();
+    selection.visit("latent");
+    let mut nuts = Nuts::new(200);
+    let mut samples = vec![];
+    for _ in 0..1200 {
+        let warmup = nuts.is_warmup();
+        (trace, _) = nuts.step(&mut rng, &noisy_point_2d, trace, &selection).unwrap();
+        if !warmup {
+            samples.push(trace.data.read::<DVector<f64>>("latent"));
+        }
+    }
+
+    // under the wide prior, the posterior is (nearly) the observation noise centered at the observation
+    let xs = samples.iter().map(|v| v[0]).collect::<Vec<f64>>();
+    let ys = samples.iter().map(|v| v[1]).collect::<Vec<f64>>();
+    approx::assert_abs_diff_eq!(mean(&xs), 0., epsilon = 0.2);
+    approx::assert_abs_diff_eq!(mean(&ys), 0., epsilon = 0.2);
+    approx::assert_abs_diff_eq!(variance(&xs), cov[(0,0)], epsilon = 0.3);
+    approx::assert_abs_diff_eq!(variance(&ys), cov[(1,1)], epsilon = 0.4);
+}
+
+#[test]
+pub fn test_hmc_discrete_selection_err() {
+    let mut rng = StdRng::seed_from_u64(14);
+    dyngen!(
+    fn coin() -> f64 {
+        let p = beta(2., 2.) %= "p";
+        bernoulli(p) %= "flip";
+        p
+    });
+    let trace = coin.simulate(&mut rng, ());
+    let mut selection = AddrMap::new();
+    selection.visit("flip");
+    let result = hmc(&mut rng, &coin, trace, &selection, 0.1, 5);
+    assert!(matches!(result.err(), Some(GenFnError::InvalidArgs(_))));
+}
@@ -1,12 +1,9 @@
-use modppl::Trace;
+use modppl::{Trace,ParamStore};
 
 
-#[derive(Clone,Copy)]
-pub struct ParamStore { }
-
 pub type HMMTrace = Trace<(i64, ParamStore),(Vec<Option<usize>>,Vec<Option<usize>>),Vec<usize>>;
 
-pub fn extend(trace: &mut HMMTrace, new_state: usize, new_observation: usize) -> () {
+pub fn extend(trace: &mut HMMTrace, new_state: usize, new_observation: usize) {
     trace.data.0.push(Some(new_state));
     trace.data.1.push(Some(new_observation));
     trace.args.0 += 1;
@@ -1,17 +1,21 @@
+#![allow(non_upper_case_globals)]
+
 use std::any::Any;
 use std::sync::Arc;
 use std::fs::{write,create_dir_all};
-use rand::rngs::ThreadRng;
-use nalgebra::{dvector,dmatrix};
+use rand::{SeedableRng,rngs::{ThreadRng,StdRng}};
+use nalgebra::{DVector,DMatrix,dvector,dmatrix};
 
-use modppl::{Trace,Trie,Distribution,importance_sampling,normal,categorical};
+use modppl::{Trace,Trie,DynTrie,GenFnError,Distribution,DynGenFn,DynGenFnHandler,mvnormal,normal,categorical};
+use modppl::{importance_sampling,importance_sampling_with_proposal,importance_resampling_with_proposal};
+use modppl::prelude::dyngen;
 
 mod pointed_model;
 use pointed_model::types_2d::Bounds;
-use pointed_model::{PointedTrace,PointedModel};
+use pointed_model::{PointedTrace,PointedModel,ObsProposal};
 
 mod dyngenfns;
-use dyngenfns::{line_model, hierarchical_model};
+use dyngenfns::{line_model, hierarchical_model, pointed_2d_model};
 
 
 #[test]
@@ -28,7 +32,7 @@ fn test_importance_handcoded() -> std::io::Result<()> {
     let constraints = (None, Some(obs));
 
     let (traces, log_normalized_weights, log_ml_estimate) = 
-        modppl::importance_sampling(&model, bounds, constraints, NUM_SAMPLES);
+        modppl::importance_sampling(&mut rng, &model, bounds, constraints, NUM_SAMPLES).unwrap();
 
     dbg!(log_ml_estimate);
 
@@ -64,10 +68,10 @@ pub fn test_importance_dyngenfn() {
         .enumerate()
         .for_each(|(i, x)| {
             observations.observe(
-                Box::leak(format!("ys / {}", i).into_boxed_str()),
+                format!("ys / {}", i),
                 Arc::new(0.5*x - 1. + normal.random(&mut rng, (0., 0.1))) as Arc<dyn Any + Send + Sync>);
             });
-    let (traces, log_normalized_weights, lml_estimate) = importance_sampling(&line_model, xs, observations, NUM_SAMPLES);
+    let (traces, log_normalized_weights, lml_estimate) = importance_sampling(&mut rng, &line_model, xs, observations, NUM_SAMPLES).unwrap();
 
     let probs = log_normalized_weights.iter()
         .map(|w| w.exp())
@@ -76,10 +80,10 @@ pub fn test_importance_dyngenfn() {
         .map(|_| categorical.random(&mut rng, probs.clone()))
         .map(|idx| &traces[idx as usize])
         .collect::<Vec<&Trace<_,_,_>>>();
-    for i in 0..20 {
+    for (i, trace) in traces.iter().take(20).enumerate() {
         println!("Trace {}", i);
-        println!("slope = {}", &traces[i].data.read::<f64>("slope"));
-        println!("intercept = {}", &traces[i].data.read::<f64>("intercept"));
+        println!("slope = {}", &trace.data.read::<f64>("slope"));
+        println!("intercept = {}", &trace.data.read::<f64>("intercept"));
     }
     dbg!(lml_estimate);
 }
@@ -103,10 +107,10 @@ pub fn test_importance_hierarchical() -> std::io::Result<()> {
         a + b*x + c*x*x + normal.random(&mut rng, (0., 0.1))
     ).collect::<Vec<f64>>();
     write("../data/hierarchical_data.json", format!("[{:?}, {:?}]", xs, ys))?;
-    ys.into_iter().enumerate().for_each(|(i, y)| { observations.observe(&format!("(y, {})", i), Arc::new(y) as Arc<dyn Any + Send + Sync>); });
+    ys.into_iter().enumerate().for_each(|(i, y)| { observations.observe(format!("(y, {})", i), Arc::new(y) as Arc<dyn Any + Send + Sync>); });
 
     let (traces, log_normalized_weights, lml_estimate) =
-        importance_sampling(&hierarchical_model, xs, observations, NUM_SAMPLES);
+        importance_sampling(&mut rng, &hierarchical_model, xs, observations, NUM_SAMPLES).unwrap();
     // dbg!(&traces[0].data);
     // return Ok(());
 
@@ -118,14 +122,14 @@ pub fn test_importance_hierarchical() -> std::io::Result<()> {
         .map(|idx| &traces[idx as usize])
         .collect::<Vec<&Trace<_,_,_>>>();
     let mut all_coeffs = vec![];
-    for i in 0..20 {
+    for (i, trace) in traces.iter().take(20).enumerate() {
         println!("Trace {}", i);
-        let is_linear = &traces[i].data.read::<bool>("is_linear");
+        let is_linear = &trace.data.read::<bool>("is_linear");
         println!("is_linear = {}", is_linear);
-        let a = traces[i].data.read::<f64>("coeffs / a");
-        let b = traces[i].data.read::<f64>("coeffs / b");
+        let a = trace.data.read::<f64>("coeffs / a");
+        let b = trace.data.read::<f64>("coeffs / b");
         let coeffs = if !*is_linear {
-            let c = traces[i].data.read::<f64>("coeffs / c");
+            let c = trace.data.read::<f64>("coeffs / c");
             vec![a, b, c]
         } else {
             vec![a, b]
@@ -137,4 +141,62 @@ pub fn test_importance_hierarchical() -> std::io::Result<()> {
 
     dbg!(lml_estimate);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_importance_with_proposal_handcoded() {
+    let mut rng = StdRng::seed_from_u64(3);
+
+    let model = PointedModel { obs_cov: dmatrix![1., -3./5.; -3./5., 2.] };
+    let proposal = ObsProposal { cov: dmatrix![1., -3./5.; -3./5., 2.] };
+    let bounds = Bounds { xmin: -5., xmax: 5., ymin: -5., ymax: 5. };
+    let obs = dvector![0., 0.];
+
+    // the proposal matches the posterior (up to the bounds), so every weight inside the bounds is the evidence
+    let (traces, log_normalized_weights, log_ml_estimate) = importance_sampling_with_proposal(
+        &mut rng, &model, bounds, (None, Some(obs.clone())), &proposal, obs.clone(), 1000
+    ).unwrap();
+    approx::assert_abs_diff_eq!(log_ml_estimate, (0.01_f64).ln(), epsilon = 0.01);
+    assert_eq!(traces.len(), 1000);
+    assert_eq!(traces[0].data.1, Some(obs.clone()));
+    approx::assert_abs_diff_eq!(log_normalized_weights.iter().map(|w| w.exp()).sum::<f64>(), 1., epsilon = 1e-10);
+
+    let (traces, resampled_indices, _) = importance_resampling_with_proposal(
+        &mut rng, &model, bounds, (None, Some(obs.clone())), &proposal, obs.clone(), 100, 10
+    ).unwrap();
+    assert_eq!(resampled_indices.len(), 10);
+    assert!(resampled_indices.iter().all(|&i| i < traces.len()));
+
+    // proposing a constrained choice is an error
+    let result = importance_sampling_with_proposal(&mut rng, &model, bounds, (Some(obs.clone()), Some(obs.clone())), &proposal, obs, 10);
+    assert_eq!(result.err(), Some(GenFnError::AddressCollision("0".to_string())));
+}
+
+#[test]
+pub fn test_importance_with_proposal_dyngenfn() {
+    let mut rng = StdRng::seed_from_u64(4);
+
+    let bounds = Bounds { xmin: -5., xmax: 5., ymin: -5., ymax: 5. };
+    let cov = dmatrix![1., -3./5.; -3./5., 2.];
+    let obs = dvector![1., -1.];
+    let mut observations = DynTrie::new();
+    observations.observe("obs", Arc::new(obs.clone()));
+
+    let proposal = dyngen!(|center: DVector<f64>, cov: DMatrix<f64>| {
+        mvnormal(center, cov) %= "latent";
+    });
+    let (_, _, log_ml_estimate) = importance_sampling_with_proposal(
+        &mut rng, &pointed_2d_model, (bounds, cov.clone()), observations.clone(), &proposal, (obs.clone(), cov.clone()), 1000
+    ).unwrap();
+    approx::assert_abs_diff_eq!(log_ml_estimate, (0.01_f64).ln(), epsilon = 0.01);
+
+    // proposing an observed choice is an error
+    let bad_proposal = dyngen!(|center: DVector<f64>, cov: DMatrix<f64>| {
+        mvnormal(center.clone(), cov.clone()) %= "latent";
+        mvnormal(center, cov) %= "obs";
+    });
+    let result = importance_sampling_with_proposal(
+        &mut rng, &pointed_2d_model, (bounds, cov.clone()), observations, &bad_proposal, (obs, cov), 10
+    );
+    assert_eq!(result.err(), Some(GenFnError::AddressCollision("obs".to_string())));
+}
@@ -0,0 +1,149 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::{Arc,Weak};
+use nalgebra::{DVector,dvector};
+use modppl::prelude::*;
+
+
+// a mean `z` of one or two components `(z1, z2)`, with an observation `y` of the (average) mean
+dyngen!(
+fn split_merge_model() {
+    let split = bernoulli(0.5) %= "split";
+    let mean = if split {
+        let z1 = normal(0., 1.) %= "z1";
+        let z2 = normal(0., 1.) %= "z2";
+        (z1 + z2) / 2.
+    } else {
+        normal(0., 1.) %= "z"
+    };
+    normal(mean, 0.5) %= "y";
+});
+
+// proposes the auxiliary offset `u` of a split (merges need no auxiliary choices)
+dyngen!(
+fn split_merge_proposal(trace: Weak<DynTrace<(),()>>, u_std: f64) {
+    let trace = trace.upgrade().unwrap();
+    if !trace.data.read::<bool>("split") {
+        normal(0., u_std) %= "u";
+    }
+});
+
+type SplitMergeTrace = DynTrace<(),()>;
+
+// the map (z, u) <-> (z1, z2) on the continuous choices
+fn split(x: &DVector<Var>) -> DVector<Var> {
+    dvector![x[0] + x[1], x[0] - x[1]]
+}
+
+fn merge(x: &DVector<Var>) -> DVector<Var> {
+    dvector![(x[0] + x[1]) / 2., (x[0] - x[1]) / 2.]
+}
+
+// apply a map over `Var`s to values
+fn apply(f: fn(&DVector<Var>) -> DVector<Var>, x: &DVector<f64>) -> DVector<f64> {
+    f(&x.map(Var::constant)).map(|y| y.value())
+}
+
+fn split_merge_involution(trace: &SplitMergeTrace, fwd_choices: DynTrie) -> Result<(DynTrie,DynTrie,f64),GenFnError> {
+    let mut constraints = DynTrie::new();
+    let mut bwd_choices = DynTrie::new();
+    if !trace.data.read::<bool>("split") {
+        let x = dvector![trace.data.read::<f64>("z"), fwd_choices.read::<f64>("u")];
+        let z = apply(split, &x);
+        constraints.observe("split", Arc::new(true));
+        constraints.observe("z1", Arc::new(z[0]));
+        constraints.observe("z2", Arc::new(z[1]));
+        Ok((constraints, bwd_choices, log_abs_det_jacobian(split, &x)?))
+    } else {
+        let x = dvector![trace.data.read::<f64>("z1"), trace.data.read::<f64>("z2")];
+        let zu = apply(merge, &x);
+        constraints.observe("split", Arc::new(false));
+        constraints.observe("z", Arc::new(zu[0]));
+        bwd_choices.observe("u", Arc::new(zu[1]));
+        Ok((constraints, bwd_choices, log_abs_det_jacobian(merge, &x)?))
+    }
+}
+
+
+#[test]
+fn test_log_abs_det_jacobian() {
+    let x = dvector![0.3, -1.2];
+    approx::assert_abs_diff_eq!(log_abs_det_jacobian(split, &x).unwrap(), 2_f64.ln(), epsilon = 1e-12);
+    approx::assert_abs_diff_eq!(log_abs_det_jacobian(merge, &apply(split, &x)).unwrap(), -2_f64.ln(), epsilon = 1e-12);
+
+    // the log Jacobian of a nonlinear map varies with `x`
+    let exp = |x: &DVector<Var>| x.map(Var::exp);
+    approx::assert_abs_diff_eq!(log_abs_det_jacobian(exp, &x).unwrap(), x.sum(), epsilon = 1e-12);
+    let polar = |x: &DVector<Var>| dvector![x[0] * x[1].cos(), x[0] * x[1].sin()];
+    approx::assert_abs_diff_eq!(log_abs_det_jacobian(polar, &dvector![2.5, 0.7]).unwrap(), 2.5_f64.ln(), epsilon = 1e-12);
+
+    // `f` must preserve the dimension
+    let project = |x: &DVector<Var>| dvector![x[0] + x[1]];
+    assert!(matches!(log_abs_det_jacobian(project, &x), Err(GenFnError::InvalidArgs(_))));
+}
+
+#[test]
+fn test_involutive_mh_split_merge() {
+    let mut rng = StdRng::seed_from_u64(80);
+    let y = 1.5;
+    let mut observations = DynTrie::new();
+    observations.observe("y", Arc::new(y));
+
+    // y ~ N(0, 1 + 0.25) with one component, and y ~ N(0, 1/2 + 0.25) with two
+    let log_evidence_merged = normal.logpdf(&y, (0., 1.25_f64.sqrt()));
+    let log_evidence_split = normal.logpdf(&y, (0., 0.75_f64.sqrt()));
+    let expected_prob_split = 1. / (1. + (log_evidence_merged - log_evidence_split).exp());
+
+    let model = split_merge_model;
+    let proposal = split_merge_proposal;
+    let mut mask = AddrMap::new();
+    mask.visit("z");
+    mask.visit("z1");
+    mask.visit("z2");
+    let mut kernel = InvolutiveMh::new(&model, &proposal, 0.5, split_merge_involution)
+        .then(RegenMh::new(&model, mask));
+
+    const NUM_ITERS: usize = 20000;
+    let (mut trace, _) = model.generate(&mut rng, (), observations);
+    let (mut num_split, mut num_dimension_changes) = (0, 0);
+    let mut stats = AcceptStats::default();
+    for _ in 0..NUM_ITERS {
+        let was_split = trace.data.read::<bool>("split");
+        let step_stats;
+        (trace, step_stats) = kernel.apply(&mut rng, trace).unwrap();
+        stats += step_stats;
+
+        let is_split = trace.data.read::<bool>("split");
+        num_split += is_split as usize;
+        num_dimension_changes += (is_split != was_split) as usize;
+        if is_split {
+            assert!(trace.data.search("z").is_none());
+        } else {
+            assert!(trace.data.search("z1").is_none() && trace.data.search("z2").is_none());
+        }
+    }
+    assert!(num_dimension_changes > NUM_ITERS / 10);
+    assert!(stats.acceptance_rate() > 0.1);
+    approx::assert_abs_diff_eq!(num_split as f64 / NUM_ITERS as f64, expected_prob_split, epsilon = 0.03);
+}
+
+#[test]
+fn test_involutive_mh_errors() {
+    let mut rng = StdRng::seed_from_u64(81);
+    let model = split_merge_model;
+    let proposal = split_merge_proposal;
+    let (trace, _) = model.generate(&mut rng, (), DynTrie::new());
+
+    let result = involutive_mh(&mut rng, &model, trace.clone(), &proposal, 0.5, |_, _| {
+        Err(GenFnError::Unimplemented("involution"))
+    });
+    assert!(result.is_err());
+
+    // backward choices the proposal doesn't make are an error, rather than a rejection
+    let result = involutive_mh(&mut rng, &model, trace, &proposal, 0.5, |trace: &SplitMergeTrace, fwd_choices| {
+        let (constraints, mut bwd_choices, log_abs_det_jacobian) = split_merge_involution(trace, fwd_choices)?;
+        bwd_choices.observe("v", Arc::new(0.));
+        Ok((constraints, bwd_choices, log_abs_det_jacobian))
+    });
+    assert!(result.is_err());
+}
@@ -0,0 +1,147 @@
+#![allow(non_upper_case_globals)]
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use modppl::prelude::*;
+
+mod pointed_model;
+
+mod dyngenfns;
+use dyngenfns::{gaussian_model, mu_drift_proposal, gaussian_observations, hierarchical_model, hierarchical_drift_proposal, add_or_remove_param_proposal};
+
+
+type GaussianTrace = DynTrace<usize,()>;
+
+// a kernel that records its `name` when applied, and accepts every other move
+fn logging_kernel<'a>(name: &'a str, log: &'a RefCell<Vec<&'a str>>) -> impl Kernel<usize,DynTrie,()> + 'a {
+    kernel_fn(move |_, trace: GaussianTrace| {
+        log.borrow_mut().push(name);
+        Ok((trace, log.borrow().len() % 2 == 0))
+    })
+}
+
+
+#[test]
+fn test_kernel_combinators() {
+    let mut rng = StdRng::seed_from_u64(70);
+    let trace = gaussian_model.simulate(&mut rng, 0);
+    let log = RefCell::new(vec![]);
+
+    // sequence and repeat
+    let mut kernel = logging_kernel("a", &log).then(logging_kernel("b", &log).repeat(2)).repeat(2);
+    let (trace, stats) = kernel.apply(&mut rng, trace).unwrap();
+    assert_eq!(*log.borrow(), ["a", "b", "b", "a", "b", "b"]);
+    assert_eq!(stats, AcceptStats { num_proposed: 6, num_accepted: 3 });
+    assert_eq!(stats.acceptance_rate(), 0.5);
+
+    // cycle applies one kernel at a time, in order
+    log.borrow_mut().clear();
+    let mut kernel = Cycle::new(vec![logging_kernel("a", &log).boxed(), logging_kernel("b", &log).boxed(), logging_kernel("c", &log).boxed()]);
+    let mut trace = trace;
+    for _ in 0..4 {
+        let stats;
+        (trace, stats) = kernel.apply(&mut rng, trace).unwrap();
+        assert_eq!(stats.num_proposed, 1);
+    }
+    assert_eq!(*log.borrow(), ["a", "b", "c", "a"]);
+
+    // mixture chooses each kernel in proportion to its weight
+    log.borrow_mut().clear();
+    let mut kernel = Mixture::new(vec![(1., logging_kernel("a", &log).boxed()), (3., logging_kernel("b", &log).boxed())]);
+    for _ in 0..4000 {
+        (trace, _) = kernel.apply(&mut rng, trace).unwrap();
+    }
+    let num_a = log.borrow().iter().filter(|name| **name == "a").count();
+    approx::assert_abs_diff_eq!(num_a as f64 / 4000., 0.25, epsilon = 0.03);
+
+    // conditional
+    log.borrow_mut().clear();
+    let mut kernel = Conditional::new(|trace: &GaussianTrace| trace.args == 0, logging_kernel("a", &log), logging_kernel("b", &log))
+        .then(logging_kernel("c", &log).when(|trace: &GaussianTrace| trace.args > 0));
+    let (trace, stats) = kernel.apply(&mut rng, trace).unwrap();
+    assert_eq!(*log.borrow(), ["a"]);
+    assert_eq!(stats.num_proposed, 1);
+
+    // the identity proposes nothing
+    let (_, stats) = Identity.apply(&mut rng, trace).unwrap();
+    assert_eq!(stats, AcceptStats::default());
+}
+
+#[test]
+#[should_panic(expected = "`kernels` must not be empty")]
+fn test_empty_cycle_panic() {
+    Cycle::<usize,DynTrie,()>::new(vec![]);
+}
+
+#[test]
+#[should_panic(expected = "must be finite and non-negative")]
+fn test_mixture_negative_weight_panic() {
+    Mixture::<usize,DynTrie,()>::new(vec![(1., Identity.boxed()), (-1., Identity.boxed())]);
+}
+
+#[test]
+#[should_panic(expected = "at least one weight must be positive")]
+fn test_mixture_zero_weights_panic() {
+    Mixture::<usize,DynTrie,()>::new(vec![(0., Identity.boxed()), (0., Identity.boxed())]);
+}
+
+#[test]
+fn test_mh_kernels() {
+    let mut rng = StdRng::seed_from_u64(71);
+    let ys = [1.2, 0.4, 2.1, 1.5, 0.9];
+    let observations = gaussian_observations(&ys);
+    let (expected_mean, expected_var) = (ys.iter().sum::<f64>() / 6., 1. / 6.);
+
+    let (model, proposal) = (gaussian_model, mu_drift_proposal);
+    let mut mask = AddrMap::new();
+    mask.visit("mu");
+    let mut kernel = RegenMh::new(&model, mask)
+        .then(Mh::new(&model, &proposal, 0.5).repeat(3));
+
+    let mut trace = model.generate(&mut rng, ys.len(), observations).0;
+    let mut stats = AcceptStats::default();
+    let mut samples = vec![];
+    for _ in 0..4000 {
+        let step_stats;
+        (trace, step_stats) = kernel.apply(&mut rng, trace).unwrap();
+        stats += step_stats;
+        samples.push(trace.data.read::<f64>("mu"));
+    }
+    assert_eq!(stats.num_proposed, 4 * 4000);
+    assert!(stats.acceptance_rate() > 0.2 && stats.acceptance_rate() < 1.);
+
+    let mean = samples.iter().sum::<f64>() / 4000.;
+    let var = samples.iter().map(|mu| (mu - mean).powi(2)).sum::<f64>() / 4000.;
+    approx::assert_abs_diff_eq!(mean, expected_mean, epsilon = 0.05);
+    approx::assert_abs_diff_eq!(var, expected_var, epsilon = 0.03);
+
+    // errors are propagated
+    let mut kernel = Identity.then(kernel_fn(|_, _: GaussianTrace| Err(GenFnError::Unimplemented("kernel"))));
+    assert_eq!(kernel.apply(&mut rng, trace).err(), Some(GenFnError::Unimplemented("kernel")));
+}
+
+#[test]
+fn test_hierarchical_kernel() {
+    let mut rng = StdRng::seed_from_u64(72);
+    let xs = vec![-5.,-4.,-3.,-2.,-1.,0.,1.,2.,3.,4.,5.];
+    let mut observations = DynTrie::new();
+    xs.iter().enumerate().for_each(|(i, x)| { observations.observe(("y", i as i64), Arc::new(0.3 + 0.4*x + 0.5*x*x)); });
+
+    // jump between the models, then drift the coefficients, and regenerate `c` only under the quadratic model
+    let mut mask = AddrMap::new();
+    mask.visit("coeffs / c");
+    let (model, jump_proposal, drift_proposal) = (hierarchical_model, add_or_remove_param_proposal, hierarchical_drift_proposal);
+    let mut kernel = Mh::new(&model, &jump_proposal, ())
+        .then(Mixture::uniform(vec![
+            Mh::new(&model, &drift_proposal, 0.1).boxed(),
+            Mh::new(&model, &drift_proposal, 0.01).boxed()
+        ]).repeat(10))
+        .then(RegenMh::new(&model, mask).when(|trace: &DynTrace<Vec<f64>,Vec<f64>>| !trace.data.read::<bool>("is_linear")));
+
+    let mut trace = model.generate(&mut rng, xs, observations).0;
+    for _ in 0..500 {
+        (trace, _) = kernel.apply(&mut rng, trace).unwrap();
+    }
+    assert!(!trace.data.read::<bool>("is_linear"));
+    approx::assert_abs_diff_eq!(trace.data.read::<f64>("coeffs / c"), 0.5, epsilon = 0.05);
+}
@@ -23,6 +23,7 @@ fn test_metropolis_hastings_handcoded() -> std::io::Result<()> {
     create_dir_all("../data")?;
 
     const NUM_ITERS: u32 = 25000;
+    let mut rng = ThreadRng::default();
 
     let model = PointedModel { obs_cov: dmatrix![1., -3./5.; -3./5., 2.] };
     let proposal = DriftProposal { drift_cov: dmatrix![0.25, 0.; 0., 0.25] };
@@ -31,10 +32,10 @@ fn test_metropolis_hastings_handcoded() -> std::io::Result<()> {
 
     let constraints = (None, Some(obs));
 
-    let (mut trace, _) = model.generate(bounds, constraints);
+    let (mut trace, _) = model.generate(&mut rng, bounds, constraints);
     for iter in 0..NUM_ITERS {
         dbg!(iter);
-        let (new_trace, accepted) = modppl::mh(&model, trace, &proposal, ());
+        let (new_trace, accepted) = modppl::mh(&mut rng, &model, trace, &proposal, ()).unwrap();
         dbg!(accepted);
         trace = new_trace;
         let data = trace.data.0.clone().unwrap();
@@ -51,6 +52,7 @@ pub fn test_metropolis_hastings_dyngenfn() -> std::io::Result<()> {
     create_dir_all("../data")?;
 
     const NUM_ITERS: u32 = 25000;
+    let mut rng = ThreadRng::default();
 
     let bounds = Bounds { xmin: -5., xmax: 5., ymin: -5., ymax: 5. };
     let obs = dvector![0., 0.];
@@ -58,10 +60,10 @@ pub fn test_metropolis_hastings_dyngenfn() -> std::io::Result<()> {
     let mut observations = Trie::new();
     observations.observe("obs", Arc::new(obs) as Arc<dyn Any + Send + Sync>);
 
-    let mut trace = pointed_2d_model.generate((bounds, dmatrix![1., -3./5.; -3./5., 2.]), observations).0;
+    let mut trace = pointed_2d_model.generate(&mut rng, (bounds, dmatrix![1., -3./5.; -3./5., 2.]), observations).0;
     for iter in 0..NUM_ITERS {
         dbg!(iter);
-        let (new_trace, accepted) = mh(&pointed_2d_model, trace, &pointed_2d_drift_proposal, dmatrix![0.25, 0.; 0., 0.25]);
+        let (new_trace, accepted) = mh(&mut rng, &pointed_2d_model, trace, &pointed_2d_drift_proposal, dmatrix![0.25, 0.; 0., 0.25]).unwrap();
         dbg!(accepted);
         trace = new_trace;
         let data = trace.data.read::<DVector<f64>>("latent");
@@ -86,25 +88,63 @@ pub fn test_metropolis_hastings_hierarchical() -> std::io::Result<()> {
         a + b*x + c*x*x + normal.random(&mut rng, (0., 0.1))
     ).collect::<Vec<f64>>();
     write("../data/hierarchical_data.json", format!("[{:?}, {:?}]", xs, ys))?;
-    ys.into_iter().enumerate().for_each(|(i, y)| { observations.observe(&format!("(y, {})", i), Arc::new(y)); });
+    ys.into_iter().enumerate().for_each(|(i, y)| { observations.observe(format!("(y, {})", i), Arc::new(y)); });
 
-    let mut trace = hierarchical_model.generate(xs, observations).0;
+    let mut trace = hierarchical_model.generate(&mut rng, xs, observations).0;
     let mut all_coeffs = vec![];
     for _ in 0..100 {
-        let (new_trace, _) = mh(&hierarchical_model, trace, &add_or_remove_param_proposal, ());
+        let (new_trace, _) = mh(&mut rng, &hierarchical_model, trace, &add_or_remove_param_proposal, ()).unwrap();
         trace = new_trace;
         all_coeffs.push(read_coeffs(&trace));
         for _ in 0..3 {
-            let (new_trace, _) = mh(&hierarchical_model, trace, &hierarchical_drift_proposal, 0.1);
+            let (new_trace, _) = mh(&mut rng, &hierarchical_model, trace, &hierarchical_drift_proposal, 0.1).unwrap();
             trace = new_trace;
             all_coeffs.push(read_coeffs(&trace));
         }
         for _ in 0..10 {
-            let (new_trace, _) = mh(&hierarchical_model, trace, &hierarchical_drift_proposal, 0.01);
+            let (new_trace, _) = mh(&mut rng, &hierarchical_model, trace, &hierarchical_drift_proposal, 0.01).unwrap();
             trace = new_trace;
             all_coeffs.push(read_coeffs(&trace));
         }
         write("../data/hierarchical_model.json", format!("{:?}", all_coeffs))?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+pub fn test_metropolis_hastings_seeded_reproducible() {
+    let xs = vec![-5.,-4.,-3.,-2.,-1.,0.,1.,2.,3.,4.,5.];
+    let mut observations = DynTrie::new();
+    for (i, x) in xs.iter().enumerate() {
+        observations.observe(format!("(y, {})", i), Arc::new(0.3 + 0.4*x));
+    }
+
+    let run_chain = |seed: u64| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut trace = hierarchical_model.generate(&mut rng, xs.clone(), observations.clone()).0;
+        let mut mask = AddrMap::new();
+        mask.visit("coeffs");
+        let mut all_coeffs = vec![];
+        for _ in 0..50 {
+            (trace, _) = regen_mh(&mut rng, &hierarchical_model, trace, &mask).unwrap();
+            (trace, _) = mh(&mut rng, &hierarchical_model, trace, &hierarchical_drift_proposal, 0.1).unwrap();
+            all_coeffs.push(read_coeffs(&trace));
+        }
+        (all_coeffs, trace.logjp)
+    };
+
+    assert_eq!(run_chain(42), run_chain(42));
+    assert_ne!(run_chain(42), run_chain(43));
+}
+
+#[test]
+pub fn test_regen_mh_unimplemented_err() {
+    let mut rng = ThreadRng::default();
+    let model = PointedModel { obs_cov: dmatrix![1., -3./5.; -3./5., 2.] };
+    let bounds = Bounds { xmin: -5., xmax: 5., ymin: -5., ymax: 5. };
+    let (trace, _) = model.generate(&mut rng, bounds, (None, Some(dvector![0., 0.])));
+    let mut mask = AddrMap::new();
+    mask.visit("latent");
+    let result = regen_mh(&mut rng, &model, trace, &mask);
+    assert_eq!(result.err(), Some(GenFnError::Unimplemented("regenerate")));
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "parallel")]
+#![allow(non_upper_case_globals)]
+
+use nalgebra::{dvector,dmatrix};
+
+use modppl::prelude::*;
+
+mod hmm;
+mod pointed_model;
+mod dyngenfns;
+use dyngenfns::{normal_normal, normal_normal_observations};
+
+
+#[test]
+fn test_par_importance_sampling() {
+    let ys = [0.8, 1.9, 1.1, 1.6];
+    let n = ys.len() as f64;
+    let mean = ys.iter().sum::<f64>() / n;
+    // marginal likelihood of ys ~ N(0, I + 11^T)
+    let expected = -0.5 * n * (2. * std::f64::consts::PI).ln() - 0.5 * (n + 1.).ln()
+        - 0.5 * (ys.iter().map(|y| y * y).sum::<f64>() - n * n * mean * mean / (n + 1.));
+
+    let run = |seed: u64| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        par_importance_sampling(&mut rng, &normal_normal, ys.len(), normal_normal_observations(&ys), 20000).unwrap()
+    };
+    let (traces, log_normalized_weights, log_ml_estimate) = run(1);
+    assert_eq!(traces.len(), 20000);
+    approx::assert_abs_diff_eq!(log_ml_estimate, expected, epsilon = 0.05);
+
+    // the same seed gives the same samples, however they are scheduled
+    let (same_traces, same_weights, same_estimate) = run(1);
+    assert_eq!(same_weights, log_normalized_weights);
+    assert_eq!(same_estimate, log_ml_estimate);
+    assert!(traces.iter().zip(&same_traces).all(|(a, b)| a.data.read::<f64>("mu") == b.data.read::<f64>("mu")));
+
+    let mut rng = StdRng::seed_from_u64(2);
+    let (_, indices, _) = par_importance_resampling(&mut rng, &normal_normal, ys.len(), normal_normal_observations(&ys), 1000, 50).unwrap();
+    assert_eq!(indices.len(), 50);
+
+    // the indices are independent draws, like those of `importance_resampling` (rather than sorted)
+    assert!(indices.windows(2).any(|pair| pair[0] > pair[1]));
+}
+
+#[test]
+fn test_par_particle_filter() {
+    let prior = dvector![0.2, 0.3, 0.5];
+    let emission_matrix = dmatrix![
+        0.1, 0.2, 0.7;
+        0.2, 0.7, 0.1;
+        0.7, 0.2, 0.1
+    ].transpose();
+    let transition_matrix = dmatrix![
+        0.4, 0.4, 0.2;
+        0.2, 0.3, 0.5;
+        0.9, 0.05, 0.05
+    ].transpose();
+    let data = vec![0, 0, 1, 2];
+    let expected = hmm::hmm_forward_alg(prior.clone(), emission_matrix.clone(), transition_matrix.clone(), &data).ln();
+
+    let run_filter = |seed: u64| {
+        let params = hmm::HMMParams::new(prior.clone(), emission_matrix.clone(), transition_matrix.clone());
+        let mut filter = ParticleSystem::new(hmm::HMM::new(params), 5000, StdRng::seed_from_u64(seed));
+        let mut data_it = data.clone().into_iter();
+        filter.par_init_step(ParamStore::new(), (vec![None], vec![data_it.next()])).unwrap();
+        for obs in data_it {
+            filter.par_step((vec![None], vec![Some(obs)])).unwrap();
+            filter.resample();
+            let num_accepted = filter.par_rejuvenate(|_, _, trace| Ok((trace, false))).unwrap();
+            assert_eq!(num_accepted, 0);
+        }
+        let states = filter.traces.iter().map(|tr| tr.data.0.clone()).collect::<Vec<_>>();
+        (states, filter.log_marginal_likelihood_estimate())
+    };
+
+    let (states, lml_estimate) = run_filter(3);
+    assert_eq!(states.len(), 5000);
+    approx::assert_abs_diff_eq!(lml_estimate, expected, epsilon = 0.03);
+    assert_eq!(run_filter(3), (states, lml_estimate));
+}
+
+#[test]
+fn test_par_mcmc() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let ys = [0.8, 1.9, 1.1, 1.6];
+    let n = ys.len() as f64;
+    let traces = (0..4)
+        .map(|_| normal_normal.generate(&mut rng, ys.len(), normal_normal_observations(&ys)).0)
+        .collect::<Vec<_>>();
+
+    let mut mask = AddrMap::new();
+    mask.visit("mu");
+    let chains = par_mcmc(&mut rng, traces, 2000,
+        |rng, trace| regen_mh(rng, &normal_normal, trace, &mask),
+        |trace| trace.data.read::<f64>("mu")
+    ).unwrap();
+    assert_eq!(chains.len(), 4);
+
+    // conjugate posterior N(sum(ys) / (n + 1), 1 / (n + 1))
+    for (_, samples, num_accepted) in chains.iter() {
+        assert_eq!(samples.len(), 2000);
+        assert!(*num_accepted > 200);
+    }
+    let samples = chains.iter().flat_map(|(_, samples, _)| samples[500..].to_vec()).collect::<Vec<f64>>();
+    let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+    approx::assert_abs_diff_eq!(mean, ys.iter().sum::<f64>() / (n + 1.), epsilon = 0.05);
+}
@@ -0,0 +1,124 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::Arc;
+use nalgebra::{DVector, dvector};
+
+use modppl::prelude::*;
+
+
+dyngen!(
+fn gaussian(store: ParamStore, n: usize) -> () {
+    let mu = store.var("mu");
+    let std = store.var("log_std").exp();
+    for i in 0..n {
+        ad::normal(mu, std) %= ("y", i);
+    }
+});
+
+dyngen!(
+fn gaussian_2d(store: ParamStore, n: usize) -> () {
+    let mu = store.vars("mu");
+    for i in 0..n {
+        ad::normal(mu[0], 1.) %= ("x", i);
+        ad::normal(mu[1], 1.) %= ("y", i);
+    }
+});
+
+fn observations(ys: &[f64]) -> DynTrie {
+    let mut observations = DynTrie::new();
+    for (i, y) in ys.iter().enumerate() {
+        observations.observe(("y", i), Arc::new(*y));
+    }
+    observations
+}
+
+
+#[test]
+pub fn test_param_store() {
+    let store = ParamStore::new();
+    assert!(store.is_empty());
+    store.init("theta", 0.5);
+    store.init(("w", 1), dvector![1., 2.]);
+    assert_eq!(store.names().len(), 2);
+    assert_eq!(store.get::<f64>("theta"), 0.5);
+    assert_eq!(store.get::<DVector<f64>>("(w, 1)"), dvector![1., 2.]);
+    assert!(matches!(store.try_get::<DVector<f64>>("theta"), Err(GenFnError::TypeMismatch { .. })));
+    assert_eq!(store.try_get::<f64>("phi"), Err(GenFnError::AddressNotFound("phi".to_string())));
+
+    // clones share the parameters
+    let shared = store.clone();
+    shared.accumulate_grad(("w", 1), &dvector![0.5, -1.], 2.).unwrap();
+    shared.accumulate_grad(("w", 1), &dvector![1., 1.], 1.).unwrap();
+    assert_eq!(store.grad::<DVector<f64>>(("w", 1)), dvector![2., -1.]);
+    assert_eq!(store.grad::<f64>("theta"), 0.);
+    store.zero_grads();
+    assert_eq!(shared.grad::<DVector<f64>>(("w", 1)), dvector![0., 0.]);
+}
+
+#[test]
+pub fn test_accumulate_param_gradients() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let store = ParamStore::new();
+    store.init("mu", 0.3);
+    store.init("log_std", 0.2);
+    let ys = [1.2, -0.4, 2.5];
+    let (trace, _) = gaussian.generate(&mut rng, (store.clone(), ys.len()), observations(&ys));
+
+    gaussian.accumulate_param_gradients(&mut rng, &trace, &store, 2.);
+    let std = 0.2_f64.exp();
+    let expected_mu_grad = ys.iter().map(|y| (y - 0.3) / (std * std)).sum::<f64>();
+    let expected_log_std_grad = ys.iter().map(|y| (y - 0.3) * (y - 0.3) / (std * std) - 1.).sum::<f64>();
+    approx::assert_abs_diff_eq!(store.grad::<f64>("mu"), 2. * expected_mu_grad, epsilon = 1e-4);
+    approx::assert_abs_diff_eq!(store.grad::<f64>("log_std"), 2. * expected_log_std_grad, epsilon = 1e-4);
+
+    // the parameters are restored
+    assert_eq!(store.get::<f64>("mu"), 0.3);
+    assert_eq!(store.get::<f64>("log_std"), 0.2);
+}
+
+#[test]
+pub fn test_maximum_likelihood_adam() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let store = ParamStore::new();
+    store.init("mu", 0.);
+    store.init("log_std", 0.);
+    let ys = [1.2, -0.4, 2.5, 0.9, 1.6];
+
+    let mut optimizer = Adam::new(0.05);
+    for _ in 0..1000 {
+        let (trace, _) = gaussian.generate(&mut rng, (store.clone(), ys.len()), observations(&ys));
+        gaussian.accumulate_param_gradients(&mut rng, &trace, &store, 1.);
+        optimizer.apply(&store);
+    }
+
+    let n = ys.len() as f64;
+    let mean = ys.iter().sum::<f64>() / n;
+    let std = (ys.iter().map(|y| (y - mean) * (y - mean)).sum::<f64>() / n).sqrt();
+    approx::assert_abs_diff_eq!(store.get::<f64>("mu"), mean, epsilon = 1e-3);
+    approx::assert_abs_diff_eq!(store.get::<f64>("log_std").exp(), std, epsilon = 1e-3);
+    assert_eq!(store.grad::<f64>("mu"), 0.);
+}
+
+#[test]
+pub fn test_maximum_likelihood_sgd() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let store = ParamStore::new();
+    store.init("mu", dvector![0., 0.]);
+    let xs = [0.5, 1.5, 1.];
+    let ys = [-2., -1., -3.];
+    let mut constraints = DynTrie::new();
+    for i in 0..xs.len() {
+        constraints.observe(("x", i), Arc::new(xs[i]));
+        constraints.observe(("y", i), Arc::new(ys[i]));
+    }
+
+    let mut optimizer = Sgd::new(0.1);
+    for _ in 0..100 {
+        let (trace, _) = gaussian_2d.generate(&mut rng, (store.clone(), xs.len()), constraints.clone());
+        gaussian_2d.accumulate_param_gradients(&mut rng, &trace, &store, 1.);
+        optimizer.apply(&store);
+    }
+    let mu = store.get::<DVector<f64>>("mu");
+    approx::assert_abs_diff_eq!(mu[0], 1., epsilon = 1e-4);
+    approx::assert_abs_diff_eq!(mu[1], -2., epsilon = 1e-4);
+}
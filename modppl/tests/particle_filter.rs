@@ -1,7 +1,7 @@
-use rand::rngs::ThreadRng;
+use rand::{SeedableRng,rngs::{ThreadRng,StdRng}};
 use nalgebra::{dvector,dmatrix};
 
-use modppl::ParticleSystem;
+use modppl::{ParticleSystem,ResamplingScheme,ParamStore,smc};
 
 mod hmm;
 
@@ -57,15 +57,15 @@ fn test_particle_filter() -> std::io::Result<()> {
 
     let mut filter = ParticleSystem::new(model, NUM_PARTICLES, rng);
 
-    let store = hmm::ParamStore { };
+    let store = ParamStore::new();
     let mut data_it = data.into_iter();
-    filter.init_step(store, (vec![None], vec![data_it.next()]));
+    filter.init_step(store, (vec![None], vec![data_it.next()])).unwrap();
     println!("T = {}", 1);
 
     for (t, obs) in data_it.enumerate() {
         println!("T = {}", t+2);  // time is 1-indexed and init_step used "1 => observation" (1 + 1 = 2)
         let constraints = (vec![None], vec![Some(obs)]);
-        filter = filter.step(constraints);
+        filter.step(constraints).unwrap();
         let ess = filter.effective_sample_size();
         dbg!(ess);
         let log_weight = filter.resample();
@@ -76,4 +76,142 @@ fn test_particle_filter() -> std::io::Result<()> {
     approx::assert_abs_diff_eq!(lml_estimate, expected, epsilon = 0.03);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_particle_filter_seeded_reproducible() {
+    let prior = dvector![0.2, 0.3, 0.5];
+    let emission_matrix = dmatrix![
+        0.1, 0.2, 0.7;
+        0.2, 0.7, 0.1;
+        0.7, 0.2, 0.1
+    ].transpose();
+    let transition_matrix = dmatrix![
+        0.4, 0.4, 0.2;
+        0.2, 0.3, 0.5;
+        0.9, 0.05, 0.05
+    ].transpose();
+
+    let run_filter = |seed: u64| {
+        let params = hmm::HMMParams::new(prior.clone(), emission_matrix.clone(), transition_matrix.clone());
+        let mut filter = ParticleSystem::new(hmm::HMM::new(params), 100, StdRng::seed_from_u64(seed));
+        let mut data_it = vec![0, 0, 1, 2].into_iter();
+        filter.init_step(ParamStore::new(), (vec![None], vec![data_it.next()])).unwrap();
+        for obs in data_it {
+            filter.step((vec![None], vec![Some(obs)])).unwrap();
+            filter.resample();
+        }
+        let states = filter.traces.iter().map(|tr| tr.data.0.clone()).collect::<Vec<_>>();
+        (states, filter.log_marginal_likelihood_estimate())
+    };
+
+    assert_eq!(run_filter(7), run_filter(7));
+}
+
+#[test]
+fn test_resampling_schemes_counts() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let weights = [0.5, 0.125, 0.375, 0.];
+
+    let counts = |parents: &[usize]| {
+        let mut counts = vec![0; weights.len()];
+        parents.iter().for_each(|&i| counts[i] += 1);
+        counts
+    };
+
+    // the low-variance schemes keep (nearly) N * w_i copies of each particle
+    for scheme in [ResamplingScheme::Systematic, ResamplingScheme::Stratified, ResamplingScheme::Residual] {
+        let parents = scheme.resample(&mut rng, &weights, 8);
+        assert_eq!(parents.len(), 8);
+        assert!(parents.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(counts(&parents), vec![4, 1, 3, 0]);
+    }
+    for scheme in [ResamplingScheme::Systematic, ResamplingScheme::Stratified, ResamplingScheme::Residual] {
+        let parents = scheme.resample(&mut rng, &weights, 10);
+        let counts = counts(&parents);
+        assert_eq!(counts[3], 0);
+        for (c, w) in counts.iter().zip(weights) {
+            assert!((*c as f64 - 10. * w).abs() < 2.);
+        }
+    }
+
+    let parents = ResamplingScheme::Multinomial.resample(&mut rng, &weights, 100000);
+    assert!(parents.windows(2).all(|w| w[0] <= w[1]));
+    for (c, w) in counts(&parents).iter().zip(weights) {
+        approx::assert_abs_diff_eq!(*c as f64 / 100000., w, epsilon = 0.01);
+    }
+}
+
+#[test]
+fn test_particle_filter_resampling_schemes() {
+    let prior = dvector![0.2, 0.3, 0.5];
+    let emission_matrix = dmatrix![
+        0.1, 0.2, 0.7;
+        0.2, 0.7, 0.1;
+        0.7, 0.2, 0.1
+    ].transpose();
+    let transition_matrix = dmatrix![
+        0.4, 0.4, 0.2;
+        0.2, 0.3, 0.5;
+        0.9, 0.05, 0.05
+    ].transpose();
+    let data = vec![0, 0, 1, 2];
+    let expected = hmm::hmm_forward_alg(prior.clone(), emission_matrix.clone(), transition_matrix.clone(), &data).ln();
+
+    for (seed, scheme) in [ResamplingScheme::Multinomial, ResamplingScheme::Systematic, ResamplingScheme::Stratified, ResamplingScheme::Residual].into_iter().enumerate() {
+        let params = hmm::HMMParams::new(prior.clone(), emission_matrix.clone(), transition_matrix.clone());
+        let mut filter = ParticleSystem::new(hmm::HMM::new(params), 5000, StdRng::seed_from_u64(seed as u64))
+            .with_resampling_scheme(scheme);
+        let mut data_it = data.clone().into_iter();
+        filter.init_step(ParamStore::new(), (vec![None], vec![data_it.next()])).unwrap();
+        for obs in data_it {
+            filter.step((vec![None], vec![Some(obs)])).unwrap();
+            filter.resample();
+        }
+        assert_eq!(filter.traces.len(), 5000);
+        approx::assert_abs_diff_eq!(filter.log_marginal_likelihood_estimate(), expected, epsilon = 0.03);
+    }
+}
+
+
+#[test]
+fn test_smc_adaptive_resampling() {
+    let prior = dvector![0.2, 0.3, 0.5];
+    let emission_matrix = dmatrix![
+        0.1, 0.2, 0.7;
+        0.2, 0.7, 0.1;
+        0.7, 0.2, 0.1
+    ].transpose();
+    let transition_matrix = dmatrix![
+        0.4, 0.4, 0.2;
+        0.2, 0.3, 0.5;
+        0.9, 0.05, 0.05
+    ].transpose();
+    let data = vec![0, 0, 1, 2, 2, 0];
+    let expected = hmm::hmm_forward_alg(prior.clone(), emission_matrix.clone(), transition_matrix.clone(), &data).ln();
+    let constraints = data.iter().map(|obs| (vec![None], vec![Some(*obs)])).collect::<Vec<_>>();
+
+    let new_filter = |seed: u64| {
+        let params = hmm::HMMParams::new(prior.clone(), emission_matrix.clone(), transition_matrix.clone());
+        ParticleSystem::new(hmm::HMM::new(params), 5000, StdRng::seed_from_u64(seed))
+            .with_resampling_scheme(ResamplingScheme::Systematic)
+    };
+
+    let (filter, summary) = smc(new_filter(1), ParamStore::new(), constraints.clone(), 0.5).unwrap();
+    assert_eq!(summary.num_steps(), data.len());
+    assert!(summary.ess.iter().all(|ess| *ess > 0. && *ess <= 5000. + 1e-6));
+    for (ess, resampled) in summary.ess.iter().zip(&summary.resampled) {
+        assert_eq!(*resampled, *ess < 2500.);
+    }
+    approx::assert_abs_diff_eq!(summary.log_ml_increments.iter().sum::<f64>(), summary.log_ml_estimate, epsilon = 1e-10);
+    approx::assert_abs_diff_eq!(summary.log_ml_estimate, filter.log_marginal_likelihood_estimate(), epsilon = 1e-10);
+    approx::assert_abs_diff_eq!(summary.log_ml_estimate, expected, epsilon = 0.1);
+
+    // without resampling, SMC reduces to importance sampling over the whole sequence
+    let (_, summary) = smc(new_filter(2), ParamStore::new(), constraints.clone(), 0.).unwrap();
+    assert_eq!(summary.num_resamples(), 0);
+    approx::assert_abs_diff_eq!(summary.log_ml_estimate, expected, epsilon = 0.1);
+
+    let (_, summary) = smc(new_filter(3), ParamStore::new(), constraints, 1.).unwrap();
+    assert_eq!(summary.num_resamples(), data.len());
+}
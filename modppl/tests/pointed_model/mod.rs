@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+#![allow(unused_imports)]
 #![allow(non_upper_case_globals)]
 
 
@@ -6,4 +8,4 @@ mod proposal;
 pub mod types_2d;
 
 pub use model::{PointedModel,PointedTrace};
-pub use proposal::DriftProposal;
\ No newline at end of file
+pub use proposal::{DriftProposal,ObsProposal};
\ No newline at end of file
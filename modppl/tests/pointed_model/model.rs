@@ -1,7 +1,7 @@
 use nalgebra::DMatrix;
-use modppl::{Distribution, mvnormal, Trace, GenFn, ArgDiff};
+use modppl::{Distribution, mvnormal, Trace, GenFn, GenFnError, ArgDiff};
 use super::types_2d::{Point,Bounds,uniform_2d};
-use rand::rngs::ThreadRng;
+use rand::RngCore;
 
 
 pub struct PointedModel {
@@ -13,18 +13,16 @@ pub type PointedTrace = Trace<Bounds,PointedBuffer,Point>;
 
 impl GenFn<Bounds,PointedBuffer,Point> for PointedModel {
 
-    fn simulate(&self, bounds: Bounds) -> PointedTrace {
-        let mut rng = ThreadRng::default();
+    fn try_simulate(&self, rng: &mut dyn RngCore, bounds: Bounds) -> Result<PointedTrace,GenFnError> {
         let mut logjp = 0.;
-        let latent = uniform_2d.random(&mut rng, bounds);
+        let latent = uniform_2d.random(rng, bounds);
         logjp += uniform_2d.logpdf(&latent, bounds);
-        let obs = mvnormal.random(&mut rng, (latent.clone(), self.obs_cov.clone()));
+        let obs = mvnormal.random(rng, (latent.clone(), self.obs_cov.clone()));
         logjp += mvnormal.logpdf(&obs, (obs.clone(), self.obs_cov.clone()));
-        PointedTrace::new(bounds, (Some(latent), Some(obs.clone())), obs, logjp)
+        Ok(PointedTrace::new(bounds, (Some(latent), Some(obs.clone())), obs, logjp))
     }
 
-    fn generate(&self, bounds: Bounds, constraints: PointedBuffer) -> (PointedTrace, f64) {
-        let mut rng = ThreadRng::default();
+    fn try_generate(&self, rng: &mut dyn RngCore, bounds: Bounds, constraints: PointedBuffer) -> Result<(PointedTrace, f64),GenFnError> {
         let mut logjp = 0.;
         let mut weight = 0.;
         let mut choices = (None, None);
@@ -38,7 +36,7 @@ impl GenFn<Bounds,PointedBuffer,Point> for PointedModel {
                 constrained_latent
             }
             None => {
-                let latent_choice = uniform_2d.random(&mut rng, bounds);
+                let latent_choice = uniform_2d.random(rng, bounds);
                 let new_weight = uniform_2d.logpdf(&latent_choice, bounds);
                 logjp += new_weight;
                 latent_choice
@@ -55,7 +53,7 @@ impl GenFn<Bounds,PointedBuffer,Point> for PointedModel {
                 constrained_obs
             }
             None => {
-                let obs_choice = mvnormal.random(&mut rng, (latent_choice.clone(), self.obs_cov.clone()));
+                let obs_choice = mvnormal.random(rng, (latent_choice.clone(), self.obs_cov.clone()));
                 let new_weight = mvnormal.logpdf(&obs_choice, (latent_choice, self.obs_cov.clone()));
                 logjp += new_weight;
                 obs_choice
@@ -63,10 +61,10 @@ impl GenFn<Bounds,PointedBuffer,Point> for PointedModel {
         };
         choices.1 = Some(obs_choice.clone());
 
-        (PointedTrace::new(bounds, choices, obs_choice, logjp), weight)
+        Ok((PointedTrace::new(bounds, choices, obs_choice, logjp), weight))
     }
 
-    fn update(&self, trace: PointedTrace, args: Bounds, diff: ArgDiff, constraints: PointedBuffer) -> (PointedTrace, PointedBuffer, f64) {
+    fn try_update(&self, _: &mut dyn RngCore, trace: PointedTrace, args: Bounds, diff: ArgDiff, constraints: PointedBuffer) -> Result<(PointedTrace, PointedBuffer, f64, ArgDiff),GenFnError> {
         match diff {
             ArgDiff::NoChange => {
                 let prev_choices = trace.data;
@@ -99,9 +97,10 @@ impl GenFn<Bounds,PointedBuffer,Point> for PointedModel {
                     new_logjp += mvnormal.logpdf(&obs_choice.clone().unwrap(), (latent_choice.clone().unwrap(), self.obs_cov.clone()));
                 }
 
-                (PointedTrace::new(args, (latent_choice, obs_choice.clone()), obs_choice.unwrap(), new_logjp), discard, new_logjp - trace.logjp)
+                let retdiff = if discard.1.is_some() { ArgDiff::Unknown } else { ArgDiff::NoChange };
+                Ok((PointedTrace::new(args, (latent_choice, obs_choice.clone()), obs_choice.unwrap(), new_logjp), discard, new_logjp - trace.logjp, retdiff))
             },
-            _ => { panic!("Can't handle GF change type: {:?}", diff) },
+            _ => { Err(GenFnError::UnsupportedArgDiff(diff)) },
         }
     }
 
@@ -1,9 +1,9 @@
 use std::sync::Weak;
 use nalgebra::DMatrix;
-use modppl::{Distribution, mvnormal, GenFn, Trace, ArgDiff};
+use modppl::{Distribution, mvnormal, GenFn, GenFnError, Trace, ArgDiff};
 use super::model::PointedBuffer;
 use super::types_2d::{Point,Bounds};
-use rand::rngs::ThreadRng;
+use rand::RngCore;
 
 
 pub struct DriftProposal {
@@ -14,19 +14,18 @@ pub type DriftProposalArgs = (Weak<Trace<Bounds,PointedBuffer,Point>>,());
 
 impl GenFn<DriftProposalArgs,PointedBuffer,()> for DriftProposal {
 
-    fn simulate(&self, args: DriftProposalArgs) -> Trace<DriftProposalArgs,PointedBuffer,()> {
-        let mut rng = ThreadRng::default();
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: DriftProposalArgs) -> Result<Trace<DriftProposalArgs,PointedBuffer,()>,GenFnError> {
         let prev_trace = args.0.upgrade().unwrap();
         let mut choices = (None, prev_trace.data.1.clone());
 
-        let new_latent = mvnormal.random(&mut rng, (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
+        let new_latent = mvnormal.random(rng, (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
         choices.0 = Some(new_latent);
         let logp = mvnormal.logpdf(&choices.0.clone().unwrap(), (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
 
-        Trace::new(args, choices, (), logp)
+        Ok(Trace::new(args, choices, (), logp))
     }
 
-    fn generate(&self, args: DriftProposalArgs, constraints: PointedBuffer) -> (Trace<DriftProposalArgs,PointedBuffer,()>, f64) {
+    fn try_generate(&self, rng: &mut dyn RngCore, args: DriftProposalArgs, constraints: PointedBuffer) -> Result<(Trace<DriftProposalArgs,PointedBuffer,()>, f64),GenFnError> {
         let prev_trace = args.0.upgrade().unwrap();
         let mut choices = (None, prev_trace.data.1.clone());
 
@@ -40,17 +39,38 @@ impl GenFn<DriftProposalArgs,PointedBuffer,()> for DriftProposal {
                 weight = logp;
             }
             None => {
-                let mut rng = ThreadRng::default();
-                new_latent = mvnormal.random(&mut rng, (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
+                new_latent = mvnormal.random(rng, (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
                 logp = mvnormal.logpdf(&new_latent, (prev_trace.data.0.clone().unwrap(), self.drift_cov.clone()));
             }
         }
         choices.0 = Some(new_latent);
 
-        (Trace::new(args, choices, (), logp), weight)
+        Ok((Trace::new(args, choices, (), logp), weight))
     }
 
-    fn update(&self, _: Trace<DriftProposalArgs,PointedBuffer,()>, _: DriftProposalArgs, _: ArgDiff, _: PointedBuffer) -> (Trace<DriftProposalArgs,PointedBuffer,()>, PointedBuffer, f64) {
-        panic!("not implemented")
+    fn try_update(&self, _: &mut dyn RngCore, _: Trace<DriftProposalArgs,PointedBuffer,()>, _: DriftProposalArgs, _: ArgDiff, _: PointedBuffer) -> Result<(Trace<DriftProposalArgs,PointedBuffer,()>, PointedBuffer, f64, ArgDiff),GenFnError> {
+        Err(GenFnError::Unimplemented("update"))
     }
-}
\ No newline at end of file
+}
+
+/// Independent proposal for the latent of a `PointedModel`, centered at a given point (eg. the observation).
+pub struct ObsProposal {
+    pub cov: DMatrix<f64>
+}
+
+impl GenFn<Point,PointedBuffer,()> for ObsProposal {
+
+    fn try_simulate(&self, rng: &mut dyn RngCore, center: Point) -> Result<Trace<Point,PointedBuffer,()>,GenFnError> {
+        let latent = mvnormal.random(rng, (center.clone(), self.cov.clone()));
+        let logp = mvnormal.logpdf(&latent, (center.clone(), self.cov.clone()));
+        Ok(Trace::new(center, (Some(latent), None), (), logp))
+    }
+
+    fn try_generate(&self, _: &mut dyn RngCore, _: Point, _: PointedBuffer) -> Result<(Trace<Point,PointedBuffer,()>, f64),GenFnError> {
+        Err(GenFnError::Unimplemented("generate"))
+    }
+
+    fn try_update(&self, _: &mut dyn RngCore, _: Trace<Point,PointedBuffer,()>, _: Point, _: ArgDiff, _: PointedBuffer) -> Result<(Trace<Point,PointedBuffer,()>, PointedBuffer, f64, ArgDiff),GenFnError> {
+        Err(GenFnError::Unimplemented("update"))
+    }
+}
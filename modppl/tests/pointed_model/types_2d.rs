@@ -1,4 +1,4 @@
-use rand::rngs::ThreadRng;
+use rand::Rng;
 use modppl::{Distribution,u01};
 use nalgebra::{DVector,dvector};
 
@@ -13,14 +13,14 @@ pub const uniform_2d: Uniform2D = Uniform2D { };
 
 impl Distribution<Point,Bounds> for Uniform2D {
     fn logpdf(&self, p: &Point, b: Bounds) -> f64 {
-        return if b.xmin <= p[0] && p[0] <= b.xmax && b.ymin <= p[1] && p[1] <= b.ymax {
-            -((b.xmax - b.xmin) as f64 * (b.ymax - b.ymin) as f64).ln()
+        if b.xmin <= p[0] && p[0] <= b.xmax && b.ymin <= p[1] && p[1] <= b.ymax {
+            -((b.xmax - b.xmin) * (b.ymax - b.ymin)).ln()
         } else {
             f64::NEG_INFINITY
         }
     }
 
-    fn random(&self, rng: &mut ThreadRng, b: Bounds) -> Point {
+    fn random<R: Rng + ?Sized>(&self, rng: &mut R, b: Bounds) -> Point {
         assert!(b.xmax > b.xmin);
         assert!(b.ymax > b.ymin);
         dvector![
@@ -1,11 +1,16 @@
+#![allow(non_upper_case_globals)]
+
 use std::fs::{write, create_dir_all};
 use std::{
-    sync::Arc,
+    cell::RefCell,
+    sync::{Arc,Weak},
     f64::consts::PI
 };
-use modppl::{Distribution,DynTrie,u01,normal,inference::ParticleSystem};
-use nalgebra::dvector;
-use rand::rngs::ThreadRng;
+use modppl::{Distribution,DynTrie,DynUnfold,GenFn,GenFnError,Trace,ArgDiff,AddrMap,u01,normal,mvnormal,regen_mh,inference::{ParticleSystem,ResamplingScheme,smc,conditional_smc,particle_gibbs,backward_simulation}};
+use rand::RngCore;
+use modppl::prelude::{dyngen,DynGenFn,DynGenFnHandler};
+use nalgebra::{DMatrix,DVector,dvector};
+use rand::{SeedableRng,rngs::{ThreadRng,StdRng}};
 
 pub mod pointed_model;
 use pointed_model::types_2d::{Bounds,Point};
@@ -14,11 +19,89 @@ pub mod dyngenfns;
 use dyngenfns::spiral_model;
 
 
+dyngen!(
+fn walk_kernel(t: i64, prev_x: f64) -> f64 {
+    let x: f64;
+    if t == 0 {
+        x = normal(0., 1.) %= "x";
+    } else {
+        x = normal(prev_x, 1.) %= "x";
+    }
+    normal(x, 0.5) %= "y";
+    x
+});
+
+const walk_model: DynUnfold<f64> = DynUnfold { kernel: walk_kernel };
+
+fn walk_observations(ys: &[f64]) -> Vec<DynTrie> {
+    ys.iter().map(|y| {
+        let mut constraints = DynTrie::new();
+        constraints.observe("y", Arc::new(*y));
+        constraints
+    }).collect()
+}
+
+// exact posterior mean of the latent walk, by conditioning the joint Gaussian of (x, y)
+fn walk_posterior_mean(ys: &[f64]) -> DVector<f64> {
+    let n = ys.len();
+    let cov_x = DMatrix::from_fn(n, n, |i, j| 1. + i.min(j) as f64);
+    let cov_y = &cov_x + DMatrix::identity(n, n) * 0.25;
+    &cov_x * cov_y.try_inverse().unwrap() * DVector::from_column_slice(ys)
+}
+
+type WalkTrace = Trace<(i64,f64),Vec<DynTrie>,Vec<f64>>;
+
+// locally optimal proposal for the next latent of the walk, given the next observation
+struct WalkProposal;
+
+impl GenFn<(Weak<WalkTrace>,f64),Vec<DynTrie>,()> for WalkProposal {
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: (Weak<WalkTrace>,f64)) -> Result<Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,GenFnError> {
+        let prev_x = *args.0.upgrade().unwrap().retv.as_ref().unwrap().last().unwrap();
+        let (mean, std) = ((prev_x + 4.*args.1) / 5., 0.2_f64.sqrt());
+        let x = normal.random(rng, (mean, std));
+        let logp = normal.logpdf(&x, (mean, std));
+        let mut choices = DynTrie::new();
+        choices.w_observe("x", Arc::new(x), logp);
+        Ok(Trace::new(args, vec![choices], (), logp))
+    }
+
+    fn try_generate(&self, _: &mut dyn RngCore, _: (Weak<WalkTrace>,f64), _: Vec<DynTrie>) -> Result<(Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,f64),GenFnError> {
+        Err(GenFnError::Unimplemented("generate"))
+    }
+
+    fn try_update(&self, _: &mut dyn RngCore, _: Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>, _: (Weak<WalkTrace>,f64), _: ArgDiff, _: Vec<DynTrie>)
+        -> Result<(Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,Vec<DynTrie>,f64,ArgDiff),GenFnError>
+    {
+        Err(GenFnError::Unimplemented("update"))
+    }
+}
+
+// a proposal that keeps a strong reference to the previous trace of each particle it extends
+struct KeepingProposal(RefCell<Vec<Arc<WalkTrace>>>);
+
+impl GenFn<(Weak<WalkTrace>,f64),Vec<DynTrie>,()> for KeepingProposal {
+    fn try_simulate(&self, rng: &mut dyn RngCore, args: (Weak<WalkTrace>,f64)) -> Result<Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,GenFnError> {
+        self.0.borrow_mut().push(args.0.upgrade().unwrap());
+        WalkProposal.try_simulate(rng, args)
+    }
+
+    fn try_generate(&self, rng: &mut dyn RngCore, args: (Weak<WalkTrace>,f64), constraints: Vec<DynTrie>) -> Result<(Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,f64),GenFnError> {
+        WalkProposal.try_generate(rng, args, constraints)
+    }
+
+    fn try_update(&self, rng: &mut dyn RngCore, trace: Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>, args: (Weak<WalkTrace>,f64), diff: ArgDiff, constraints: Vec<DynTrie>)
+        -> Result<(Trace<(Weak<WalkTrace>,f64),Vec<DynTrie>,()>,Vec<DynTrie>,f64,ArgDiff),GenFnError>
+    {
+        WalkProposal.try_update(rng, trace, args, diff, constraints)
+    }
+}
+
+
 fn simulate_loop(rng: &mut ThreadRng, bounds: &Bounds, timesteps: i64) -> Vec<DynTrie>{
     let init_angle = u01(rng) * 2.*PI;
 
-    let xrange = (bounds.xmax - bounds.xmin) as f64;
-    let yrange = (bounds.ymax - bounds.ymin) as f64;
+    let xrange = bounds.xmax - bounds.xmin;
+    let yrange = bounds.ymax - bounds.ymin;
     let center = dvector![
         xrange / 2. + bounds.xmin,
         yrange / 2. + bounds.ymin
@@ -30,7 +113,7 @@ fn simulate_loop(rng: &mut ThreadRng, bounds: &Bounds, timesteps: i64) -> Vec<Dy
     for t in 0..timesteps {
         let mut deformation = 0.;
         for perturb_t in &perturb_means {
-            deformation += normal.logpdf(&(t as f64), (perturb_t.clone() as f64, 1.)).exp()
+            deformation += normal.logpdf(&(t as f64), (*perturb_t as f64, 1.)).exp()
         }
         let r = radius + deformation;
         let t = 2.*PI*(t as f64) / timesteps as f64;
@@ -58,16 +141,16 @@ fn test_smc() -> std::io::Result<()> {
     let obs = data.iter().map(|t| t.read::<Point>("obs")).collect::<Vec<Point>>();
     let obs_strs = obs.iter().map(|obs| format!("[{},{}]", obs[0], obs[1])).collect::<Vec<String>>();
     let json = "[".to_owned() + &obs_strs.join(", ") + "]";
-    write(format!("../data/smc_obs.json"), json)?;
+    write("../data/smc_obs.json", json)?;
 
     let mut filter = ParticleSystem::new(spiral_model, NUM_PARTICLES, rng);
     let mut data_it = data.into_iter();
-    filter.init_step(dvector![0.,0.], vec![data_it.next().unwrap()]);
+    filter.init_step(dvector![0.,0.], vec![data_it.next().unwrap()]).unwrap();
 
     let states = filter.traces.iter().map(|vtr| vtr.retv.as_ref().unwrap().last().unwrap().clone()).collect::<Vec<_>>();
     let state_strs = states.iter().map(|latent| format!("[{},{}]", latent[0], latent[1])).collect::<Vec<String>>();
     let json = "[".to_owned() + &state_strs.join(", ") + "]";
-    write(format!("../data/smc_traces_before_resample_0.json"), json)?;
+    write("../data/smc_traces_before_resample_0.json", json)?;
     filter.resample();
 
     let states = filter.traces.iter().map(|vtr| vtr.retv.as_ref().unwrap().last().unwrap().clone()).collect::<Vec<_>>();
@@ -76,7 +159,7 @@ fn test_smc() -> std::io::Result<()> {
     write(format!("../data/smc_traces_{}.json", 0), json)?;
 
     for (t,constraints) in data_it.enumerate() {
-        filter = filter.step(vec![constraints]);
+        filter.step(vec![constraints]).unwrap();
         let states = filter.traces.iter().map(|vtr| vtr.retv.as_ref().unwrap().last().unwrap().clone()).collect::<Vec<_>>();
         let state_strs = states.iter().map(|latent| format!("[{},{}]", latent[0], latent[1])).collect::<Vec<String>>();
         let json = "[".to_owned() + &state_strs.join(", ") + "]";
@@ -89,4 +172,404 @@ fn test_smc() -> std::io::Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_smc_driver() {
+    let mut rng = ThreadRng::default();
+    const NUM_TIMESTEPS: i64 = 20;
+
+    let bounds = Bounds { xmin: -1., xmax: 1., ymin: -1., ymax: 1.};
+    let data = simulate_loop(&mut rng, &bounds, NUM_TIMESTEPS);
+
+    let filter = ParticleSystem::new(spiral_model, 200, rng).with_resampling_scheme(ResamplingScheme::Residual);
+    let (filter, summary) = smc(filter, dvector![0.,0.], data.into_iter().map(|constraints| vec![constraints]), 0.5).unwrap();
+    assert_eq!(summary.num_steps(), NUM_TIMESTEPS as usize);
+    assert!(summary.num_resamples() > 0);
+    assert!(filter.traces.iter().all(|vtr| vtr.data.len() == NUM_TIMESTEPS as usize));
+    assert!(summary.log_ml_estimate.is_finite());
+}
+
+
+#[test]
+fn test_unfold_update_past_constraints() {
+    let mut rng = StdRng::seed_from_u64(21);
+    let ys = [0.3, 1.1, 0.7];
+    let (trace, _) = walk_model.generate(&mut rng, (3, 0.), walk_observations(&ys));
+    let xs = trace.retv.clone().unwrap();
+
+    // constrain x at the first step only: later steps are rescored, but keep their choices
+    let mut constraints = DynTrie::new();
+    constraints.observe("x", Arc::new(-0.5));
+    let (new_trace, discard, weight, retdiff) = walk_model.update(&mut rng, trace.clone(), (3, 0.), ArgDiff::NoChange, vec![constraints]);
+    assert_eq!(retdiff, ArgDiff::Unknown);
+    assert_eq!(discard.len(), 3);
+    assert_eq!(discard[0].read::<f64>("x"), xs[0]);
+    assert!(discard[1].is_empty() && discard[2].is_empty());
+    let new_xs = new_trace.retv.clone().unwrap();
+    assert_eq!(new_xs, vec![-0.5, xs[1], xs[2]]);
+    let expected_logjp = normal.logpdf(&-0.5, (0., 1.)) + normal.logpdf(&ys[0], (-0.5, 0.5))
+        + normal.logpdf(&xs[1], (-0.5, 1.)) + normal.logpdf(&ys[1], (xs[1], 0.5))
+        + normal.logpdf(&xs[2], (xs[1], 1.)) + normal.logpdf(&ys[2], (xs[2], 0.5));
+    approx::assert_abs_diff_eq!(new_trace.logjp, expected_logjp, epsilon = 1e-10);
+    approx::assert_abs_diff_eq!(weight, new_trace.logjp - trace.logjp, epsilon = 1e-10);
+
+    // without constraints nothing changes
+    let (same_trace, _, weight, retdiff) = walk_model.update(&mut rng, trace.clone(), (3, 0.), ArgDiff::NoChange, vec![]);
+    assert_eq!(retdiff, ArgDiff::NoChange);
+    assert_eq!(weight, 0.);
+    assert_eq!(same_trace.retv, trace.retv);
+
+    // regenerating x at the second step leaves the other choices alone
+    let mut mask = AddrMap::new();
+    mask.visit("1 / x");
+    let (new_trace, _, _) = walk_model.regenerate(&mut rng, trace.clone(), (3, 0.), ArgDiff::NoChange, &mask);
+    let new_xs = new_trace.retv.clone().unwrap();
+    assert_eq!((new_xs[0], new_xs[2]), (xs[0], xs[2]));
+    assert_ne!(new_xs[1], xs[1]);
+    approx::assert_abs_diff_eq!(new_trace.logjp, new_trace.data.iter().map(|data| data.weight()).sum::<f64>(), epsilon = 1e-10);
+}
+
+#[test]
+fn test_unfold_update_invalid_args() {
+    let mut rng = StdRng::seed_from_u64(22);
+    let ys = [0.3, 1.1, 0.7];
+    let (trace, _) = walk_model.generate(&mut rng, (3, 0.), walk_observations(&ys));
+    let is_invalid = |result: Result<_,GenFnError>| matches!(result, Err(GenFnError::InvalidArgs(_)));
+
+    // inconsistent time steps and constraints are errors, rather than panics
+    assert!(matches!(walk_model.try_simulate(&mut rng, (0, 0.)), Err(GenFnError::InvalidArgs(_))));
+    assert!(is_invalid(walk_model.try_update(&mut rng, trace.clone(), (0, 0.), ArgDiff::NoChange, vec![])));
+    assert!(is_invalid(walk_model.try_update(&mut rng, trace.clone(), (4, 0.), ArgDiff::NoChange, vec![])));
+    assert!(is_invalid(walk_model.try_update(&mut rng, trace.clone(), (3, 0.), ArgDiff::NoChange, walk_observations(&[0.; 4]))));
+    assert!(is_invalid(walk_model.try_update(&mut rng, trace.clone(), (5, 0.), ArgDiff::Extend, walk_observations(&[0.]))));
+    assert_eq!(
+        walk_model.try_update(&mut rng, trace, (5, 0.), ArgDiff::Unknown, walk_observations(&[0.])).err(),
+        Some(GenFnError::UnsupportedArgDiff(ArgDiff::Unknown))
+    );
+}
+
+#[test]
+fn test_resample_move() {
+    let ys = [0.3, 1.1, 0.7, 1.8, 2.4];
+    let expected_mean = walk_posterior_mean(&ys);
+    let mut data_it = walk_observations(&ys).into_iter();
+
+    let mut filter = ParticleSystem::new(walk_model, 2000, StdRng::seed_from_u64(22));
+    filter.init_step(0., vec![data_it.next().unwrap()]).unwrap();
+    let mut masks = vec![];
+    for (t, constraints) in data_it.enumerate() {
+        filter.step(vec![constraints]).unwrap();
+        filter.resample();
+
+        // regenerate the latent of each step in turn
+        for s in masks.len()..t + 2 {
+            let mut mask = AddrMap::new();
+            mask.visit(format!("{} / x", s).as_str());
+            masks.push(mask);
+        }
+        let lml_estimate = filter.log_marginal_likelihood_estimate();
+        let num_accepted = filter.rejuvenate(|rng, model, mut trace| {
+            let mut any_accepted = false;
+            for mask in masks.iter() {
+                let accepted;
+                (trace, accepted) = regen_mh(rng, model, trace, mask)?;
+                any_accepted |= accepted;
+            }
+            Ok((trace, any_accepted))
+        }).unwrap();
+        assert!(num_accepted > 0);
+        assert_eq!(filter.log_marginal_likelihood_estimate(), lml_estimate);
+    }
+
+    // rejuvenation restores the diversity of the first latent, which resampling alone collapses
+    let mut first_xs = filter.traces.iter().map(|trace| trace.retv.as_ref().unwrap()[0]).collect::<Vec<f64>>();
+    first_xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    first_xs.dedup();
+    assert!(first_xs.len() > 500);
+
+    for t in 0..ys.len() {
+        let mean = filter.traces.iter().map(|trace| trace.retv.as_ref().unwrap()[t]).sum::<f64>() / 2000.;
+        approx::assert_abs_diff_eq!(mean, expected_mean[t], epsilon = 0.1);
+    }
+
+    // a kernel that fails part-way leaves the particles unchanged
+    let retvs = filter.traces.iter().map(|trace| trace.retv.clone()).collect::<Vec<_>>();
+    let mut num_moves = 0;
+    let result = filter.rejuvenate(|rng, model, trace| {
+        num_moves += 1;
+        if num_moves > 100 {
+            return Err(GenFnError::InvalidArgs("kernel failed".to_string()));
+        }
+        regen_mh(rng, model, trace, &masks[0])
+    });
+    assert!(result.is_err());
+    assert_eq!(filter.traces.iter().map(|trace| trace.retv.clone()).collect::<Vec<_>>(), retvs);
+}
+
+
+#[test]
+fn test_step_with_proposal() {
+    let ys = [0.3, 1.1, 0.7, 1.8, 2.4, 2.1];
+    let n = ys.len();
+    let cov_y = DMatrix::from_fn(n, n, |i, j| 1. + i.min(j) as f64) + DMatrix::identity(n, n) * 0.25;
+    let expected = mvnormal.logpdf(&DVector::from_column_slice(&ys), (DVector::zeros(n), cov_y));
+
+    let run_filter = |guided: bool| {
+        let mut filter = ParticleSystem::new(walk_model, 1000, StdRng::seed_from_u64(23));
+        let mut data_it = walk_observations(&ys).into_iter().zip(ys);
+        filter.init_step(0., vec![data_it.next().unwrap().0]).unwrap();
+        let mut min_ess = f64::INFINITY;
+        for (constraints, y) in data_it {
+            if guided {
+                filter.step_with_proposal(&WalkProposal, y, vec![constraints]).unwrap();
+            } else {
+                filter.step(vec![constraints]).unwrap();
+            }
+            min_ess = min_ess.min(filter.effective_sample_size());
+            filter.resample();
+        }
+        (min_ess, filter.log_marginal_likelihood_estimate())
+    };
+
+    // the guided filter keeps more effective samples than the bootstrap filter
+    let (guided_ess, guided_lml) = run_filter(true);
+    let (bootstrap_ess, _) = run_filter(false);
+    assert!(guided_ess > 1.5 * bootstrap_ess);
+    approx::assert_abs_diff_eq!(guided_lml, expected, epsilon = 0.05);
+
+    // proposing an observed choice is an error
+    let mut filter = ParticleSystem::new(walk_model, 10, StdRng::seed_from_u64(24));
+    filter.init_step(0., walk_observations(&ys[..1])).unwrap();
+    let mut constraints = DynTrie::new();
+    constraints.observe("x", Arc::new(0.));
+    let result = filter.step_with_proposal(&WalkProposal, ys[1], vec![constraints]);
+    assert_eq!(result.err(), Some(GenFnError::AddressCollision("0 / x".to_string())));
+
+    // as is keeping a strong reference to the previous trace
+    let result = filter.step_with_proposal(&KeepingProposal(RefCell::new(vec![])), ys[1], walk_observations(&ys[1..2]));
+    assert!(matches!(result.err(), Some(GenFnError::InvalidArgs(_))));
+
+    // and either error leaves the particles unchanged
+    assert!(filter.traces.iter().all(|trace| trace.args.0 == 1));
+    assert_eq!(filter.ancestry().len(), 0);
+    filter.step_with_proposal(&WalkProposal, ys[1], walk_observations(&ys[1..2])).unwrap();
+    assert!(filter.traces.iter().all(|trace| trace.args.0 == 2));
+}
+
+#[test]
+fn test_ancestry() {
+    let ys = (0..30).map(|t| (t as f64 / 5.).sin() * 2.).collect::<Vec<f64>>();
+    let mut data_it = walk_observations(&ys).into_iter();
+
+    let mut filter = ParticleSystem::new(walk_model, 100, StdRng::seed_from_u64(25));
+    filter.init_step(0., vec![data_it.next().unwrap()]).unwrap();
+    let mut populations = vec![filter.traces.clone()];
+    filter.resample();
+    for constraints in data_it {
+        filter.step(vec![constraints]).unwrap();
+        populations.push(filter.traces.clone());
+        filter.adaptive_resample(0.5);
+    }
+    assert_eq!(filter.ancestry().len(), ys.len() - 1);
+
+    // each particle's history is the history of its ancestors
+    let genealogy = filter.genealogy();
+    for (trace, lineage) in filter.traces.iter().zip(&genealogy) {
+        assert_eq!(lineage.len(), ys.len());
+        let xs = trace.retv.as_ref().unwrap();
+        for (t, &j) in lineage.iter().enumerate() {
+            assert_eq!(populations[t][j].retv.as_ref().unwrap()[..], xs[..t + 1]);
+        }
+    }
+    assert_eq!(filter.lineage(7), genealogy[7]);
+
+    // the paths coalesce going back in time
+    let counts = filter.num_unique_ancestors();
+    assert_eq!(counts.len(), ys.len());
+    for (t, count) in counts.iter().enumerate() {
+        let mut ancestors = genealogy.iter().map(|lineage| lineage[t]).collect::<Vec<usize>>();
+        ancestors.sort();
+        ancestors.dedup();
+        assert_eq!(*count, ancestors.len());
+    }
+    assert!(counts.windows(2).all(|w| w[0] <= w[1]));
+    assert!(counts[0] < counts[ys.len() - 1]);
+}
+
+
+dyngen!(
+fn drift_kernel(_t: i64, state: (f64,f64)) -> (f64,f64) {
+    let (prev_x, mu) = state;
+    let x = normal(prev_x + mu, 1.) %= "x";
+    normal(x, 0.5) %= "y";
+    (x, mu)
+});
+
+const drift_model: DynUnfold<(f64,f64)> = DynUnfold { kernel: drift_kernel };
+
+#[test]
+fn test_conditional_smc() {
+    let mut rng = StdRng::seed_from_u64(26);
+    let ys = [0.3, 1.1, 0.7, 1.8, 2.4, 2.1];
+    let observations = walk_observations(&ys).into_iter().map(|c| vec![c]).collect::<Vec<Vec<DynTrie>>>();
+    let trace = walk_model.generate(&mut rng, (ys.len() as i64, 0.), observations.concat()).0;
+    let reference = DynUnfold::latent_choices(&trace, &observations);
+    assert_eq!(reference.len(), ys.len());
+    assert!(reference.iter().all(|step| step[0].search("y").is_none() && step[0].search("x").is_some()));
+
+    // observed addresses missing from the trace are skipped
+    let mut extra_observations = observations.clone();
+    extra_observations[0][0].observe("z", Arc::new(0.));
+    assert!(DynUnfold::latent_choices(&trace, &extra_observations)[0][0].schema() == reference[0][0].schema());
+
+    // the reference trajectory is retained as the last particle
+    let (traces, log_weights, _) = conditional_smc(&mut rng, &walk_model, 0., &observations, &reference, 50, false).unwrap();
+    assert_eq!(traces.len(), 50);
+    assert_eq!(log_weights.len(), 50);
+    assert_eq!(traces[49].retv, trace.retv);
+    approx::assert_abs_diff_eq!(traces[49].logjp, trace.logjp, epsilon = 1e-10);
+
+    // with ancestor sampling, only its last state is retained
+    let (traces, _, _) = conditional_smc(&mut rng, &walk_model, 0., &observations, &reference, 50, true).unwrap();
+    assert_eq!(traces[49].retv.as_ref().unwrap().last(), trace.retv.as_ref().unwrap().last());
+
+    // the log marginal likelihood estimate is unbiased
+    let n = ys.len();
+    let cov_y = DMatrix::from_fn(n, n, |i, j| 1. + i.min(j) as f64) + DMatrix::identity(n, n) * 0.25;
+    let expected = mvnormal.logpdf(&DVector::from_column_slice(&ys), (DVector::zeros(n), cov_y));
+    let mean_ml = (0..200)
+        .map(|_| conditional_smc(&mut rng, &walk_model, 0., &observations, &reference, 50, false).unwrap().2.exp())
+        .sum::<f64>() / 200.;
+    assert!((mean_ml.ln() - expected).abs() < 0.3);
+
+    // the reference can't overlap the observations
+    let result = conditional_smc(&mut rng, &walk_model, 0., &observations, &observations, 50, false);
+    assert_eq!(result.err(), Some(GenFnError::AddressCollision("0 / y".to_string())));
+
+    // invalid arguments are errors
+    let result = conditional_smc(&mut rng, &walk_model, 0., &observations, &reference, 1, false);
+    assert!(matches!(result, Err(GenFnError::InvalidArgs(_))));
+    let result = conditional_smc(&mut rng, &walk_model, 0., &[], &[], 50, false);
+    assert!(matches!(result, Err(GenFnError::InvalidArgs(_))));
+    let result = conditional_smc(&mut rng, &walk_model, 0., &observations, &reference[1..], 50, false);
+    assert!(matches!(result, Err(GenFnError::InvalidArgs(_))));
+}
+
+#[test]
+fn test_particle_gibbs() {
+    let ys = [0.3, 1.1, 0.7, 1.8, 2.4, 2.1, 1.5, 2.9];
+    let observations = walk_observations(&ys).into_iter().map(|c| vec![c]).collect::<Vec<Vec<DynTrie>>>();
+    let expected = walk_posterior_mean(&ys);
+
+    for ancestor_sampling in [false, true] {
+        let mut rng = StdRng::seed_from_u64(27);
+        let mut trace = walk_model.generate(&mut rng, (ys.len() as i64, 0.), observations.concat()).0;
+        let mut reference = DynUnfold::latent_choices(&trace, &observations);
+        let mut mean = DVector::zeros(ys.len());
+        let (burn_in, num_iters) = (50, 500);
+        for iter in 0..burn_in + num_iters {
+            trace = particle_gibbs(&mut rng, &walk_model, 0., &observations, &reference, 20, ancestor_sampling).unwrap();
+            reference = DynUnfold::latent_choices(&trace, &observations);
+            if iter >= burn_in {
+                mean += DVector::from_column_slice(trace.retv.as_ref().unwrap()) / num_iters as f64;
+            }
+        }
+        approx::assert_abs_diff_eq!(mean, expected, epsilon = 0.15);
+    }
+}
+
+#[test]
+fn test_particle_gibbs_static_parameters() {
+    let ys = [0.8, 1.1, 2.7, 3.2, 4.6, 5.1, 6.5, 7.2, 8.9, 9.4];
+    let n = ys.len();
+    let observations = walk_observations(&ys).into_iter().map(|c| vec![c]).collect::<Vec<Vec<DynTrie>>>();
+
+    // exact posterior mean of the drift (with a standard normal prior), as x_t = (t+1)*mu + (a random walk)
+    let steps = DVector::from_fn(n, |i, _| (i + 1) as f64);
+    let cov_y = &steps * steps.transpose() + DMatrix::from_fn(n, n, |i, j| 1. + i.min(j) as f64) + DMatrix::identity(n, n) * 0.25;
+    let expected = (steps.transpose() * cov_y.try_inverse().unwrap() * DVector::from_column_slice(&ys))[0];
+
+    let mut rng = StdRng::seed_from_u64(28);
+    let mut mu = 0.;
+    let mut trace = drift_model.generate(&mut rng, (n as i64, (0., mu)), observations.concat()).0;
+    let mut mean_mu = 0.;
+    let (burn_in, num_iters) = (50, 500);
+    for iter in 0..burn_in + num_iters {
+        // update the latent walk given the drift
+        let reference = DynUnfold::latent_choices(&trace, &observations);
+        trace = particle_gibbs(&mut rng, &drift_model, (0., mu), &observations, &reference, 20, true).unwrap();
+
+        // update the drift given the latent walk (conjugate to the increments)
+        let xs = trace.retv.as_ref().unwrap().iter().map(|(x, _)| *x).collect::<Vec<f64>>();
+        let increments = xs[0] + xs.windows(2).map(|w| w[1] - w[0]).sum::<f64>();
+        let precision = 1. + n as f64;
+        mu = normal.random(&mut rng, (increments / precision, precision.powf(-0.5)));
+        if iter >= burn_in {
+            mean_mu += mu / num_iters as f64;
+        }
+    }
+    approx::assert_abs_diff_eq!(mean_mu, expected, epsilon = 0.05);
+}
+
+#[test]
+fn test_assess_step() {
+    let mut rng = StdRng::seed_from_u64(29);
+    let mut choices = DynTrie::new();
+    choices.observe("x", Arc::new(1.2));
+    choices.observe("y", Arc::new(0.9));
+    let (weight, state) = walk_model.assess_step(&mut rng, 3, 0.5, choices.clone());
+    assert_eq!(state, 1.2);
+    let expected = normal.logpdf(&1.2, (0.5, 1.)) + normal.logpdf(&0.9, (1.2, 0.5));
+    approx::assert_abs_diff_eq!(weight, expected, epsilon = 1e-10);
+
+    // agrees with the choices of a trace
+    let trace = walk_model.simulate(&mut rng, (4, 0.));
+    let xs = trace.retv.as_ref().unwrap();
+    let (weight, state) = walk_model.assess_step(&mut rng, 2, xs[1], trace.data[2].clone());
+    assert_eq!(state, xs[2]);
+    approx::assert_abs_diff_eq!(weight, trace.data[2].weight(), epsilon = 1e-10);
+
+    let mut choices = DynTrie::new();
+    choices.observe("z", Arc::new(0.));
+    assert!(walk_model.try_assess_step(&mut rng, 0, 0., choices).is_err());
+}
+
+#[test]
+fn test_backward_simulation() {
+    let ys = (0..20).map(|t| (t as f64 / 4.).sin() * 2.).collect::<Vec<f64>>();
+    let mut data_it = walk_observations(&ys).into_iter();
+
+    let mut filter = ParticleSystem::new(walk_model, 200, StdRng::seed_from_u64(30)).with_history();
+    filter.init_step(0., vec![data_it.next().unwrap()]).unwrap();
+    filter.resample();
+    for constraints in data_it {
+        filter.step(vec![constraints]).unwrap();
+        filter.resample();
+    }
+    assert_eq!(filter.history().unwrap().len(), ys.len());
+
+    let mut rng = StdRng::seed_from_u64(31);
+    let trajectories = backward_simulation(&mut rng, &filter, 300).unwrap();
+    assert_eq!(trajectories.len(), 300);
+    assert!(trajectories.iter().all(|xs| xs.len() == ys.len()));
+
+    // the smoothed trajectories match the exact posterior mean
+    let mean = trajectories.iter()
+        .map(|xs| DVector::from_column_slice(xs))
+        .sum::<DVector<f64>>() / 300.;
+    approx::assert_abs_diff_eq!(mean, walk_posterior_mean(&ys), epsilon = 0.15);
+
+    // and (unlike the filter's lineages) keep many distinct states at the first step
+    let mut first_states = trajectories.iter().map(|xs| xs[0]).collect::<Vec<f64>>();
+    first_states.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    first_states.dedup();
+    assert!(first_states.len() > 10 * filter.num_unique_ancestors()[0]);
+
+    // without a history, there's nothing to smooth
+    let filter = ParticleSystem::new(walk_model, 10, StdRng::seed_from_u64(32));
+    assert!(filter.history().is_none());
+    assert!(matches!(backward_simulation(&mut rng, &filter, 1), Err(GenFnError::InvalidArgs(_))));
+    let filter = ParticleSystem::new(walk_model, 10, StdRng::seed_from_u64(33)).with_history();
+    assert!(matches!(backward_simulation(&mut rng, &filter, 1), Err(GenFnError::InvalidArgs(_))));
+}
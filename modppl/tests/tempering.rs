@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use nalgebra::{DMatrix, DVector};
+
+use modppl::prelude::*;
+use modppl::logsumexp;
+
+mod pointed_model;
+
+mod dyngenfns;
+use dyngenfns::{hierarchical_model, hierarchical_drift_proposal, add_or_remove_param_proposal};
+
+
+const XS: [f64; 11] = [-5.,-4.,-3.,-2.,-1.,0.,1.,2.,3.,4.,5.];
+
+fn hierarchical_observations(rng: &mut StdRng) -> (Vec<f64>, DynTrie) {
+    let (a, b, c) = (0.3, 0.4, 0.01);
+    let ys = XS.iter().map(|x|
+        a + b*x + c*x*x + normal.random(rng, (0., 0.1))
+    ).collect::<Vec<f64>>();
+    let mut observations = DynTrie::new();
+    ys.iter().enumerate().for_each(|(i, y)| { observations.observe(("y", i as i64), Arc::new(*y)); });
+    (ys, observations)
+}
+
+// exact log marginal likelihood and posterior probability of the linear model,
+// as the coefficients have standard normal priors and the noise is gaussian
+fn hierarchical_evidence(ys: &[f64]) -> (f64, f64) {
+    let ys = DVector::from_column_slice(ys);
+    let log_evidence = |degree: usize| {
+        let design = DMatrix::from_fn(XS.len(), degree + 1, |i, j| XS[i].powi(j as i32));
+        let cov = &design * design.transpose() + DMatrix::identity(XS.len(), XS.len()) * 0.01;
+        mvnormal.logpdf(&ys, (DVector::zeros(XS.len()), cov))
+    };
+    let linear = 0.7_f64.ln() + log_evidence(1);
+    let quadratic = 0.3_f64.ln() + log_evidence(2);
+    let lml = logsumexp(&vec![linear, quadratic]);
+    (lml, (linear - lml).exp())
+}
+
+fn temperatures(num_temperatures: usize) -> Vec<f64> {
+    (1..=num_temperatures).map(|k| (k as f64 / num_temperatures as f64).powi(4)).collect()
+}
+
+type HierarchicalTrace = DynTrace<Vec<f64>,Vec<f64>>;
+
+// a jump between the linear and quadratic models, followed by drift moves at decreasing scales
+fn hierarchical_kernel(rng: &mut dyn RngCore, mut trace: HierarchicalTrace, observations: &AddrMap, temperature: f64) -> Result<(HierarchicalTrace,bool),GenFnError> {
+    (trace, _) = tempered_mh(rng, &hierarchical_model, trace, &add_or_remove_param_proposal, (), observations, temperature)?;
+    for drift_std in [0.1, 0.01, 0.002] {
+        (trace, _) = tempered_mh(rng, &hierarchical_model, trace, &hierarchical_drift_proposal, drift_std, observations, temperature)?;
+    }
+    Ok((trace, true))
+}
+
+fn probability_linear(traces: &[HierarchicalTrace], log_normalized_weights: &[f64]) -> f64 {
+    traces.iter()
+        .zip(log_normalized_weights)
+        .filter(|(trace, _)| trace.data.read::<bool>("is_linear"))
+        .map(|(_, w)| w.exp())
+        .sum()
+}
+
+
+#[test]
+fn test_tempered_smc() {
+    let mut rng = StdRng::seed_from_u64(40);
+    let (ys, observations) = hierarchical_observations(&mut rng);
+    let (expected_lml, expected_linear) = hierarchical_evidence(&ys);
+    let schema = observations.schema();
+
+    let temperatures = temperatures(50);
+    let (traces, log_normalized_weights, summary) = tempered_smc(
+        &mut rng, &hierarchical_model, XS.to_vec(), observations, 200, &temperatures, 0.5, |rng, trace, temperature| hierarchical_kernel(rng, trace, &schema, temperature)
+    ).unwrap();
+    assert_eq!(traces.len(), 200);
+    assert_eq!(summary.num_steps(), temperatures.len());
+    assert!(summary.num_resamples() > 0);
+    approx::assert_abs_diff_eq!(summary.log_ml_increments.iter().sum::<f64>(), summary.log_ml_estimate, epsilon = 1e-8);
+    approx::assert_abs_diff_eq!(summary.log_ml_estimate, expected_lml, epsilon = 0.5);
+    approx::assert_abs_diff_eq!(probability_linear(&traces, &log_normalized_weights), expected_linear, epsilon = 0.15);
+
+    // the temperatures must increase from above 0 to 1
+    for temperatures in [vec![], vec![0.5, 0.9], vec![0., 0.5, 1.], vec![0.5, 0.5, 1.], vec![0.5, f64::NAN, 1.]] {
+        let result = tempered_smc(
+            &mut rng, &hierarchical_model, XS.to_vec(), DynTrie::new(), 10, &temperatures, 0.5, |_, trace, _| Ok((trace, false))
+        );
+        assert!(matches!(result, Err(GenFnError::InvalidArgs(_))));
+    }
+}
+
+#[test]
+fn test_annealed_importance_sampling() {
+    let mut rng = StdRng::seed_from_u64(41);
+    let (ys, observations) = hierarchical_observations(&mut rng);
+    let (expected_lml, _) = hierarchical_evidence(&ys);
+    let schema = observations.schema();
+
+    let (traces, log_normalized_weights, summary) = annealed_importance_sampling(
+        &mut rng, &hierarchical_model, XS.to_vec(), observations, 100, &temperatures(60), |rng, trace, temperature| hierarchical_kernel(rng, trace, &schema, temperature)
+    ).unwrap();
+    assert_eq!(traces.len(), 100);
+    assert_eq!(summary.num_resamples(), 0);
+    approx::assert_abs_diff_eq!(log_normalized_weights.iter().map(|w| w.exp()).sum::<f64>(), 1., epsilon = 1e-8);
+    approx::assert_abs_diff_eq!(summary.log_ml_estimate, expected_lml, epsilon = 1.);
+}
+
+#[test]
+fn test_tempered_regen_mh() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let (_, observations) = hierarchical_observations(&mut rng);
+    let schema = observations.schema();
+    let mut mask = AddrMap::new();
+    mask.visit("coeffs / a");
+
+    // at temperature zero the observations are ignored, so the prior is invariant
+    let mut trace = hierarchical_model.generate(&mut rng, XS.to_vec(), observations).0;
+    let mut num_accepted = 0;
+    let mut samples = vec![];
+    for _ in 0..2000 {
+        let accepted;
+        (trace, accepted) = tempered_regen_mh(&mut rng, &hierarchical_model, trace, &mask, &schema, 0.).unwrap();
+        num_accepted += accepted as usize;
+        samples.push(trace.data.read::<f64>("coeffs / a"));
+    }
+    assert_eq!(num_accepted, 2000);
+    let mean = samples.iter().sum::<f64>() / 2000.;
+    let var = samples.iter().map(|a| (a - mean).powi(2)).sum::<f64>() / 2000.;
+    approx::assert_abs_diff_eq!(mean, 0., epsilon = 0.1);
+    approx::assert_abs_diff_eq!(var, 1., epsilon = 0.15);
+}
@@ -1,4 +1,4 @@
-use modppl::Trie;
+use modppl::{Trie,GenFnError,Addr,AddrKey,Merge};
 
 
 // inserting a trie into a root and then removing it should yield the previous tries
@@ -89,6 +89,110 @@ pub fn test_insert_into_occupied_panic() {
     root.w_observe("some/address", (-1,0), 0.);
 }
 
+// try_w_observeing an occupied address should return an error and leave the trie unchanged
+#[test]
+pub fn test_try_insert_into_occupied_err() {
+    let mut root = Trie::<(i32,u8)>::new();
+    root.w_observe("some/address", (-10431451, 200), -0.5);
+    let root_before = root.clone();
+    let result = root.try_w_observe("some/address", (-1,0), 3.);
+    assert_eq!(result, Err(GenFnError::AddressCollision(String::from("some / address"))));
+    assert_eq!(root, root_before);
+    assert_eq!(root.weight(), -0.5);
+}
+
+// searching beneath an unoccupied prefix should yield nothing
+#[test]
+pub fn test_search_missing_prefix() {
+    let mut root = Trie::<i32>::new();
+    root.observe("some/address", 1);
+    assert!(root.search("other/address").is_none());
+    assert!(root.search("some/address/deeper").is_none());
+}
+
+// structured addresses and their string forms should identify the same node
+#[test]
+pub fn test_structured_addr_matches_string() {
+    let mut root = Trie::<i32>::new();
+    for i in 0..3_usize {
+        let mut addr = Addr::from("ys");
+        addr.push(("y", i));
+        root.w_observe(addr, i as i32, 0.5);
+    }
+    assert_eq!(root.search("ys / (y, 2)").and_then(|sub| sub.ref_inner()), Some(&2));
+    assert_eq!(root.search("ys").unwrap().len(), 3);
+    assert!(root.search("ys").unwrap().iter().all(|(key, _)| matches!(key, AddrKey::Tuple(_))));
+    assert_eq!(root.remove("ys/(y,1)").map(|sub| sub.weight()), Some(0.5));
+    assert_eq!(root.weight(), 1.);
+    assert_eq!(root.try_observe(("ys", 0), 5), Ok(()));
+    assert_eq!(
+        root.try_observe("ys / (y, 0)", 5),
+        Err(GenFnError::AddressCollision(String::from("ys / (y, 0)")))
+    );
+}
+
+// merging disjoint tries should union their leaves and sum their weights
+#[test]
+pub fn test_try_merge() {
+    let mut left = Trie::<i32>::new();
+    left.w_observe("a / x", 1, 0.5);
+    left.w_observe("b", 2, 1.);
+    let mut right = Trie::<i32>::new();
+    right.w_observe("a / y", 3, -0.25);
+    right.w_observe("c", 4, 2.);
+
+    let merged = left.clone().try_merge(right.clone()).unwrap();
+    assert_eq!(merged.weight(), 3.25);
+    assert_eq!(merged.search("a").unwrap().weight(), 0.25);
+    assert_eq!(merged.search("a / y").and_then(|sub| sub.ref_inner()), Some(&3));
+    assert_eq!(merged.search("c").and_then(|sub| sub.ref_inner()), Some(&4));
+
+    right.w_observe("a / x", 5, 0.);
+    assert_eq!(left.try_merge(right), Err(GenFnError::AddressCollision(String::from("a / x"))));
+}
+
+// merging overlapping tries should keep the values and weights of the merged trie
+#[test]
+pub fn test_merge_prefers_other() {
+    let mut left = Trie::<i32>::new();
+    left.w_observe("a / x", 1, 0.5);
+    left.w_observe("b", 2, 1.);
+    let mut right = Trie::<i32>::new();
+    right.w_observe("a / x", 3, 0.25);
+    right.w_observe("a / y", 4, 2.);
+    left.merge(right);
+    assert_eq!(left.search("a / x").and_then(|sub| sub.ref_inner()), Some(&3));
+    assert_eq!(left.search("a / y").and_then(|sub| sub.ref_inner()), Some(&4));
+    assert_eq!(left.search("a").unwrap().weight(), 2.25);
+    assert_eq!(left.weight(), 3.25);
+}
+
+// plain names shouldn't be parsed as integers, tuples or paths
+#[test]
+pub fn test_addr_key_name() {
+    assert_eq!(AddrKey::name("3"), AddrKey::Str("3".into()));
+    assert_eq!(AddrKey::from("3"), AddrKey::Int(3));
+    let mut root = Trie::<i32>::new();
+    root.observe(AddrKey::name("a / b"), 1);
+    assert_eq!(root.len(), 1);
+    assert!(root.search("a / b").is_none());
+    assert_eq!(root.search(AddrKey::name("a / b")).and_then(|sub| sub.ref_inner()), Some(&1));
+}
+
+// unsigned integer components convert without wrapping around
+#[test]
+pub fn test_addr_key_from_unsigned() {
+    assert_eq!(AddrKey::from(("y", 3_usize)), AddrKey::from(("y", 3)));
+    assert_eq!(AddrKey::from(i64::MAX as u64), AddrKey::Int(i64::MAX));
+}
+
+// unsigned integer components that don't fit in an `i64` should panic
+#[test]
+#[should_panic(expected = "integer address components must fit in an i64")]
+pub fn test_addr_key_from_unsigned_overflow_panic() {
+    let _ = AddrKey::from(u64::MAX);
+}
+
 // unwrapping the inner value from an empty trie should panic
 #[test]
 #[should_panic]